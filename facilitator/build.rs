@@ -1,7 +1,26 @@
 extern crate vergen;
 
+use serde_json::{Map, Value};
+use std::{env, fmt::Write, fs, path::Path};
 use vergen::{generate_cargo_keys, ConstantsFlags};
 
+/// The Avro schemas from which record types are generated, and the names of
+/// the generated types.
+const RECORDS: &[(&str, &str)] = &[
+    ("batch-signature.avsc", "BatchSignature"),
+    ("batch-manifest.avsc", "BatchManifest"),
+    ("ingestion-header.avsc", "IngestionHeader"),
+    (
+        "ingestion-data-share-packet.avsc",
+        "IngestionDataSharePacket",
+    ),
+    ("validation-header.avsc", "ValidationHeader"),
+    ("validation-packet.avsc", "ValidationPacket"),
+    ("sum-part.avsc", "SumPart"),
+    ("invalid-packet.avsc", "InvalidPacket"),
+    ("invalid-uuid-set.avsc", "InvalidUuidSet"),
+];
+
 fn main() {
     generate_cargo_keys(
         ConstantsFlags::SHA_SHORT
@@ -9,4 +28,326 @@ fn main() {
             | ConstantsFlags::BUILD_TIMESTAMP,
     )
     .expect("Unable to generate cargo keys");
+
+    let schema_dir = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("../avro-schema");
+    let mut code = String::new();
+    for (file, type_name) in RECORDS {
+        let path = schema_dir.join(file);
+        println!("cargo:rerun-if-changed={}", path.display());
+        let schema: Value = serde_json::from_str(
+            &fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("failed to read {}: {}", path.display(), e)),
+        )
+        .unwrap_or_else(|e| panic!("failed to parse {}: {}", path.display(), e));
+        generate_record(&mut code, &path, type_name, &schema)
+            .unwrap_or_else(|e| panic!("failed to generate {}: {}", type_name, e));
+    }
+
+    let out_path = Path::new(&env::var("OUT_DIR").unwrap()).join("idl_records.rs");
+    fs::write(&out_path, code).expect("failed to write generated records");
+}
+
+/// The Avro types that may appear in the fields of our records, along with the
+/// logical types we map to richer Rust types.
+enum AvroType {
+    Int,
+    Long,
+    TimestampMillis,
+    Boolean,
+    Double,
+    String,
+    Uuid,
+    Bytes,
+    /// A union of null and another type.
+    Nullable(Box<AvroType>),
+    Array(Box<AvroType>),
+}
+
+impl AvroType {
+    /// Parses a type, given the JSON object in which it appears as "type".
+    /// Following the Avro specification as avro-rs implements it, a
+    /// logicalType may appear alongside a primitive type in a record field as
+    /// well as in a type object.
+    fn parse(object: &Map<String, Value>) -> Result<AvroType, String> {
+        let logical_type = object.get("logicalType").and_then(Value::as_str);
+        match (object.get("type"), logical_type) {
+            (Some(Value::String(t)), Some(logical_type)) => match (t.as_str(), logical_type) {
+                ("string", "uuid") => Ok(AvroType::Uuid),
+                ("long", "timestamp-millis") => Ok(AvroType::TimestampMillis),
+                _ => Err(format!(
+                    "unsupported logical type {} of {}",
+                    logical_type, t
+                )),
+            },
+            (Some(t), _) => AvroType::parse_type(t),
+            (None, _) => Err("missing type".to_owned()),
+        }
+    }
+
+    fn parse_type(value: &Value) -> Result<AvroType, String> {
+        match value {
+            Value::String(t) => match t.as_str() {
+                "int" => Ok(AvroType::Int),
+                "long" => Ok(AvroType::Long),
+                "boolean" => Ok(AvroType::Boolean),
+                "double" => Ok(AvroType::Double),
+                "string" => Ok(AvroType::String),
+                "bytes" => Ok(AvroType::Bytes),
+                _ => Err(format!("unsupported type {}", t)),
+            },
+            Value::Array(variants) => {
+                let non_null: Vec<_> = variants.iter().filter(|v| *v != "null").collect();
+                if variants.len() != 2 || non_null.len() != 1 {
+                    return Err(format!("unsupported union {:?}", variants));
+                }
+                Ok(AvroType::Nullable(Box::new(AvroType::parse_type(
+                    non_null[0],
+                )?)))
+            }
+            Value::Object(object) => match object.get("type").and_then(Value::as_str) {
+                Some("array") => Ok(AvroType::Array(Box::new(AvroType::parse_type(
+                    object.get("items").ok_or("array without items")?,
+                )?))),
+                _ => AvroType::parse(object),
+            },
+            _ => Err(format!("unsupported type {}", value)),
+        }
+    }
+
+    fn rust_type(&self) -> String {
+        match self {
+            AvroType::Int => "i32".to_owned(),
+            AvroType::Long | AvroType::TimestampMillis => "i64".to_owned(),
+            AvroType::Boolean => "bool".to_owned(),
+            AvroType::Double => "f64".to_owned(),
+            AvroType::String => "String".to_owned(),
+            AvroType::Uuid => "Uuid".to_owned(),
+            AvroType::Bytes => "Vec<u8>".to_owned(),
+            AvroType::Nullable(t) => format!("Option<{}>", t.rust_type()),
+            AvroType::Array(t) => format!("Vec<{}>", t.rust_type()),
+        }
+    }
+
+    fn contains_double(&self) -> bool {
+        match self {
+            AvroType::Double => true,
+            AvroType::Nullable(t) | AvroType::Array(t) => t.contains_double(),
+            _ => false,
+        }
+    }
+
+    /// Returns an expression converting expr to an avro_rs::types::Value.
+    /// If is_ref, expr is a reference, otherwise it is a place expression.
+    fn to_value(&self, expr: &str, is_ref: bool) -> String {
+        let copy = if is_ref {
+            format!("*{}", expr)
+        } else {
+            expr.to_owned()
+        };
+        let borrow = if is_ref {
+            expr.to_owned()
+        } else {
+            format!("&{}", expr)
+        };
+        match self {
+            AvroType::Int => format!("Value::Int({})", copy),
+            AvroType::Long => format!("Value::Long({})", copy),
+            AvroType::TimestampMillis => format!("Value::TimestampMillis({})", copy),
+            AvroType::Boolean => format!("Value::Boolean({})", copy),
+            AvroType::Double => format!("Value::Double({})", copy),
+            AvroType::Uuid => format!("Value::Uuid({})", copy),
+            AvroType::String => format!("Value::String({}.clone())", expr),
+            AvroType::Bytes => format!("Value::Bytes({}.clone())", expr),
+            AvroType::Nullable(t) => format!(
+                "Value::Union(Box::new(match {} {{ Some(v) => {}, None => Value::Null }}))",
+                borrow,
+                t.to_value("v", true)
+            ),
+            AvroType::Array(t) => format!(
+                "Value::Array({}.iter().map(|v| {}).collect())",
+                expr,
+                t.to_value("v", true)
+            ),
+        }
+    }
+
+    /// Returns an expression converting expr, an avro_rs::types::Value, to a
+    /// Result holding the Rust type or an error message.
+    fn parse_value(&self, expr: &str, field: &str) -> String {
+        let unexpected = format!(
+            "v => Err(format!(\"unexpected value {{:?}} for {}\", v))",
+            field
+        );
+        match self {
+            AvroType::Int => format!("match {} {{ Value::Int(v) => Ok(v), {} }}", expr, unexpected),
+            AvroType::Long => format!("match {} {{ Value::Long(v) => Ok(v), {} }}", expr, unexpected),
+            AvroType::TimestampMillis => format!(
+                "match {} {{ Value::TimestampMillis(v) => Ok(v), {} }}",
+                expr, unexpected
+            ),
+            AvroType::Boolean => format!(
+                "match {} {{ Value::Boolean(v) => Ok(v), {} }}",
+                expr, unexpected
+            ),
+            AvroType::Double => format!(
+                "match {} {{ Value::Double(v) => Ok(v), {} }}",
+                expr, unexpected
+            ),
+            AvroType::String => format!(
+                "match {} {{ Value::String(v) => Ok(v), {} }}",
+                expr, unexpected
+            ),
+            AvroType::Uuid => format!("match {} {{ Value::Uuid(v) => Ok(v), {} }}", expr, unexpected),
+            AvroType::Bytes => format!(
+                "match {} {{ Value::Bytes(v) => Ok(v), {} }}",
+                expr, unexpected
+            ),
+            AvroType::Nullable(t) => format!(
+                "match {} {{ Value::Union(boxed) => match *boxed {{ Value::Null => Ok(None), v => ({}).map(Some) }}, {} }}",
+                expr,
+                t.parse_value("v", field),
+                unexpected
+            ),
+            AvroType::Array(t) => format!(
+                "match {} {{ Value::Array(values) => values.into_iter().map(|v| {}).collect::<Result<Vec<_>, String>>(), {} }}",
+                expr,
+                t.parse_value("v", field),
+                unexpected
+            ),
+        }
+    }
+}
+
+fn generate_record(
+    code: &mut String,
+    path: &Path,
+    type_name: &str,
+    schema: &Value,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let schema = schema.as_object().ok_or("schema is not an object")?;
+    let const_name = format!(
+        "{}_SCHEMA",
+        path.file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or("bad schema file name")?
+            .replace('-', "_")
+            .to_uppercase()
+    );
+    let mut fields = Vec::new();
+    for field in schema
+        .get("fields")
+        .and_then(Value::as_array)
+        .ok_or("schema has no fields")?
+    {
+        let field = field.as_object().ok_or("field is not an object")?;
+        let name = field
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or("field has no name")?;
+        let doc = field.get("doc").and_then(Value::as_str);
+        fields.push((name, doc, AvroType::parse(field)?));
+    }
+    let is_eq = !fields.iter().any(|(_, _, t)| t.contains_double());
+
+    writeln!(
+        code,
+        "pub(crate) const {}: &str = include_str!({:?});\n",
+        const_name,
+        path.display().to_string()
+    )?;
+
+    if let Some(doc) = schema.get("doc").and_then(Value::as_str) {
+        writeln!(code, "#[doc = {:?}]", doc)?;
+    }
+    writeln!(
+        code,
+        "#[derive(Clone, Debug, Deserialize, Serialize, PartialEq{})]",
+        if is_eq { ", Eq" } else { "" }
+    )?;
+    writeln!(code, "pub struct {} {{", type_name)?;
+    for (name, doc, avro_type) in &fields {
+        if let Some(doc) = doc {
+            writeln!(code, "    #[doc = {:?}]", doc)?;
+        }
+        writeln!(code, "    pub {}: {},", name, avro_type.rust_type())?;
+    }
+    writeln!(code, "}}\n")?;
+
+    writeln!(code, "impl AvroRecord for {} {{", type_name)?;
+    writeln!(
+        code,
+        "    fn schema_raw() -> &'static str {{ {} }}\n",
+        const_name
+    )?;
+    writeln!(code, "    fn avro_schema() -> &'static Schema {{")?;
+    writeln!(
+        code,
+        "        static SCHEMA: Lazy<Schema> = Lazy::new(|| Schema::parse_str({}).unwrap());",
+        const_name
+    )?;
+    writeln!(code, "        &SCHEMA")?;
+    writeln!(code, "    }}\n")?;
+    writeln!(code, "    fn to_avro_value(&self) -> Value {{")?;
+    writeln!(code, "        Value::Record(vec![")?;
+    for (name, _, avro_type) in &fields {
+        writeln!(
+            code,
+            "            ({:?}.to_owned(), {}),",
+            name,
+            avro_type.to_value(&format!("self.{}", name), false)
+        )?;
+    }
+    writeln!(code, "        ])")?;
+    writeln!(code, "    }}\n")?;
+
+    writeln!(
+        code,
+        "    fn from_avro_value(value: Value) -> Result<Self, String> {{"
+    )?;
+    writeln!(code, "        let record = match value {{")?;
+    writeln!(code, "            Value::Record(record) => record,")?;
+    writeln!(
+        code,
+        "            _ => return Err(\"value is not a record\".to_owned()),"
+    )?;
+    writeln!(code, "        }};")?;
+    for (name, _, _) in &fields {
+        writeln!(code, "        let mut {} = None;", name)?;
+    }
+    writeln!(code, "        for (field, value) in record {{")?;
+    writeln!(code, "            match field.as_str() {{")?;
+    for (name, _, avro_type) in &fields {
+        writeln!(
+            code,
+            "                {:?} => {} = Some({}?),",
+            name,
+            name,
+            avro_type.parse_value("value", name)
+        )?;
+    }
+    writeln!(
+        code,
+        "                f => return Err(format!(\"unexpected field {{}} in record\", f)),"
+    )?;
+    writeln!(code, "            }}")?;
+    writeln!(code, "        }}")?;
+    writeln!(code, "        Ok({} {{", type_name)?;
+    for (name, _, avro_type) in &fields {
+        match avro_type {
+            // Nullable fields may be missing from records written with older
+            // versions of a schema.
+            AvroType::Nullable(_) => {
+                writeln!(code, "            {}: {}.unwrap_or(None),", name, name)?
+            }
+            _ => writeln!(
+                code,
+                "            {}: {}.ok_or(\"missing field {} in record\")?,",
+                name, name, name
+            )?,
+        }
+    }
+    writeln!(code, "        }})")?;
+    writeln!(code, "    }}")?;
+    writeln!(code, "}}\n")?;
+    Ok(())
 }
@@ -0,0 +1,177 @@
+//! Compares the size of the packet files facilitator writes, and the CPU time
+//! spent writing and reading them, under each Avro codec, to help choose the
+//! values of --validation-codec and --invalid-packet-codec.
+//!
+//! The validation packets are those generated by intake of a sample ingestion
+//! batch. The invalid packets are the UUIDs of every packet in that batch, as
+//! if all of them had failed validation, which is the worst case.
+//!
+//! Run with:
+//!
+//!     cargo run --release --example packet_file_codecs -- [PACKETS] [DIMENSION]
+//!
+//! PACKETS defaults to 10000 and DIMENSION to 100.
+
+use anyhow::Result;
+use avro_rs::{Codec, Writer};
+use chrono::NaiveDateTime;
+use facilitator::{
+    batch::{Batch, BatchReader},
+    idl::{resolving_reader, InvalidPacket, Packet, ValidationHeader, ValidationPacket},
+    intake::BatchIntaker,
+    sample::generate_ingestion_sample,
+    test_utils::{
+        default_facilitator_signing_private_key, default_facilitator_signing_public_key,
+        default_ingestor_private_key, default_ingestor_public_key,
+        DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY, DEFAULT_PHA_ECIES_PRIVATE_KEY,
+    },
+    transport::{
+        LocalFileTransport, SignableTransport, VerifiableAndDecryptableTransport,
+        VerifiableTransport,
+    },
+};
+use prio::encrypt::PrivateKey;
+use std::{collections::HashMap, env, time::Instant};
+use uuid::Uuid;
+
+/// How many times each packet file is encoded and decoded. The reported times
+/// are the means.
+const ROUNDS: u32 = 10;
+
+fn main() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let packet_count = args.next().map_or(Ok(10000), |v| v.parse())?;
+    let dimension = args.next().map_or(Ok(100), |v| v.parse())?;
+
+    let validation_packets = sample_validation_packets(packet_count, dimension)?;
+    let invalid_packets: Vec<_> = validation_packets
+        .iter()
+        .map(|p| InvalidPacket { uuid: p.uuid })
+        .collect();
+
+    println!(
+        "{} packets, dimension {}, mean of {} rounds\n",
+        packet_count, dimension, ROUNDS
+    );
+    println!(
+        "{:<16} {:<8} {:>12} {:>7} {:>12} {:>12}",
+        "packet file", "codec", "bytes", "ratio", "write ms", "read ms"
+    );
+    compare_codecs("validation", &validation_packets)?;
+    compare_codecs("invalid-packet", &invalid_packets)?;
+    Ok(())
+}
+
+/// Generates a sample ingestion batch and runs intake on it as the PHA,
+/// returning the validation packets it emits.
+fn sample_validation_packets(packet_count: usize, dimension: i32) -> Result<Vec<ValidationPacket>> {
+    let pha_tempdir = tempfile::TempDir::new()?;
+    let facilitator_tempdir = tempfile::TempDir::new()?;
+    let aggregation_name = "fake-aggregation";
+    let batch_uuid = Uuid::new_v4();
+    let date = NaiveDateTime::from_timestamp(1234567890, 0);
+
+    generate_ingestion_sample(
+        &mut LocalFileTransport::new(pha_tempdir.path().to_path_buf()),
+        &mut LocalFileTransport::new(facilitator_tempdir.path().to_path_buf()),
+        &batch_uuid,
+        aggregation_name,
+        &date,
+        &PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
+        &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+        &default_ingestor_private_key(),
+        dimension,
+        packet_count,
+        0.11,
+        100,
+        100,
+        None,
+    )?;
+
+    let mut ingestor_public_keys = HashMap::new();
+    ingestor_public_keys.insert(
+        default_ingestor_private_key().identifier,
+        default_ingestor_public_key(),
+    );
+    let mut ingestion_transport = VerifiableAndDecryptableTransport {
+        transport: VerifiableTransport {
+            transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
+            batch_signing_public_keys: ingestor_public_keys,
+        },
+        packet_decryption_keys: vec![
+            PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap()
+        ],
+    };
+    let mut validation_transport = SignableTransport {
+        transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
+        batch_signer: Box::new(default_facilitator_signing_private_key()),
+        sign_batch_manifest: false,
+        packet_file_codec: Codec::Null,
+    };
+    BatchIntaker::new(
+        aggregation_name,
+        &batch_uuid,
+        &date,
+        &mut ingestion_transport,
+        &mut validation_transport,
+        0,
+    )?
+    .generate_validation_share()?;
+
+    let mut transport = LocalFileTransport::new(pha_tempdir.path().to_path_buf());
+    let mut public_keys = HashMap::new();
+    public_keys.insert(
+        default_facilitator_signing_private_key().identifier,
+        default_facilitator_signing_public_key(),
+    );
+    let mut reader: BatchReader<'_, ValidationHeader, ValidationPacket> = BatchReader::new(
+        Batch::new_validation(aggregation_name, &batch_uuid, &date, 0),
+        &mut transport,
+    );
+    let header = reader.header(&public_keys)?;
+    let mut packet_reader = reader.packet_file_reader(&header)?;
+    (0..packet_count)
+        .map(|_| Ok(ValidationPacket::read(&mut packet_reader)?))
+        .collect()
+}
+
+/// Writes and reads the packets with each codec, printing a row of results for
+/// each.
+fn compare_codecs<P: Packet + PartialEq>(name: &str, packets: &[P]) -> Result<()> {
+    let schema = P::schema();
+    let mut uncompressed_size = None;
+    for codec in &[Codec::Null, Codec::Deflate, Codec::Snappy] {
+        let start = Instant::now();
+        let mut packet_file = Vec::new();
+        for _ in 0..ROUNDS {
+            let mut writer = Writer::with_codec(&schema, Vec::new(), *codec);
+            for packet in packets {
+                packet.write(&mut writer)?;
+            }
+            packet_file = writer.into_inner()?;
+        }
+        let write_time = start.elapsed() / ROUNDS;
+
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            let mut reader = resolving_reader(&schema, &packet_file[..])?;
+            for packet in packets {
+                assert!(P::read(&mut reader)? == *packet);
+            }
+        }
+        let read_time = start.elapsed() / ROUNDS;
+
+        let size = packet_file.len();
+        let uncompressed_size = *uncompressed_size.get_or_insert(size);
+        println!(
+            "{:<16} {:<8} {:>12} {:>7.3} {:>12.2} {:>12.2}",
+            name,
+            <&str>::from(*codec),
+            size,
+            size as f64 / uncompressed_size as f64,
+            write_time.as_secs_f64() * 1000.0,
+            read_time.as_secs_f64() * 1000.0,
+        );
+    }
+    Ok(())
+}
@@ -1,28 +1,49 @@
 use crate::{
-    batch::{Batch, BatchReader, BatchWriter},
+    batch::{transport_error, Batch, BatchReader, BatchWriter},
     idl::{
-        IngestionDataSharePacket, IngestionHeader, InvalidPacket, Packet, SumPart,
+        IngestionDataSharePacket, IngestionHeader, InvalidPacket, InvalidUuidSet, Packet, SumPart,
         ValidationHeader, ValidationPacket,
     },
-    transport::{SignableTransport, VerifiableAndDecryptableTransport, VerifiableTransport},
-    BatchSigningKey, Error,
+    logging::LogContext,
+    metrics::{Registry, StageMetrics},
+    noise::add_discrete_laplace_noise,
+    policy::AggregationPolicy,
+    report::{PacketCounts, StageReport},
+    signing::BatchSigner,
+    transport::{
+        find_ingestor, IngestorTransport, PeerValidationTransport, SignableTransport,
+        VerifiableTransport,
+    },
+    Error, MAX_NUMBER_OF_SERVERS,
+};
+use anyhow::Result;
+use avro_rs::Codec;
+use chrono::{Duration, NaiveDateTime};
+use log::{error, info, warn};
+use prio::{
+    finite_field::Field,
+    server::{Server, VerificationMessage},
 };
-use anyhow::{anyhow, Context, Result};
-use chrono::NaiveDateTime;
-use prio::server::{Server, VerificationMessage};
-use std::convert::TryFrom;
+use rand::thread_rng;
+use std::{collections::HashSet, convert::TryFrom, time::Instant};
 use uuid::Uuid;
 
 pub struct BatchAggregator<'a> {
-    is_first: bool,
+    server_index: usize,
     aggregation_name: &'a str,
     aggregation_start: &'a NaiveDateTime,
     aggregation_end: &'a NaiveDateTime,
     own_validation_transport: &'a mut VerifiableTransport,
-    peer_validation_transport: &'a mut VerifiableTransport,
-    ingestion_transport: &'a mut VerifiableAndDecryptableTransport,
+    peer_validation_transports: &'a mut [PeerValidationTransport],
+    ingestion_transports: &'a mut [IngestorTransport],
     aggregation_batch: BatchWriter<'a, SumPart, InvalidPacket>,
-    share_processor_signing_key: &'a BatchSigningKey,
+    share_processor_signer: &'a dyn BatchSigner,
+    sign_batch_manifest: bool,
+    packet_file_codec: Codec,
+    batch_time_tolerance: Option<Duration>,
+    policy: AggregationPolicy,
+    metrics: StageMetrics,
+    report: StageReport,
 }
 
 impl<'a> BatchAggregator<'a> {
@@ -31,58 +52,230 @@ impl<'a> BatchAggregator<'a> {
         aggregation_name: &'a str,
         aggregation_start: &'a NaiveDateTime,
         aggregation_end: &'a NaiveDateTime,
-        is_first: bool,
-        ingestion_transport: &'a mut VerifiableAndDecryptableTransport,
+        server_index: usize,
+        ingestion_transports: &'a mut [IngestorTransport],
         own_validation_transport: &'a mut VerifiableTransport,
-        peer_validation_transport: &'a mut VerifiableTransport,
+        peer_validation_transports: &'a mut [PeerValidationTransport],
         aggregation_transport: &'a mut SignableTransport,
     ) -> Result<BatchAggregator<'a>> {
+        if peer_validation_transports.len() + 1 > MAX_NUMBER_OF_SERVERS {
+            return Err(Error::ConfigurationError(format!(
+                "{} peers configured but at most {} servers are supported",
+                peer_validation_transports.len(),
+                MAX_NUMBER_OF_SERVERS
+            ))
+            .into());
+        }
+        let mut aggregation_batch = BatchWriter::new(
+            Batch::new_sum(
+                aggregation_name,
+                aggregation_start,
+                aggregation_end,
+                server_index,
+            ),
+            &mut *aggregation_transport.transport,
+        );
+        aggregation_batch.set_sign_batch_manifest(aggregation_transport.sign_batch_manifest);
+        aggregation_batch.set_packet_file_codec(aggregation_transport.packet_file_codec);
         Ok(BatchAggregator {
-            is_first,
+            server_index,
             aggregation_name,
             aggregation_start,
             aggregation_end,
             own_validation_transport,
-            peer_validation_transport,
-            ingestion_transport,
-            aggregation_batch: BatchWriter::new(
-                Batch::new_sum(
-                    aggregation_name,
-                    aggregation_start,
-                    aggregation_end,
-                    is_first,
-                ),
-                &mut *aggregation_transport.transport,
-            ),
-            share_processor_signing_key: &aggregation_transport.batch_signing_key,
+            peer_validation_transports,
+            ingestion_transports,
+            aggregation_batch,
+            share_processor_signer: &*aggregation_transport.batch_signer,
+            sign_batch_manifest: aggregation_transport.sign_batch_manifest,
+            packet_file_codec: aggregation_transport.packet_file_codec,
+            batch_time_tolerance: None,
+            policy: AggregationPolicy::default(),
+            metrics: StageMetrics::default(),
+            report: StageReport::default(),
         })
     }
 
+    /// Configures how far the time range in ingestion batch headers may lie
+    /// outside the batch's date. If None, the time range is not checked.
+    pub fn set_batch_time_tolerance(&mut self, tolerance: Option<Duration>) {
+        self.batch_time_tolerance = tolerance;
+    }
+
+    /// Configures the policy the parameters of each ingestion batch must
+    /// satisfy. If the policy limits batch time skew, time ranges are checked
+    /// with the stricter of that and the batch time tolerance. A policy that
+    /// cross-checks invalid UUIDs is an Error::ConfigurationError unless there
+    /// is exactly one peer, since this server's set is written alongside its
+    /// validations, which reach only that peer.
+    pub fn set_policy(&mut self, policy: AggregationPolicy) -> Result<()> {
+        if policy.cross_check_invalid_uuids == Some(true)
+            && self.peer_validation_transports.len() != 1
+        {
+            return Err(Error::ConfigurationError(format!(
+                "invalid packets can only be cross-checked between two servers, not {}",
+                self.peer_validation_transports.len() + 1
+            ))
+            .into());
+        }
+        self.policy = policy;
+        Ok(())
+    }
+
+    /// Records this aggregator's metrics in registry.
+    pub fn set_metrics(&mut self, registry: &Registry) -> Result<()> {
+        self.metrics = StageMetrics::validating(registry, "aggregate")?;
+        Ok(())
+    }
+
+    /// Returns the batches read and written and the packets processed by
+    /// generate_sum_part.
+    pub fn report(&self) -> &StageReport {
+        &self.report
+    }
+
+    /// Returns the batches among those provided for which any of the peer
+    /// share processors has not yet finished writing validations. A validation
+    /// batch is complete once its signature has been written.
+    pub fn pending_peer_validations(
+        &mut self,
+        batch_ids: &[(Uuid, NaiveDateTime)],
+    ) -> Result<Vec<(Uuid, NaiveDateTime)>> {
+        let mut pending = Vec::new();
+        for (batch_id, batch_date) in batch_ids {
+            for peer in self.peer_validation_transports.iter_mut() {
+                let batch = Batch::new_validation(
+                    self.aggregation_name,
+                    batch_id,
+                    batch_date,
+                    peer.server_index,
+                );
+                if !peer
+                    .transport
+                    .transport
+                    .exists(batch.signature_key())
+                    .map_err(transport_error(batch.signature_key()))?
+                {
+                    pending.push((*batch_id, *batch_date));
+                    break;
+                }
+            }
+        }
+        Ok(pending)
+    }
+
     /// Compute the sum part for all the provided batch IDs and write it out to
-    /// the aggregation transport.
+    /// the aggregation transport. Batches for which peer validations are
+    /// missing are left out of the sum part and listed in the report's
+    /// pending_batches. If they are missing for all of the batches, or for any
+    /// of them when the policy cross-checks invalid UUIDs, since the servers
+    /// must then sum the same batches, nothing is written and the error is an
+    /// Error::PeerValidationsPendingError listing the pending ones. If there are
+    /// fewer valid packets than the policy's min_contributions, nothing is
+    /// written either, and the report records why. If the policy cross-checks
+    /// invalid UUIDs, packets the peer found invalid are excluded from the sum
+    /// too, and until the peer has written the set of those, the error is an
+    /// Error::PeerInvalidUuidsPendingError. If only one of the servers
+    /// cross-checks invalid UUIDs, the error is an Error::ParameterMismatchError.
     pub fn generate_sum_part(&mut self, batch_ids: &[(Uuid, NaiveDateTime)]) -> Result<()> {
-        let mut invalid_uuids = Vec::new();
+        let _context = LogContext::new()
+            .aggregation_name(self.aggregation_name)
+            .date_range(self.aggregation_start, self.aggregation_end)
+            .role(self.server_index)
+            .enter();
+        info!("generating sum part over {} batches", batch_ids.len());
+        self.policy.validate(self.aggregation_name)?;
+
+        let pending = self.pending_peer_validations(batch_ids)?;
+        for (batch_id, batch_date) in &pending {
+            let _context = LogContext::new()
+                .batch_uuid(batch_id)
+                .date(batch_date)
+                .enter();
+            warn!("waiting on peer validations");
+        }
+        let pending_batch_ids: Vec<Uuid> = pending.iter().map(|(batch_id, _)| *batch_id).collect();
+        if pending.len() == batch_ids.len()
+            || (!pending.is_empty() && self.policy.cross_check_invalid_uuids == Some(true))
+        {
+            return Err(Error::PeerValidationsPendingError(pending_batch_ids).into());
+        }
+        let ready_batch_ids: Vec<(Uuid, NaiveDateTime)> = batch_ids
+            .iter()
+            .filter(|batch_id| !pending.contains(batch_id))
+            .copied()
+            .collect();
+        if !pending.is_empty() {
+            warn!(
+                "summing {} batches without the {} waiting on peer validations",
+                ready_batch_ids.len(),
+                pending.len()
+            );
+        }
+        self.report.pending_batches = pending_batch_ids;
+        let batch_ids = &ready_batch_ids[..];
 
         let ingestion_header = self.ingestion_header(&batch_ids[0].0, &batch_ids[0].1)?;
 
-        // Ideally, we would use the encryption_key_id in the ingestion packet
-        // to figure out which private key to use for decryption, but that field
-        // is optional. Instead we try all the keys we have available until one
-        // works.
-        // https://github.com/abetterinternet/prio-server/issues/73
-        let mut servers = self
-            .ingestion_transport
-            .packet_decryption_keys
-            .iter()
-            .map(|k| Server::new(ingestion_header.bins as usize, self.is_first, k.clone()))
-            .collect::<Vec<Server>>();
+        let first_input = self.report.inputs.len();
+        let (mut servers, mut invalid_uuids, mut batch_durations) =
+            self.aggregate_shares(batch_ids, &ingestion_header, &HashSet::new())?;
 
-        for batch_id in batch_ids {
-            self.aggregate_share(&batch_id.0, &batch_id.1, &mut servers, &mut invalid_uuids)?;
+        // If the servers disagree on which packets are invalid, the sum
+        // reconstructed from their sum parts is wrong, so when cross-checking
+        // each excludes the packets that any of them found invalid. The sets
+        // exchanged are those found before excluding any, so every server
+        // excludes the same union. Servers that don't cross-check exchange sets
+        // too, so that a peer that does finds out rather than waiting forever.
+        let last_input = self.report.inputs.len();
+        let peer_invalid_uuids =
+            self.exchange_invalid_uuids(batch_ids, &ingestion_header, &invalid_uuids)?;
+        let own_invalid_uuids: HashSet<Uuid> = invalid_uuids.iter().copied().collect();
+        if !peer_invalid_uuids.is_subset(&own_invalid_uuids) {
+            let excluded_uuids: HashSet<Uuid> = own_invalid_uuids
+                .union(&peer_invalid_uuids)
+                .copied()
+                .collect();
+            info!(
+                "aggregating again without {} packets found invalid by peers",
+                excluded_uuids.len() - own_invalid_uuids.len()
+            );
+            // The second pass reads the same batches and counts the packets
+            // anew.
+            self.report.inputs.drain(first_input..last_input);
+            self.report.packets = PacketCounts::default();
+            let (second_servers, second_invalid_uuids, second_batch_durations) =
+                self.aggregate_shares(batch_ids, &ingestion_header, &excluded_uuids)?;
+            servers = second_servers;
+            invalid_uuids = second_invalid_uuids;
+            batch_durations = second_batch_durations;
+        }
+
+        // Only the pass whose packets are summed is counted.
+        for batch_duration in batch_durations {
+            self.metrics.batches.inc();
+            self.metrics.duration.observe(batch_duration);
+        }
+        self.metrics
+            .packets
+            .inc_by(self.report.packets.total as f64);
+        if let Some(invalid_packets) = &self.metrics.invalid_packets {
+            invalid_packets.inc_by(self.report.packets.invalid as f64);
+        }
+
+        // When invalid UUIDs are cross-checked, both servers count the same
+        // valid packets, so they agree on whether to withhold the sum part.
+        // Otherwise they may not, but then their sum parts would not have
+        // reconstructed the right sum anyway.
+        if let Some(reason) = self.policy.sum_withheld_reason(self.report.packets.valid) {
+            warn!("not writing sum part: {}", reason);
+            self.report.sum_part_withheld = Some(reason);
+            return Ok(());
         }
 
         // TODO(timg) what exactly do we write out when there are no invalid
         // packets? Right now we will write an empty file.
+        let invalid_packet_count = invalid_uuids.len();
         let invalid_packets_digest =
             self.aggregation_batch
                 .packet_file_writer(|mut packet_file_writer| {
@@ -100,20 +293,29 @@ impl<'a> BatchAggregator<'a> {
         // packets with this Server instance, just accumulating data vectors.
         let mut accumulator_server = Server::new(
             ingestion_header.bins as usize,
-            self.is_first,
-            self.ingestion_transport.packet_decryption_keys[0].clone(),
+            self.server_index == 0,
+            self.ingestion_transports[0]
+                .transport
+                .packet_decryption_keys[0]
+                .clone(),
         );
         for server in servers.iter() {
             accumulator_server.merge_total_shares(server.total_shares());
         }
 
-        let sum = accumulator_server
-            .total_shares()
-            .iter()
-            .map(|f| u32::from(*f) as i64)
-            .collect();
+        let mut sum = accumulator_server.total_shares().to_vec();
+        if let Some(noise_epsilon) = self.policy.sum_noise_epsilon {
+            // Each client changes the sum by at most the number of bits set in
+            // its vector.
+            let sensitivity = ingestion_header
+                .hamming_weight
+                .unwrap_or(ingestion_header.bins);
+            add_discrete_laplace_noise(&mut sum, sensitivity, noise_epsilon, &mut thread_rng())?;
+        }
+        let sum = sum.iter().map(|f| u32::from(*f) as i64).collect();
 
-        let total_individual_clients = accumulator_server.total_shares().len() as i64;
+        // Each valid packet summed is one client's contribution.
+        let total_individual_clients = self.report.packets.valid as i64;
 
         let sum_signature = self.aggregation_batch.put_header(
             &SumPart {
@@ -129,12 +331,187 @@ impl<'a> BatchAggregator<'a> {
                 aggregation_end_time: self.aggregation_end.timestamp_millis(),
                 packet_file_digest: invalid_packets_digest.as_ref().to_vec(),
                 total_individual_clients,
+                noise_epsilon: self.policy.sum_noise_epsilon,
             },
-            &self.share_processor_signing_key.key,
+            self.share_processor_signer,
         )?;
 
         self.aggregation_batch
-            .put_signature(&sum_signature, &self.share_processor_signing_key.identifier)
+            .put_signature(&sum_signature, self.share_processor_signer)?;
+        self.report.outputs.push(self.aggregation_batch.report());
+        info!(
+            "wrote sum part with {} invalid packets",
+            invalid_packet_count
+        );
+        Ok(())
+    }
+
+    /// Aggregates the shares in all the provided batches, counting the packets
+    /// whose UUIDs are in excluded_uuids as invalid without checking them.
+    /// Returns a Server for each packet decryption key, holding the shares it
+    /// decrypted, the UUIDs of the invalid packets and the time taken to
+    /// aggregate each batch.
+    fn aggregate_shares(
+        &mut self,
+        batch_ids: &[(Uuid, NaiveDateTime)],
+        ingestion_header: &IngestionHeader,
+        excluded_uuids: &HashSet<Uuid>,
+    ) -> Result<(Vec<Server>, Vec<Uuid>, Vec<std::time::Duration>)> {
+        let mut invalid_uuids = Vec::new();
+        let mut batch_durations = Vec::new();
+
+        // Ideally, we would use the encryption_key_id in the ingestion packet
+        // to figure out which private key to use for decryption, but that field
+        // is optional. Instead we try all the keys we have available until one
+        // works.
+        // https://github.com/abetterinternet/prio-server/issues/73
+        let mut servers = self
+            .ingestion_transports
+            .iter()
+            .flat_map(|ingestor| ingestor.transport.packet_decryption_keys.iter())
+            .map(|k| {
+                Server::new(
+                    ingestion_header.bins as usize,
+                    self.server_index == 0,
+                    k.clone(),
+                )
+            })
+            .collect::<Vec<Server>>();
+
+        for batch_id in batch_ids {
+            let _context = LogContext::new()
+                .batch_uuid(&batch_id.0)
+                .date(&batch_id.1)
+                .enter();
+            let start = Instant::now();
+            if let Err(e) = self.aggregate_share(
+                &batch_id.0,
+                &batch_id.1,
+                ingestion_header,
+                &mut servers,
+                &mut invalid_uuids,
+                excluded_uuids,
+            ) {
+                error!("failed to aggregate batch: {:?}", e);
+                return Err(e);
+            }
+            batch_durations.push(start.elapsed());
+        }
+        Ok((servers, invalid_uuids, batch_durations))
+    }
+
+    /// Writes the UUIDs of the packets this server found invalid to the peer's
+    /// validation bucket, next to this server's validations, and reads the set
+    /// the peer wrote to this server's. If the policy cross-checks invalid
+    /// UUIDs, returns the UUIDs the peer found invalid, and if the peer has not
+    /// yet written its set, the error is an Error::PeerInvalidUuidsPendingError.
+    /// Otherwise returns none, without waiting on the peer's set. Either way, if
+    /// the peer's set shows that it does not agree on whether to cross-check,
+    /// the error is an Error::ParameterMismatchError.
+    fn exchange_invalid_uuids(
+        &mut self,
+        batch_ids: &[(Uuid, NaiveDateTime)],
+        ingestion_header: &IngestionHeader,
+        invalid_uuids: &[Uuid],
+    ) -> Result<HashSet<Uuid>> {
+        let cross_check = self.policy.cross_check_invalid_uuids == Some(true);
+        let mut writer: BatchWriter<'_, InvalidUuidSet, InvalidPacket> = BatchWriter::new(
+            Batch::new_invalid_uuid_set(
+                self.aggregation_name,
+                self.aggregation_start,
+                self.aggregation_end,
+                self.server_index,
+            ),
+            &mut *self.own_validation_transport.transport,
+        );
+        writer.set_sign_batch_manifest(self.sign_batch_manifest);
+        writer.set_packet_file_codec(self.packet_file_codec);
+        let invalid_packets_digest = writer.packet_file_writer(|packet_file_writer| {
+            for invalid_uuid in invalid_uuids {
+                InvalidPacket {
+                    uuid: *invalid_uuid,
+                }
+                .write(packet_file_writer)?
+            }
+            Ok(())
+        })?;
+        let own_set = InvalidUuidSet {
+            batch_uuids: batch_ids.iter().map(|pair| pair.0).collect(),
+            name: ingestion_header.name.clone(),
+            aggregation_start_time: self.aggregation_start.timestamp_millis(),
+            aggregation_end_time: self.aggregation_end.timestamp_millis(),
+            packet_file_digest: invalid_packets_digest.as_ref().to_vec(),
+            cross_checked: cross_check,
+        };
+        let signature = writer.put_header(&own_set, self.share_processor_signer)?;
+        writer.put_signature(&signature, self.share_processor_signer)?;
+        self.report.outputs.push(writer.report());
+
+        let mut pending = Vec::new();
+        for peer in self.peer_validation_transports.iter_mut() {
+            let batch = Batch::new_invalid_uuid_set(
+                self.aggregation_name,
+                self.aggregation_start,
+                self.aggregation_end,
+                peer.server_index,
+            );
+            if !peer
+                .transport
+                .transport
+                .exists(batch.signature_key())
+                .map_err(transport_error(batch.signature_key()))?
+            {
+                pending.push(peer.server_index);
+            }
+        }
+        if !pending.is_empty() && cross_check {
+            warn!("waiting on invalid packets found by peers {:?}", pending);
+            return Err(Error::PeerInvalidUuidsPendingError(pending).into());
+        }
+
+        let mut peer_invalid_uuids = HashSet::new();
+        for peer in self.peer_validation_transports.iter_mut() {
+            if pending.contains(&peer.server_index) {
+                continue;
+            }
+            let mut reader: BatchReader<'_, InvalidUuidSet, InvalidPacket> = BatchReader::new(
+                Batch::new_invalid_uuid_set(
+                    self.aggregation_name,
+                    self.aggregation_start,
+                    self.aggregation_end,
+                    peer.server_index,
+                ),
+                &mut *peer.transport.transport,
+            );
+            let peer_set = reader.header(&peer.transport.batch_signing_public_keys)?;
+            if peer_set.cross_checked != cross_check {
+                return Err(Error::ParameterMismatchError(format!(
+                    "server {} {} invalid packets but this server {}",
+                    peer.server_index,
+                    if peer_set.cross_checked {
+                        "cross-checks"
+                    } else {
+                        "does not cross-check"
+                    },
+                    if cross_check { "does" } else { "does not" }
+                ))
+                .into());
+            }
+            if !cross_check {
+                continue;
+            }
+            if !peer_set.check_parameters(&own_set) {
+                return Err(Error::ParameterMismatchError(format!(
+                    "invalid packets found by server {} are over different batches. \
+                    Peer: {:?}\nOwn: {:?}",
+                    peer.server_index, peer_set, own_set
+                ))
+                .into());
+            }
+            peer_invalid_uuids.extend(reader.invalid_uuids(&peer_set)?);
+            self.report.inputs.push(reader.report());
+        }
+        Ok(peer_invalid_uuids)
     }
 
     /// Fetch the ingestion header from one of the batches so various parameters
@@ -144,77 +521,128 @@ impl<'a> BatchAggregator<'a> {
         batch_id: &Uuid,
         batch_date: &NaiveDateTime,
     ) -> Result<IngestionHeader> {
+        let batch = Batch::new_ingestion(self.aggregation_name, batch_id, batch_date);
+        let ingestor = &mut self.ingestion_transports
+            [find_ingestor(self.ingestion_transports, batch.signature_key())?];
         let mut ingestion_batch: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
-            BatchReader::new(
-                Batch::new_ingestion(self.aggregation_name, batch_id, batch_date),
-                &mut *self.ingestion_transport.transport.transport,
-            );
-        let ingestion_header = ingestion_batch
-            .header(&self.ingestion_transport.transport.batch_signing_public_keys)?;
+            BatchReader::new(batch, &mut *ingestor.transport.transport.transport);
+        ingestion_batch
+            .set_time_tolerance(self.policy.batch_time_tolerance(self.batch_time_tolerance));
+        let ingestion_header =
+            ingestion_batch.header(&ingestor.transport.transport.batch_signing_public_keys)?;
         Ok(ingestion_header)
     }
 
     /// Aggregate the batch for the provided batch_id into the provided server.
-    /// The UUIDs of packets for which aggregation fails are recorded in the
-    /// provided invalid_uuids vector.
+    /// The UUIDs of packets for which aggregation fails, or which are in
+    /// excluded_uuids, are recorded in the provided invalid_uuids vector. The
+    /// batch's parameters must match those in first_ingestion_header, which
+    /// the sum part is written with.
     fn aggregate_share(
         &mut self,
         batch_id: &Uuid,
         batch_date: &NaiveDateTime,
+        first_ingestion_header: &IngestionHeader,
         servers: &mut Vec<Server>,
         invalid_uuids: &mut Vec<Uuid>,
+        excluded_uuids: &HashSet<Uuid>,
     ) -> Result<()> {
+        // The batch may have been written by any of the ingestors.
+        let batch = Batch::new_ingestion(self.aggregation_name, batch_id, batch_date);
+        let ingestor = &mut self.ingestion_transports
+            [find_ingestor(self.ingestion_transports, batch.signature_key())?];
         let mut ingestion_batch: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
-            BatchReader::new(
-                Batch::new_ingestion(self.aggregation_name, batch_id, batch_date),
-                &mut *self.ingestion_transport.transport.transport,
-            );
+            BatchReader::new(batch, &mut *ingestor.transport.transport.transport);
+        ingestion_batch
+            .set_time_tolerance(self.policy.batch_time_tolerance(self.batch_time_tolerance));
         let mut own_validation_batch: BatchReader<'_, ValidationHeader, ValidationPacket> =
             BatchReader::new(
-                Batch::new_validation(self.aggregation_name, batch_id, batch_date, self.is_first),
+                Batch::new_validation(
+                    self.aggregation_name,
+                    batch_id,
+                    batch_date,
+                    self.server_index,
+                ),
                 &mut *self.own_validation_transport.transport,
             );
-        let mut peer_validation_batch: BatchReader<'_, ValidationHeader, ValidationPacket> =
-            BatchReader::new(
-                Batch::new_validation(self.aggregation_name, batch_id, batch_date, !self.is_first),
-                &mut *self.peer_validation_transport.transport,
-            );
-        let peer_validation_header = peer_validation_batch
-            .header(&self.peer_validation_transport.batch_signing_public_keys)?;
+        let mut peer_validation_batches = Vec::new();
+        let mut peer_validation_headers = Vec::new();
+        for peer in self.peer_validation_transports.iter_mut() {
+            let mut peer_validation_batch: BatchReader<'_, ValidationHeader, ValidationPacket> =
+                BatchReader::new(
+                    Batch::new_validation(
+                        self.aggregation_name,
+                        batch_id,
+                        batch_date,
+                        peer.server_index,
+                    ),
+                    &mut *peer.transport.transport,
+                );
+            peer_validation_headers
+                .push(peer_validation_batch.header(&peer.transport.batch_signing_public_keys)?);
+            peer_validation_batches.push(peer_validation_batch);
+        }
         let own_validation_header = own_validation_batch
             .header(&self.own_validation_transport.batch_signing_public_keys)?;
-        let ingestion_header = ingestion_batch
-            .header(&self.ingestion_transport.transport.batch_signing_public_keys)?;
+        let ingestion_header =
+            ingestion_batch.header(&ingestor.transport.transport.batch_signing_public_keys)?;
 
         // Make sure all the parameters in the headers line up
-        if !peer_validation_header.check_parameters(&own_validation_header) {
-            return Err(anyhow!(
-                "validation headers do not match. Peer: {:?}\nOwn: {:?}",
-                peer_validation_header,
-                own_validation_header
-            ));
+        for peer_validation_header in &peer_validation_headers {
+            if !peer_validation_header.check_parameters(&own_validation_header) {
+                return Err(Error::ParameterMismatchError(format!(
+                    "validation headers do not match. Peer: {:?}\nOwn: {:?}",
+                    peer_validation_header, own_validation_header
+                ))
+                .into());
+            }
         }
-        if !ingestion_header.check_parameters(&peer_validation_header) {
-            return Err(anyhow!(
-                "ingestion header does not match peer validation header. Ingestion: {:?}\nPeer:{:?}",
-                ingestion_header,
-                peer_validation_header
-            ));
+        if !ingestion_header.check_aggregation_parameters(first_ingestion_header) {
+            return Err(Error::ParameterMismatchError(format!(
+                "ingestion header does not match that of the first batch in the aggregation. \
+                Ingestion: {:?}\nFirst: {:?}",
+                ingestion_header, first_ingestion_header
+            ))
+            .into());
         }
+        if !ingestion_header.check_parameters(&own_validation_header) {
+            return Err(Error::ParameterMismatchError(format!(
+                "ingestion header does not match validation headers. Ingestion: {:?}\nOwn:{:?}",
+                ingestion_header, own_validation_header
+            ))
+            .into());
+        }
+        // Every server, i.e. this one and each of its peers, must have
+        // received a share of the batch.
+        let number_of_servers = peer_validation_headers.len() + 1;
+        if !ingestion_header.check_number_of_servers(number_of_servers) {
+            return Err(Error::ParameterMismatchError(format!(
+                "ingestion batch is for {} servers but {} are configured",
+                ingestion_header.number_of_servers, number_of_servers
+            ))
+            .into());
+        }
+        self.policy.check_header(&ingestion_header)?;
 
-        let mut peer_validation_packet_reader =
-            peer_validation_batch.packet_file_reader(&peer_validation_header)?;
+        let mut peer_validation_packet_readers = peer_validation_batches
+            .iter_mut()
+            .zip(peer_validation_headers.iter())
+            .map(|(batch, header)| batch.packet_file_reader(header))
+            .collect::<Result<Vec<_>>>()?;
         let mut own_validation_packet_reader =
             own_validation_batch.packet_file_reader(&own_validation_header)?;
         let mut ingestion_packet_reader = ingestion_batch.packet_file_reader(&ingestion_header)?;
 
+        let mut packet_count = 0;
         loop {
-            let peer_validation_packet =
-                match ValidationPacket::read(&mut peer_validation_packet_reader) {
-                    Ok(p) => Some(p),
-                    Err(Error::EofError) => None,
-                    Err(e) => return Err(e.into()),
-                };
+            let peer_validation_packets = peer_validation_packet_readers
+                .iter_mut()
+                .map(|reader| match ValidationPacket::read(reader) {
+                    Ok(p) => Ok(Some(p)),
+                    Err(Error::EofError) => Ok(None),
+                    Err(e) => Err(e),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
             let own_validation_packet =
                 match ValidationPacket::read(&mut own_validation_packet_reader) {
                     Ok(p) => Some(p),
@@ -228,20 +656,25 @@ impl<'a> BatchAggregator<'a> {
                     Err(e) => return Err(e.into()),
                 };
 
-            // All three packet files should contain the same number of packets,
+            // All the packet files should contain the same number of packets,
             // so if any of the readers hit EOF before the others, something is
             // fishy.
-            let (peer_validation_packet, own_validation_packet, ingestion_packet) = match (
-                &peer_validation_packet,
+            let peer_validation_packets = peer_validation_packets
+                .into_iter()
+                .collect::<Option<Vec<ValidationPacket>>>();
+            let (peer_validation_packets, own_validation_packet, ingestion_packet) = match (
+                &peer_validation_packets,
                 &own_validation_packet,
                 &ingestion_packet,
             ) {
                 (Some(a), Some(b), Some(c)) => (a, b, c),
                 (None, None, None) => break,
                 (_, _, _) => {
-                    return Err(anyhow!(
-                        "unexpected early EOF when checking peer validations"
-                    ));
+                    return Err(Error::PacketMismatchError(format!(
+                        "unexpected early EOF when checking peer validations for batch {}",
+                        batch_id
+                    ))
+                    .into());
                 }
             };
 
@@ -257,48 +690,122 @@ impl<'a> BatchAggregator<'a> {
             // the whole batch?
             // For now I am assuming that all batches maintain the same order
             // and that they are required to contain the same set of UUIDs.
-            if peer_validation_packet.uuid != own_validation_packet.uuid
-                || peer_validation_packet.uuid != ingestion_packet.uuid
-                || own_validation_packet.uuid != ingestion_packet.uuid
+            if own_validation_packet.uuid != ingestion_packet.uuid
+                || peer_validation_packets
+                    .iter()
+                    .any(|p| p.uuid != ingestion_packet.uuid)
             {
-                return Err(anyhow!(
-                    "mismatch between peer validation, own validation and ingestion packet UUIDs: {} {} {}",
-                    peer_validation_packet.uuid,
+                return Err(Error::PacketMismatchError(format!(
+                    "mismatch between peer validation, own validation and ingestion packet UUIDs: {:?} {} {}",
+                    peer_validation_packets.iter().map(|p| p.uuid).collect::<Vec<_>>(),
                     own_validation_packet.uuid,
-                    ingestion_packet.uuid));
+                    ingestion_packet.uuid))
+                .into());
             }
 
+            // The validity check is over the sum of all the servers'
+            // verification messages, so the peers' are added together and
+            // checked against our own.
+            let mut peer_verification_message = VerificationMessage {
+                f_r: Field::from(0),
+                g_r: Field::from(0),
+                h_r: Field::from(0),
+            };
+            for peer_validation_packet in peer_validation_packets {
+                let message = VerificationMessage::try_from(peer_validation_packet)
+                    .map_err(|e| Error::MalformedDataPacketError(e.to_string()))?;
+                peer_verification_message.f_r += message.f_r;
+                peer_verification_message.g_r += message.g_r;
+                peer_verification_message.h_r += message.h_r;
+            }
+            let own_verification_message = VerificationMessage::try_from(own_validation_packet)
+                .map_err(|e| Error::MalformedDataPacketError(e.to_string()))?;
+
+            // A packet whose data vector does not have the hamming weight
+            // declared in the header is invalid, whatever its proof says.
+            let has_declared_hamming_weight = match ingestion_header.hamming_weight {
+                Some(hamming_weight) => has_hamming_weight(
+                    hamming_weight,
+                    peer_validation_packets
+                        .iter()
+                        .chain(std::iter::once(own_validation_packet)),
+                )?,
+                None => true,
+            };
+            // So is one that a peer found invalid.
+            let may_be_valid =
+                has_declared_hamming_weight && !excluded_uuids.contains(&ingestion_packet.uuid);
+
             let mut did_aggregate_shares = false;
             let mut last_err = None;
             for server in servers.iter_mut() {
-                match server.aggregate(
-                    &ingestion_packet.encrypted_payload,
-                    &VerificationMessage::try_from(peer_validation_packet)?,
-                    &VerificationMessage::try_from(own_validation_packet)?,
-                ) {
+                let result = if may_be_valid {
+                    server.aggregate(
+                        &ingestion_packet.encrypted_payload,
+                        &peer_verification_message,
+                        &own_verification_message,
+                    )
+                } else {
+                    Ok(false)
+                };
+                match result {
                     Ok(valid) => {
                         if !valid {
-                            invalid_uuids.push(peer_validation_packet.uuid);
+                            invalid_uuids.push(ingestion_packet.uuid);
+                            self.report.packets.invalid += 1;
+                        } else {
+                            self.report.packets.valid += 1;
                         }
+                        self.report.packets.total += 1;
+                        packet_count += 1;
+                        self.policy
+                            .check_packet_count(&ingestion_header.name, packet_count)?;
                         did_aggregate_shares = true;
                         break;
                     }
                     Err(e) => {
-                        last_err = Some(Err(e));
+                        last_err = Some(e);
                         continue;
                     }
                 }
             }
             if !did_aggregate_shares {
-                return last_err
-                    // Unwrap the optional, providing an error if it is None
-                    .context("unknown validation error")?
-                    // Wrap either the default error or what we got from
-                    // server.aggregate
-                    .context("failed to validate packets");
+                let reason = match last_err {
+                    Some(e) => format!("failed to validate packets: {}", e),
+                    None => "unknown validation error".to_owned(),
+                };
+                return Err(Error::PacketProcessingError(ingestion_packet.uuid, reason).into());
             }
         }
 
+        let mut ingestion_report = ingestion_batch.report();
+        ingestion_report.ingestor = ingestor.name.clone();
+        self.report.inputs.push(ingestion_report);
+        self.report.inputs.push(own_validation_batch.report());
+        for peer_validation_batch in &peer_validation_batches {
+            self.report.inputs.push(peer_validation_batch.report());
+        }
         Ok(())
     }
 }
+
+/// Returns true if the hamming weight shares in the provided validation
+/// packets, one from each server, sum to hamming_weight.
+fn has_hamming_weight<'a>(
+    hamming_weight: i32,
+    validation_packets: impl Iterator<Item = &'a ValidationPacket>,
+) -> Result<bool, Error> {
+    let mut sum = Field::from(0);
+    for packet in validation_packets {
+        let share = packet.hamming_weight_share.ok_or_else(|| {
+            Error::MalformedDataPacketError(format!(
+                "validation packet {} has no hamming weight share",
+                packet.uuid
+            ))
+        })?;
+        let share =
+            u32::try_from(share).map_err(|e| Error::MalformedDataPacketError(e.to_string()))?;
+        sum += Field::from(share);
+    }
+    Ok(u32::try_from(hamming_weight).is_ok_and(|weight| sum == Field::from(weight)))
+}
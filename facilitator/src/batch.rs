@@ -1,18 +1,18 @@
 use crate::{
-    idl::{BatchSignature, Header, Packet},
+    idl::{resolving_reader, BatchManifest, BatchSignature, Header, InvalidPacket, Packet},
+    logging::LogContext,
+    manifest::BatchSigningPublicKeys,
+    report::BatchReport,
+    signing::BatchSigner,
     transport::{Transport, TransportWriter},
-    DigestWriter, SidecarWriter, DATE_FORMAT,
+    DigestWriter, Error, SidecarWriter, DATE_FORMAT,
 };
 use anyhow::{anyhow, Context, Result};
-use avro_rs::{Reader, Schema, Writer};
-use chrono::NaiveDateTime;
-use ring::{
-    digest::Digest,
-    rand::SystemRandom,
-    signature::{EcdsaKeyPair, Signature, UnparsedPublicKey},
-};
+use avro_rs::{Codec, Reader, Schema, Writer};
+use chrono::{Duration, NaiveDateTime};
+use log::{debug, info, warn};
+use ring::digest::{digest, Digest, SHA256};
 use std::{
-    collections::HashMap,
     io::{Cursor, Read},
     marker::PhantomData,
 };
@@ -20,6 +20,12 @@ use uuid::Uuid;
 
 /// Manages the paths to the different files in a batch
 pub struct Batch {
+    aggregation_name: String,
+    batch_id: Option<Uuid>,
+    // The time range named in the batch path. For ingestion and validation
+    // batches, start_time and end_time are both the batch's date.
+    start_time: NaiveDateTime,
+    end_time: NaiveDateTime,
     header_path: String,
     signature_path: String,
     packet_file_path: String,
@@ -31,27 +37,64 @@ impl Batch {
         Batch::new(aggregation_name, batch_id, date, "batch")
     }
 
-    /// Creates a Batch representing a validation batch
+    /// Creates a Batch representing a validation batch written by the server
+    /// with the provided index
     pub fn new_validation(
         aggregation_name: &str,
         batch_id: &Uuid,
         date: &NaiveDateTime,
-        is_first: bool,
+        server_index: usize,
     ) -> Batch {
         Batch::new(
             aggregation_name,
             batch_id,
             date,
-            &format!("validity_{}", if is_first { 0 } else { 1 }),
+            &format!("validity_{}", server_index),
         )
     }
 
-    // Creates a batch representing a sum part batch
+    // Creates a batch representing a sum part batch written by the server with
+    // the provided index
     pub fn new_sum(
         aggregation_name: &str,
         aggregation_start: &NaiveDateTime,
         aggregation_end: &NaiveDateTime,
-        is_first: bool,
+        server_index: usize,
+    ) -> Batch {
+        Batch::new_aggregation(
+            aggregation_name,
+            aggregation_start,
+            aggregation_end,
+            &format!("sum_{}", server_index),
+            &format!("invalid_uuid_{}", server_index),
+        )
+    }
+
+    /// Creates a Batch representing the set of packets that the server with
+    /// the provided index found invalid while aggregating, which it writes to
+    /// its peers' validation buckets.
+    pub fn new_invalid_uuid_set(
+        aggregation_name: &str,
+        aggregation_start: &NaiveDateTime,
+        aggregation_end: &NaiveDateTime,
+        server_index: usize,
+    ) -> Batch {
+        let filename = format!("invalid_set_{}", server_index);
+        Batch::new_aggregation(
+            aggregation_name,
+            aggregation_start,
+            aggregation_end,
+            &filename,
+            &filename,
+        )
+    }
+
+    fn new_aggregation(
+        aggregation_name: &str,
+        aggregation_start: &NaiveDateTime,
+        aggregation_end: &NaiveDateTime,
+        filename: &str,
+        packet_filename: &str,
     ) -> Batch {
         let batch_path = format!(
             "{}/{}-{}",
@@ -59,16 +102,15 @@ impl Batch {
             aggregation_start.format(DATE_FORMAT),
             aggregation_end.format(DATE_FORMAT)
         );
-        let filename = format!("sum_{}", if is_first { 0 } else { 1 });
 
         Batch {
+            aggregation_name: aggregation_name.to_owned(),
+            batch_id: None,
+            start_time: *aggregation_start,
+            end_time: *aggregation_end,
             header_path: format!("{}.{}", batch_path, filename),
             signature_path: format!("{}.{}.sig", batch_path, filename),
-            packet_file_path: format!(
-                "{}.invalid_uuid_{}.avro",
-                batch_path,
-                if is_first { 0 } else { 1 }
-            ),
+            packet_file_path: format!("{}.{}.avro", batch_path, packet_filename),
         }
     }
 
@@ -80,23 +122,125 @@ impl Batch {
             batch_id.to_hyphenated()
         );
         Batch {
+            aggregation_name: aggregation_name.to_owned(),
+            batch_id: Some(*batch_id),
+            start_time: *date,
+            end_time: *date,
             header_path: format!("{}.{}", batch_path, filename),
             signature_path: format!("{}.{}.sig", batch_path, filename),
             packet_file_path: format!("{}.{}.avro", batch_path, filename),
         }
     }
 
-    fn header_key(&self) -> &str {
+    /// Returns a LogContext with the aggregation name, batch UUID (if any) and
+    /// date or date range of this batch.
+    pub fn log_context(&self) -> LogContext {
+        let context = LogContext::new().aggregation_name(&self.aggregation_name);
+        let context = match &self.batch_id {
+            Some(batch_id) => context.batch_uuid(batch_id),
+            None => context,
+        };
+        if self.start_time == self.end_time {
+            context.date(&self.start_time)
+        } else {
+            context.date_range(&self.start_time, &self.end_time)
+        }
+    }
+
+    fn report(
+        &self,
+        key_identifier: &Option<String>,
+        header_digest: &Option<Vec<u8>>,
+        packet_file_digest: &Option<Vec<u8>>,
+    ) -> BatchReport {
+        BatchReport {
+            header_key: self.header_key().to_owned(),
+            packet_file_key: self.packet_file_key().to_owned(),
+            signature_key: self.signature_key().to_owned(),
+            header_digest: header_digest.as_ref().map(base64::encode),
+            packet_file_digest: packet_file_digest.as_ref().map(base64::encode),
+            key_identifier: key_identifier.clone(),
+            ingestor: None,
+        }
+    }
+
+    /// Returns the key of the batch's header in its transport.
+    pub fn header_key(&self) -> &str {
         self.header_path.as_ref()
     }
 
-    fn signature_key(&self) -> &str {
+    /// Returns the key of the batch's signature in its transport.
+    pub fn signature_key(&self) -> &str {
         self.signature_path.as_ref()
     }
 
-    fn packet_file_key(&self) -> &str {
+    /// Returns the key of the batch's packet file in its transport.
+    pub fn packet_file_key(&self) -> &str {
         self.packet_file_path.as_ref()
     }
+
+    /// Checks that the aggregation name and batch UUID in the provided header
+    /// match those in this batch's path and, if tolerance is not None, that
+    /// the header's time range is within tolerance of the date or dates in the
+    /// path.
+    fn check_header<H: Header>(&self, header: &H, tolerance: Option<Duration>) -> Result<()> {
+        if header.name() != self.aggregation_name {
+            return Err(Error::BatchPathMismatchError {
+                field: "name",
+                header_value: header.name().to_owned(),
+                path_value: self.aggregation_name.clone(),
+            }
+            .into());
+        }
+
+        if let (Some(header_uuid), Some(path_uuid)) = (header.batch_uuid(), self.batch_id) {
+            if *header_uuid != path_uuid {
+                return Err(Error::BatchPathMismatchError {
+                    field: "batch_uuid",
+                    header_value: header_uuid.to_string(),
+                    path_value: path_uuid.to_string(),
+                }
+                .into());
+            }
+        }
+
+        if let (Some((start_time, end_time)), Some(tolerance)) = (header.time_range(), tolerance) {
+            // The time range in the header must overlap the one in the path,
+            // allowing for the tolerance on either side. Since the path has
+            // minute granularity, the tolerance should be at least a minute.
+            let path_start_time = self.start_time.timestamp_millis();
+            let path_end_time = self.end_time.timestamp_millis();
+            let tolerance_millis = tolerance.num_milliseconds();
+            if start_time > end_time
+                || start_time - tolerance_millis > path_end_time
+                || end_time + tolerance_millis < path_start_time
+            {
+                return Err(Error::BatchTimeError {
+                    start_time,
+                    end_time,
+                    path_start_time,
+                    path_end_time,
+                    tolerance,
+                }
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Constructs the manifest describing this batch, given the digests of
+    /// its header and packet file.
+    fn manifest(&self, header_digest: &[u8], packet_file_digest: &[u8]) -> BatchManifest {
+        BatchManifest {
+            aggregation_name: self.aggregation_name.clone(),
+            batch_uuid: self.batch_id,
+            header_object_key: self.header_path.clone(),
+            packet_file_object_key: self.packet_file_path.clone(),
+            header_digest: header_digest.to_vec(),
+            packet_file_digest: packet_file_digest.to_vec(),
+        }
+    }
 }
 
 /// Allows reading files, including signature validation, from an ingestion or
@@ -105,6 +249,10 @@ pub struct BatchReader<'a, H, P> {
     batch: Batch,
     transport: &'a mut dyn Transport,
     packet_schema: Schema,
+    time_tolerance: Option<Duration>,
+    key_identifier: Option<String>,
+    header_digest: Option<Vec<u8>>,
+    packet_file_digest: Option<Vec<u8>>,
 
     // These next two fields are not real and are used because not using H and P
     // in the struct definition is an error.
@@ -118,38 +266,102 @@ impl<'a, H: Header, P: Packet> BatchReader<'a, H, P> {
             batch,
             transport,
             packet_schema: P::schema(),
+            time_tolerance: None,
+            key_identifier: None,
+            header_digest: None,
+            packet_file_digest: None,
             phantom_header: PhantomData,
             phantom_packet: PhantomData,
         }
     }
 
+    /// Configures how far the time range in the header may lie outside the
+    /// date in the batch path. If None, which is the default, the time range
+    /// is not checked.
+    pub fn set_time_tolerance(&mut self, time_tolerance: Option<Duration>) {
+        self.time_tolerance = time_tolerance;
+    }
+
     /// Return the parsed header from this batch, but only if its signature is
     /// valid. The signature is checked by getting the key_identifier value from
     /// the signature message, using that to obtain a public key from the
-    /// provided public_keys map, and using that key to check the ECDSA P256
-    /// signature.
-    pub fn header(
-        &mut self,
-        public_keys: &HashMap<String, UnparsedPublicKey<Vec<u8>>>,
-    ) -> Result<H> {
-        let signature = BatchSignature::read(self.transport.get(self.batch.signature_key())?)?;
+    /// provided public_keys map, and using that key to check the signature
+    /// with whichever algorithm the key was parsed for. If the signature
+    /// message also contains a batch manifest signature (i.e., it is a version
+    /// 2 signature), it is checked against the manifest of this batch, which
+    /// binds the header to the object keys it was read from. Finally, the
+    /// header's aggregation name, batch UUID and time range are checked against
+    /// the batch path.
+    pub fn header(&mut self, public_keys: &BatchSigningPublicKeys) -> Result<H> {
+        let header_key = self.batch.header_key();
+        let _context = self.batch.log_context().object_key(header_key).enter();
+        debug!("reading batch header");
+
+        let signature_key = self.batch.signature_key();
+        let signature = BatchSignature::read(
+            self.transport
+                .get(signature_key)
+                .map_err(transport_error(signature_key))?,
+        )?;
 
         let mut header_buf = Vec::new();
         self.transport
-            .get(self.batch.header_key())?
+            .get(header_key)
+            .map_err(transport_error(header_key))?
             .read_to_end(&mut header_buf)
-            .context("failed to read header from transport")?;
+            .map_err(transport_error(header_key))?;
 
-        public_keys
+        let public_key = public_keys
             .get(&signature.key_identifier)
-            .context(format!(
-                "key identifier {} not present in key map",
-                signature.key_identifier,
-            ))?
+            .ok_or_else(|| Error::KeyNotFoundError(signature.key_identifier.clone()))?;
+        public_key
             .verify(&header_buf, &signature.batch_header_signature)
-            .context("invalid signature on header")?;
+            .map_err(|_| {
+                warn!(
+                    "invalid header signature with key {}",
+                    signature.key_identifier
+                );
+                Error::SignatureError(header_key.to_owned())
+            })?;
+
+        let header_digest = digest(&SHA256, &header_buf);
+        let header = H::read(Cursor::new(header_buf))?;
+
+        if let Some(manifest_signature) = &signature.batch_manifest_signature {
+            let manifest = self
+                .batch
+                .manifest(header_digest.as_ref(), header.packet_file_digest());
+            public_key
+                .verify(&manifest.to_bytes()?, manifest_signature)
+                .map_err(|_| {
+                    warn!(
+                        "invalid batch manifest signature with key {}",
+                        signature.key_identifier
+                    );
+                    Error::SignatureError(format!("manifest of {}", header_key))
+                })?;
+        }
 
-        Ok(H::read(Cursor::new(header_buf))?)
+        self.batch.check_header(&header, self.time_tolerance)?;
+        self.key_identifier = Some(signature.key_identifier.clone());
+        self.header_digest = Some(header_digest.as_ref().to_vec());
+        debug!(
+            "verified batch header signed with key {}",
+            signature.key_identifier
+        );
+
+        Ok(header)
+    }
+
+    /// Returns the keys of this batch's objects, along with the signing key
+    /// identifier and digests verified by header and packet_file_reader, if
+    /// they have been called.
+    pub fn report(&self) -> BatchReport {
+        self.batch.report(
+            &self.key_identifier,
+            &self.header_digest,
+            &self.packet_file_digest,
+        )
     }
 
     /// Return an avro_rs::Reader that yields the packets in the packet file,
@@ -168,22 +380,54 @@ impl<'a, H: Header, P: Packet> BatchReader<'a, H, P> {
         // will be no more than 300-400 MB, which fits quite reasonably into the
         // memory of anything we're going to run the facilitator on, so we load
         // the entire packet file into memory ...
-        let mut packet_file_reader = self.transport.get(self.batch.packet_file_key())?;
+        let packet_file_key = self.batch.packet_file_key();
+        let _context = self.batch.log_context().object_key(packet_file_key).enter();
+        debug!("reading packet file");
+        let mut packet_file_reader = self
+            .transport
+            .get(packet_file_key)
+            .map_err(transport_error(packet_file_key))?;
         let entire_packet_file = Vec::new();
         let digest_writer = DigestWriter::new();
         let mut sidecar_writer = SidecarWriter::new(entire_packet_file, digest_writer);
 
         std::io::copy(&mut packet_file_reader, &mut sidecar_writer)
-            .context("failed to load packet file")?;
+            .map_err(transport_error(packet_file_key))?;
 
         // ... then verify the digest over it ...
         if header.packet_file_digest().as_slice() != sidecar_writer.sidecar.finish().as_ref() {
-            return Err(anyhow!("packet file digest does not match header"));
+            warn!("packet file digest does not match header");
+            return Err(Error::DigestMismatchError(packet_file_key.to_owned()).into());
         }
+        self.packet_file_digest = Some(header.packet_file_digest().clone());
 
         // ... then return a packet reader.
-        Reader::with_schema(&self.packet_schema, Cursor::new(sidecar_writer.writer))
-            .context("failed to create Avro reader for packets")
+        Ok(resolving_reader(
+            &self.packet_schema,
+            Cursor::new(sidecar_writer.writer),
+        )?)
+    }
+}
+
+impl<'a, H: Header> BatchReader<'a, H, InvalidPacket> {
+    /// Returns the UUIDs of the invalid packets listed in the packet file of
+    /// this sum part or invalid UUID set batch, whose header is assumed to be
+    /// trusted. A batch without invalid packets has an empty packet file,
+    /// which is not an Avro object container, so it is not read.
+    pub fn invalid_uuids(&mut self, header: &H) -> Result<Vec<Uuid>> {
+        if header.packet_file_digest().as_slice() == digest(&SHA256, &[]).as_ref() {
+            self.packet_file_digest = Some(header.packet_file_digest().clone());
+            return Ok(Vec::new());
+        }
+        let mut reader = self.packet_file_reader(header)?;
+        let mut invalid_uuids = Vec::new();
+        loop {
+            match InvalidPacket::read(&mut reader) {
+                Ok(packet) => invalid_uuids.push(packet.uuid),
+                Err(Error::EofError) => return Ok(invalid_uuids),
+                Err(e) => return Err(e.into()),
+            }
+        }
     }
 }
 
@@ -194,6 +438,11 @@ pub struct BatchWriter<'a, H, P> {
     batch: Batch,
     transport: &'a mut dyn Transport,
     packet_schema: Schema,
+    sign_batch_manifest: bool,
+    packet_file_codec: Codec,
+    header_digest: Option<Digest>,
+    packet_file_digest: Option<Digest>,
+    key_identifier: Option<String>,
     phantom_header: PhantomData<*const H>,
     phantom_packet: PhantomData<*const P>,
 }
@@ -204,26 +453,63 @@ impl<'a, H: Header, P: Packet> BatchWriter<'a, H, P> {
             batch,
             transport,
             packet_schema: P::schema(),
+            sign_batch_manifest: false,
+            packet_file_codec: Codec::Null,
+            header_digest: None,
+            packet_file_digest: None,
+            key_identifier: None,
             phantom_header: PhantomData,
             phantom_packet: PhantomData,
         }
     }
 
+    /// Returns the keys of this batch's objects, along with the digests and
+    /// signing key identifier of whichever of them have been written.
+    pub fn report(&self) -> BatchReport {
+        self.batch.report(
+            &self.key_identifier,
+            &self.header_digest.map(|d| d.as_ref().to_vec()),
+            &self.packet_file_digest.map(|d| d.as_ref().to_vec()),
+        )
+    }
+
+    /// Configures whether put_signature will write a version 2 signature, which
+    /// additionally signs the manifest of the batch. Version 1 signatures cover
+    /// only the header.
+    pub fn set_sign_batch_manifest(&mut self, sign_batch_manifest: bool) {
+        self.sign_batch_manifest = sign_batch_manifest;
+    }
+
+    /// Configures the Avro codec with which packet_file_writer compresses the
+    /// packet file. Readers handle any codec, since it is recorded in the
+    /// packet file itself.
+    pub fn set_packet_file_codec(&mut self, codec: Codec) {
+        self.packet_file_codec = codec;
+    }
+
     /// Encode the provided header into Avro, sign that representation with the
-    /// provided key and write the header into the batch. Returns the signature
-    /// on success.
-    pub fn put_header(&mut self, header: &H, key: &EcdsaKeyPair) -> Result<Signature> {
-        let mut sidecar_writer =
-            SidecarWriter::new(self.transport.put(self.batch.header_key())?, Vec::new());
+    /// provided signer and write the header into the batch. Returns the
+    /// signature on success.
+    pub fn put_header(&mut self, header: &H, signer: &dyn BatchSigner) -> Result<Vec<u8>> {
+        let header_key = self.batch.header_key();
+        let _context = self.batch.log_context().object_key(header_key).enter();
+        debug!("writing batch header");
+        let mut sidecar_writer = SidecarWriter::new(
+            self.transport
+                .put(header_key)
+                .map_err(transport_error(header_key))?,
+            Vec::new(),
+        );
         header.write(&mut sidecar_writer)?;
         sidecar_writer
             .writer
             .complete_upload()
-            .context("failed to complete batch header upload")?;
+            .map_err(transport_error(header_key))?;
 
-        let header_signature = key
-            .sign(&SystemRandom::new(), &sidecar_writer.sidecar)
-            .context("failed to sign header file")?;
+        let header_signature = signer
+            .sign(&sidecar_writer.sidecar)
+            .map_err(signing_error(header_key.to_owned()))?;
+        self.header_digest = Some(digest(&SHA256, &sidecar_writer.sidecar));
         Ok(header_signature)
     }
 
@@ -237,23 +523,31 @@ impl<'a, H: Header, P: Packet> BatchWriter<'a, H, P> {
     where
         F: FnOnce(&mut Writer<SidecarWriter<Box<dyn TransportWriter>, DigestWriter>>) -> Result<()>,
     {
-        let mut writer = Writer::new(
+        let packet_file_key = self.batch.packet_file_key();
+        let _context = self.batch.log_context().object_key(packet_file_key).enter();
+        debug!("writing packet file");
+        let mut writer = Writer::with_codec(
             &self.packet_schema,
             SidecarWriter::new(
-                self.transport.put(self.batch.packet_file_key())?,
+                self.transport
+                    .put(packet_file_key)
+                    .map_err(transport_error(packet_file_key))?,
                 DigestWriter::new(),
             ),
+            self.packet_file_codec,
         );
 
         let result = operation(&mut writer);
-        let mut sidecar_writer = writer
-            .into_inner()
-            .with_context(|| format!("failed to flush Avro writer ({:?})", result))?;
+        let mut sidecar_writer = writer.into_inner().map_err(|e| {
+            Error::AvroError(format!("failed to flush Avro writer ({:?})", result), e)
+        })?;
 
         if let Err(e) = result {
+            warn!("cancelling packet file upload: {}", e);
             sidecar_writer
                 .writer
                 .cancel_upload()
+                .map_err(transport_error(packet_file_key))
                 .with_context(|| format!("Encountered while handling: {}", e))?;
             return Err(e);
         }
@@ -261,24 +555,112 @@ impl<'a, H: Header, P: Packet> BatchWriter<'a, H, P> {
         sidecar_writer
             .writer
             .complete_upload()
-            .context("failed to complete packet file upload")?;
-        Ok(sidecar_writer.sidecar.finish())
+            .map_err(transport_error(packet_file_key))?;
+        let packet_file_digest = sidecar_writer.sidecar.finish();
+        self.packet_file_digest = Some(packet_file_digest);
+        Ok(packet_file_digest)
     }
 
-    /// Constructs a signature structure from the provided buffers and writes it
-    /// to the batch's signature file
-    pub fn put_signature(&mut self, signature: &Signature, key_identifier: &str) -> Result<()> {
+    /// Constructs a signature structure from the provided header signature and
+    /// writes it to the batch's signature file. If this writer is configured to
+    /// sign the batch manifest, the manifest is signed with the provided signer,
+    /// which must be the one the header was signed with, and included in the
+    /// signature structure. In that case, the packet file and header must
+    /// already have been written.
+    pub fn put_signature(&mut self, signature: &[u8], signer: &dyn BatchSigner) -> Result<()> {
+        let signature_key = self.batch.signature_key();
+        let _context = self.batch.log_context().object_key(signature_key).enter();
+        let batch_manifest_signature = if self.sign_batch_manifest {
+            match (&self.header_digest, &self.packet_file_digest) {
+                (Some(header_digest), Some(packet_file_digest)) => Some(
+                    signer
+                        .sign(
+                            &self
+                                .batch
+                                .manifest(header_digest.as_ref(), packet_file_digest.as_ref())
+                                .to_bytes()?,
+                        )
+                        .map_err(signing_error(format!(
+                            "manifest of {}",
+                            self.batch.header_key()
+                        )))?,
+                ),
+                _ => {
+                    return Err(anyhow!(
+                        "header and packet file must be written before signing batch manifest"
+                    ))
+                }
+            }
+        } else {
+            None
+        };
         let batch_signature = BatchSignature {
-            batch_header_signature: signature.as_ref().to_vec(),
-            key_identifier: key_identifier.to_string(),
+            batch_header_signature: signature.to_vec(),
+            key_identifier: signer.key_identifier().to_string(),
+            batch_manifest_signature,
         };
-        let mut writer = self.transport.put(self.batch.signature_key())?;
-        batch_signature
-            .write(&mut writer)
-            .context("failed to write signature")?;
+        let mut writer = self
+            .transport
+            .put(signature_key)
+            .map_err(transport_error(signature_key))?;
+        batch_signature.write(&mut writer)?;
         writer
             .complete_upload()
-            .context("failed to complete signature upload")
+            .map_err(transport_error(signature_key))?;
+        self.key_identifier = Some(batch_signature.key_identifier.clone());
+        info!(
+            "wrote batch signed with key {} (manifest signed: {})",
+            batch_signature.key_identifier,
+            batch_signature.batch_manifest_signature.is_some()
+        );
+        Ok(())
+    }
+
+    /// Copies the header, packet file and signature already written by this
+    /// writer to the same keys in the provided transport, so that the batch
+    /// can be sent to more than one peer without being computed again.
+    pub fn copy_to(&mut self, transport: &mut dyn Transport) -> Result<()> {
+        for key in &[
+            self.batch.header_key(),
+            self.batch.packet_file_key(),
+            self.batch.signature_key(),
+        ] {
+            let _context = self.batch.log_context().object_key(key).enter();
+            debug!("copying batch object");
+            let mut reader = self.transport.get(key).map_err(transport_error(key))?;
+            let mut writer = transport.put(key).map_err(transport_error(key))?;
+            if let Err(e) = std::io::copy(&mut reader, &mut writer) {
+                warn!("cancelling copy: {}", e);
+                writer
+                    .cancel_upload()
+                    .map_err(transport_error(key))
+                    .with_context(|| format!("Encountered while handling: {}", e))?;
+                return Err(Error::TransportError((*key).to_owned(), e.into()).into());
+            }
+            writer.complete_upload().map_err(transport_error(key))?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns a function that wraps an error from a transport operation on the
+/// object at key into Error::TransportError. Error::ObjectNotFoundError is
+/// passed through so that callers can tell a missing object from a failure.
+pub(crate) fn transport_error<E: Into<anyhow::Error>>(key: &str) -> impl FnOnce(E) -> Error + '_ {
+    move |e| match e.into().downcast::<Error>() {
+        Ok(e @ Error::ObjectNotFoundError(_)) => e,
+        Ok(e) => Error::TransportError(key.to_owned(), e.into()),
+        Err(e) => Error::TransportError(key.to_owned(), e.into()),
+    }
+}
+
+/// Returns a function that wraps an error from a BatchSigner signing object
+/// into Error::SigningError, which is retryable if the signer's error is.
+fn signing_error(object: String) -> impl FnOnce(anyhow::Error) -> Error {
+    move |e| Error::SigningError {
+        object,
+        retryable: crate::is_retryable(&e),
+        source: e.into(),
     }
 }
 
@@ -287,13 +669,17 @@ mod tests {
     use super::*;
     use crate::{
         idl::{IngestionDataSharePacket, IngestionHeader},
+        signing::RemoteBatchSigner,
         test_utils::{
             default_facilitator_signing_public_key, default_ingestor_private_key,
             default_ingestor_public_key,
         },
         transport::LocalFileTransport,
-        Error,
+        BatchSigningKey, Error,
     };
+    use assert_matches::assert_matches;
+    use ring::signature::UnparsedPublicKey;
+    use std::{collections::HashMap, io::Write};
 
     #[allow(clippy::too_many_arguments)] // Grandfathered in
     fn roundtrip_batch<'a>(
@@ -304,7 +690,7 @@ mod tests {
         batch_writer: &mut BatchWriter<'a, IngestionHeader, IngestionDataSharePacket>,
         batch_reader: &mut BatchReader<'a, IngestionHeader, IngestionDataSharePacket>,
         transport: &mut LocalFileTransport,
-        write_key: &EcdsaKeyPair,
+        write_key: &BatchSigningKey,
         read_key: &UnparsedPublicKey<Vec<u8>>,
         keys_match: bool,
     ) {
@@ -366,7 +752,7 @@ mod tests {
             .put_header(&header, write_key)
             .expect("failed to write header");
 
-        let res = batch_writer.put_signature(&header_signature, "key-identifier");
+        let res = batch_writer.put_signature(&header_signature, write_key);
         assert!(res.is_ok(), "failed to put signature: {:?}", res.err());
 
         // Verify file layout is as expected
@@ -377,13 +763,12 @@ mod tests {
         }
 
         let mut key_map = HashMap::new();
-        key_map.insert("key-identifier".to_owned(), read_key.clone());
+        key_map.insert(write_key.identifier.clone(), read_key.clone());
         let header_again = batch_reader.header(&key_map);
         if !keys_match {
-            assert!(
-                header_again.is_err(),
-                "read should fail with mismatched keys"
-            );
+            let err = header_again.expect_err("read should fail with mismatched keys");
+            assert_matches!(err.downcast_ref::<Error>(), Some(Error::SignatureError(_)));
+            assert!(!crate::is_retryable(&err));
             return;
         }
         assert!(
@@ -465,33 +850,86 @@ mod tests {
             &mut batch_writer,
             &mut batch_reader,
             &mut verify_transport,
-            &default_ingestor_private_key().key,
+            &default_ingestor_private_key(),
             &read_key,
             keys_match,
         )
     }
 
+    #[test]
+    fn roundtrip_compressed_packet_file() {
+        for codec in &[Codec::Deflate, Codec::Snappy] {
+            let tempdir = tempfile::TempDir::new().unwrap();
+            let mut write_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+            let mut read_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+            let mut verify_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+
+            let aggregation_name = "fake-aggregation";
+            let batch_id = Uuid::new_v4();
+            let date = NaiveDateTime::from_timestamp(2234567890, 654321);
+
+            let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchWriter::new(
+                    Batch::new_ingestion(&aggregation_name, &batch_id, &date),
+                    &mut write_transport,
+                );
+            batch_writer.set_packet_file_codec(*codec);
+            let mut batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchReader::new(
+                    Batch::new_ingestion(&aggregation_name, &batch_id, &date),
+                    &mut read_transport,
+                );
+            let base_path = format!(
+                "{}/{}/{}",
+                aggregation_name,
+                date.format(DATE_FORMAT),
+                batch_id.to_hyphenated()
+            );
+            roundtrip_batch(
+                aggregation_name.to_string(),
+                batch_id,
+                base_path,
+                &[
+                    "batch".to_owned(),
+                    "batch.avro".to_owned(),
+                    "batch.sig".to_owned(),
+                ],
+                &mut batch_writer,
+                &mut batch_reader,
+                &mut verify_transport,
+                &default_ingestor_private_key(),
+                &default_ingestor_public_key(),
+                true,
+            )
+        }
+    }
+
     #[test]
     fn roundtrip_validation_batch_first_ok() {
-        roundtrip_validation_batch(true, true)
+        roundtrip_validation_batch(0, true)
     }
 
     #[test]
     fn roundtrip_validation_batch_first_bad_read_key() {
-        roundtrip_validation_batch(true, false)
+        roundtrip_validation_batch(0, false)
     }
 
     #[test]
     fn roundtrip_validation_batch_second_ok() {
-        roundtrip_validation_batch(false, true)
+        roundtrip_validation_batch(1, true)
     }
 
     #[test]
     fn roundtrip_validation_batch_second_bad_read_key() {
-        roundtrip_validation_batch(false, false)
+        roundtrip_validation_batch(1, false)
+    }
+
+    #[test]
+    fn roundtrip_validation_batch_third_ok() {
+        roundtrip_validation_batch(2, true)
     }
 
-    fn roundtrip_validation_batch(is_first: bool, keys_match: bool) {
+    fn roundtrip_validation_batch(server_index: usize, keys_match: bool) {
         let tempdir = tempfile::TempDir::new().unwrap();
         let mut write_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
         let mut read_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
@@ -503,12 +941,12 @@ mod tests {
 
         let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
             BatchWriter::new(
-                Batch::new_validation(&aggregation_name, &batch_id, &date, is_first),
+                Batch::new_validation(&aggregation_name, &batch_id, &date, server_index),
                 &mut write_transport,
             );
         let mut batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
             BatchReader::new(
-                Batch::new_validation(&aggregation_name, &batch_id, &date, is_first),
+                Batch::new_validation(&aggregation_name, &batch_id, &date, server_index),
                 &mut read_transport,
             );
         let base_path = format!(
@@ -517,15 +955,10 @@ mod tests {
             date.format(DATE_FORMAT),
             batch_id.to_hyphenated()
         );
-        let first_filenames = &[
-            "validity_0".to_owned(),
-            "validity_0.avro".to_owned(),
-            "validity_0.sig".to_owned(),
-        ];
-        let second_filenames = &[
-            "validity_1".to_owned(),
-            "validity_1.avro".to_owned(),
-            "validity_1.sig".to_owned(),
+        let filenames = &[
+            format!("validity_{}", server_index),
+            format!("validity_{}.avro", server_index),
+            format!("validity_{}.sig", server_index),
         ];
         let read_key = if keys_match {
             default_ingestor_public_key()
@@ -536,15 +969,11 @@ mod tests {
             aggregation_name.to_string(),
             batch_id,
             base_path,
-            if is_first {
-                first_filenames
-            } else {
-                second_filenames
-            },
+            filenames,
             &mut batch_writer,
             &mut batch_reader,
             &mut verify_transport,
-            &default_ingestor_private_key().key,
+            &default_ingestor_private_key(),
             &read_key,
             keys_match,
         )
@@ -552,25 +981,30 @@ mod tests {
 
     #[test]
     fn roundtrip_sum_batch_first_ok() {
-        roundtrip_sum_batch(true, true)
+        roundtrip_sum_batch(0, true)
     }
 
     #[test]
     fn roundtrip_sum_batch_first_bad_read_key() {
-        roundtrip_sum_batch(true, false)
+        roundtrip_sum_batch(0, false)
     }
 
     #[test]
     fn roundtrip_sum_batch_second_ok() {
-        roundtrip_sum_batch(false, true)
+        roundtrip_sum_batch(1, true)
     }
 
     #[test]
     fn roundtrip_sum_batch_second_bad_read_key() {
-        roundtrip_sum_batch(false, false)
+        roundtrip_sum_batch(1, false)
+    }
+
+    #[test]
+    fn roundtrip_sum_batch_third_ok() {
+        roundtrip_sum_batch(2, true)
     }
 
-    fn roundtrip_sum_batch(is_first: bool, keys_match: bool) {
+    fn roundtrip_sum_batch(server_index: usize, keys_match: bool) {
         let tempdir = tempfile::TempDir::new().unwrap();
         let mut write_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
         let mut read_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
@@ -583,12 +1017,12 @@ mod tests {
 
         let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
             BatchWriter::new(
-                Batch::new_sum(&aggregation_name, &start, &end, is_first),
+                Batch::new_sum(&aggregation_name, &start, &end, server_index),
                 &mut write_transport,
             );
         let mut batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
             BatchReader::new(
-                Batch::new_sum(&aggregation_name, &start, &end, is_first),
+                Batch::new_sum(&aggregation_name, &start, &end, server_index),
                 &mut read_transport,
             );
         let batch_path = format!(
@@ -597,15 +1031,10 @@ mod tests {
             start.format(DATE_FORMAT),
             end.format(DATE_FORMAT)
         );
-        let first_filenames = &[
-            "sum_0".to_owned(),
-            "invalid_uuid_0.avro".to_owned(),
-            "sum_0.sig".to_owned(),
-        ];
-        let second_filenames = &[
-            "sum_1".to_owned(),
-            "invalid_uuid_1.avro".to_owned(),
-            "sum_1.sig".to_owned(),
+        let filenames = &[
+            format!("sum_{}", server_index),
+            format!("invalid_uuid_{}.avro", server_index),
+            format!("sum_{}.sig", server_index),
         ];
         let read_key = if keys_match {
             default_ingestor_public_key()
@@ -616,17 +1045,330 @@ mod tests {
             aggregation_name.to_string(),
             batch_id,
             batch_path,
-            if is_first {
-                first_filenames
-            } else {
-                second_filenames
-            },
+            filenames,
             &mut batch_writer,
             &mut batch_reader,
             &mut verify_transport,
-            &default_ingestor_private_key().key,
+            &default_ingestor_private_key(),
             &read_key,
             keys_match,
         )
     }
+
+    #[test]
+    fn batch_manifest_signature() {
+        roundtrip_replayed_batch(true);
+    }
+
+    #[test]
+    fn replayed_batch_without_manifest_signature() {
+        roundtrip_replayed_batch(false);
+    }
+
+    /// Writes an ingestion batch with the provided header, after filling in its
+    /// packet_file_digest, containing no packets.
+    fn write_ingestion_batch(
+        transport: &mut LocalFileTransport,
+        batch: Batch,
+        header: &mut IngestionHeader,
+        sign_batch_manifest: bool,
+    ) {
+        let signing_key = default_ingestor_private_key();
+        let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchWriter::new(batch, transport);
+        batch_writer.set_sign_batch_manifest(sign_batch_manifest);
+        let packet_file_digest = batch_writer
+            .packet_file_writer(|_| Ok(()))
+            .expect("failed to write packets");
+        header.packet_file_digest = packet_file_digest.as_ref().to_vec();
+        let header_signature = batch_writer
+            .put_header(header, &signing_key)
+            .expect("failed to write header");
+        batch_writer
+            .put_signature(&header_signature, &signing_key)
+            .expect("failed to put signature");
+    }
+
+    fn ingestor_key_map() -> BatchSigningPublicKeys {
+        let mut key_map = HashMap::new();
+        key_map.insert(
+            default_ingestor_private_key().identifier,
+            default_ingestor_public_key(),
+        );
+        key_map
+    }
+
+    fn fake_ingestion_header(aggregation_name: &str, batch_id: &Uuid) -> IngestionHeader {
+        IngestionHeader {
+            batch_uuid: *batch_id,
+            name: aggregation_name.to_owned(),
+            bins: 2,
+            epsilon: 1.601,
+            prime: 17,
+            number_of_servers: 2,
+            hamming_weight: None,
+            batch_start_time: 789456123,
+            batch_end_time: 789456321,
+            packet_file_digest: vec![],
+        }
+    }
+
+    /// Writes an ingestion batch, then copies its files to the object keys of
+    /// the same batch under another date and checks that the original batch
+    /// can always be read, but the copy only if the batch manifest was not
+    /// signed.
+    fn roundtrip_replayed_batch(sign_batch_manifest: bool) {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+
+        let aggregation_name = "fake-aggregation";
+        let batch_id = Uuid::new_v4();
+        let date = NaiveDateTime::from_timestamp(2234567890, 654321);
+        let replayed_date = NaiveDateTime::from_timestamp(2234568890, 654321);
+
+        let mut header = fake_ingestion_header(aggregation_name, &batch_id);
+        write_ingestion_batch(
+            &mut transport,
+            Batch::new_ingestion(&aggregation_name, &batch_id, &date),
+            &mut header,
+            sign_batch_manifest,
+        );
+
+        let original = Batch::new_ingestion(&aggregation_name, &batch_id, &date);
+        let replayed = Batch::new_ingestion(&aggregation_name, &batch_id, &replayed_date);
+        for (from, to) in &[
+            (original.header_key(), replayed.header_key()),
+            (original.packet_file_key(), replayed.packet_file_key()),
+            (original.signature_key(), replayed.signature_key()),
+        ] {
+            let mut content = Vec::new();
+            transport
+                .get(from)
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap();
+            let mut writer = transport.put(to).unwrap();
+            writer.write_all(&content).unwrap();
+            writer.complete_upload().unwrap();
+        }
+
+        let key_map = ingestor_key_map();
+
+        let mut batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(original, &mut transport);
+        assert_eq!(batch_reader.header(&key_map).unwrap(), header);
+
+        let mut replayed_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(replayed, &mut transport);
+        assert_eq!(
+            replayed_reader.header(&key_map).is_ok(),
+            !sign_batch_manifest
+        );
+    }
+
+    #[test]
+    fn header_path_mismatch() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let date = NaiveDateTime::from_timestamp(2234567890, 654321);
+        let batch_id = Uuid::new_v4();
+        let other_batch_id = Uuid::new_v4();
+
+        let mut header = fake_ingestion_header("fake-aggregation", &other_batch_id);
+        write_ingestion_batch(
+            &mut transport,
+            Batch::new_ingestion("fake-aggregation", &batch_id, &date),
+            &mut header,
+            false,
+        );
+        let mut batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(
+                Batch::new_ingestion("fake-aggregation", &batch_id, &date),
+                &mut transport,
+            );
+        let err = batch_reader.header(&ingestor_key_map()).unwrap_err();
+        assert_matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::BatchPathMismatchError {
+                field: "batch_uuid",
+                ..
+            })
+        );
+
+        let mut header = fake_ingestion_header("other-aggregation", &batch_id);
+        write_ingestion_batch(
+            &mut transport,
+            Batch::new_ingestion("fake-aggregation", &batch_id, &date),
+            &mut header,
+            false,
+        );
+        let mut batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(
+                Batch::new_ingestion("fake-aggregation", &batch_id, &date),
+                &mut transport,
+            );
+        let err = batch_reader.header(&ingestor_key_map()).unwrap_err();
+        assert_matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::BatchPathMismatchError { field: "name", .. })
+        );
+    }
+
+    #[test]
+    fn error_classification() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let date = NaiveDateTime::from_timestamp(2234567890, 654321);
+        let batch_id = Uuid::new_v4();
+        let batch = || Batch::new_ingestion("fake-aggregation", &batch_id, &date);
+
+        // A batch that has not been written yet may show up later.
+        let mut batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(batch(), &mut transport);
+        let err = batch_reader.header(&ingestor_key_map()).unwrap_err();
+        assert_matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::ObjectNotFoundError(_))
+        );
+        assert!(crate::is_retryable(&err));
+
+        // A packet file that does not match the header never will.
+        let mut header = fake_ingestion_header("fake-aggregation", &batch_id);
+        write_ingestion_batch(&mut transport, batch(), &mut header, false);
+        let mut writer = transport.put(batch().packet_file_key()).unwrap();
+        writer.write_all(b"tampered").unwrap();
+        writer.complete_upload().unwrap();
+        let mut batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(batch(), &mut transport);
+        let header = batch_reader.header(&ingestor_key_map()).unwrap();
+        let err = match batch_reader.packet_file_reader(&header) {
+            Ok(_) => panic!("tampered packet file should not be read"),
+            Err(e) => e,
+        };
+        assert_matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::DigestMismatchError(_))
+        );
+        assert!(!crate::is_retryable(&err));
+
+        // A signing service may recover from failing, but a signer whose key
+        // is unusable or unknown to the service won't.
+        struct UnusableKey;
+        impl BatchSigner for UnusableKey {
+            fn key_identifier(&self) -> &str {
+                "unusable-key"
+            }
+
+            fn sign(&self, _: &[u8]) -> Result<Vec<u8>> {
+                Err(anyhow!("failed to parse PKCS#8 private key"))
+            }
+        }
+        let unavailable = mockito::mock("POST", "/sign").with_status(503).create();
+        let signers: Vec<(Box<dyn BatchSigner>, bool)> = vec![
+            (
+                Box::new(RemoteBatchSigner::new(&mockito::server_url(), "key")),
+                true,
+            ),
+            (Box::new(UnusableKey), false),
+        ];
+        for (signer, retryable) in signers {
+            let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchWriter::new(batch(), &mut transport);
+            let err = batch_writer.put_header(&header, &*signer).unwrap_err();
+            assert_matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::SigningError { .. })
+            );
+            assert_eq!(crate::is_retryable(&err), retryable);
+        }
+        drop(unavailable);
+        let _unknown_key = mockito::mock("POST", "/sign").with_status(400).create();
+        let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchWriter::new(batch(), &mut transport);
+        let err = batch_writer
+            .put_header(
+                &header,
+                &RemoteBatchSigner::new(&mockito::server_url(), "key"),
+            )
+            .unwrap_err();
+        assert!(!crate::is_retryable(&err));
+    }
+
+    #[test]
+    fn header_time_tolerance() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let batch_id = Uuid::new_v4();
+        // Batch dates have minute granularity.
+        let date = NaiveDateTime::parse_from_str("2020/10/31/20/29", DATE_FORMAT).unwrap();
+
+        let mut header = fake_ingestion_header("fake-aggregation", &batch_id);
+        header.batch_start_time = date.timestamp_millis() - 3_600_000;
+        header.batch_end_time = date.timestamp_millis() + 30_000;
+        write_ingestion_batch(
+            &mut transport,
+            Batch::new_ingestion("fake-aggregation", &batch_id, &date),
+            &mut header,
+            false,
+        );
+
+        for (batch_date, tolerance, ok) in &[
+            // Batch date within time range
+            (date, Some(Duration::seconds(0)), true),
+            // Batch date after time range, but within tolerance
+            (
+                date + Duration::seconds(90),
+                Some(Duration::seconds(60)),
+                true,
+            ),
+            // Batch date after time range and tolerance
+            (
+                date + Duration::seconds(120),
+                Some(Duration::seconds(60)),
+                false,
+            ),
+            // Batch date before time range and tolerance
+            (
+                date - Duration::hours(2),
+                Some(Duration::seconds(60)),
+                false,
+            ),
+            // Time range not checked
+            (date + Duration::days(1), None, true),
+        ] {
+            // Copy the batch to the other date so that only the time check
+            // can fail.
+            let batch = Batch::new_ingestion("fake-aggregation", &batch_id, &date);
+            let other_batch = Batch::new_ingestion("fake-aggregation", &batch_id, batch_date);
+            if batch.header_key() != other_batch.header_key() {
+                for (from, to) in &[
+                    (batch.header_key(), other_batch.header_key()),
+                    (batch.signature_key(), other_batch.signature_key()),
+                ] {
+                    let mut content = Vec::new();
+                    transport
+                        .get(from)
+                        .unwrap()
+                        .read_to_end(&mut content)
+                        .unwrap();
+                    let mut writer = transport.put(to).unwrap();
+                    writer.write_all(&content).unwrap();
+                    writer.complete_upload().unwrap();
+                }
+            }
+
+            let mut batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchReader::new(other_batch, &mut transport);
+            batch_reader.set_time_tolerance(*tolerance);
+            let result = batch_reader.header(&ingestor_key_map());
+            if *ok {
+                assert!(result.is_ok(), "{:?} {:?}", batch_date, result.err());
+            } else {
+                assert_matches!(
+                    result.unwrap_err().downcast_ref::<Error>(),
+                    Some(Error::BatchTimeError { .. })
+                );
+            }
+        }
+    }
 }
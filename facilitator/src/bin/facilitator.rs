@@ -1,29 +1,55 @@
 use anyhow::{anyhow, Context, Result};
-use chrono::{prelude::Utc, NaiveDateTime};
-use clap::{App, Arg, ArgMatches, SubCommand};
+use avro_rs::{Codec, Schema};
+use chrono::{prelude::Utc, Duration, NaiveDateTime};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use log::{debug, error, info, warn, LevelFilter};
+use once_cell::sync::Lazy;
 use prio::encrypt::PrivateKey;
-use ring::signature::{
-    EcdsaKeyPair, KeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_ASN1,
-    ECDSA_P256_SHA256_ASN1_SIGNING,
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    env,
+    ffi::OsString,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    str::FromStr,
 };
-use std::{collections::HashMap, str::FromStr};
 use uuid::Uuid;
 
 use facilitator::{
     aggregation::BatchAggregator,
-    config::{Identity, StoragePath},
+    batch::Batch,
+    combine::combine_sums,
+    compatibility::SchemaChange,
+    config::{ConfigFile, Identity, StoragePath},
+    dump::{dump, DumpFormat, ObjectKind, PacketDecryption},
+    idl::{Header, IngestionHeader},
     intake::BatchIntaker,
-    manifest::{IngestionServerGlobalManifest, PortalServerGlobalManifest, SpecificManifest},
+    logging::{self, LogContext, LogFormat},
+    manifest::{
+        public_key_from_spki_der, BatchSigningPublicKeys, IngestionServerGlobalManifest,
+        PortalServerGlobalManifest, SpecificManifest,
+    },
+    metrics::Registry,
+    policy::{AggregationPolicies, AggregationPolicy},
+    reduce::SumPartReducer,
+    report::{JobReport, StageReport},
     sample::generate_ingestion_sample,
+    signing::{
+        BatchSigner, BatchSigningKeySet, FileBatchSigner, RemoteBatchSigner, SigningKeyPair,
+    },
     test_utils::{
         DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY, DEFAULT_FACILITATOR_SIGNING_PRIVATE_KEY,
         DEFAULT_PHA_ECIES_PRIVATE_KEY,
     },
     transport::{
-        GCSTransport, LocalFileTransport, S3Transport, SignableTransport, Transport,
+        find_ingestor, GCSTransport, IngestorTransport, LocalFileTransport,
+        PeerValidationTransport, S3Transport, SignableTransport, Transport,
         VerifiableAndDecryptableTransport, VerifiableTransport,
     },
-    BatchSigningKey, DATE_FORMAT,
+    verify::{verify_batch, BatchKind},
+    BatchSigningKey, Error, DATE_FORMAT, MAX_NUMBER_OF_SERVERS,
 };
 
 fn num_validator<F: FromStr>(s: String) -> Result<(), String> {
@@ -57,6 +83,8 @@ fn path_validator(s: String) -> Result<(), String> {
 trait AppArgumentAdder {
     fn add_instance_name_argument(self: Self) -> Self;
 
+    fn add_ingestor_name_argument(self: Self) -> Self;
+
     fn add_manifest_base_url_argument(self: Self, entity: Entity) -> Self;
 
     fn add_storage_arguments(self: Self, entity: Entity, in_out: InOut) -> Self;
@@ -65,7 +93,21 @@ trait AppArgumentAdder {
 
     fn add_batch_signing_key_arguments(self: Self) -> Self;
 
+    fn add_batch_manifest_signature_argument(self: Self) -> Self;
+
+    fn add_batch_time_tolerance_argument(self: Self) -> Self;
+
+    fn add_upload_report_argument(self: Self) -> Self;
+
+    fn add_packet_file_codec_argument(self: Self, output: &str) -> Self;
+
     fn add_packet_decryption_key_argument(self: Self) -> Self;
+
+    fn add_server_index_argument(self: Self) -> Self;
+
+    fn add_number_of_servers_argument(self: Self) -> Self;
+
+    fn add_aggregation_policy_argument(self: Self) -> Self;
 }
 
 const SHARED_HELP: &str = "Storage arguments: Any flag ending in -input or -output can take an \
@@ -89,9 +131,10 @@ const SHARED_HELP: &str = "Storage arguments: Any flag ending in -input or -outp
      to impersonate a different account, which should have permissions to write \
      to or read from the named bucket. \
      \
-     Keys: All keys are P-256. Public keys are base64-encoded DER SPKI. Private \
-     keys are in the base64 encoded format expected by libprio-rs, or base64-encoded \
-     PKCS#8, as documented. \
+     Keys: Batch signing public keys are base64-encoded DER SPKI containing a \
+     P-256, P-384 or Ed25519 key. Other keys are P-256. Private keys are in the \
+     base64 encoded format expected by libprio-rs, or base64-encoded PKCS#8, \
+     as documented. \
     ";
 
 /// The string "-input" or "-output", for appending to arg names.
@@ -115,6 +158,8 @@ enum Entity {
     Peer,
     Own,
     Portal,
+    /// Each of the data share processors, when read by the portal server.
+    Server,
 }
 
 /// We need to be able to give &'static strs to `clap`, but sometimes we want to generate them
@@ -130,6 +175,7 @@ impl Entity {
             Entity::Peer => "peer",
             Entity::Own => "own",
             Entity::Portal => "portal",
+            Entity::Server => "server",
         }
     }
 
@@ -139,6 +185,19 @@ impl Entity {
     fn suffix(&self, s: &str) -> &'static str {
         leak_string(format!("{}{}", self.str(), s))
     }
+
+    /// There may be several ingestors, peers or servers, in which case each of
+    /// their arguments is given either once per ingestor, in the same order as
+    /// ingestor-name, or once per peer or server, in order of server index, or
+    /// once for all of them.
+    fn allow_repeats<'a, 'b>(&self, arg: Arg<'a, 'b>) -> Arg<'a, 'b> {
+        match self {
+            Entity::Ingestor | Entity::Peer | Entity::Server => {
+                arg.multiple(true).number_of_values(1)
+            }
+            _ => arg,
+        }
+    }
 }
 
 impl<'a, 'b> AppArgumentAdder for App<'a, 'b> {
@@ -158,10 +217,31 @@ impl<'a, 'b> AppArgumentAdder for App<'a, 'b> {
         )
     }
 
+    fn add_ingestor_name_argument(self: App<'a, 'b>) -> App<'a, 'b> {
+        self.arg(
+            Arg::with_name("ingestor-name")
+                .long("ingestor-name")
+                .value_name("NAME")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Name of an ingestor, given once per ingestor")
+                .long_help(
+                    "Name of an ingestor, e.g. \"apple\", given once per \
+                    ingestor when batches are read from several. The other \
+                    ingestor arguments are then each given either once per \
+                    ingestor, in the same order, or once for all of them, \
+                    with an empty value standing for an absent one. Job \
+                    reports record the name of the ingestor each ingestion \
+                    batch was read from.",
+                ),
+        )
+    }
+
     fn add_manifest_base_url_argument(self: App<'a, 'b>, entity: Entity) -> App<'a, 'b> {
         let name = entity.suffix("-manifest-base-url");
         self.arg(
-            Arg::with_name(name)
+            entity
+                .allow_repeats(Arg::with_name(name))
                 .long(name)
                 .value_name("BASE_URL")
                 .help("Base URL relative to which manifests should be fetched")
@@ -177,7 +257,8 @@ impl<'a, 'b> AppArgumentAdder for App<'a, 'b> {
 
     fn add_storage_arguments(self: App<'a, 'b>, entity: Entity, in_out: InOut) -> App<'a, 'b> {
         self.arg(
-            Arg::with_name(entity.suffix(in_out.str()))
+            entity
+                .allow_repeats(Arg::with_name(entity.suffix(in_out.str())))
                 .long(entity.suffix(in_out.str()))
                 .value_name("PATH")
                 .validator(path_validator)
@@ -185,7 +266,8 @@ impl<'a, 'b> AppArgumentAdder for App<'a, 'b> {
                 .help("Storage path (gs://, s3:// or local dir name)"),
         )
         .arg(
-            Arg::with_name(entity.suffix("-identity"))
+            entity
+                .allow_repeats(Arg::with_name(entity.suffix("-identity")))
                 .long(entity.suffix("-identity"))
                 .value_name("IAM_ROLE_OR_SERVICE_ACCOUNT")
                 .help(leak_string(format!(
@@ -197,7 +279,8 @@ impl<'a, 'b> AppArgumentAdder for App<'a, 'b> {
 
     fn add_batch_public_key_arguments(self: App<'a, 'b>, entity: Entity) -> App<'a, 'b> {
         self.arg(
-            Arg::with_name(entity.suffix("-public-key"))
+            entity
+                .allow_repeats(Arg::with_name(entity.suffix("-public-key")))
                 .long(entity.suffix("-public-key"))
                 .value_name("B64")
                 .help(leak_string(format!(
@@ -209,7 +292,8 @@ impl<'a, 'b> AppArgumentAdder for App<'a, 'b> {
                 .validator(b64_validator),
         )
         .arg(
-            Arg::with_name(entity.suffix("-public-key-identifier"))
+            entity
+                .allow_repeats(Arg::with_name(entity.suffix("-public-key-identifier")))
                 .long(entity.suffix("-public-key-identifier"))
                 .value_name("KEY_ID")
                 .help(leak_string(format!(
@@ -228,15 +312,63 @@ impl<'a, 'b> AppArgumentAdder for App<'a, 'b> {
                 .value_name("B64_PKCS8")
                 .help("Batch signing private key for this server")
                 .long_help(
-                    "Base64 encoded PKCS#8 document containing P-256 \
-                    batch signing private key to be used by this server when \
-                    sending messages to other servers. If not specified, a \
-                    fixed private key is used.",
+                    "Base64 encoded PKCS#8 document containing an ECDSA \
+                    P-256 or P-384 or an Ed25519 batch signing private key to \
+                    be used by this server when sending messages to other \
+                    servers. If not specified, a fixed private key is used. \
+                    Ignored if batch-signing-private-key-file, \
+                    batch-signer-url or batch-signing-key-set is provided.",
                 )
                 .default_value(DEFAULT_FACILITATOR_SIGNING_PRIVATE_KEY)
                 .hide_default_value(true)
                 .validator(b64_validator),
         )
+        .arg(
+            Arg::with_name("batch-signing-private-key-file")
+                .long("batch-signing-private-key-file")
+                .env("BATCH_SIGNING_PRIVATE_KEY_FILE")
+                .value_name("PATH")
+                .conflicts_with("batch-signer-url")
+                .help("File containing batch signing private key for this server")
+                .long_help(
+                    "Path to a file, such as a mounted Kubernetes secret, \
+                    containing the ECDSA P-256 or P-384 or Ed25519 batch \
+                    signing private key as a PKCS#8 document, either PEM \
+                    armored, base64 encoded or DER. The file is read once \
+                    per job, so a rotated key is picked up by the next job.",
+                ),
+        )
+        .arg(
+            Arg::with_name("batch-signer-url")
+                .long("batch-signer-url")
+                .env("BATCH_SIGNER_URL")
+                .value_name("URL")
+                .help("Base URL of an external batch signing service")
+                .long_help(
+                    "Base URL of an external signing service, such as a \
+                    sidecar fronting a KMS or HSM, to which batch headers are \
+                    sent to be signed so that the private key never enters \
+                    this process. Signatures are requested by POSTing \
+                    {\"key_identifier\", \"message\"} to <URL>/sign.",
+                ),
+        )
+        .arg(
+            Arg::with_name("batch-signing-key-set")
+                .long("batch-signing-key-set")
+                .env("BATCH_SIGNING_KEY_SET")
+                .value_name("PATH")
+                .conflicts_with_all(&["batch-signing-private-key-file", "batch-signer-url"])
+                .help("File containing a set of batch signing keys to rotate through")
+                .long_help(
+                    "Path to a JSON file listing batch signing keys, each with \
+                    an identifier, an RFC 3339 activation-time and one of \
+                    private-key, private-key-file or signer-url. The key with \
+                    the latest activation time in the past is used, and only \
+                    if our specific manifest, fetched from \
+                    own-manifest-base-url, already publishes it. \
+                    batch-signing-private-key-identifier is ignored.",
+                ),
+        )
         .arg(
             Arg::with_name("batch-signing-private-key-identifier")
                 .long("batch-signing-private-key-identifier")
@@ -254,6 +386,79 @@ impl<'a, 'b> AppArgumentAdder for App<'a, 'b> {
         )
     }
 
+    fn add_batch_manifest_signature_argument(self: App<'a, 'b>) -> App<'a, 'b> {
+        self.arg(
+            Arg::with_name("sign-batch-manifest")
+                .long("sign-batch-manifest")
+                .env("SIGN_BATCH_MANIFEST")
+                .help("Write version 2 batch signatures")
+                .long_help(
+                    "Include in each batch signature message a signature over \
+                    the batch manifest, which covers the object keys, \
+                    aggregation name and batch UUID as well as the header and \
+                    packet file digests, so that peers can detect batches \
+                    replayed under another path. Peers that do not \
+                    understand version 2 signatures ignore it.",
+                ),
+        )
+    }
+
+    fn add_batch_time_tolerance_argument(self: App<'a, 'b>) -> App<'a, 'b> {
+        self.arg(
+            Arg::with_name("batch-time-tolerance")
+                .long("batch-time-tolerance")
+                .env("BATCH_TIME_TOLERANCE")
+                .value_name("SECONDS")
+                .validator(num_validator::<i64>)
+                .help("Tolerance when checking ingestion batch times against their date")
+                .long_help(
+                    "If set, ingestion batches whose time range, as given by \
+                    batch_start_time and batch_end_time in the header, is \
+                    further than this many seconds from the date in the batch \
+                    path are rejected. Since batch paths have minute \
+                    granularity, this should be at least 60.",
+                ),
+        )
+    }
+
+    fn add_upload_report_argument(self: App<'a, 'b>) -> App<'a, 'b> {
+        self.arg(
+            Arg::with_name("upload-report")
+                .long("upload-report")
+                .env("UPLOAD_REPORT")
+                .help("Also upload the job report next to the output batch")
+                .long_help(
+                    "Also upload the job report next to the output batch, to \
+                    the key of the output batch's header with .report.json \
+                    appended. The report is only uploaded if the job \
+                    succeeds.",
+                ),
+        )
+    }
+
+    fn add_packet_file_codec_argument(self: App<'a, 'b>, output: &str) -> App<'a, 'b> {
+        let name = leak_string(format!("{}-codec", output));
+        self.arg(
+            Arg::with_name(name)
+                .long(name)
+                .env(leak_string(name.to_uppercase().replace('-', "_")))
+                .value_name("CODEC")
+                .possible_values(&["null", "deflate", "snappy"])
+                .default_value("null")
+                .help(leak_string(format!(
+                    "Avro codec with which to compress {} packet files",
+                    output
+                )))
+                .long_help(leak_string(format!(
+                    "Avro codec with which to compress {} packet files. \
+                    Readers handle any codec, since it is recorded in the \
+                    packet file. See examples/packet_file_codecs.rs for \
+                    the size and CPU cost of each.",
+                    output
+                ))),
+        )
+    }
+
     fn add_packet_decryption_key_argument(self: App<'a, 'b>) -> App<'a, 'b> {
         self.arg(
             Arg::with_name("packet-decryption-keys")
@@ -273,24 +478,173 @@ impl<'a, 'b> AppArgumentAdder for App<'a, 'b> {
                 .hide_default_value(true),
         )
     }
+
+    fn add_server_index_argument(self: App<'a, 'b>) -> App<'a, 'b> {
+        self.arg(
+            Arg::with_name("server-index")
+                .long("server-index")
+                .env("SERVER_INDEX")
+                .value_name("INDEX")
+                .validator(num_validator::<usize>)
+                .conflicts_with("is-first")
+                .help("Index of this server among those receiving shares")
+                .long_help(
+                    "Index of this server among those receiving shares, which \
+                    names the validation batches and sum parts it writes. The \
+                    PHA is server 0 and facilitators are numbered from 1. \
+                    is-first is equivalent to 0. If neither is given, this is \
+                    server 1.",
+                ),
+        )
+    }
+
+    fn add_number_of_servers_argument(self: App<'a, 'b>) -> App<'a, 'b> {
+        self.arg(
+            Arg::with_name("number-of-servers")
+                .long("number-of-servers")
+                .env("NUMBER_OF_SERVERS")
+                .value_name("COUNT")
+                .validator(num_validator::<usize>)
+                .default_value("2")
+                .help("Number of servers receiving shares, i.e. this one and its peers")
+                .long_help(
+                    "Number of servers receiving shares: the PHA and one or \
+                    more facilitators. Ingestion batches must have been split \
+                    for this many servers. libprio only supports two, so more \
+                    are rejected for now. With more than two, the peer \
+                    arguments are given once per peer, in order of server \
+                    index and skipping this server, or once for all of them.",
+                ),
+        )
+    }
+
+    fn add_aggregation_policy_argument(self: App<'a, 'b>) -> App<'a, 'b> {
+        self.arg(
+            Arg::with_name("aggregation-policy")
+                .long("aggregation-policy")
+                .env("AGGREGATION_POLICY")
+                .value_name("PATH")
+                .help("TOML or YAML file of limits on ingestion batch parameters")
+                .long_help(
+                    "TOML or YAML file of limits on the parameters of \
+                    ingestion batches, per aggregation: allowed bins range, \
+                    expected prime, epsilon bounds, required hamming weight, \
+                    maximum packets per batch and maximum batch time skew. \
+                    Batches that violate them are rejected. Without a policy, \
+                    only the prime is checked, against libprio's modulus.",
+                ),
+        )
+    }
 }
 
-fn main() -> Result<(), anyhow::Error> {
-    let matches = App::new("facilitator")
-        .about("Prio data share processor")
-        // Environment variables are injected via build.rs
-        .version(&*format!(
+/// Exit status for failures that may succeed if the same command is retried.
+const EXIT_RETRYABLE: i32 = 1;
+/// Exit status for failures inherent to the batch being processed, which
+/// should be quarantined rather than retried.
+const EXIT_PERMANENT: i32 = 2;
+
+fn main() {
+    if let Err(e) = run() {
+        // Once the logger is installed, run has already logged the error.
+        if log::max_level() == LevelFilter::Off {
+            eprintln!("Error: {:?}", e);
+        }
+        std::process::exit(if facilitator::is_retryable(&e) {
+            EXIT_RETRYABLE
+        } else {
+            EXIT_PERMANENT
+        });
+    }
+}
+
+/// The subcommands run by deployments, against which config files are
+/// validated.
+const CONFIGURABLE_SUBCOMMANDS: &[&str] = &[
+    "generate-ingestion-sample",
+    "intake-batch",
+    "aggregate",
+    "reduce",
+    "combine-sums",
+];
+
+fn app() -> App<'static, 'static> {
+    // Environment variables are injected via build.rs
+    static VERSION: Lazy<String> = Lazy::new(|| {
+        format!(
             "{} {} {}",
             env!("VERGEN_SEMVER"),
             env!("VERGEN_SHA_SHORT"),
             env!("VERGEN_BUILD_TIMESTAMP"),
-        ))
+        )
+    });
+
+    App::new("facilitator")
+        .about("Prio data share processor")
+        .version(VERSION.as_str())
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .env("CONFIG_FILE")
+                .value_name("PATH")
+                .help("TOML or YAML file providing values for arguments")
+                .long_help(
+                    "TOML or YAML file providing values for arguments, whose \
+                    keys are argument names, optionally grouped into tables by \
+                    entity, e.g. input in the table ingestor is the value of \
+                    ingestor-input. Arguments are taken from the command line, \
+                    then the config file, then environment variables, then \
+                    defaults. Settings the subcommand does not take are \
+                    ignored; use \"config validate\" to check the file.",
+                ),
+        )
         .arg(
             Arg::with_name("verbose")
                 .long("verbose")
                 .short("v")
                 .help("Enable verbose output to stderr"),
         )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .env("LOG_FORMAT")
+                .value_name("FORMAT")
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("Format of log records written to stderr")
+                .long_help(
+                    "Format of log records written to stderr. With \"json\", \
+                    each record is a JSON object on its own line, carrying the \
+                    aggregation name, batch UUID, date, role and object key it \
+                    pertains to as members.",
+                ),
+        )
+        .arg(
+            Arg::with_name("report")
+                .long("report")
+                .env("REPORT")
+                .value_name("PATH")
+                .help("File to write the JSON job report to, instead of stdout"),
+        )
+        .arg(
+            Arg::with_name("metrics-push-gateway")
+                .long("metrics-push-gateway")
+                .env("METRICS_PUSH_GATEWAY")
+                .value_name("URL")
+                .help("Prometheus Pushgateway to push metrics to when exiting"),
+        )
+        .arg(
+            Arg::with_name("metrics-textfile")
+                .long("metrics-textfile")
+                .env("METRICS_TEXTFILE")
+                .value_name("PATH")
+                .help("File to write metrics to when exiting")
+                .long_help(
+                    "File to write metrics to when exiting, in the Prometheus \
+                    text format, e.g. for the node exporter's textfile \
+                    collector.",
+                ),
+        )
         .subcommand(
             SubCommand::with_name("generate-ingestion-sample")
                 .about("Generate sample data files")
@@ -407,11 +761,24 @@ fn main() -> Result<(), anyhow::Error> {
                         .help("End of timespan covered by the batch, in milliseconds since epoch")
                         .default_value("1000000100")
                         .validator(num_validator::<i64>),
+                )
+                .arg(
+                    Arg::with_name("hamming-weight")
+                        .long("hamming-weight")
+                        .value_name("INT")
+                        .help("Number of bits set in each vector, declared in the batch header")
+                        .long_help(
+                            "If set, each vector has exactly this many bits \
+                            set, and the batch header declares the hamming \
+                            weight so that it is verified during aggregation. \
+                            Otherwise, bits are set at random.",
+                        )
+                        .validator(num_validator::<i32>),
                 ),
         )
         .subcommand(
             SubCommand::with_name("intake-batch")
-                .about(format!("Validate an input share (from an ingestor's bucket) and emit a validation share.\n\n{}", SHARED_HELP).as_str())
+                .about(leak_string(format!("Validate an input share (from an ingestor's bucket) and emit a validation share.\n\n{}", SHARED_HELP)))
                 .add_instance_name_argument()
                 .arg(
                     Arg::with_name("aggregation-id")
@@ -445,17 +812,26 @@ fn main() -> Result<(), anyhow::Error> {
                     "Whether this is the \"first\" server receiving a share, \
                     i.e., the PHA.",
                 ))
+                .add_server_index_argument()
+                .add_number_of_servers_argument()
+                .add_aggregation_policy_argument()
                 .add_packet_decryption_key_argument()
+                .add_ingestor_name_argument()
                 .add_batch_public_key_arguments(Entity::Ingestor)
                 .add_batch_signing_key_arguments()
+                .add_batch_manifest_signature_argument()
+                .add_batch_time_tolerance_argument()
+                .add_upload_report_argument()
+                .add_packet_file_codec_argument("validation")
                 .add_manifest_base_url_argument(Entity::Ingestor)
                 .add_storage_arguments(Entity::Ingestor, InOut::Input)
+                .add_manifest_base_url_argument(Entity::Own)
                 .add_manifest_base_url_argument(Entity::Peer)
                 .add_storage_arguments(Entity::Peer, InOut::Output)
         )
         .subcommand(
             SubCommand::with_name("aggregate")
-                .about(format!("Verify peer validation share and emit sum part.\n\n{}", SHARED_HELP).as_str())
+                .about(leak_string(format!("Verify peer validation share and emit sum part.\n\n{}", SHARED_HELP)))
                 .add_instance_name_argument()
                 .arg(
                     Arg::with_name("aggregation-id")
@@ -517,6 +893,7 @@ fn main() -> Result<(), anyhow::Error> {
                         )
                         .validator(date_validator),
                 )
+                .add_ingestor_name_argument()
                 .add_manifest_base_url_argument(Entity::Ingestor)
                 .add_storage_arguments(Entity::Ingestor, InOut::Input)
                 .add_batch_public_key_arguments(Entity::Ingestor)
@@ -529,14 +906,520 @@ fn main() -> Result<(), anyhow::Error> {
                 .add_storage_arguments(Entity::Portal,  InOut::Output)
                 .add_packet_decryption_key_argument()
                 .add_batch_signing_key_arguments()
+                .add_batch_manifest_signature_argument()
+                .add_batch_time_tolerance_argument()
+                .add_upload_report_argument()
+                .add_packet_file_codec_argument("invalid-packet")
+                .arg(Arg::with_name("is-first").long("is-first").help(
+                    "Whether this is the \"first\" server receiving a share, i.e., the PHA.",
+                ))
+                .add_server_index_argument()
+                .add_number_of_servers_argument()
+                .add_aggregation_policy_argument(),
+        )
+        .subcommand(
+            SubCommand::with_name("reduce")
+                .about(leak_string(format!("Combine sum parts over several aggregation windows into one.\n\n{}", SHARED_HELP)))
+                .add_instance_name_argument()
+                .arg(
+                    Arg::with_name("aggregation-id")
+                        .long("aggregation-id")
+                        .value_name("ID")
+                        .default_value("fake-aggregation")
+                        .help("Name of the aggregation"),
+                )
+                .arg(
+                    Arg::with_name("sum-part-start")
+                        .long("sum-part-start")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("DATE")
+                        .required(true)
+                        .help("Beginning of the window of a sum part to combine")
+                        .long_help(
+                            "Beginning of the aggregation window of a sum part \
+                            to combine, in YYYY/mm/dd/HH/MM format. Given once \
+                            per sum part, in the same order as sum-part-end \
+                            values.",
+                        )
+                        .validator(date_validator),
+                )
+                .arg(
+                    Arg::with_name("sum-part-end")
+                        .long("sum-part-end")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("DATE")
+                        .required(true)
+                        .help("End of the window of a sum part to combine")
+                        .long_help(
+                            "End of the aggregation window of a sum part to \
+                            combine, in YYYY/mm/dd/HH/MM format. Given once per \
+                            sum part, in the same order as sum-part-start \
+                            values. The combined sum part covers the earliest \
+                            start to the latest end.",
+                        )
+                        .validator(date_validator),
+                )
+                .add_manifest_base_url_argument(Entity::Own)
+                .add_storage_arguments(Entity::Own, InOut::Input)
+                .add_manifest_base_url_argument(Entity::Portal)
+                .add_storage_arguments(Entity::Portal, InOut::Output)
+                .add_batch_signing_key_arguments()
+                .add_batch_manifest_signature_argument()
+                .add_batch_time_tolerance_argument()
+                .add_upload_report_argument()
+                .add_packet_file_codec_argument("invalid-packet")
                 .arg(Arg::with_name("is-first").long("is-first").help(
                     "Whether this is the \"first\" server receiving a share, i.e., the PHA.",
-                )),
+                ))
+                .add_server_index_argument(),
+        )
+        .subcommand(
+            SubCommand::with_name("combine-sums")
+                .about(leak_string(format!("Combine the servers' sum parts for an aggregation window and print the aggregate as JSON or CSV.\n\n{}", SHARED_HELP)))
+                .add_instance_name_argument()
+                .arg(
+                    Arg::with_name("aggregation-id")
+                        .long("aggregation-id")
+                        .value_name("ID")
+                        .default_value("fake-aggregation")
+                        .help("Name of the aggregation"),
+                )
+                .arg(
+                    Arg::with_name("aggregation-start")
+                        .long("aggregation-start")
+                        .value_name("DATE")
+                        .required(true)
+                        .help("Beginning of the timespan covered by the sum parts.")
+                        .validator(date_validator),
+                )
+                .arg(
+                    Arg::with_name("aggregation-end")
+                        .long("aggregation-end")
+                        .value_name("DATE")
+                        .required(true)
+                        .help("End of the timespan covered by the sum parts.")
+                        .validator(date_validator),
+                )
+                .add_manifest_base_url_argument(Entity::Server)
+                .add_storage_arguments(Entity::Server, InOut::Input)
+                .add_batch_public_key_arguments(Entity::Server)
+                .add_number_of_servers_argument()
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&["json", "csv"])
+                        .default_value("json")
+                        .help("Output format: a JSON object or a CSV histogram"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-batch")
+                .about(leak_string(format!("Check the signature, digest, schema and parameters of a batch, without processing it.\n\n{}", SHARED_HELP)))
+                .add_instance_name_argument()
+                .arg(
+                    Arg::with_name("batch-kind")
+                        .long("batch-kind")
+                        .value_name("KIND")
+                        .possible_values(&["ingestion", "validation", "sum"])
+                        .required(true)
+                        .help("Kind of batch to verify"),
+                )
+                .arg(
+                    Arg::with_name("aggregation-id")
+                        .long("aggregation-id")
+                        .value_name("ID")
+                        .required(true)
+                        .help("Name of the aggregation"),
+                )
+                .arg(
+                    Arg::with_name("batch-id")
+                        .long("batch-id")
+                        .value_name("UUID")
+                        .required_ifs(&[("batch-kind", "ingestion"), ("batch-kind", "validation")])
+                        .help("UUID of the batch. Required for ingestion and validation batches.")
+                        .validator(uuid_validator),
+                )
+                .arg(
+                    Arg::with_name("date")
+                        .long("date")
+                        .value_name("DATE")
+                        .required_ifs(&[("batch-kind", "ingestion"), ("batch-kind", "validation")])
+                        .help(
+                            "Date for the batch in YYYY/mm/dd/HH/MM format. \
+                            Required for ingestion and validation batches.",
+                        )
+                        .validator(date_validator),
+                )
+                .arg(
+                    Arg::with_name("aggregation-start")
+                        .long("aggregation-start")
+                        .value_name("DATE")
+                        .required_if("batch-kind", "sum")
+                        .help(
+                            "Beginning of the timespan covered by the \
+                            aggregation. Required for sum batches.",
+                        )
+                        .validator(date_validator),
+                )
+                .arg(
+                    Arg::with_name("aggregation-end")
+                        .long("aggregation-end")
+                        .value_name("DATE")
+                        .required_if("batch-kind", "sum")
+                        .help(
+                            "End of the timespan covered by the aggregation. \
+                            Required for sum batches.",
+                        )
+                        .validator(date_validator),
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .long("input")
+                        .value_name("PATH")
+                        .validator(path_validator)
+                        .required(true)
+                        .help("Storage path (gs://, s3:// or local dir name) to read the batch from"),
+                )
+                .arg(
+                    Arg::with_name("identity")
+                        .long("identity")
+                        .value_name("IAM_ROLE_OR_SERVICE_ACCOUNT")
+                        .help("Identity to assume when using S3 or GS storage APIs"),
+                )
+                .arg(
+                    Arg::with_name("public-key")
+                        .long("public-key")
+                        .value_name("B64")
+                        .help("Public key the batch should be signed with")
+                        .validator(b64_validator),
+                )
+                .arg(
+                    Arg::with_name("public-key-identifier")
+                        .long("public-key-identifier")
+                        .value_name("KEY_ID")
+                        .help("Identifier for the public key")
+                        .default_value("default-batch-signing-key-id"),
+                )
+                .arg(
+                    Arg::with_name("manifest-base-url")
+                        .long("manifest-base-url")
+                        .value_name("BASE_URL")
+                        .help("Base URL of the manifest listing the batch's signing keys")
+                        .long_help(
+                            "Base URL from which the author of the batch vends \
+                            manifests. For ingestion batches, the ingestor's \
+                            global manifest is used, otherwise the specific \
+                            manifest for instance-name. Takes precedence over \
+                            public-key.",
+                        ),
+                )
+                .add_batch_time_tolerance_argument()
+                .arg(Arg::with_name("is-first").long("is-first").help(
+                    "Whether the batch was written by the \"first\" server, i.e., the PHA. \
+                    Ignored for ingestion batches.",
+                ))
+                .add_server_index_argument(),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about(leak_string(format!("Decode a batch object and print its records as JSON lines or CSV.\n\n{}", SHARED_HELP)))
+                .arg(
+                    Arg::with_name("input")
+                        .long("input")
+                        .value_name("PATH")
+                        .validator(path_validator)
+                        .required(true)
+                        .help("Storage path (gs://, s3:// or local dir name) to read the object from"),
+                )
+                .arg(
+                    Arg::with_name("identity")
+                        .long("identity")
+                        .value_name("IAM_ROLE_OR_SERVICE_ACCOUNT")
+                        .help("Identity to assume when using S3 or GS storage APIs"),
+                )
+                .arg(
+                    Arg::with_name("key")
+                        .long("key")
+                        .value_name("KEY")
+                        .required(true)
+                        .help("Key of the object to dump, relative to input"),
+                )
+                .arg(
+                    Arg::with_name("kind")
+                        .long("kind")
+                        .value_name("KIND")
+                        .possible_values(ObjectKind::NAMES)
+                        .help("Kind of object to dump")
+                        .long_help(
+                            "Kind of object to dump. If omitted, it is worked \
+                            out from the object's key.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&["json", "csv"])
+                        .default_value("json")
+                        .help("Output format: JSON lines or CSV"),
+                )
+                .arg(
+                    Arg::with_name("decryption-keys")
+                        .long("decryption-keys")
+                        .value_name("B64")
+                        .help("Keys with which to decrypt ingestion packet payloads")
+                        .long_help(
+                            "Packet decryption private keys, comma separated. \
+                            If present, ingestion packet payloads are decrypted \
+                            with whichever key works and dumped along with the \
+                            packets. Intended for test environments.",
+                        )
+                        .multiple(true)
+                        .min_values(1)
+                        .use_delimiter(true)
+                        .validator(b64_validator),
+                )
+                .arg(Arg::with_name("is-first").long("is-first").help(
+                    "Whether decryption-keys belong to the \"first\" server, i.e., the PHA, \
+                    whose shares are encrypted as field elements. Otherwise the share is \
+                    derived from the encrypted seed, using the number of bins in the \
+                    ingestion header next to the packet file.",
+                ))
+                .add_server_index_argument(),
+        )
+        .subcommand(
+            SubCommand::with_name("check-schema-change")
+                .about("Check that a proposed change to an Avro schema is compatible with the existing schema")
+                .long_about(
+                    "Check that a proposed change to an Avro schema is compatible \
+                    with the existing schema, i.e. that data written with either \
+                    version can be read with the other under Avro schema \
+                    resolution rules, so that we and our peers can adopt the new \
+                    version in any order. Exits with status 2 if not.",
+                )
+                .arg(
+                    Arg::with_name("old")
+                        .long("old")
+                        .value_name("FILE")
+                        .required(true)
+                        .help("Path to the existing .avsc file"),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .long("new")
+                        .value_name("FILE")
+                        .required(true)
+                        .help("Path to the proposed .avsc file"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Work with config files")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("validate")
+                        .about("Check the config file given by --config")
+                        .long_about(
+                            "Check that every setting in the config file given \
+                            by --config is taken by at least one of the \
+                            generate-ingestion-sample, intake-batch, \
+                            aggregate, reduce and combine-sums subcommands, \
+                            and that its value is valid for each subcommand \
+                            that takes it.",
+                        ),
+                ),
         )
-        .get_matches();
+}
+
+fn run() -> Result<(), anyhow::Error> {
+    let args: Vec<OsString> = env::args_os().collect();
+    let config_path = config_path_from_args(&args);
+    let config_file = config_path
+        .as_deref()
+        .map(ConfigFile::from_path)
+        .transpose()?;
+    let (matches, ignored_settings) = match &config_file {
+        Some(config_file) => config_file.get_matches(app(), args),
+        None => app().get_matches_from_safe(args).map(|m| (m, vec![])),
+    }
+    .unwrap_or_else(|mut e| {
+        if let (Some(path), true) = (&config_path, e.use_stderr()) {
+            e.message.push_str(&format!(
+                "\nArguments were also taken from config file {}\n",
+                path.display()
+            ));
+        }
+        e.exit()
+    });
+
+    logging::init(
+        LogFormat::from_str(matches.value_of("log-format").unwrap())?,
+        if matches.is_present("verbose") {
+            LevelFilter::Debug
+        } else {
+            LevelFilter::Info
+        },
+    )?;
+    let _context = log_context_from_args(&matches).enter();
+    for setting in &ignored_settings {
+        debug!(
+            "config file setting {} is not used by this subcommand",
+            setting
+        );
+    }
+
+    let registry = Registry::new();
+    let mut report = JobReport::new(
+        matches.subcommand_name().unwrap_or("none"),
+        matches
+            .subcommand()
+            .1
+            .and_then(|sub_matches| sub_matches.value_of("aggregation-id")),
+    );
+    let result = run_subcommand(&matches, &registry, &mut report).map_err(|e| {
+        error!("{:?}", e);
+        e
+    });
+    report.finish(&result);
+    // As with metrics below, a failure to write the report does not fail the
+    // job.
+    if let Err(e) = write_report(&matches, &report) {
+        warn!("failed to write job report: {:?}", e);
+    }
+    // Metrics are exported whether or not the subcommand succeeded, but a
+    // failure to export them does not fail the job.
+    if let Err(e) = export_metrics(&matches, &registry) {
+        warn!("failed to export metrics: {:?}", e);
+    }
+    result
+}
+
+/// Writes report to the file named by the report argument, or to stdout.
+/// Since dump and combine-sums write their output to stdout, their reports are
+/// only written if the report argument is present.
+fn write_report(matches: &ArgMatches, report: &JobReport) -> Result<()> {
+    match matches.value_of("report") {
+        Some(path) => {
+            report.write(File::create(path).with_context(|| format!("failed to create {}", path))?)
+        }
+        None if matches!(
+            matches.subcommand_name(),
+            Some("dump") | Some("combine-sums")
+        ) =>
+        {
+            Ok(())
+        }
+        None => report.write(std::io::stdout().lock()),
+    }
+}
+
+/// Records stage in report and, if the upload-report argument is present,
+/// uploads the report to transport next to the batch written by the stage.
+/// The uploaded report describes a successful job, since the outputs have
+/// already been written by the time it is uploaded.
+fn complete_report(
+    sub_matches: &ArgMatches,
+    report: &mut JobReport,
+    stage: StageReport,
+    transport: &mut dyn Transport,
+) -> Result<()> {
+    report.stage = stage;
+    if !sub_matches.is_present("upload-report") {
+        return Ok(());
+    }
+    let key = match report.stage.outputs.first() {
+        Some(output) => format!("{}.report.json", output.header_key),
+        None => return Err(anyhow!("no output batch to upload job report next to")),
+    };
+    let mut uploaded = report.clone();
+    uploaded.finish(&Ok(()));
+    uploaded.upload(transport, &key)?;
+    *report = uploaded;
+    Ok(())
+}
+
+/// Pushes the metrics in registry to a Pushgateway and/or writes them to a
+/// textfile, if configured to by arguments.
+fn export_metrics(matches: &ArgMatches, registry: &Registry) -> Result<()> {
+    if let Some(gateway_url) = matches.value_of("metrics-push-gateway") {
+        registry.push(
+            gateway_url,
+            "facilitator",
+            &[("subcommand", matches.subcommand_name().unwrap_or("none"))],
+        )?;
+    }
+    if let Some(path) = matches.value_of("metrics-textfile") {
+        registry.write_textfile(Path::new(path))?;
+    }
+    Ok(())
+}
+
+/// Returns a LogContext with whichever of the aggregation name, batch UUID,
+/// date and role the invoked subcommand was given.
+fn log_context_from_args(matches: &ArgMatches) -> LogContext {
+    let (subcommand, sub_matches) = match matches.subcommand() {
+        (subcommand, Some(sub_matches)) => (subcommand, sub_matches),
+        (_, None) => return LogContext::new(),
+    };
+    let parse_date = |arg| {
+        sub_matches
+            .value_of(arg)
+            .and_then(|v| NaiveDateTime::parse_from_str(v, DATE_FORMAT).ok())
+    };
 
-    let _verbose = matches.is_present("verbose");
+    let mut context = LogContext::new();
+    if let Some(aggregation_name) = sub_matches.value_of("aggregation-id") {
+        context = context.aggregation_name(aggregation_name);
+    }
+    match subcommand {
+        "intake-batch" => {
+            if let Some(batch_id) = sub_matches
+                .value_of("batch-id")
+                .and_then(|v| Uuid::parse_str(v).ok())
+            {
+                context = context.batch_uuid(&batch_id);
+            }
+            if let Some(date) = parse_date("date") {
+                context = context.date(&date);
+            }
+            context.role(server_index_from_args(sub_matches))
+        }
+        "aggregate" => {
+            if let (Some(start), Some(end)) = (
+                parse_date("aggregation-start"),
+                parse_date("aggregation-end"),
+            ) {
+                context = context.date_range(&start, &end);
+            }
+            context.role(server_index_from_args(sub_matches))
+        }
+        "reduce" => context.role(server_index_from_args(sub_matches)),
+        "verify-batch" => {
+            if let Some(batch_id) = sub_matches
+                .value_of("batch-id")
+                .and_then(|v| Uuid::parse_str(v).ok())
+            {
+                context = context.batch_uuid(&batch_id);
+            }
+            if let Some(date) = parse_date("date") {
+                context = context.date(&date);
+            }
+            if let (Some(start), Some(end)) = (
+                parse_date("aggregation-start"),
+                parse_date("aggregation-end"),
+            ) {
+                context = context.date_range(&start, &end);
+            }
+            context.role(server_index_from_args(sub_matches))
+        }
+        _ => context,
+    }
+}
 
+fn run_subcommand(matches: &ArgMatches, registry: &Registry, report: &mut JobReport) -> Result<()> {
     match matches.subcommand() {
         // The configuration of the Args above should guarantee that the
         // various parameters are present and valid, so it is safe to use
@@ -545,13 +1428,13 @@ fn main() -> Result<(), anyhow::Error> {
             let peer_output_path =
                 StoragePath::from_str(sub_matches.value_of("peer-output").unwrap())?;
             let peer_identity = sub_matches.value_of("peer-identity");
-            let mut peer_transport = transport_for_path(peer_output_path, peer_identity)?;
+            let mut peer_transport = transport_for_path(peer_output_path, peer_identity, registry)?;
 
             let own_output_path =
                 StoragePath::from_str(sub_matches.value_of("own-output").unwrap())?;
             let own_identity = sub_matches.value_of("own-identity");
-            let mut own_transport = transport_for_path(own_output_path, own_identity)?;
-            let ingestor_batch_signing_key = batch_signing_key_from_arg(sub_matches)?;
+            let mut own_transport = transport_for_path(own_output_path, own_identity, registry)?;
+            let ingestor_batch_signer = batch_signer_from_args(sub_matches)?;
 
             generate_ingestion_sample(
                 &mut *peer_transport,
@@ -572,7 +1455,7 @@ fn main() -> Result<(), anyhow::Error> {
                         .unwrap(),
                 )
                 .unwrap(),
-                &ingestor_batch_signing_key,
+                &*ingestor_batch_signer,
                 sub_matches
                     .value_of("dimension")
                     .unwrap()
@@ -598,55 +1481,107 @@ fn main() -> Result<(), anyhow::Error> {
                     .unwrap()
                     .parse::<i64>()
                     .unwrap(),
+                sub_matches
+                    .value_of("hamming-weight")
+                    .map(|v| v.parse::<i32>().unwrap()),
             )?;
             Ok(())
         }
-        ("batch-intake", Some(sub_matches)) => {
-            let mut intake_transport = intake_transport_from_args(sub_matches)?;
+        ("intake-batch", Some(sub_matches)) => {
+            let aggregation_name = sub_matches.value_of("aggregation-id").unwrap();
+            let batch_id = sub_matches
+                .value_of("batch-id")
+                .map_or_else(Uuid::new_v4, |v| Uuid::parse_str(v).unwrap());
+            let date = sub_matches.value_of("date").map_or_else(
+                || Utc::now().naive_utc(),
+                |v| NaiveDateTime::parse_from_str(&v, DATE_FORMAT).unwrap(),
+            );
+
+            // The batch is read from whichever ingestor wrote it.
+            let mut ingestors = ingestors_from_args(sub_matches, registry)?;
+            let ingestor = find_ingestor(
+                &mut ingestors,
+                Batch::new_ingestion(aggregation_name, &batch_id, &date).signature_key(),
+            )?;
+            let ingestor = &mut ingestors[ingestor];
 
-            // We need the bucket to which we will write validations for the
+            // We need the buckets to which we will write validations for each
             // peer data share processor, which can be provided either directly
             // via command line argument or must be fetched from the peer
             // specific manifest.
-            let validation_bucket = if let Some(path) = sub_matches.value_of("peer-output") {
-                StoragePath::from_str(path)
-            } else if let Some(base_url) = sub_matches.value_of("peer-manifest-base-url") {
-                SpecificManifest::from_https(
-                    base_url,
-                    sub_matches.value_of("instance-name").unwrap(),
-                )?
-                .validation_bucket()
-            } else {
-                Err(anyhow!("peer-output or peer-manifest-base-url required."))
-            }?;
+            let server_index = server_index_from_args(sub_matches);
+            let number_of_servers = number_of_servers_from_args(sub_matches, server_index)?;
+            let peer_count = number_of_servers - 1;
+            let outputs = repeated_values(sub_matches, Entity::Peer, "peer-output", peer_count)?;
+            let identities =
+                repeated_values(sub_matches, Entity::Peer, "peer-identity", peer_count)?;
+            let manifest_base_urls = repeated_values(
+                sub_matches,
+                Entity::Peer,
+                "peer-manifest-base-url",
+                peer_count,
+            )?;
+            let mut validation_transports = Vec::with_capacity(peer_count);
+            for index in 0..peer_count {
+                let validation_bucket = if let Some(path) = outputs[index] {
+                    StoragePath::from_str(path)
+                } else if let Some(base_url) = manifest_base_urls[index] {
+                    SpecificManifest::from_https(
+                        base_url,
+                        sub_matches.value_of("instance-name").unwrap(),
+                    )?
+                    .validation_bucket()
+                } else {
+                    Err(anyhow!("peer-output or peer-manifest-base-url required."))
+                }?;
+                validation_transports.push(transport_for_path(
+                    validation_bucket,
+                    identities[index],
+                    registry,
+                )?);
+            }
 
-            let peer_identity = sub_matches.value_of("peer-identity");
+            // The validation batch is written to the first peer's bucket and
+            // copied to the others'.
+            let mut validation_transports = validation_transports.into_iter();
             let mut validation_transport = SignableTransport {
-                transport: transport_for_path(validation_bucket, peer_identity)?,
-                batch_signing_key: batch_signing_key_from_arg(sub_matches)?,
+                transport: validation_transports.next().unwrap(),
+                batch_signer: batch_signer_from_args(sub_matches)?,
+                sign_batch_manifest: sub_matches.is_present("sign-batch-manifest"),
+                packet_file_codec: packet_file_codec_from_args(sub_matches, "validation"),
             };
+            let mut additional_validation_transports: Vec<_> = validation_transports.collect();
 
             let mut batch_intaker = BatchIntaker::new(
-                &sub_matches.value_of("aggregation-id").unwrap(),
-                &sub_matches
-                    .value_of("batch-id")
-                    .map_or_else(Uuid::new_v4, |v| Uuid::parse_str(v).unwrap()),
-                &sub_matches.value_of("date").map_or_else(
-                    || Utc::now().naive_utc(),
-                    |v| NaiveDateTime::parse_from_str(&v, DATE_FORMAT).unwrap(),
-                ),
-                &mut intake_transport,
+                aggregation_name,
+                &batch_id,
+                &date,
+                &mut ingestor.transport,
                 &mut validation_transport,
-                sub_matches.is_present("is-first"),
+                server_index,
             )?;
+            for transport in additional_validation_transports.iter_mut() {
+                batch_intaker.add_validation_transport(&mut **transport);
+            }
+            batch_intaker.set_number_of_servers(number_of_servers)?;
+            batch_intaker.set_ingestor_name(ingestor.name.as_deref());
+            batch_intaker.set_batch_time_tolerance(batch_time_tolerance_from_args(sub_matches));
+            batch_intaker.set_policy(policy_from_args(sub_matches, aggregation_name)?);
+            batch_intaker.set_metrics(registry)?;
             batch_intaker.generate_validation_share()?;
-            Ok(())
+            let stage = batch_intaker.report().clone();
+            complete_report(
+                sub_matches,
+                report,
+                stage,
+                &mut *validation_transport.transport,
+            )
         }
         ("aggregate", Some(sub_matches)) => {
-            let is_first = sub_matches.is_present("is-first");
+            let server_index = server_index_from_args(sub_matches);
             let instance_name = sub_matches.value_of("instance-name").unwrap();
 
-            let mut intake_transport = intake_transport_from_args(sub_matches)?;
+            let mut ingestors = ingestors_from_args(sub_matches, registry)?;
 
             // We need the bucket to which we previously wrote our validation
             // shares, which is owned by the peer data share processor and can
@@ -668,87 +1603,28 @@ fn main() -> Result<(), anyhow::Error> {
             }?;
 
             let own_identity = sub_matches.value_of("own-identity");
-            let own_validation_transport = transport_for_path(own_validation_bucket, own_identity)?;
-
-            // To read our own validation shares, we require our own public keys
-            // which we discover in our own specific manifest.
-            let own_public_key_map = match (
-                sub_matches.value_of("batch-signing-private-key"),
-                sub_matches.value_of("batch-signing-private-key-identifier"),
-                sub_matches.value_of("own-manifest-base-url"),
-            ) {
-                (Some(private_key), Some(private_key_identifier), _) => {
-                    public_key_map_from_arg(private_key, private_key_identifier)
-                }
-                (_, _, Some(manifest_base_url)) => {
-                    SpecificManifest::from_https(manifest_base_url, instance_name)?
-                        .batch_signing_public_keys()?
-                }
-                _ => {
-                    return Err(anyhow!(
-                        "batch-signing-private-key and \
-                        batch-signing-private-key-identifier are required if \
-                        own-manifest-base-url is not provided."
-                    ))
-                }
-            };
-
-            // We created the bucket that peers wrote validations into, and so
-            // it is simply provided via argument.
-            let peer_validation_bucket =
-                StoragePath::from_str(sub_matches.value_of("peer-input").unwrap())?;
-            let peer_identity = sub_matches.value_of("peer-identity");
+            let own_validation_transport =
+                transport_for_path(own_validation_bucket, own_identity, registry)?;
 
-            let peer_validation_transport =
-                transport_for_path(peer_validation_bucket, peer_identity)?;
+            // To read our own validation shares, we require our own public keys.
+            let own_public_key_map = own_public_key_map_from_args(sub_matches, instance_name)?;
 
-            // We need the public keys the peer data share processor used to
-            // sign messages, which we can obtain by argument or by discovering
-            // their specific manifest.
-            let peer_share_processor_pub_key_map = match (
-                sub_matches.value_of("peer-public-key"),
-                sub_matches.value_of("peer-public-key-identifier"),
-                sub_matches.value_of("peer-manifest-base-url"),
-            ) {
-                (Some(public_key), Some(public_key_identifier), _) => {
-                    public_key_map_from_arg(public_key, public_key_identifier)
-                }
-                (_, _, Some(manifest_base_url)) => {
-                    SpecificManifest::from_https(manifest_base_url, instance_name)?
-                        .batch_signing_public_keys()?
-                }
-                _ => {
-                    return Err(anyhow!(
-                        "peer-public-key and peer-public-key-identifier are \
-                        required if peer-manifest-base-url is not provided."
-                    ))
-                }
-            };
+            // We created the buckets that peers wrote validations into, and
+            // need the public keys each of them signed with.
+            let mut peer_validation_transports =
+                peers_from_args(sub_matches, server_index, instance_name, registry)?;
 
             // We need the portal server owned bucket to which to write sum part
-            // messages aka aggregations. We can get that from an argument,
-            // absent which we discover it from the portal server global
-            // manifest.
-            let portal_bucket = match (
-                sub_matches.value_of("portal-output"),
-                sub_matches.value_of("portal-manifest-base-url"),
-            ) {
-                (Some(path), _) => StoragePath::from_str(path),
-                (None, Some(manifest_base_url)) => {
-                    PortalServerGlobalManifest::from_https(manifest_base_url)?
-                        .sum_part_bucket(is_first)
-                }
-                _ => Err(anyhow!(
-                    "portal-output or portal-manifest-base-url required"
-                )),
-            }?;
-            let aggregation_identity = sub_matches.value_of("aggregation-identity");
+            // messages aka aggregations.
+            let portal_bucket = portal_bucket_from_args(sub_matches, server_index)?;
+            let portal_identity = sub_matches.value_of("portal-identity");
 
-            let aggregation_transport = transport_for_path(portal_bucket, aggregation_identity)?;
+            let aggregation_transport =
+                transport_for_path(portal_bucket, portal_identity, registry)?;
 
-            // Get the key we will use to sign sum part messages sent to the
+            // Get the signer we will use to sign sum part messages sent to the
             // portal server.
-            let batch_signing_key = batch_signing_key_from_arg(sub_matches)?;
+            let batch_signer = batch_signer_from_args(sub_matches)?;
 
             let batch_ids: Vec<Uuid> = sub_matches
                 .values_of("batch-id")
@@ -767,36 +1643,324 @@ fn main() -> Result<(), anyhow::Error> {
             }
 
             let batch_info: Vec<_> = batch_ids.into_iter().zip(batch_dates).collect();
-            BatchAggregator::new(
+            let aggregation_start = sub_matches.value_of("aggregation-start").map_or_else(
+                || Utc::now().naive_utc(),
+                |v| NaiveDateTime::parse_from_str(&v, DATE_FORMAT).unwrap(),
+            );
+            let aggregation_end = sub_matches.value_of("aggregation-end").map_or_else(
+                || Utc::now().naive_utc(),
+                |v| NaiveDateTime::parse_from_str(&v, DATE_FORMAT).unwrap(),
+            );
+            let mut own_validation_transport = VerifiableTransport {
+                transport: own_validation_transport,
+                batch_signing_public_keys: own_public_key_map,
+            };
+            let mut aggregation_transport = SignableTransport {
+                transport: aggregation_transport,
+                batch_signer,
+                sign_batch_manifest: sub_matches.is_present("sign-batch-manifest"),
+                packet_file_codec: packet_file_codec_from_args(sub_matches, "invalid-packet"),
+            };
+            let mut batch_aggregator = BatchAggregator::new(
                 &sub_matches.value_of("aggregation-id").unwrap(),
-                &sub_matches.value_of("aggregation-start").map_or_else(
-                    || Utc::now().naive_utc(),
-                    |v| NaiveDateTime::parse_from_str(&v, DATE_FORMAT).unwrap(),
+                &aggregation_start,
+                &aggregation_end,
+                server_index,
+                &mut ingestors,
+                &mut own_validation_transport,
+                &mut peer_validation_transports,
+                &mut aggregation_transport,
+            )?;
+            batch_aggregator.set_batch_time_tolerance(batch_time_tolerance_from_args(sub_matches));
+            batch_aggregator.set_policy(policy_from_args(
+                sub_matches,
+                sub_matches.value_of("aggregation-id").unwrap(),
+            )?)?;
+            batch_aggregator.set_metrics(registry)?;
+            batch_aggregator.generate_sum_part(&batch_info)?;
+            let stage = batch_aggregator.report().clone();
+            complete_report(
+                sub_matches,
+                report,
+                stage,
+                &mut *aggregation_transport.transport,
+            )
+        }
+        ("reduce", Some(sub_matches)) => {
+            let server_index = server_index_from_args(sub_matches);
+            let instance_name = sub_matches.value_of("instance-name").unwrap();
+
+            // The sum parts to combine were signed by us, so we need our own
+            // public keys to verify them.
+            let mut sum_part_transport = VerifiableTransport {
+                transport: transport_for_path(
+                    StoragePath::from_str(sub_matches.value_of("own-input").unwrap())?,
+                    sub_matches.value_of("own-identity"),
+                    registry,
+                )?,
+                batch_signing_public_keys: own_public_key_map_from_args(
+                    sub_matches,
+                    instance_name,
+                )?,
+            };
+            let mut reduction_transport = SignableTransport {
+                transport: transport_for_path(
+                    portal_bucket_from_args(sub_matches, server_index)?,
+                    sub_matches.value_of("portal-identity"),
+                    registry,
+                )?,
+                batch_signer: batch_signer_from_args(sub_matches)?,
+                sign_batch_manifest: sub_matches.is_present("sign-batch-manifest"),
+                packet_file_codec: packet_file_codec_from_args(sub_matches, "invalid-packet"),
+            };
+
+            let starts = dates_from_args(sub_matches, "sum-part-start")?;
+            let ends = dates_from_args(sub_matches, "sum-part-end")?;
+            if starts.len() != ends.len() {
+                return Err(anyhow!(
+                    "must provide same number of sum-part-start and sum-part-end values"
+                ));
+            }
+            let windows: Vec<_> = starts.into_iter().zip(ends).collect();
+
+            let mut reducer = SumPartReducer::new(
+                sub_matches.value_of("aggregation-id").unwrap(),
+                server_index,
+                &mut sum_part_transport,
+                &mut reduction_transport,
+            );
+            reducer.set_batch_time_tolerance(batch_time_tolerance_from_args(sub_matches));
+            reducer.set_metrics(registry)?;
+            reducer.reduce(&windows)?;
+            let stage = reducer.report().clone();
+            complete_report(
+                sub_matches,
+                report,
+                stage,
+                &mut *reduction_transport.transport,
+            )
+        }
+        ("combine-sums", Some(sub_matches)) => {
+            let mut sum_part_transports = servers_from_args(sub_matches, registry)?;
+            let combined_sum = combine_sums(
+                sub_matches.value_of("aggregation-id").unwrap(),
+                &date_from_args(sub_matches, "aggregation-start")?,
+                &date_from_args(sub_matches, "aggregation-end")?,
+                &mut sum_part_transports,
+                &mut report.stage,
+            )?;
+            combined_sum.write(
+                std::io::stdout().lock(),
+                DumpFormat::from_str(sub_matches.value_of("format").unwrap())?,
+            )
+        }
+        ("verify-batch", Some(sub_matches)) => {
+            let kind = BatchKind::from_str(sub_matches.value_of("batch-kind").unwrap())?;
+            let aggregation_name = sub_matches.value_of("aggregation-id").unwrap();
+            let server_index = server_index_from_args(sub_matches);
+
+            let batch = match kind {
+                BatchKind::Sum => Batch::new_sum(
+                    aggregation_name,
+                    &date_from_args(sub_matches, "aggregation-start")?,
+                    &date_from_args(sub_matches, "aggregation-end")?,
+                    server_index,
                 ),
-                &sub_matches.value_of("aggregation-end").map_or_else(
-                    || Utc::now().naive_utc(),
-                    |v| NaiveDateTime::parse_from_str(&v, DATE_FORMAT).unwrap(),
+                _ => {
+                    let batch_id = Uuid::parse_str(sub_matches.value_of("batch-id").unwrap())?;
+                    let date = date_from_args(sub_matches, "date")?;
+                    if kind == BatchKind::Ingestion {
+                        Batch::new_ingestion(aggregation_name, &batch_id, &date)
+                    } else {
+                        Batch::new_validation(aggregation_name, &batch_id, &date, server_index)
+                    }
+                }
+            };
+
+            // Ingestion batches are signed with keys from the ingestor's
+            // global manifest, and the others with keys from the specific
+            // manifest of the data share processor that wrote them.
+            let public_keys = match (
+                sub_matches.value_of("manifest-base-url"),
+                sub_matches.value_of("public-key"),
+            ) {
+                (Some(base_url), _) if kind == BatchKind::Ingestion => {
+                    IngestionServerGlobalManifest::from_https(base_url)?
+                        .batch_signing_public_keys()?
+                }
+                (Some(base_url), _) => SpecificManifest::from_https(
+                    base_url,
+                    sub_matches.value_of("instance-name").unwrap(),
+                )?
+                .batch_signing_public_keys()?,
+                (None, Some(public_key)) => public_key_map_from_arg(
+                    public_key,
+                    sub_matches.value_of("public-key-identifier").unwrap(),
                 ),
-                is_first,
-                &mut intake_transport,
-                &mut VerifiableTransport {
-                    transport: own_validation_transport,
-                    batch_signing_public_keys: own_public_key_map,
-                },
-                &mut VerifiableTransport {
-                    transport: peer_validation_transport,
-                    batch_signing_public_keys: peer_share_processor_pub_key_map,
-                },
-                &mut SignableTransport {
-                    transport: aggregation_transport,
-                    batch_signing_key,
-                },
-            )?
-            .generate_sum_part(&batch_info)?;
+                (None, None) => {
+                    return Err(anyhow!("public-key or manifest-base-url required"));
+                }
+            };
+
+            let mut transport = transport_for_path(
+                StoragePath::from_str(sub_matches.value_of("input").unwrap())?,
+                sub_matches.value_of("identity"),
+                registry,
+            )?;
+            let verification = verify_batch(
+                kind,
+                batch,
+                &mut *transport,
+                &public_keys,
+                batch_time_tolerance_from_args(sub_matches),
+            );
+            // The verification report is meant for people, so it goes to
+            // stderr, leaving stdout to the job report.
+            eprintln!("{}", verification);
+            report.stage.inputs.push(verification.batch.clone());
+            report.stage.packets.total = verification.packet_count.unwrap_or(0);
+            verification.into_result()
+        }
+        ("dump", Some(sub_matches)) => {
+            let key = sub_matches.value_of("key").unwrap();
+            let kind = match sub_matches.value_of("kind") {
+                Some(kind) => ObjectKind::from_str(kind)?,
+                None => ObjectKind::from_key(key).ok_or_else(|| {
+                    anyhow!("cannot tell what kind of object {} is; use --kind", key)
+                })?,
+            };
+            let mut transport = transport_for_path(
+                StoragePath::from_str(sub_matches.value_of("input").unwrap())?,
+                sub_matches.value_of("identity"),
+                registry,
+            )?;
+            let decryption = match sub_matches.values_of("decryption-keys") {
+                Some(keys) if kind == ObjectKind::IngestionPackets => {
+                    // The shares' length depends on the number of bins, which
+                    // is recorded in the ingestion header.
+                    let header_key = key.strip_suffix(".avro").ok_or_else(|| {
+                        anyhow!("cannot find the ingestion header for packet file {}", key)
+                    })?;
+                    let header = IngestionHeader::read(transport.get(header_key)?)?;
+                    Some(PacketDecryption {
+                        keys: keys
+                            .map(|k| {
+                                PrivateKey::from_base64(k)
+                                    .context("could not parse encoded packet decryption key")
+                            })
+                            .collect::<Result<_>>()?,
+                        server_index: server_index_from_args(sub_matches),
+                        bins: usize::try_from(header.bins).map_err(|_| {
+                            Error::MalformedHeaderError(format!(
+                                "invalid bins/dimension value {}",
+                                header.bins
+                            ))
+                        })?,
+                    })
+                }
+                _ => None,
+            };
+            let count = dump(
+                kind,
+                transport.get(key)?,
+                std::io::stdout().lock(),
+                DumpFormat::from_str(sub_matches.value_of("format").unwrap())?,
+                decryption.as_ref(),
+            )?;
+            info!("dumped {} records from {}", count, key);
             Ok(())
         }
-        (_, _) => Ok(()),
+        ("check-schema-change", Some(sub_matches)) => {
+            let old = schema_from_arg(sub_matches, "old")?;
+            let new = schema_from_arg(sub_matches, "new")?;
+            let change = SchemaChange::check(&old, &new);
+            eprintln!("{}", change);
+            // Report whichever direction fails first as the error
+            let (reader, incompatibilities) = if !change.backward.is_empty() {
+                ("new", &change.backward)
+            } else {
+                ("old", &change.forward)
+            };
+            if incompatibilities.is_empty() {
+                Ok(())
+            } else {
+                Err(Error::IncompatibleSchemaError(
+                    sub_matches.value_of(reader).unwrap().to_owned(),
+                    incompatibilities
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join("; "),
+                )
+                .into())
+            }
+        }
+        ("config", Some(sub_matches)) => match sub_matches.subcommand() {
+            ("validate", Some(_)) => validate_config(matches),
+            (subcommand, _) => unreachable!("unhandled config subcommand {:?}", subcommand),
+        },
+        // clap requires one of the subcommands above.
+        (subcommand, _) => unreachable!("unhandled subcommand {:?}", subcommand),
+    }
+}
+
+/// Returns the path of the config file given by the config argument. This is
+/// needed before the command line is parsed, since the config file provides
+/// arguments to it.
+fn config_path_from_args(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter().skip(1).filter_map(|arg| arg.to_str());
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    env::var_os("CONFIG_FILE").map(PathBuf::from)
+}
+
+/// Checks that every setting in the config file is taken by at least one of
+/// CONFIGURABLE_SUBCOMMANDS, and that clap accepts its value for each of those
+/// that take it.
+fn validate_config(matches: &ArgMatches) -> Result<()> {
+    let path = matches
+        .value_of("config")
+        .context("config validate requires --config")?;
+    let config_file = ConfigFile::from_path(Path::new(path))?;
+
+    let mut unused: Vec<String> = config_file
+        .arguments()
+        .iter()
+        .map(|argument| argument.name.clone())
+        .collect();
+    for subcommand in CONFIGURABLE_SUBCOMMANDS {
+        let args = vec!["facilitator".into(), subcommand.into()];
+        let (_, ignored) = config_file
+            .get_matches(app(), args)
+            .map_err(|e| anyhow!("invalid config for {}: {}", subcommand, e.message))?;
+        unused.retain(|name| ignored.contains(name));
     }
+    if !unused.is_empty() {
+        return Err(anyhow!(
+            "config file {} sets unknown arguments: {}",
+            path,
+            unused.join(", ")
+        ));
+    }
+    info!(
+        "config file {} is valid, providing {} arguments",
+        path,
+        config_file.arguments().len()
+    );
+    Ok(())
+}
+
+fn schema_from_arg(matches: &ArgMatches, arg: &str) -> Result<Schema> {
+    let path = matches.value_of(arg).unwrap();
+    let schema = fs::read_to_string(path).with_context(|| format!("failed to read {}", path))?;
+    Ok(Schema::parse_str(&schema)
+        .map_err(|e| Error::AvroError(format!("failed to parse schema {}", path), e))?)
 }
 
 fn public_key_map_from_arg(
@@ -804,14 +1968,14 @@ fn public_key_map_from_arg(
     key_identifier: &str,
 ) -> HashMap<String, UnparsedPublicKey<Vec<u8>>> {
     // UnparsedPublicKey::new doesn't return an error, so try parsing the
-    // argument as a private key first.
+    // argument as a private key first, then as a DER encoded
+    // SubjectPublicKeyInfo, and finally fall back to treating it as a bare
+    // P-256 point.
     let key_bytes = base64::decode(key).unwrap();
-    let public_key = match EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &key_bytes) {
-        Ok(priv_key) => UnparsedPublicKey::new(
-            &ECDSA_P256_SHA256_ASN1,
-            Vec::from(priv_key.public_key().as_ref()),
-        ),
-        Err(_) => UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, key_bytes),
+    let public_key = match SigningKeyPair::from_pkcs8(&key_bytes) {
+        Ok(priv_key) => priv_key.public_key(),
+        Err(_) => public_key_from_spki_der(&key_bytes)
+            .unwrap_or_else(|_| UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, key_bytes)),
     };
 
     let mut key_map = HashMap::new();
@@ -819,52 +1983,209 @@ fn public_key_map_from_arg(
     key_map
 }
 
-fn batch_signing_key_from_arg(matches: &ArgMatches) -> Result<BatchSigningKey> {
-    let key_bytes = base64::decode(matches.value_of("batch-signing-private-key").unwrap()).unwrap();
-    let key_identifier = matches
-        .value_of("batch-signing-private-key-identifier")
-        .unwrap();
-    Ok(BatchSigningKey {
-        key: EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &key_bytes)?,
-        identifier: key_identifier.to_owned(),
-    })
+fn packet_file_codec_from_args(matches: &ArgMatches, output: &str) -> Codec {
+    Codec::from_str(matches.value_of(format!("{}-codec", output)).unwrap()).unwrap()
+}
+
+fn batch_time_tolerance_from_args(matches: &ArgMatches) -> Option<Duration> {
+    matches
+        .value_of("batch-time-tolerance")
+        .map(|v| Duration::seconds(v.parse::<i64>().unwrap()))
+}
+
+/// Parses the date given for arg.
+fn date_from_args(matches: &ArgMatches, arg: &str) -> Result<NaiveDateTime> {
+    let value = matches
+        .value_of(arg)
+        .ok_or_else(|| anyhow!("{} is required", arg))?;
+    NaiveDateTime::parse_from_str(value, DATE_FORMAT)
+        .with_context(|| format!("could not parse {} {}", arg, value))
 }
 
-fn intake_transport_from_args(matches: &ArgMatches) -> Result<VerifiableAndDecryptableTransport> {
-    // To read (intake) content from an ingestor's bucket, we need the bucket, which we
-    // know because our deployment created it, so it is always provided via the
-    // ingestor-input argument.
-    let ingestor_bucket = StoragePath::from_str(matches.value_of("ingestor-input").unwrap())?;
-    let ingestor_identity = matches.value_of("ingestor-identity");
+/// Parses each of the dates given for arg.
+fn dates_from_args(matches: &ArgMatches, arg: &str) -> Result<Vec<NaiveDateTime>> {
+    matches
+        .values_of(arg)
+        .into_iter()
+        .flatten()
+        .map(|value| {
+            NaiveDateTime::parse_from_str(value, DATE_FORMAT)
+                .with_context(|| format!("could not parse {} {}", arg, value))
+        })
+        .collect()
+}
 
-    let intake_transport = transport_for_path(ingestor_bucket, ingestor_identity)?;
+/// Returns the policy for the named aggregation from the aggregation-policy
+/// file, or the default policy if none is given.
+fn policy_from_args(matches: &ArgMatches, aggregation_name: &str) -> Result<AggregationPolicy> {
+    Ok(match matches.value_of("aggregation-policy") {
+        Some(path) => AggregationPolicies::from_path(Path::new(path))?.policy(aggregation_name),
+        None => AggregationPolicy::default(),
+    })
+}
 
-    // We also need the public keys the ingestor may have used to sign the
-    // the batch, which can be provided either directly via command line or must
-    // be fetched from the ingestor global manifest.
-    let ingestor_pub_key_map = match (
-        matches.value_of("ingestor-public-key"),
-        matches.value_of("ingestor-public-key-identifier"),
-        matches.value_of("ingestor-manifest-base-url"),
+/// Returns our own batch signing public keys, which we discover in our own
+/// specific manifest, or, if our private key was provided inline, derive from
+/// it.
+fn own_public_key_map_from_args(
+    matches: &ArgMatches,
+    instance_name: &str,
+) -> Result<BatchSigningPublicKeys> {
+    let inline_private_key = if uses_external_batch_signer(matches) {
+        None
+    } else {
+        matches.value_of("batch-signing-private-key")
+    };
+    match (
+        inline_private_key,
+        matches.value_of("batch-signing-private-key-identifier"),
+        matches.value_of("own-manifest-base-url"),
     ) {
-        (Some(public_key), Some(public_key_identifier), _) => {
-            public_key_map_from_arg(public_key, public_key_identifier)
+        (Some(private_key), Some(private_key_identifier), _) => {
+            Ok(public_key_map_from_arg(private_key, private_key_identifier))
         }
         (_, _, Some(manifest_base_url)) => {
-            IngestionServerGlobalManifest::from_https(manifest_base_url)?
-                .batch_signing_public_keys()?
+            SpecificManifest::from_https(manifest_base_url, instance_name)?
+                .batch_signing_public_keys()
         }
-        _ => {
-            return Err(anyhow!(
-                "ingestor-public-key and ingestor-public-key-identifier are \
-                required if ingestor-manifest-base-url is not provided."
-            ))
+        _ => Err(anyhow!(
+            "own-manifest-base-url is required when using \
+            batch-signing-private-key-file or batch-signer-url, \
+            otherwise batch-signing-private-key and \
+            batch-signing-private-key-identifier are required."
+        )),
+    }
+}
+
+/// Returns the portal server owned bucket to which to write sum parts, given
+/// as an argument, absent which we discover it from the portal server global
+/// manifest.
+fn portal_bucket_from_args(matches: &ArgMatches, server_index: usize) -> Result<StoragePath> {
+    match (
+        matches.value_of("portal-output"),
+        matches.value_of("portal-manifest-base-url"),
+    ) {
+        (Some(path), _) => StoragePath::from_str(path),
+        (None, Some(manifest_base_url)) => {
+            PortalServerGlobalManifest::from_https(manifest_base_url)?
+                .sum_part_bucket(server_index == 0)
         }
+        _ => Err(anyhow!(
+            "portal-output or portal-manifest-base-url required"
+        )),
+    }
+}
+
+fn uses_external_batch_signer(matches: &ArgMatches) -> bool {
+    matches.is_present("batch-signing-private-key-file")
+        || matches.is_present("batch-signer-url")
+        || matches.is_present("batch-signing-key-set")
+}
+
+fn batch_signer_from_args(matches: &ArgMatches) -> Result<Box<dyn BatchSigner>> {
+    if let Some(path) = matches.value_of("batch-signing-key-set") {
+        // Before signing with a key from the set, check that peers can already
+        // verify it by looking for it in our own specific manifest.
+        let published_keys = match (
+            matches.value_of("own-manifest-base-url"),
+            matches.value_of("instance-name"),
+        ) {
+            (Some(base_url), Some(instance_name)) => {
+                SpecificManifest::from_https(base_url, instance_name)?
+                    .batch_signing_public_keys()?
+            }
+            _ => {
+                return Err(anyhow!(
+                    "own-manifest-base-url and instance-name are required with \
+                    batch-signing-key-set"
+                ))
+            }
+        };
+        return BatchSigningKeySet::from_file(Path::new(path))?
+            .into_active_signer(&Utc::now(), &published_keys);
+    }
+
+    let key_identifier = matches
+        .value_of("batch-signing-private-key-identifier")
+        .unwrap();
+    if let Some(url) = matches.value_of("batch-signer-url") {
+        return Ok(Box::new(RemoteBatchSigner::new(url, key_identifier)));
+    }
+    if let Some(path) = matches.value_of("batch-signing-private-key-file") {
+        return Ok(Box::new(FileBatchSigner::new(
+            PathBuf::from(path),
+            key_identifier,
+        )?));
+    }
+
+    let key_bytes = base64::decode(matches.value_of("batch-signing-private-key").unwrap()).unwrap();
+    Ok(Box::new(BatchSigningKey {
+        key: SigningKeyPair::from_pkcs8(&key_bytes)?,
+        identifier: key_identifier.to_owned(),
+    }))
+}
+
+/// Returns the values of the argument arg for each of count ingestors or
+/// peers. An empty value stands for an absent one.
+fn repeated_values<'a>(
+    matches: &'a ArgMatches,
+    entity: Entity,
+    arg: &str,
+    count: usize,
+) -> Result<Vec<Option<&'a str>>> {
+    let values: Vec<_> = match matches.values_of(arg) {
+        Some(values) => values.map(|v| Some(v).filter(|v| !v.is_empty())).collect(),
+        None => return Ok(vec![None; count]),
+    };
+    match values.len() {
+        1 => Ok(vec![values[0]; count]),
+        n if n == count => Ok(values),
+        n => Err(anyhow!(
+            "{} given {} times, but there are {} {}s",
+            arg,
+            n,
+            count,
+            entity.str()
+        )),
+    }
+}
+
+fn ingestors_from_args(
+    matches: &ArgMatches,
+    registry: &Registry,
+) -> Result<Vec<IngestorTransport>> {
+    let names: Vec<Option<&str>> = match matches.values_of("ingestor-name") {
+        Some(names) => names.map(Some).collect(),
+        None => vec![None],
     };
+    for (index, name) in names.iter().enumerate() {
+        if names[..index].contains(name) {
+            return Err(anyhow!(
+                "ingestor-name {} given more than once",
+                name.unwrap()
+            ));
+        }
+    }
+    let count = names.len();
+    let inputs = repeated_values(matches, Entity::Ingestor, "ingestor-input", count)?;
+    let identities = repeated_values(matches, Entity::Ingestor, "ingestor-identity", count)?;
+    let public_keys = repeated_values(matches, Entity::Ingestor, "ingestor-public-key", count)?;
+    let public_key_identifiers = repeated_values(
+        matches,
+        Entity::Ingestor,
+        "ingestor-public-key-identifier",
+        count,
+    )?;
+    let manifest_base_urls = repeated_values(
+        matches,
+        Entity::Ingestor,
+        "ingestor-manifest-base-url",
+        count,
+    )?;
 
     // Get the keys we will use to decrypt packets in the ingestion
     // batch
-    let packet_decryption_keys = matches
+    let packet_decryption_keys: Vec<PrivateKey> = matches
         .values_of("packet-decryption-keys")
         .unwrap()
         .map(|k| {
@@ -874,19 +2195,236 @@ fn intake_transport_from_args(matches: &ArgMatches) -> Result<VerifiableAndDecry
         })
         .collect();
 
-    Ok(VerifiableAndDecryptableTransport {
-        transport: VerifiableTransport {
-            transport: intake_transport,
-            batch_signing_public_keys: ingestor_pub_key_map,
-        },
-        packet_decryption_keys,
-    })
+    let mut ingestors = Vec::with_capacity(count);
+    for index in 0..count {
+        let name = names[index].unwrap_or("the ingestor");
+
+        // To read (intake) content from an ingestor's bucket, we need the bucket, which we
+        // know because our deployment created it, so it is always provided via the
+        // ingestor-input argument.
+        let ingestor_bucket = StoragePath::from_str(
+            inputs[index].ok_or_else(|| anyhow!("ingestor-input required for {}", name))?,
+        )?;
+        let intake_transport = transport_for_path(ingestor_bucket, identities[index], registry)?;
+
+        // We also need the public keys the ingestor may have used to sign the
+        // the batch, which can be provided either directly via command line or must
+        // be fetched from the ingestor global manifest.
+        let ingestor_pub_key_map = match (
+            public_keys[index],
+            public_key_identifiers[index],
+            manifest_base_urls[index],
+        ) {
+            (Some(public_key), Some(public_key_identifier), _) => {
+                public_key_map_from_arg(public_key, public_key_identifier)
+            }
+            (_, _, Some(manifest_base_url)) => {
+                IngestionServerGlobalManifest::from_https(manifest_base_url)?
+                    .batch_signing_public_keys()?
+            }
+            _ => {
+                return Err(anyhow!(
+                    "ingestor-public-key and ingestor-public-key-identifier are \
+                    required for {} if ingestor-manifest-base-url is not provided.",
+                    name
+                ))
+            }
+        };
+
+        ingestors.push(IngestorTransport {
+            name: names[index].map(str::to_owned),
+            transport: VerifiableAndDecryptableTransport {
+                transport: VerifiableTransport {
+                    transport: intake_transport,
+                    batch_signing_public_keys: ingestor_pub_key_map,
+                },
+                packet_decryption_keys: packet_decryption_keys.clone(),
+            },
+        });
+    }
+    Ok(ingestors)
+}
+
+/// Returns the index of this server: 0 if is-first is given, otherwise
+/// server-index, which defaults to 1.
+fn server_index_from_args(matches: &ArgMatches) -> usize {
+    if matches.is_present("is-first") {
+        return 0;
+    }
+    matches
+        .value_of("server-index")
+        .map_or(1, |v| v.parse().unwrap())
+}
+
+/// Returns the number of servers this deployment is configured with, checking
+/// that it includes this server and at least one peer.
+fn number_of_servers_from_args(matches: &ArgMatches, server_index: usize) -> Result<usize> {
+    let number_of_servers: usize = matches.value_of("number-of-servers").unwrap().parse()?;
+    if number_of_servers < 2 {
+        return Err(anyhow!(
+            "number-of-servers must be at least 2, not {}",
+            number_of_servers
+        ));
+    }
+    if number_of_servers > MAX_NUMBER_OF_SERVERS {
+        return Err(Error::ConfigurationError(format!(
+            "number-of-servers must be at most {} until libprio supports more, not {}",
+            MAX_NUMBER_OF_SERVERS, number_of_servers
+        ))
+        .into());
+    }
+    if server_index >= number_of_servers {
+        return Err(anyhow!(
+            "server-index {} is out of range for {} servers",
+            server_index,
+            number_of_servers
+        ));
+    }
+    Ok(number_of_servers)
+}
+
+fn peers_from_args(
+    matches: &ArgMatches,
+    server_index: usize,
+    instance_name: &str,
+    registry: &Registry,
+) -> Result<Vec<PeerValidationTransport>> {
+    // The peers are all the other servers, in order of server index.
+    let peer_indexes: Vec<usize> = (0..number_of_servers_from_args(matches, server_index)?)
+        .filter(|index| *index != server_index)
+        .collect();
+    let count = peer_indexes.len();
+    let inputs = repeated_values(matches, Entity::Peer, "peer-input", count)?;
+    let identities = repeated_values(matches, Entity::Peer, "peer-identity", count)?;
+    let public_keys = repeated_values(matches, Entity::Peer, "peer-public-key", count)?;
+    let public_key_identifiers =
+        repeated_values(matches, Entity::Peer, "peer-public-key-identifier", count)?;
+    let manifest_base_urls =
+        repeated_values(matches, Entity::Peer, "peer-manifest-base-url", count)?;
+
+    let mut peers = Vec::with_capacity(count);
+    for (index, peer_index) in peer_indexes.into_iter().enumerate() {
+        // We created the bucket that the peer wrote validations into, and so
+        // it is simply provided via argument.
+        let peer_validation_bucket = StoragePath::from_str(
+            inputs[index]
+                .ok_or_else(|| anyhow!("peer-input required for server {}", peer_index))?,
+        )?;
+        let peer_validation_transport =
+            transport_for_path(peer_validation_bucket, identities[index], registry)?;
+
+        // We need the public keys the peer data share processor used to
+        // sign messages, which we can obtain by argument or by discovering
+        // their specific manifest.
+        let peer_share_processor_pub_key_map = match (
+            public_keys[index],
+            public_key_identifiers[index],
+            manifest_base_urls[index],
+        ) {
+            (Some(public_key), Some(public_key_identifier), _) => {
+                public_key_map_from_arg(public_key, public_key_identifier)
+            }
+            (_, _, Some(manifest_base_url)) => {
+                SpecificManifest::from_https(manifest_base_url, instance_name)?
+                    .batch_signing_public_keys()?
+            }
+            _ => {
+                return Err(anyhow!(
+                    "peer-public-key and peer-public-key-identifier are \
+                    required for server {} if peer-manifest-base-url is not \
+                    provided.",
+                    peer_index
+                ))
+            }
+        };
+
+        peers.push(PeerValidationTransport {
+            server_index: peer_index,
+            transport: VerifiableTransport {
+                transport: peer_validation_transport,
+                batch_signing_public_keys: peer_share_processor_pub_key_map,
+            },
+        });
+    }
+    Ok(peers)
+}
+
+/// Returns transports from which to read the sum parts of all the servers, in
+/// order of server index, for the portal server.
+fn servers_from_args(
+    matches: &ArgMatches,
+    registry: &Registry,
+) -> Result<Vec<VerifiableTransport>> {
+    let count: usize = matches.value_of("number-of-servers").unwrap().parse()?;
+    let instance_name = matches.value_of("instance-name").unwrap();
+    let inputs = repeated_values(matches, Entity::Server, "server-input", count)?;
+    let identities = repeated_values(matches, Entity::Server, "server-identity", count)?;
+    let public_keys = repeated_values(matches, Entity::Server, "server-public-key", count)?;
+    let public_key_identifiers = repeated_values(
+        matches,
+        Entity::Server,
+        "server-public-key-identifier",
+        count,
+    )?;
+    let manifest_base_urls =
+        repeated_values(matches, Entity::Server, "server-manifest-base-url", count)?;
+
+    let mut servers = Vec::with_capacity(count);
+    for index in 0..count {
+        let sum_part_bucket = StoragePath::from_str(
+            inputs[index].ok_or_else(|| anyhow!("server-input required for server {}", index))?,
+        )?;
+        // The sum parts are signed with the keys in the server's specific
+        // manifest, unless they are provided by argument.
+        let public_key_map = match (
+            public_keys[index],
+            public_key_identifiers[index],
+            manifest_base_urls[index],
+        ) {
+            (Some(public_key), Some(public_key_identifier), _) => {
+                public_key_map_from_arg(public_key, public_key_identifier)
+            }
+            (_, _, Some(manifest_base_url)) => {
+                SpecificManifest::from_https(manifest_base_url, instance_name)?
+                    .batch_signing_public_keys()?
+            }
+            _ => {
+                return Err(anyhow!(
+                    "server-public-key and server-public-key-identifier are \
+                    required for server {} if server-manifest-base-url is not \
+                    provided.",
+                    index
+                ))
+            }
+        };
+        servers.push(VerifiableTransport {
+            transport: transport_for_path(sum_part_bucket, identities[index], registry)?,
+            batch_signing_public_keys: public_key_map,
+        });
+    }
+    Ok(servers)
 }
 
-fn transport_for_path(path: StoragePath, identity: Identity) -> Result<Box<dyn Transport>> {
+fn transport_for_path(
+    path: StoragePath,
+    identity: Identity,
+    registry: &Registry,
+) -> Result<Box<dyn Transport>> {
     match path {
-        StoragePath::S3Path(path) => Ok(Box::new(S3Transport::new(path, identity))),
-        StoragePath::GCSPath(path) => Ok(Box::new(GCSTransport::new(path, identity))),
-        StoragePath::LocalPath(path) => Ok(Box::new(LocalFileTransport::new(path))),
+        StoragePath::S3Path(path) => {
+            let mut transport = S3Transport::new(path, identity);
+            transport.set_metrics(registry)?;
+            Ok(Box::new(transport))
+        }
+        StoragePath::GCSPath(path) => {
+            let mut transport = GCSTransport::new(path, identity);
+            transport.set_metrics(registry)?;
+            Ok(Box::new(transport))
+        }
+        StoragePath::LocalPath(path) => {
+            let mut transport = LocalFileTransport::new(path);
+            transport.set_metrics(registry)?;
+            Ok(Box::new(transport))
+        }
     }
 }
@@ -0,0 +1,221 @@
+use crate::{
+    batch::{Batch, BatchReader},
+    dump::DumpFormat,
+    idl::{InvalidPacket, SumPart},
+    logging::LogContext,
+    report::StageReport,
+    transport::VerifiableTransport,
+    Error,
+};
+use anyhow::{anyhow, Context, Result};
+use chrono::NaiveDateTime;
+use log::info;
+use prio::finite_field::{Field, MODULUS};
+use serde::Serialize;
+use std::{collections::BTreeSet, io::Write};
+use uuid::Uuid;
+
+/// The result of an aggregation, reconstructed from the sum parts of all the
+/// servers.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CombinedSum {
+    pub name: String,
+    pub bins: i32,
+    pub epsilon: f64,
+    pub hamming_weight: Option<i32>,
+    pub noise_epsilon: Option<f64>,
+    pub aggregation_start_time: i64,
+    pub aggregation_end_time: i64,
+    pub batch_uuids: Vec<Uuid>,
+    pub total_individual_clients: i64,
+    pub invalid_uuids: Vec<Uuid>,
+    /// The number of clients that reported each bin. Field elements above
+    /// half the modulus are taken to be negative, which only noise added to
+    /// the sum parts can make them.
+    pub histogram: Vec<i64>,
+}
+
+impl CombinedSum {
+    /// Writes the combined sum to writer, either as a single line JSON object
+    /// or as CSV with a row for each bin of the histogram.
+    pub fn write<W: Write>(&self, mut writer: W, format: DumpFormat) -> Result<()> {
+        match format {
+            DumpFormat::JsonLines => {
+                serde_json::to_writer(&mut writer, self)
+                    .context("failed to serialize combined sum")?;
+                writeln!(writer)?;
+            }
+            DumpFormat::Csv => {
+                writeln!(writer, "bin,count")?;
+                for (bin, count) in self.histogram.iter().enumerate() {
+                    writeln!(writer, "{},{}", bin, count)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the sum part each server wrote for the provided aggregation window,
+/// from the transports given in order of server index, and reconstructs the
+/// aggregate from them. The sum parts must cover the same batches and agree on
+/// their parameters, or the error is an Error::ParameterMismatchError, and on
+/// which packets were invalid, or the error is an Error::PacketMismatchError.
+/// The batches read are recorded in report.
+pub fn combine_sums(
+    aggregation_name: &str,
+    aggregation_start: &NaiveDateTime,
+    aggregation_end: &NaiveDateTime,
+    sum_part_transports: &mut [VerifiableTransport],
+    report: &mut StageReport,
+) -> Result<CombinedSum> {
+    let _context = LogContext::new()
+        .aggregation_name(aggregation_name)
+        .date_range(aggregation_start, aggregation_end)
+        .enter();
+    if sum_part_transports.len() < 2 {
+        return Err(anyhow!("sum parts from at least two servers are required"));
+    }
+
+    let mut sum_parts = Vec::with_capacity(sum_part_transports.len());
+    let mut invalid_uuid_sets = Vec::with_capacity(sum_part_transports.len());
+    for (server_index, transport) in sum_part_transports.iter_mut().enumerate() {
+        let mut reader: BatchReader<'_, SumPart, InvalidPacket> = BatchReader::new(
+            Batch::new_sum(
+                aggregation_name,
+                aggregation_start,
+                aggregation_end,
+                server_index,
+            ),
+            &mut *transport.transport,
+        );
+        let sum_part = reader.header(&transport.batch_signing_public_keys)?;
+        invalid_uuid_sets.push(
+            reader
+                .invalid_uuids(&sum_part)?
+                .into_iter()
+                .collect::<BTreeSet<_>>(),
+        );
+        report.inputs.push(reader.report());
+        sum_parts.push(sum_part);
+    }
+
+    let first = &sum_parts[0];
+    let first_batch_uuids: BTreeSet<_> = first.batch_uuids.iter().collect();
+    for (server_index, sum_part) in sum_parts.iter().enumerate().skip(1) {
+        if !first.check_reduction_parameters(sum_part)
+            || sum_part.aggregation_start_time != first.aggregation_start_time
+            || sum_part.aggregation_end_time != first.aggregation_end_time
+            || sum_part.total_individual_clients != first.total_individual_clients
+        {
+            return Err(Error::ParameterMismatchError(format!(
+                "sum part of server {} does not match that of server 0",
+                server_index
+            ))
+            .into());
+        }
+        if sum_part.batch_uuids.iter().collect::<BTreeSet<_>>() != first_batch_uuids {
+            return Err(Error::ParameterMismatchError(format!(
+                "sum part of server {} covers different batches than that of server 0",
+                server_index
+            ))
+            .into());
+        }
+        let disagreements = invalid_uuid_sets[0]
+            .symmetric_difference(&invalid_uuid_sets[server_index])
+            .count();
+        if disagreements > 0 {
+            return Err(Error::PacketMismatchError(format!(
+                "servers 0 and {} disagree on whether {} packets are valid",
+                server_index, disagreements
+            ))
+            .into());
+        }
+    }
+
+    let mut sum: Vec<Field> = Vec::new();
+    for sum_part in &sum_parts {
+        let part_sum = sum_part.sum().map_err(|e| {
+            Error::MalformedHeaderError(format!("sum part element not in field: {}", e))
+        })?;
+        if part_sum.len() != sum_part.bins as usize {
+            return Err(Error::MalformedHeaderError(format!(
+                "sum part has {} elements but bins is {}",
+                part_sum.len(),
+                sum_part.bins
+            ))
+            .into());
+        }
+        if sum.is_empty() {
+            sum = part_sum;
+        } else {
+            for (element, part_element) in sum.iter_mut().zip(part_sum) {
+                *element += part_element;
+            }
+        }
+    }
+    let histogram = sum
+        .iter()
+        .map(|f| match u32::from(*f) as i64 {
+            n if n > MODULUS as i64 / 2 => n - MODULUS as i64,
+            n => n,
+        })
+        .collect();
+
+    let first = sum_parts.swap_remove(0);
+    info!(
+        "combined sum parts of {} servers over {} batches",
+        sum_part_transports.len(),
+        first.batch_uuids.len()
+    );
+    Ok(CombinedSum {
+        name: first.name,
+        bins: first.bins,
+        epsilon: first.epsilon,
+        hamming_weight: first.hamming_weight,
+        noise_epsilon: first.noise_epsilon,
+        aggregation_start_time: first.aggregation_start_time,
+        aggregation_end_time: first.aggregation_end_time,
+        batch_uuids: first.batch_uuids,
+        total_individual_clients: first.total_individual_clients,
+        invalid_uuids: invalid_uuid_sets.swap_remove(0).into_iter().collect(),
+        histogram,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_combined_sum() {
+        let combined_sum = CombinedSum {
+            name: "fake-aggregation".to_owned(),
+            bins: 3,
+            epsilon: 0.5,
+            hamming_weight: None,
+            noise_epsilon: Some(1.0),
+            aggregation_start_time: 0,
+            aggregation_end_time: 1,
+            batch_uuids: vec![Uuid::nil()],
+            total_individual_clients: 3,
+            invalid_uuids: vec![],
+            histogram: vec![4, 0, -1],
+        };
+
+        let mut csv = Vec::new();
+        combined_sum.write(&mut csv, DumpFormat::Csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "bin,count\n0,4\n1,0\n2,-1\n"
+        );
+
+        let mut json = Vec::new();
+        combined_sum
+            .write(&mut json, DumpFormat::JsonLines)
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(value["histogram"], serde_json::json!([4, 0, -1]));
+        assert_eq!(value["noise_epsilon"], 1.0);
+    }
+}
@@ -0,0 +1,408 @@
+//! Checks whether data written with one Avro schema can be read with another,
+//! following the schema resolution rules in the Avro specification[1]. We read
+//! every Avro object against the writer schema embedded in its container and
+//! resolve the records to the reader schema compiled into this crate, so this
+//! is what determines which changes to the schemas in avro-schema we and our
+//! peers can make without breaking one another.
+//!
+//! [1] https://avro.apache.org/docs/1.8.2/spec.html#Schema+Resolution
+
+use avro_rs::Schema;
+use std::fmt::{self, Display, Formatter};
+
+/// A reason data written with one schema cannot be read with another.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Incompatibility {
+    /// The location in the reader schema of the problem, e.g.
+    /// "PrioIngestionHeader.batch_uuid".
+    pub path: String,
+    pub reason: String,
+}
+
+impl Display for Incompatibility {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.reason)
+    }
+}
+
+/// Returns the reasons that data written with the writer schema cannot be
+/// resolved to the reader schema, or an empty Vec if the schemas are
+/// compatible.
+pub fn check_compatibility(writer: &Schema, reader: &Schema) -> Vec<Incompatibility> {
+    let mut incompatibilities = Vec::new();
+    check(writer, reader, &type_name(reader), &mut incompatibilities);
+    incompatibilities
+}
+
+/// The incompatibilities in each direction between an existing schema and a
+/// proposed replacement for it.
+#[derive(Debug, PartialEq)]
+pub struct SchemaChange {
+    /// Reasons data written with the old schema could not be read with the new
+    /// one.
+    pub backward: Vec<Incompatibility>,
+    /// Reasons data written with the new schema could not be read by peers
+    /// still using the old one.
+    pub forward: Vec<Incompatibility>,
+}
+
+impl SchemaChange {
+    /// Checks the change from the old schema to the new one.
+    pub fn check(old: &Schema, new: &Schema) -> SchemaChange {
+        SchemaChange {
+            backward: check_compatibility(old, new),
+            forward: check_compatibility(new, old),
+        }
+    }
+
+    /// Returns true if the change can be rolled out by us and our peers in any
+    /// order, i.e. if it is both backward and forward compatible.
+    pub fn is_compatible(&self) -> bool {
+        self.backward.is_empty() && self.forward.is_empty()
+    }
+}
+
+impl Display for SchemaChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (incompatibilities, description) in &[
+            (
+                &self.backward,
+                "new schema reads data written with old schema",
+            ),
+            (
+                &self.forward,
+                "old schema reads data written with new schema",
+            ),
+        ] {
+            if incompatibilities.is_empty() {
+                writeln!(f, "PASS {}", description)?;
+            } else {
+                writeln!(f, "FAIL {}", description)?;
+                for incompatibility in incompatibilities.iter() {
+                    writeln!(f, "    {}", incompatibility)?;
+                }
+            }
+        }
+        if self.is_compatible() {
+            write!(f, "schema change is compatible")
+        } else {
+            write!(f, "schema change is NOT compatible")
+        }
+    }
+}
+
+fn check(
+    writer: &Schema,
+    reader: &Schema,
+    path: &str,
+    incompatibilities: &mut Vec<Incompatibility>,
+) {
+    let mut incompatible = |reason: String| {
+        incompatibilities.push(Incompatibility {
+            path: path.to_owned(),
+            reason,
+        })
+    };
+
+    match (writer, reader) {
+        // Each branch of a writer union must be readable, since any of them
+        // may have been written.
+        (Schema::Union(writer_union), _) => {
+            for variant in writer_union.variants() {
+                check(variant, reader, path, incompatibilities);
+            }
+        }
+        // A reader union can read anything one of its branches can read.
+        (_, Schema::Union(reader_union)) => {
+            if !reader_union
+                .variants()
+                .iter()
+                .any(|variant| check_compatibility(writer, variant).is_empty())
+            {
+                incompatible(format!(
+                    "writer type {} matches no branch of reader union",
+                    type_name(writer)
+                ));
+            }
+        }
+        (
+            Schema::Record {
+                name: writer_name,
+                fields: writer_fields,
+                ..
+            },
+            Schema::Record {
+                name: reader_name,
+                fields: reader_fields,
+                ..
+            },
+        ) => {
+            if writer_name.name != reader_name.name {
+                incompatible(format!(
+                    "writer record {} does not match reader record {}",
+                    writer_name.name, reader_name.name
+                ));
+                return;
+            }
+            // Fields present only in the writer schema are ignored.
+            for reader_field in reader_fields {
+                let field_path = format!("{}.{}", path, reader_field.name);
+                match writer_fields.iter().find(|f| f.name == reader_field.name) {
+                    Some(writer_field) => check(
+                        &writer_field.schema,
+                        &reader_field.schema,
+                        &field_path,
+                        incompatibilities,
+                    ),
+                    None if reader_field.default.is_some() => (),
+                    None => incompatibilities.push(Incompatibility {
+                        path: field_path,
+                        reason: "field is missing from writer schema and has no default".to_owned(),
+                    }),
+                }
+            }
+        }
+        (
+            Schema::Enum {
+                symbols: writer_symbols,
+                ..
+            },
+            Schema::Enum {
+                symbols: reader_symbols,
+                ..
+            },
+        ) => {
+            let missing: Vec<_> = writer_symbols
+                .iter()
+                .filter(|s| !reader_symbols.contains(s))
+                .collect();
+            if !missing.is_empty() {
+                incompatible(format!("reader enum lacks symbols {:?}", missing));
+            }
+        }
+        (
+            Schema::Fixed {
+                size: writer_size, ..
+            },
+            Schema::Fixed {
+                size: reader_size, ..
+            },
+        ) => {
+            if writer_size != reader_size {
+                incompatible(format!(
+                    "writer fixed size {} does not match reader fixed size {}",
+                    writer_size, reader_size
+                ));
+            }
+        }
+        (Schema::Array(writer_items), Schema::Array(reader_items)) => check(
+            writer_items,
+            reader_items,
+            &format!("{}[]", path),
+            incompatibilities,
+        ),
+        (Schema::Map(writer_values), Schema::Map(reader_values)) => check(
+            writer_values,
+            reader_values,
+            &format!("{}{{}}", path),
+            incompatibilities,
+        ),
+        (writer, reader) => {
+            if !promotes_to(writer, reader) {
+                incompatible(format!(
+                    "writer type {} cannot be read as {}",
+                    type_name(writer),
+                    type_name(reader)
+                ));
+            }
+        }
+    }
+}
+
+/// Returns true if a value of the non-composite writer type can be read as the
+/// reader type, either because they are the same type, because the
+/// specification permits promoting one to the other, or because one is a
+/// logical type annotating the other. Logical types are encoded as their
+/// underlying type, so adding or removing the annotation is compatible.
+fn promotes_to(writer: &Schema, reader: &Schema) -> bool {
+    matches!(
+        (writer, reader),
+        (Schema::Null, Schema::Null)
+            | (Schema::Boolean, Schema::Boolean)
+            | (Schema::Int, Schema::Int)
+            | (Schema::Int, Schema::Long)
+            | (Schema::Int, Schema::Float)
+            | (Schema::Int, Schema::Double)
+            | (Schema::Long, Schema::Long)
+            | (Schema::Long, Schema::Float)
+            | (Schema::Long, Schema::Double)
+            | (Schema::Long, Schema::TimestampMillis)
+            | (Schema::TimestampMillis, Schema::Long)
+            | (Schema::Float, Schema::Float)
+            | (Schema::Float, Schema::Double)
+            | (Schema::Double, Schema::Double)
+            | (Schema::Bytes, Schema::Bytes)
+            | (Schema::Bytes, Schema::String)
+            | (Schema::String, Schema::String)
+            | (Schema::String, Schema::Bytes)
+            | (Schema::String, Schema::Uuid)
+            | (Schema::Uuid, Schema::String)
+            | (Schema::Uuid, Schema::Uuid)
+            | (Schema::TimestampMillis, Schema::TimestampMillis)
+            | (Schema::TimestampMicros, Schema::TimestampMicros)
+            | (Schema::TimeMillis, Schema::TimeMillis)
+            | (Schema::TimeMicros, Schema::TimeMicros)
+            | (Schema::Date, Schema::Date)
+    )
+}
+
+fn type_name(schema: &Schema) -> String {
+    match schema {
+        Schema::Null => "null".to_owned(),
+        Schema::Boolean => "boolean".to_owned(),
+        Schema::Int => "int".to_owned(),
+        Schema::Long => "long".to_owned(),
+        Schema::Float => "float".to_owned(),
+        Schema::Double => "double".to_owned(),
+        Schema::Bytes => "bytes".to_owned(),
+        Schema::String => "string".to_owned(),
+        Schema::Array(_) => "array".to_owned(),
+        Schema::Map(_) => "map".to_owned(),
+        Schema::Union(_) => "union".to_owned(),
+        Schema::Record { name, .. } | Schema::Enum { name, .. } | Schema::Fixed { name, .. } => {
+            name.name.clone()
+        }
+        Schema::Decimal { .. } => "decimal".to_owned(),
+        Schema::Uuid => "uuid".to_owned(),
+        Schema::Date => "date".to_owned(),
+        Schema::TimeMillis => "time-millis".to_owned(),
+        Schema::TimeMicros => "time-micros".to_owned(),
+        Schema::TimestampMillis => "timestamp-millis".to_owned(),
+        Schema::TimestampMicros => "timestamp-micros".to_owned(),
+        Schema::Duration => "duration".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::idl::{
+        AvroRecord, BatchSignature, IngestionDataSharePacket, IngestionHeader, InvalidPacket,
+        InvalidUuidSet, SumPart, ValidationHeader, ValidationPacket,
+    };
+
+    fn header_schema(extra_fields: &str) -> Schema {
+        Schema::parse_str(&format!(
+            r#"{{
+                "type": "record",
+                "name": "PrioIngestionHeader",
+                "fields": [
+                    {{"name": "batch_uuid", "type": "string", "logicalType": "uuid"}},
+                    {{"name": "bins", "type": "int"}}{}
+                ]
+            }}"#,
+            extra_fields
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn compiled_schemas_are_self_compatible() {
+        for raw in &[
+            BatchSignature::schema_raw(),
+            IngestionHeader::schema_raw(),
+            IngestionDataSharePacket::schema_raw(),
+            ValidationHeader::schema_raw(),
+            ValidationPacket::schema_raw(),
+            SumPart::schema_raw(),
+            InvalidPacket::schema_raw(),
+            InvalidUuidSet::schema_raw(),
+        ] {
+            let schema = Schema::parse_str(raw).unwrap();
+            assert_eq!(check_compatibility(&schema, &schema), vec![]);
+        }
+    }
+
+    #[test]
+    fn optional_field_with_default() {
+        let old = header_schema("");
+        let new =
+            header_schema(r#", {"name": "region", "type": ["null", "string"], "default": null}"#);
+        let change = SchemaChange::check(&old, &new);
+        assert!(change.is_compatible(), "{}", change);
+    }
+
+    #[test]
+    fn required_field_without_default() {
+        let old = header_schema("");
+        let new = header_schema(r#", {"name": "region", "type": "string"}"#);
+        let SchemaChange { backward, forward } = SchemaChange::check(&old, &new);
+        assert_eq!(
+            backward,
+            vec![Incompatibility {
+                path: "PrioIngestionHeader.region".to_owned(),
+                reason: "field is missing from writer schema and has no default".to_owned(),
+            }]
+        );
+        // Peers still on the old schema ignore the new field.
+        assert_eq!(forward, vec![]);
+    }
+
+    #[test]
+    fn type_changes() {
+        let int_bins = header_schema("");
+        let long_bins = Schema::parse_str(
+            r#"{
+                "type": "record",
+                "name": "PrioIngestionHeader",
+                "fields": [
+                    {"name": "batch_uuid", "type": "string", "logicalType": "uuid"},
+                    {"name": "bins", "type": ["null", "long"], "default": null}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        // Promoting int to long, and making the field nullable, is backward
+        // compatible, but old readers can't read a long or a null as an int.
+        let SchemaChange { backward, forward } = SchemaChange::check(&int_bins, &long_bins);
+        assert_eq!(backward, vec![]);
+        assert_eq!(
+            forward,
+            vec![
+                Incompatibility {
+                    path: "PrioIngestionHeader.bins".to_owned(),
+                    reason: "writer type null cannot be read as int".to_owned(),
+                },
+                Incompatibility {
+                    path: "PrioIngestionHeader.bins".to_owned(),
+                    reason: "writer type long cannot be read as int".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn logical_type_annotation() {
+        let uuid_schema = |batch_uuid_type: &str| {
+            Schema::parse_str(&format!(
+                r#"{{
+                    "type": "record",
+                    "name": "PrioIngestionHeader",
+                    "fields": [{{"name": "batch_uuid", "type": {}}}]
+                }}"#,
+                batch_uuid_type
+            ))
+            .unwrap()
+        };
+        let string_uuid = uuid_schema(r#""string""#);
+        let logical_uuid = uuid_schema(r#"{"type": "string", "logicalType": "uuid"}"#);
+
+        // Uuids are encoded as strings, so the annotation can be added or
+        // removed in either direction.
+        for (old, new) in &[(&string_uuid, &logical_uuid), (&logical_uuid, &string_uuid)] {
+            let change = SchemaChange::check(old, new);
+            assert!(change.is_compatible(), "{}", change);
+        }
+    }
+}
@@ -1,10 +1,17 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use chrono::Duration;
+use clap::{App, ArgMatches, ErrorKind};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use rusoto_core::{region::ParseRegionError, Region};
 use serde::{de, export::Formatter, Deserialize, Deserializer, Serialize, Serializer};
-use std::{fmt, path::PathBuf, str::FromStr};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 /// Identity represents a cloud identity: Either an AWS IAM ARN (i.e. "arn:...")
 /// or a GCP ServiceAccount (i.e. "foo@bar.com").
@@ -243,11 +250,247 @@ impl<'de> Deserialize<'de> for DayDuration {
     }
 }
 
+/// A value in a config file. Tables group settings whose argument names share
+/// a prefix, so that e.g. `input` in the table `ingestor` is the value of
+/// `--ingestor-input`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum Setting {
+    Flag(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    List(Vec<Setting>),
+    Table(BTreeMap<String, Setting>),
+}
+
+/// A command line argument whose value comes from a config file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigArgument {
+    /// The long name of the argument, e.g. "ingestor-input".
+    pub name: String,
+    /// The values of the argument, of which there is more than one only if the
+    /// setting was a list.
+    pub values: Vec<String>,
+}
+
+impl ConfigArgument {
+    /// Returns the argument as it would be given on the command line.
+    fn tokens(&self) -> Vec<OsString> {
+        self.values
+            .iter()
+            .map(|value| format!("--{}={}", self.name, value).into())
+            .collect()
+    }
+
+    /// Returns true if the argument appears in args by its long name.
+    fn is_given_in(&self, args: &[OsString]) -> bool {
+        let flag = format!("--{}", self.name);
+        let prefix = format!("--{}=", self.name);
+        args.iter()
+            .filter_map(|arg| arg.to_str())
+            .any(|arg| arg == flag || arg.starts_with(&prefix))
+    }
+}
+
+/// Where a config file argument is placed on the command line.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Placement {
+    /// After the subcommand, for the subcommand's own arguments.
+    Subcommand,
+    /// Before the subcommand, for arguments of facilitator itself.
+    Global,
+    /// Not at all, since neither takes the argument.
+    Ignored,
+    /// Not at all, since the command line gives it by its short name.
+    CommandLine,
+}
+
+/// A TOML or YAML file providing values for facilitator's command line
+/// arguments, so that deployments need not pass dozens of flags. Keys are
+/// argument names, optionally grouped into tables by entity, e.g.:
+///
+/// ```toml
+/// instance-name = "zc-megacorp"
+/// packet-decryption-keys = ["BIl6...", "BNn2..."]
+///
+/// [ingestor]
+/// input = "s3://us-west-2/ingestor-bucket"
+/// manifest-base-url = "https://megacorp.example/manifests"
+///
+/// [peer]
+/// output = "gs://peer-validation-bucket"
+/// identity = "validator@peer.iam.gserviceaccount.com"
+/// ```
+///
+/// Flags are set with `true`. Arguments given on the command line take
+/// precedence over the config file, which in turn takes precedence over
+/// environment variables and then defaults.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigFile {
+    arguments: Vec<ConfigArgument>,
+}
+
+impl ConfigFile {
+    /// Reads the config file at path, which is parsed as YAML if it has the
+    /// extension .yaml or .yml and as TOML otherwise.
+    pub fn from_path(path: &Path) -> Result<ConfigFile> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => ConfigFile::from_yaml(&contents),
+            _ => ConfigFile::from_toml(&contents),
+        }
+        .with_context(|| format!("failed to parse config file {}", path.display()))
+    }
+
+    pub fn from_toml(contents: &str) -> Result<ConfigFile> {
+        ConfigFile::from_settings(toml::from_str(contents)?)
+    }
+
+    pub fn from_yaml(contents: &str) -> Result<ConfigFile> {
+        ConfigFile::from_settings(serde_yaml::from_str(contents)?)
+    }
+
+    fn from_settings(settings: BTreeMap<String, Setting>) -> Result<ConfigFile> {
+        let mut arguments = Vec::new();
+        flatten_settings(None, settings, &mut arguments)?;
+        Ok(ConfigFile { arguments })
+    }
+
+    /// The arguments the config file provides, in order of name.
+    pub fn arguments(&self) -> &[ConfigArgument] {
+        &self.arguments
+    }
+
+    /// Parses args with app, first adding the arguments from the config file
+    /// that are not given in args. The config file is shared by all of the
+    /// app's subcommands, so arguments that neither the subcommand nor the
+    /// app itself take are ignored. Their names are returned along with the
+    /// matches.
+    pub fn get_matches<'a, 'b>(
+        &self,
+        app: App<'a, 'b>,
+        args: Vec<OsString>,
+    ) -> clap::Result<(ArgMatches<'a>, Vec<String>)> {
+        let arguments: Vec<_> = self
+            .arguments
+            .iter()
+            .filter(|argument| !argument.is_given_in(&args))
+            .collect();
+        let mut placements = vec![Placement::Subcommand; arguments.len()];
+
+        // clap has no way to ask which arguments a subcommand takes, so we
+        // find out by parsing and moving whichever argument it rejects.
+        loop {
+            let placed = |placement| {
+                arguments
+                    .iter()
+                    .zip(&placements)
+                    .filter(move |(_, p)| **p == placement)
+                    .flat_map(|(argument, _)| argument.tokens())
+            };
+            let mut full_args = args.clone();
+            full_args.splice(
+                full_args.len().min(1)..full_args.len().min(1),
+                placed(Placement::Global),
+            );
+            full_args.extend(placed(Placement::Subcommand));
+
+            let error = match app.clone().get_matches_from_safe(full_args) {
+                Ok(matches) => {
+                    let ignored = arguments
+                        .iter()
+                        .zip(&placements)
+                        .filter(|(_, p)| **p == Placement::Ignored)
+                        .map(|(argument, _)| argument.name.clone())
+                        .collect();
+                    return Ok((matches, ignored));
+                }
+                Err(error) => error,
+            };
+
+            // Nor does it say which short names arguments have, but one given
+            // both by its short name and in the config file is given twice.
+            let rejected = match (&error.kind, &error.info) {
+                (ErrorKind::UnknownArgument, Some(info)) => arguments
+                    .iter()
+                    .position(|argument| info.contains(&format!("--{}", argument.name))),
+                (ErrorKind::UnexpectedMultipleUsage, Some(info)) => {
+                    arguments.iter().position(|argument| {
+                        argument.values.len() == 1 && info.contains(&argument.name)
+                    })
+                }
+                _ => None,
+            };
+            match rejected.map(|index| (index, placements[index], &error.kind)) {
+                Some((index, Placement::Subcommand, ErrorKind::UnknownArgument)) => {
+                    placements[index] = Placement::Global
+                }
+                Some((index, Placement::Global, ErrorKind::UnknownArgument)) => {
+                    placements[index] = Placement::Ignored
+                }
+                Some((index, Placement::Subcommand, ErrorKind::UnexpectedMultipleUsage))
+                | Some((index, Placement::Global, ErrorKind::UnexpectedMultipleUsage)) => {
+                    placements[index] = Placement::CommandLine
+                }
+                _ => return Err(error),
+            }
+        }
+    }
+}
+
+fn flatten_settings(
+    prefix: Option<&str>,
+    settings: BTreeMap<String, Setting>,
+    arguments: &mut Vec<ConfigArgument>,
+) -> Result<()> {
+    for (key, setting) in settings {
+        let name = match prefix {
+            Some(prefix) => format!("{}-{}", prefix, key),
+            None => key,
+        };
+        let values = match setting {
+            Setting::Table(table) => {
+                flatten_settings(Some(&name), table, arguments)?;
+                continue;
+            }
+            // A flag that is false is the same as one that is absent.
+            Setting::Flag(false) => continue,
+            Setting::List(list) => list
+                .into_iter()
+                .map(|setting| {
+                    setting_value(setting)
+                        .with_context(|| format!("invalid value in list {}", name))
+                })
+                .collect::<Result<_>>()?,
+            setting => vec![setting_value(setting)?],
+        };
+        arguments.push(ConfigArgument { name, values });
+    }
+    arguments.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(())
+}
+
+fn setting_value(setting: Setting) -> Result<String> {
+    match setting {
+        Setting::Flag(flag) => Ok(flag.to_string()),
+        Setting::Integer(integer) => Ok(integer.to_string()),
+        Setting::Float(float) => Ok(float.to_string()),
+        Setting::String(string) => Ok(string),
+        Setting::List(_) | Setting::Table(_) => Err(anyhow!("lists and tables cannot be nested")),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{GCSPath, GCSPathParseError, S3Path, S3PathParseError, StoragePath};
+    use super::{
+        ConfigArgument, ConfigFile, GCSPath, GCSPathParseError, S3Path, S3PathParseError,
+        StoragePath,
+    };
     use crate::config::DayDuration;
     use assert_matches::assert_matches;
+    use clap::{App, Arg, SubCommand};
     use rusoto_core::Region;
     use serde_test::{assert_de_tokens, assert_tokens, Token};
     use std::str::FromStr;
@@ -403,4 +646,165 @@ mod tests {
         let p = p.ensure_directory_prefix();
         assert_eq!(p.key, "key-prefix/");
     }
+
+    fn argument(name: &str, values: &[&str]) -> ConfigArgument {
+        ConfigArgument {
+            name: name.to_owned(),
+            values: values.iter().map(|v| v.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn parse_config_file() {
+        let toml = ConfigFile::from_toml(
+            r#"
+            instance-name = "zc-megacorp"
+            is-first = true
+            upload-report = false
+            batch-time-tolerance = 120
+            packet-decryption-keys = ["a", "b"]
+
+            [ingestor]
+            input = "s3://us-west-2/ingestor-bucket"
+            identity = "arn:aws:iam::1234:role/ingestor"
+
+            [peer]
+            output = "gs://peer-bucket"
+            "#,
+        )
+        .unwrap();
+        let yaml = ConfigFile::from_yaml(
+            r#"
+            instance-name: zc-megacorp
+            is-first: true
+            upload-report: false
+            batch-time-tolerance: 120
+            packet-decryption-keys: [a, b]
+            ingestor:
+              input: s3://us-west-2/ingestor-bucket
+              identity: arn:aws:iam::1234:role/ingestor
+            peer:
+              output: gs://peer-bucket
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(toml, yaml);
+        assert_eq!(
+            toml.arguments(),
+            &[
+                argument("batch-time-tolerance", &["120"]),
+                argument("ingestor-identity", &["arn:aws:iam::1234:role/ingestor"]),
+                argument("ingestor-input", &["s3://us-west-2/ingestor-bucket"]),
+                argument("instance-name", &["zc-megacorp"]),
+                argument("is-first", &["true"]),
+                argument("packet-decryption-keys", &["a", "b"]),
+                argument("peer-output", &["gs://peer-bucket"]),
+            ][..]
+        );
+    }
+
+    #[test]
+    fn parse_invalid_config_file() {
+        ConfigFile::from_toml("keys = [[\"a\"]]").unwrap_err();
+        ConfigFile::from_toml("instance-name = ").unwrap_err();
+        ConfigFile::from_yaml("- not a table").unwrap_err();
+    }
+
+    #[test]
+    fn config_file_arguments() {
+        let app = App::new("facilitator")
+            .arg(
+                Arg::with_name("log-format")
+                    .long("log-format")
+                    .takes_value(true),
+            )
+            .arg(Arg::with_name("verbose").long("verbose").short("v"))
+            .subcommand(
+                SubCommand::with_name("aggregate")
+                    .arg(Arg::with_name("is-first").long("is-first"))
+                    .arg(
+                        Arg::with_name("date")
+                            .long("date")
+                            .short("d")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::with_name("peer-input")
+                            .long("peer-input")
+                            .takes_value(true)
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("keys")
+                            .long("keys")
+                            .takes_value(true)
+                            .multiple(true)
+                            .use_delimiter(true),
+                    ),
+            );
+        let config_file = ConfigFile::from_toml(
+            r#"
+            log-format = "json"
+            verbose = true
+            is-first = true
+            date = "2020/10/01/00/00"
+            keys = ["a", "b"]
+            unknown = "value"
+
+            [peer]
+            input = "/config/peer"
+            "#,
+        )
+        .unwrap();
+        let args = |args: &[&str]| args.iter().map(Into::into).collect();
+
+        let (matches, ignored) = config_file
+            .get_matches(app.clone(), args(&["facilitator", "aggregate"]))
+            .unwrap();
+        assert_eq!(matches.value_of("log-format"), Some("json"));
+        let sub_matches = matches.subcommand_matches("aggregate").unwrap();
+        assert!(sub_matches.is_present("is-first"));
+        assert_eq!(sub_matches.value_of("peer-input"), Some("/config/peer"));
+        assert_eq!(
+            sub_matches.values_of("keys").unwrap().collect::<Vec<_>>(),
+            vec!["a", "b"]
+        );
+        assert_eq!(ignored, vec!["unknown".to_owned()]);
+
+        // The command line takes precedence over the config file
+        let (matches, _) = config_file
+            .get_matches(
+                app.clone(),
+                args(&[
+                    "facilitator",
+                    "--log-format",
+                    "text",
+                    "aggregate",
+                    "--peer-input=/cli/peer",
+                ]),
+            )
+            .unwrap();
+        assert_eq!(matches.value_of("log-format"), Some("text"));
+        let sub_matches = matches.subcommand_matches("aggregate").unwrap();
+        assert_eq!(sub_matches.value_of("peer-input"), Some("/cli/peer"));
+
+        // Including when it gives arguments by their short names
+        let (matches, ignored) = config_file
+            .get_matches(
+                app.clone(),
+                args(&["facilitator", "-v", "aggregate", "-d", "2020/11/01/00/00"]),
+            )
+            .unwrap();
+        assert_eq!(matches.occurrences_of("verbose"), 1);
+        let sub_matches = matches.subcommand_matches("aggregate").unwrap();
+        assert_eq!(sub_matches.value_of("date"), Some("2020/11/01/00/00"));
+        assert_eq!(ignored, vec!["unknown".to_owned()]);
+
+        // Errors in config file values are reported
+        let config_file = ConfigFile::from_toml("peer-input = [\"a\", \"b\"]").unwrap();
+        config_file
+            .get_matches(app, args(&["facilitator", "aggregate"]))
+            .unwrap_err();
+    }
 }
@@ -0,0 +1,637 @@
+use crate::{
+    idl::{
+        resolving_reader, BatchSignature, Header, IngestionDataSharePacket, IngestionHeader,
+        InvalidPacket, InvalidUuidSet, Packet, SumPart, ValidationHeader, ValidationPacket,
+    },
+    Error,
+};
+use anyhow::{anyhow, Context, Result};
+use prio::{
+    encrypt::{decrypt_share, PrivateKey},
+    finite_field::Field,
+    server::{Server, VerificationMessage},
+    util::{deserialize, proof_length},
+};
+use serde_json::{json, Value};
+use std::{
+    io::{Read, Write},
+    str::FromStr,
+};
+
+/// The kinds of object that can be dumped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ObjectKind {
+    IngestionHeader,
+    IngestionPackets,
+    ValidationHeader,
+    ValidationPackets,
+    SumPart,
+    InvalidPackets,
+    InvalidUuidSet,
+    BatchSignature,
+}
+
+impl FromStr for ObjectKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ingestion-header" => Ok(ObjectKind::IngestionHeader),
+            "ingestion-packets" => Ok(ObjectKind::IngestionPackets),
+            "validation-header" => Ok(ObjectKind::ValidationHeader),
+            "validation-packets" => Ok(ObjectKind::ValidationPackets),
+            "sum-part" => Ok(ObjectKind::SumPart),
+            "invalid-packets" => Ok(ObjectKind::InvalidPackets),
+            "invalid-uuid-set" => Ok(ObjectKind::InvalidUuidSet),
+            "batch-signature" => Ok(ObjectKind::BatchSignature),
+            _ => Err(anyhow!("unknown object kind {}", s)),
+        }
+    }
+}
+
+impl ObjectKind {
+    /// The names accepted by ObjectKind::from_str.
+    pub const NAMES: &'static [&'static str] = &[
+        "ingestion-header",
+        "ingestion-packets",
+        "validation-header",
+        "validation-packets",
+        "sum-part",
+        "invalid-packets",
+        "invalid-uuid-set",
+        "batch-signature",
+    ];
+
+    /// Works out the kind of object from its key, following the naming scheme
+    /// in batch::Batch. Returns None if the key does not look like part of a
+    /// batch.
+    pub fn from_key(key: &str) -> Option<ObjectKind> {
+        let (key, is_packet_file) = match key.strip_suffix(".avro") {
+            Some(key) => (key, true),
+            None => (key, false),
+        };
+        if key.ends_with(".sig") {
+            return if is_packet_file {
+                None
+            } else {
+                Some(ObjectKind::BatchSignature)
+            };
+        }
+        let extension = key.rsplit('.').next()?;
+        let kind = if extension == "batch" {
+            if is_packet_file {
+                ObjectKind::IngestionPackets
+            } else {
+                ObjectKind::IngestionHeader
+            }
+        } else if extension.starts_with("validity_") {
+            if is_packet_file {
+                ObjectKind::ValidationPackets
+            } else {
+                ObjectKind::ValidationHeader
+            }
+        } else if extension.starts_with("sum_") && !is_packet_file {
+            ObjectKind::SumPart
+        } else if extension.starts_with("invalid_uuid_") && is_packet_file {
+            ObjectKind::InvalidPackets
+        } else if extension.starts_with("invalid_set_") {
+            if is_packet_file {
+                ObjectKind::InvalidPackets
+            } else {
+                ObjectKind::InvalidUuidSet
+            }
+        } else {
+            return None;
+        };
+        Some(kind)
+    }
+}
+
+/// How dumped records are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DumpFormat {
+    /// One JSON object per line.
+    JsonLines,
+    /// Comma separated values, with a header row naming the fields. Fields
+    /// holding arrays are written as JSON arrays.
+    Csv,
+}
+
+impl FromStr for DumpFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(DumpFormat::JsonLines),
+            "csv" => Ok(DumpFormat::Csv),
+            _ => Err(anyhow!("unknown dump format {}", s)),
+        }
+    }
+}
+
+/// Keys with which to decrypt the payloads of ingestion packets when dumping
+/// them.
+pub struct PacketDecryption {
+    pub keys: Vec<PrivateKey>,
+    /// The index of the server the keys belong to. Only the first server's,
+    /// i.e. the PHA's, share is encrypted as field elements: the others' are
+    /// seeds from which libprio derives them.
+    pub server_index: usize,
+    /// The number of bins in the batch's ingestion header, which determines
+    /// how many field elements are derived from a seed.
+    pub bins: usize,
+}
+
+/// Decodes the object of the provided kind read from reader and writes each
+/// record in it to writer in the provided format, with byte fields base64
+/// encoded. If decryption is provided, ingestion packet payloads are decrypted
+/// too. Returns the number of records written.
+pub fn dump<R: Read, W: Write>(
+    kind: ObjectKind,
+    reader: R,
+    writer: W,
+    format: DumpFormat,
+    decryption: Option<&PacketDecryption>,
+) -> Result<usize> {
+    let mut writer = RecordWriter::new(writer, format);
+    match kind {
+        ObjectKind::IngestionHeader => writer.write(IngestionHeader::read(reader)?.fields())?,
+        ObjectKind::ValidationHeader => writer.write(ValidationHeader::read(reader)?.fields())?,
+        ObjectKind::SumPart => writer.write(SumPart::read(reader)?.fields())?,
+        ObjectKind::InvalidUuidSet => writer.write(InvalidUuidSet::read(reader)?.fields())?,
+        ObjectKind::BatchSignature => writer.write(BatchSignature::read(reader)?.fields())?,
+        ObjectKind::IngestionPackets => {
+            dump_packets::<IngestionDataSharePacket, _, _>(reader, &mut writer, |packet| {
+                let mut fields = packet.fields();
+                if let Some(decryption) = decryption {
+                    fields.push(decrypted_payload(packet, decryption)?);
+                }
+                Ok(fields)
+            })?
+        }
+        ObjectKind::ValidationPackets => {
+            dump_packets::<ValidationPacket, _, _>(reader, &mut writer, |packet| {
+                Ok(packet.fields())
+            })?
+        }
+        ObjectKind::InvalidPackets => {
+            dump_packets::<InvalidPacket, _, _>(reader, &mut writer, |packet| Ok(packet.fields()))?
+        }
+    }
+    Ok(writer.count)
+}
+
+fn dump_packets<P, R, W>(
+    reader: R,
+    writer: &mut RecordWriter<W>,
+    fields: impl Fn(&P) -> Result<Fields>,
+) -> Result<()>
+where
+    P: Packet,
+    R: Read,
+    W: Write,
+{
+    let schema = P::schema();
+    let mut reader = resolving_reader(&schema, reader)?;
+    loop {
+        match P::read(&mut reader) {
+            Ok(packet) => writer.write(fields(&packet)?)?,
+            Err(Error::EofError) => return Ok(()),
+            Err(e) => {
+                return Err(e).context(format!("failed to read packet {}", writer.count));
+            }
+        }
+    }
+}
+
+/// Decrypts the packet's payload with whichever of the keys works.
+fn decrypted_payload(
+    packet: &IngestionDataSharePacket,
+    decryption: &PacketDecryption,
+) -> Result<(&'static str, Value)> {
+    let undecryptable = || {
+        Error::PacketProcessingError(
+            packet.uuid,
+            "payload could not be decrypted with any key".to_owned(),
+        )
+    };
+    let share = if decryption.server_index == 0 {
+        let share = decryption
+            .keys
+            .iter()
+            .find_map(|key| decrypt_share(&packet.encrypted_payload, key).ok())
+            .ok_or_else(undecryptable)?;
+        deserialize(&share)
+    } else {
+        // libprio's PRNG is private, but a Server whose dimension is the
+        // length of the proof expands the whole share from the seed when it
+        // aggregates it. Zero verification messages always pass validation.
+        let zero = VerificationMessage {
+            f_r: Field::from(0),
+            g_r: Field::from(0),
+            h_r: Field::from(0),
+        };
+        decryption
+            .keys
+            .iter()
+            .find_map(|key| {
+                let mut server = Server::new(proof_length(decryption.bins), false, key.clone());
+                server
+                    .aggregate(&packet.encrypted_payload, &zero, &zero)
+                    .ok()
+                    .map(|_| server.total_shares().to_vec())
+            })
+            .ok_or_else(undecryptable)?
+    };
+    let share: Vec<u32> = share.into_iter().map(u32::from).collect();
+    Ok(("share", json!(share)))
+}
+
+/// The names and values of a record's fields, in schema order.
+type Fields = Vec<(&'static str, Value)>;
+
+/// Records that can be dumped.
+trait Dump {
+    fn fields(&self) -> Fields;
+}
+
+fn bytes(bytes: &[u8]) -> Value {
+    Value::String(base64::encode(bytes))
+}
+
+impl Dump for IngestionHeader {
+    fn fields(&self) -> Fields {
+        vec![
+            ("batch_uuid", json!(self.batch_uuid)),
+            ("name", json!(self.name)),
+            ("bins", json!(self.bins)),
+            ("epsilon", json!(self.epsilon)),
+            ("prime", json!(self.prime)),
+            ("number_of_servers", json!(self.number_of_servers)),
+            ("hamming_weight", json!(self.hamming_weight)),
+            ("batch_start_time", json!(self.batch_start_time)),
+            ("batch_end_time", json!(self.batch_end_time)),
+            ("packet_file_digest", bytes(&self.packet_file_digest)),
+        ]
+    }
+}
+
+impl Dump for IngestionDataSharePacket {
+    fn fields(&self) -> Fields {
+        vec![
+            ("uuid", json!(self.uuid)),
+            ("encrypted_payload", bytes(&self.encrypted_payload)),
+            ("encryption_key_id", json!(self.encryption_key_id)),
+            ("r_pit", json!(self.r_pit)),
+            ("version_configuration", json!(self.version_configuration)),
+            (
+                "device_nonce",
+                self.device_nonce.as_deref().map_or(Value::Null, bytes),
+            ),
+        ]
+    }
+}
+
+impl Dump for ValidationHeader {
+    fn fields(&self) -> Fields {
+        vec![
+            ("batch_uuid", json!(self.batch_uuid)),
+            ("name", json!(self.name)),
+            ("bins", json!(self.bins)),
+            ("epsilon", json!(self.epsilon)),
+            ("prime", json!(self.prime)),
+            ("number_of_servers", json!(self.number_of_servers)),
+            ("hamming_weight", json!(self.hamming_weight)),
+            ("packet_file_digest", bytes(&self.packet_file_digest)),
+        ]
+    }
+}
+
+impl Dump for ValidationPacket {
+    fn fields(&self) -> Fields {
+        vec![
+            ("uuid", json!(self.uuid)),
+            ("f_r", json!(self.f_r)),
+            ("g_r", json!(self.g_r)),
+            ("h_r", json!(self.h_r)),
+            ("hamming_weight_share", json!(self.hamming_weight_share)),
+        ]
+    }
+}
+
+impl Dump for SumPart {
+    fn fields(&self) -> Fields {
+        vec![
+            ("batch_uuids", json!(self.batch_uuids)),
+            ("name", json!(self.name)),
+            ("bins", json!(self.bins)),
+            ("epsilon", json!(self.epsilon)),
+            ("prime", json!(self.prime)),
+            ("number_of_servers", json!(self.number_of_servers)),
+            ("hamming_weight", json!(self.hamming_weight)),
+            ("sum", json!(self.sum)),
+            ("aggregation_start_time", json!(self.aggregation_start_time)),
+            ("aggregation_end_time", json!(self.aggregation_end_time)),
+            ("packet_file_digest", bytes(&self.packet_file_digest)),
+            (
+                "total_individual_clients",
+                json!(self.total_individual_clients),
+            ),
+            ("noise_epsilon", json!(self.noise_epsilon)),
+        ]
+    }
+}
+
+impl Dump for InvalidUuidSet {
+    fn fields(&self) -> Fields {
+        vec![
+            ("batch_uuids", json!(self.batch_uuids)),
+            ("name", json!(self.name)),
+            ("aggregation_start_time", json!(self.aggregation_start_time)),
+            ("aggregation_end_time", json!(self.aggregation_end_time)),
+            ("packet_file_digest", bytes(&self.packet_file_digest)),
+            ("cross_checked", json!(self.cross_checked)),
+        ]
+    }
+}
+
+impl Dump for InvalidPacket {
+    fn fields(&self) -> Fields {
+        vec![("uuid", json!(self.uuid))]
+    }
+}
+
+impl Dump for BatchSignature {
+    fn fields(&self) -> Fields {
+        vec![
+            (
+                "batch_header_signature",
+                bytes(&self.batch_header_signature),
+            ),
+            ("key_identifier", json!(self.key_identifier)),
+            (
+                "batch_manifest_signature",
+                self.batch_manifest_signature
+                    .as_deref()
+                    .map_or(Value::Null, bytes),
+            ),
+        ]
+    }
+}
+
+/// Writes records in a DumpFormat. Every record written must have the same
+/// fields, in the same order.
+struct RecordWriter<W> {
+    writer: W,
+    format: DumpFormat,
+    count: usize,
+}
+
+impl<W: Write> RecordWriter<W> {
+    fn new(writer: W, format: DumpFormat) -> Self {
+        RecordWriter {
+            writer,
+            format,
+            count: 0,
+        }
+    }
+
+    fn write(&mut self, fields: Fields) -> Result<()> {
+        let line = match self.format {
+            // serde_json's Map sorts its keys, so the object is assembled by
+            // hand to keep the fields in schema order.
+            DumpFormat::JsonLines => format!(
+                "{{{}}}",
+                fields
+                    .iter()
+                    .map(|(name, value)| format!("{}:{}", json!(name), value))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            DumpFormat::Csv => {
+                if self.count == 0 {
+                    let names: Vec<_> = fields.iter().map(|(name, _)| csv_field(name)).collect();
+                    writeln!(self.writer, "{}", names.join(","))
+                        .context("failed to write CSV header")?;
+                }
+                fields
+                    .iter()
+                    .map(|(_, value)| match value {
+                        Value::Null => String::new(),
+                        Value::String(s) => csv_field(s),
+                        v => csv_field(&v.to_string()),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",")
+            }
+        };
+        writeln!(self.writer, "{}", line).context("failed to write record")?;
+        self.count += 1;
+        Ok(())
+    }
+}
+
+/// Quotes a CSV field if it contains a separator, quote or line break.
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sample::generate_ingestion_sample,
+        test_utils::{
+            default_ingestor_private_key, DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY,
+            DEFAULT_PHA_ECIES_PRIVATE_KEY,
+        },
+        transport::{LocalFileTransport, Transport},
+    };
+    use chrono::NaiveDate;
+    use uuid::Uuid;
+
+    #[test]
+    fn object_kind_from_key() {
+        for (key, kind) in &[
+            (
+                "a/2020/10/31/20/29/b.batch",
+                Some(ObjectKind::IngestionHeader),
+            ),
+            (
+                "a/2020/10/31/20/29/b.batch.avro",
+                Some(ObjectKind::IngestionPackets),
+            ),
+            (
+                "a/2020/10/31/20/29/b.batch.sig",
+                Some(ObjectKind::BatchSignature),
+            ),
+            (
+                "a/2020/10/31/20/29/b.validity_1",
+                Some(ObjectKind::ValidationHeader),
+            ),
+            (
+                "a/2020/10/31/20/29/b.validity_0.avro",
+                Some(ObjectKind::ValidationPackets),
+            ),
+            (
+                "a/2020/10/31/20/29-2020/11/01/20/29.sum_0",
+                Some(ObjectKind::SumPart),
+            ),
+            (
+                "a/2020/10/31/20/29-2020/11/01/20/29.invalid_uuid_0.avro",
+                Some(ObjectKind::InvalidPackets),
+            ),
+            (
+                "a/2020/10/31/20/29-2020/11/01/20/29.invalid_set_1",
+                Some(ObjectKind::InvalidUuidSet),
+            ),
+            (
+                "a/2020/10/31/20/29-2020/11/01/20/29.invalid_set_1.avro",
+                Some(ObjectKind::InvalidPackets),
+            ),
+            (
+                "a/2020/10/31/20/29-2020/11/01/20/29.sum_0.sig",
+                Some(ObjectKind::BatchSignature),
+            ),
+            ("a/b.report.json", None),
+            ("a/b.batch.sig.avro", None),
+        ] {
+            assert_eq!(ObjectKind::from_key(key), *kind, "key {}", key);
+        }
+    }
+
+    #[test]
+    fn dump_ingestion_batch() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut pha_transport = LocalFileTransport::new(tempdir.path().join("pha"));
+        let mut facilitator_transport = LocalFileTransport::new(tempdir.path().join("facilitator"));
+        let batch_uuid = Uuid::new_v4();
+        generate_ingestion_sample(
+            &mut pha_transport,
+            &mut facilitator_transport,
+            &batch_uuid,
+            "fake-aggregation",
+            &NaiveDate::from_ymd(2009, 2, 13).and_hms(23, 31, 0),
+            &PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
+            &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+            &default_ingestor_private_key(),
+            10,
+            3,
+            0.11,
+            100,
+            200,
+            None,
+        )
+        .unwrap();
+        let key = format!("fake-aggregation/2009/02/13/23/31/{}.batch", batch_uuid);
+
+        let mut output = Vec::new();
+        let count = dump(
+            ObjectKind::IngestionHeader,
+            pha_transport.get(&key).unwrap(),
+            &mut output,
+            DumpFormat::JsonLines,
+            None,
+        )
+        .unwrap();
+        assert_eq!(count, 1);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with(&format!("{{\"batch_uuid\":\"{}\",", batch_uuid)));
+        let header: Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(header["bins"], 10);
+        assert_eq!(header["batch_end_time"], 200);
+        assert_eq!(
+            base64::decode(header["packet_file_digest"].as_str().unwrap())
+                .unwrap()
+                .len(),
+            32
+        );
+
+        let mut output = Vec::new();
+        let decryption = PacketDecryption {
+            keys: vec![
+                // A key that does not decrypt the packets is skipped.
+                PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+                PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
+            ],
+            server_index: 0,
+            bins: 10,
+        };
+        let count = dump(
+            ObjectKind::IngestionPackets,
+            pha_transport.get(&format!("{}.avro", key)).unwrap(),
+            &mut output,
+            DumpFormat::Csv,
+            Some(&decryption),
+        )
+        .unwrap();
+        assert_eq!(count, 3);
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0],
+            "uuid,encrypted_payload,encryption_key_id,r_pit,version_configuration,\
+            device_nonce,share"
+        );
+        // The share is a JSON array, quoted since it contains commas.
+        let share = lines[1].rsplit(",\"").next().unwrap().trim_end_matches('"');
+        let share: Vec<u32> = serde_json::from_str(share).unwrap();
+        assert_eq!(share.len(), proof_length(10));
+
+        // The facilitator's share is expanded from its seed, and together with
+        // the PHA's share reconstructs the packet's data.
+        let mut output = Vec::new();
+        let decryption = PacketDecryption {
+            keys: vec![PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap()],
+            server_index: 1,
+            bins: 10,
+        };
+        dump(
+            ObjectKind::IngestionPackets,
+            facilitator_transport.get(&format!("{}.avro", key)).unwrap(),
+            &mut output,
+            DumpFormat::JsonLines,
+            Some(&decryption),
+        )
+        .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let packet: Value = serde_json::from_str(output.lines().next().unwrap()).unwrap();
+        let facilitator_share: Vec<u32> = serde_json::from_value(packet["share"].clone()).unwrap();
+        assert_eq!(facilitator_share.len(), proof_length(10));
+        for (pha, facilitator) in share.iter().zip(&facilitator_share).take(10) {
+            let data = Field::from(*pha) + Field::from(*facilitator);
+            assert!(data == Field::from(0) || data == Field::from(1));
+        }
+
+        // Without the right key, the packets cannot be dumped.
+        let decryption = PacketDecryption {
+            keys: vec![PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap()],
+            server_index: 0,
+            bins: 10,
+        };
+        let err = dump(
+            ObjectKind::IngestionPackets,
+            pha_transport.get(&format!("{}.avro", key)).unwrap(),
+            Vec::new(),
+            DumpFormat::JsonLines,
+            Some(&decryption),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::PacketProcessingError(..))
+        ));
+    }
+
+    #[test]
+    fn csv_quoting() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
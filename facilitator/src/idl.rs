@@ -1,186 +1,199 @@
-use crate::Error;
-use avro_rs::{
-    from_value,
-    types::{Record, Value},
-    Reader, Schema, Writer,
-};
+use crate::{compatibility::check_compatibility, Error};
+use avro_rs::{to_avro_datum, types::Value, Reader, Schema, Writer};
+use once_cell::sync::Lazy;
 use prio::{finite_field::Field, server::VerificationMessage};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashSet,
     convert::TryFrom,
     io::{Read, Write},
     num::TryFromIntError,
 };
 use uuid::Uuid;
 
-const BATCH_SIGNATURE_SCHEMA: &str = include_str!("../../avro-schema/batch-signature.avsc");
-const INGESTION_HEADER_SCHEMA: &str = include_str!("../../avro-schema/ingestion-header.avsc");
-const INGESTION_DATA_SHARE_PACKET_SCHEMA: &str =
-    include_str!("../../avro-schema/ingestion-data-share-packet.avsc");
-const VALIDATION_HEADER_SCHEMA: &str = include_str!("../../avro-schema/validation-header.avsc");
-const VALIDATION_PACKET_SCHEMA: &str = include_str!("../../avro-schema/validation-packet.avsc");
-const SUM_PART_SCHEMA: &str = include_str!("../../avro-schema/sum-part.avsc");
-const INVALID_PACKET_SCHEMA: &str = include_str!("../../avro-schema/invalid-packet.avsc");
+// The record types below, and the constants holding their schemas, are
+// generated by build.rs from the schemas in avro-schema, along with their
+// AvroRecord implementations. Fields with logicalType uuid are represented as
+// Uuid and fields of type bytes as Vec<u8>, which avro_rs's serde support does
+// not handle, so the conversions to and from avro_rs::types::Value are
+// generated rather than derived.
+include!(concat!(env!("OUT_DIR"), "/idl_records.rs"));
+
+/// Conversion between a record type and avro_rs::types::Value. Implemented by
+/// the types generated from the Avro schemas.
+pub trait AvroRecord: Sized {
+    /// Returns the JSON text of the record's schema.
+    fn schema_raw() -> &'static str;
+    /// Returns the record's schema, parsed once. Since this only ever uses a
+    /// schema whose correctness we can guarantee, it panics on failure.
+    fn avro_schema() -> &'static Schema;
+    /// Converts this record into a Value::Record.
+    fn to_avro_value(&self) -> Value;
+    /// Converts a Value::Record into this record, or describes why it could
+    /// not be converted. Nullable fields missing from the Value, as they are in
+    /// records written with older versions of a schema, are None.
+    fn from_avro_value(value: Value) -> Result<Self, String>;
+}
 
-pub trait Header: Sized {
+/// Creates an avro_rs::Reader over an Avro object container that decodes
+/// records with the writer schema embedded in the container, after checking
+/// that they can be resolved to the provided reader schema, so that we can read
+/// objects written with older or newer versions of our schemas. Header::read
+/// and Packet::read then resolve each record: fields the writer added are
+/// ignored and fields it lacks take their default values.
+///
+/// We don't use avro_rs::Reader::with_schema, which resolves records itself,
+/// because it compares the writer and reader schemas by their canonical form,
+/// which avro_rs 0.11 panics computing for schemas with null defaults.
+pub fn resolving_reader<'a, R: Read>(schema: &Schema, reader: R) -> Result<Reader<'a, R>, Error> {
+    let reader = Reader::new(reader)
+        .map_err(|e| Error::AvroError("failed to create Avro reader".to_owned(), e))?;
+    let incompatibilities = check_compatibility(reader.writer_schema(), schema);
+    if !incompatibilities.is_empty() {
+        let schema_name = match schema {
+            Schema::Record { name, .. } => name.name.clone(),
+            _ => "reader".to_owned(),
+        };
+        return Err(Error::IncompatibleSchemaError(
+            schema_name,
+            incompatibilities
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("; "),
+        ));
+    }
+    Ok(reader)
+}
+
+/// Resolves a record read with its writer's schema to T's schema, and converts
+/// it to T.
+fn resolve_record<T: AvroRecord>(value: Value) -> Result<T, String> {
+    let value = value
+        .resolve(T::avro_schema())
+        .map_err(|e| format!("failed to resolve record to reader schema: {}", e))?;
+    T::from_avro_value(value)
+}
+
+/// Reads the single record from the provided avro_rs::Reader, failing if there
+/// is more than one.
+fn read_single_record<T: AvroRecord, R: Read>(mut reader: Reader<R>) -> Result<T, Error> {
+    let value = match reader.next() {
+        Some(Ok(value)) => value,
+        Some(Err(e)) => {
+            return Err(Error::AvroError(
+                "failed to read record from Avro reader".to_owned(),
+                e,
+            ))
+        }
+        None => return Err(Error::EofError),
+    };
+    if reader.next().is_some() {
+        return Err(Error::MalformedHeaderError(
+            "excess value in reader".to_owned(),
+        ));
+    }
+    resolve_record(value).map_err(Error::MalformedHeaderError)
+}
+
+/// Writes the record as the single record in an Avro object container file.
+fn write_single_record<T: AvroRecord, W: Write>(record: &T, writer: &mut W) -> Result<(), Error> {
+    let schema = T::avro_schema();
+    let mut writer = Writer::new(schema, writer);
+    writer
+        .append(record.to_avro_value())
+        .map_err(|e| Error::AvroError("failed to append record to Avro writer".to_owned(), e))?;
+    writer
+        .flush()
+        .map_err(|e| Error::AvroError("failed to flush Avro writer".to_owned(), e))?;
+    Ok(())
+}
+
+pub trait Header: AvroRecord {
     /// Returns the SHA256 digest of the packet file this header describes.
     fn packet_file_digest(&self) -> &Vec<u8>;
+    /// Returns the name of the aggregation this header belongs to.
+    fn name(&self) -> &str;
+    /// Returns the UUID of the batch this header describes, or None if it
+    /// describes several batches.
+    fn batch_uuid(&self) -> Option<&Uuid>;
+    /// Returns the start and end of the time range covered by the batch, in
+    /// milliseconds since the epoch, if the header records one.
+    fn time_range(&self) -> Option<(i64, i64)>;
+
     /// Reads and parses one Header from the provided std::io::Read instance.
-    fn read<R: Read>(reader: R) -> Result<Self, Error>;
+    fn read<R: Read>(reader: R) -> Result<Self, Error> {
+        read_single_record(resolving_reader(Self::avro_schema(), reader)?)
+    }
+
     /// Serializes this message into Avro format and writes it to the provided
     /// std::io::Write instance.
-    fn write<W: Write>(&self, writer: &mut W) -> Result<(), Error>;
+    fn write<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        write_single_record(self, writer)
+    }
 }
 
-pub trait Packet: Sized {
+pub trait Packet: AvroRecord {
     /// Reads and parses a single Packet from the provided avro_rs::Reader. Note
     /// that unlike other structures, this does not take a primitive
     /// std::io::Read, because we do not want to create a new Avro schema and
-    /// reader for each packet. The Reader must have been created with the
-    //// schema returned from Packet::schema.
-    fn read<R: Read>(reader: &mut Reader<R>) -> Result<Self, Error>;
+    /// reader for each packet. The Reader should have been created with
+    /// idl::resolving_reader and the schema returned from Packet::schema.
+    fn read<R: Read>(reader: &mut Reader<R>) -> Result<Self, Error> {
+        match reader.next() {
+            Some(Ok(value)) => resolve_record(value).map_err(Error::MalformedDataPacketError),
+            Some(Err(e)) => Err(Error::AvroError(
+                "failed to read record from Avro reader".to_owned(),
+                e,
+            )),
+            None => Err(Error::EofError),
+        }
+    }
 
     /// Serializes and writes a single Packet to the provided avro_rs::Writer.
     /// Note that unlike other structures, this does not take a primitive
     /// std::io::Write, because we do not want to create a new Avro schema and
     /// reader for each packet. The Reader must have been created with the
     /// schema returned from Packet::schema.
-    fn write<W: Write>(&self, writer: &mut Writer<W>) -> Result<(), Error>;
-
-    fn schema_raw() -> &'static str;
+    fn write<W: Write>(&self, writer: &mut Writer<W>) -> Result<(), Error> {
+        writer.append(self.to_avro_value()).map_err(|e| {
+            Error::AvroError("failed to append record to Avro writer".to_owned(), e)
+        })?;
+        Ok(())
+    }
 
     /// Creates an avro_rs::Schema from the packet schema. For constructing the
     /// avro_rs::{Reader, Writer} to use in Packet::{read, write}. Since this
     /// only ever uses a schema whose correctness we can guarantee, it panics on
     /// failure.
     fn schema() -> Schema {
-        Schema::parse_str(Self::schema_raw()).unwrap()
+        Self::avro_schema().clone()
     }
 }
 
-/// The file containing signatures over the ingestion batch header and packet
-/// file.
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
-pub struct BatchSignature {
-    pub batch_header_signature: Vec<u8>,
-    pub key_identifier: String,
-}
-
 impl BatchSignature {
     /// Reads and parses one BatchSignature from the provided std::io::Read
     /// instance.
     pub fn read<R: Read>(reader: R) -> Result<BatchSignature, Error> {
-        let schema = Schema::parse_str(BATCH_SIGNATURE_SCHEMA).map_err(|e| {
-            Error::AvroError("failed to parse ingestion signature schema".to_owned(), e)
-        })?;
-        let mut reader = Reader::with_schema(&schema, reader)
-            .map_err(|e| Error::AvroError("failed to create Avro reader".to_owned(), e))?;
-
-        // We expect exactly one record and for it to be an ingestion signature
-        let record = match reader.next() {
-            Some(Ok(Value::Record(r))) => r,
-            Some(Ok(_)) => {
-                return Err(Error::MalformedHeaderError(
-                    "value is not a record".to_owned(),
-                ))
-            }
-            Some(Err(e)) => {
-                return Err(Error::AvroError(
-                    "failed to read record from Avro reader".to_owned(),
-                    e,
-                ));
-            }
-            None => return Err(Error::EofError),
-        };
-        if reader.next().is_some() {
-            return Err(Error::MalformedHeaderError(
-                "excess value in reader".to_owned(),
-            ));
-        }
-
-        // Here we might wish to use from_value::<BatchSignature>(record) but
-        // avro_rs does not seem to recognize it as a Bytes and fails to
-        // deserialize it. The value we unwrapped from reader.next above is a
-        // vector of (String, avro_rs::Value) tuples, which we now iterate to
-        // find the struct members.
-        let mut batch_header_signature = None;
-        let mut key_identifier = None;
-
-        for tuple in record {
-            match (tuple.0.as_str(), tuple.1) {
-                ("batch_header_signature", Value::Bytes(v)) => batch_header_signature = Some(v),
-                ("key_identifier", Value::String(v)) => key_identifier = Some(v),
-                (f, _) => {
-                    return Err(Error::MalformedHeaderError(format!(
-                        "unexpected field {} in record",
-                        f
-                    )))
-                }
-            }
-        }
-
-        if batch_header_signature.is_none() || key_identifier.is_none() {
-            return Err(Error::MalformedHeaderError(
-                "missing fields in record".to_owned(),
-            ));
-        }
-
-        Ok(BatchSignature {
-            batch_header_signature: batch_header_signature.unwrap(),
-            key_identifier: key_identifier.unwrap(),
-        })
+        // Version 1 signatures lack the batch_manifest_signature field, which
+        // resolves to its default of null.
+        read_single_record(resolving_reader(Self::avro_schema(), reader)?)
     }
 
     /// Serializes this signature into Avro format and writes it to the provided
     /// std::io::Write instance.
     pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
-        let schema = Schema::parse_str(BATCH_SIGNATURE_SCHEMA).map_err(|e| {
-            Error::AvroError("failed to parse ingestion signature schema".to_owned(), e)
-        })?;
-        let mut writer = Writer::new(&schema, writer);
-
-        let mut record = match Record::new(writer.schema()) {
-            Some(r) => r,
-            None => {
-                // avro_rs docs say this can only happen "if the `Schema is not
-                // a `Schema::Record` variant", which shouldn't ever happen, so
-                // panic for debugging
-                // https://docs.rs/avro-rs/0.11.0/avro_rs/types/struct.Record.html#method.new
-                panic!("Unable to create Record from ingestion signature schema");
-            }
-        };
-
-        record.put(
-            "batch_header_signature",
-            Value::Bytes(self.batch_header_signature.clone()),
-        );
-        record.put("key_identifier", Value::String(self.key_identifier.clone()));
-
-        writer.append(record).map_err(|e| {
-            Error::AvroError("failed to append record to Avro writer".to_owned(), e)
-        })?;
-
-        writer
-            .flush()
-            .map_err(|e| Error::AvroError("failed to flush Avro writer".to_owned(), e))?;
-
-        Ok(())
+        write_single_record(self, writer)
     }
 }
 
-/// The header on a Prio ingestion batch.
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
-pub struct IngestionHeader {
-    pub batch_uuid: Uuid,
-    pub name: String,
-    pub bins: i32,
-    pub epsilon: f64,
-    pub prime: i64,
-    pub number_of_servers: i32,
-    pub hamming_weight: Option<i32>,
-    pub batch_start_time: i64,
-    pub batch_end_time: i64,
-    pub packet_file_digest: Vec<u8>,
+impl BatchManifest {
+    /// Returns the Avro binary encoding of this manifest, without any object
+    /// container framing, which is the message that is signed.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        to_avro_datum(BatchManifest::avro_schema(), self.to_avro_value())
+            .map_err(|e| Error::AvroError("failed to encode batch manifest".to_owned(), e))
+    }
 }
 
 impl IngestionHeader {
@@ -194,337 +207,47 @@ impl IngestionHeader {
             && self.number_of_servers == validation_header.number_of_servers
             && self.hamming_weight == validation_header.hamming_weight
     }
-}
-
-impl Header for IngestionHeader {
-    fn packet_file_digest(&self) -> &Vec<u8> {
-        &self.packet_file_digest
-    }
-
-    fn read<R: Read>(reader: R) -> Result<IngestionHeader, Error> {
-        let schema = Schema::parse_str(INGESTION_HEADER_SCHEMA).map_err(|e| {
-            Error::AvroError("failed to parse ingestion header schema".to_owned(), e)
-        })?;
-        let mut reader = Reader::with_schema(&schema, reader).map_err(|e| {
-            Error::AvroError("failed to create reader for ingestion header".to_owned(), e)
-        })?;
-
-        // We expect exactly one record in the reader and for it to be an ingestion header
-        let record = match reader.next() {
-            Some(Ok(Value::Record(r))) => r,
-            Some(Ok(_)) => {
-                return Err(Error::MalformedHeaderError(
-                    "value is not a record".to_owned(),
-                ))
-            }
-            Some(Err(e)) => {
-                return Err(Error::AvroError(
-                    "failed to read header from Avro reader".to_owned(),
-                    e,
-                ))
-            }
-            None => return Err(Error::EofError),
-        };
-        if reader.next().is_some() {
-            return Err(Error::MalformedHeaderError(
-                "excess header in reader".to_owned(),
-            ));
-        }
 
-        // Here we might wish to use from_value::<IngestionSignature>(record) but avro_rs does not
-        // seem to recognize it as a Bytes and fails to deserialize it. The value we unwrapped from
-        // reader.next above is a vector of (String, avro_rs::Value) tuples, which we now iterate to
-        // find the struct members.
-        let mut batch_uuid = None;
-        let mut name = None;
-        let mut bins = None;
-        let mut epsilon = None;
-        let mut prime = None;
-        let mut number_of_servers = None;
-        let mut hamming_weight = None;
-        let mut batch_start_time = None;
-        let mut batch_end_time = None;
-        let mut packet_file_digest = None;
-
-        for tuple in record {
-            match (tuple.0.as_str(), tuple.1) {
-                ("batch_uuid", Value::Uuid(v)) => batch_uuid = Some(v),
-                ("name", Value::String(v)) => name = Some(v),
-                ("bins", Value::Int(v)) => bins = Some(v),
-                ("epsilon", Value::Double(v)) => epsilon = Some(v),
-                ("prime", Value::Long(v)) => prime = Some(v),
-                ("number_of_servers", Value::Int(v)) => number_of_servers = Some(v),
-                ("hamming_weight", Value::Union(boxed)) => {
-                    hamming_weight = match *boxed {
-                        Value::Int(v) => Some(v),
-                        Value::Null => None,
-                        v => {
-                            return Err(Error::MalformedHeaderError(format!(
-                                "unexpected value {:?} for hamming weight",
-                                v
-                            )));
-                        }
-                    }
-                }
-                ("batch_start_time", Value::TimestampMillis(v)) => batch_start_time = Some(v),
-                ("batch_end_time", Value::TimestampMillis(v)) => batch_end_time = Some(v),
-                ("packet_file_digest", Value::Bytes(v)) => packet_file_digest = Some(v),
-                (f, v) => {
-                    return Err(Error::MalformedHeaderError(format!(
-                        "unexpected field {} -> {:?} in record",
-                        f, v
-                    )))
-                }
-            }
-        }
-
-        if batch_uuid.is_none()
-            || name.is_none()
-            || bins.is_none()
-            || epsilon.is_none()
-            || prime.is_none()
-            || number_of_servers.is_none()
-            || batch_start_time.is_none()
-            || batch_end_time.is_none()
-            || packet_file_digest.is_none()
-        {
-            return Err(Error::MalformedHeaderError(
-                "missing field(s) in record".to_owned(),
-            ));
-        }
-
-        Ok(IngestionHeader {
-            batch_uuid: batch_uuid.unwrap(),
-            name: name.unwrap(),
-            bins: bins.unwrap(),
-            epsilon: epsilon.unwrap(),
-            prime: prime.unwrap(),
-            number_of_servers: number_of_servers.unwrap(),
-            hamming_weight,
-            batch_start_time: batch_start_time.unwrap(),
-            batch_end_time: batch_end_time.unwrap(),
-            packet_file_digest: packet_file_digest.unwrap(),
-        })
+    /// Returns true if the batch described by other may be aggregated together
+    /// with the one described by this header, i.e. if they have the same
+    /// parameters.
+    #[allow(clippy::float_cmp)]
+    pub fn check_aggregation_parameters(&self, other: &IngestionHeader) -> bool {
+        self.name == other.name
+            && self.bins == other.bins
+            && self.epsilon == other.epsilon
+            && self.prime == other.prime
+            && self.number_of_servers == other.number_of_servers
+            && self.hamming_weight == other.hamming_weight
     }
 
-    fn write<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
-        let schema = Schema::parse_str(INGESTION_HEADER_SCHEMA).map_err(|e| {
-            Error::AvroError("failed to parse ingestion header schema".to_owned(), e)
-        })?;
-        let mut writer = Writer::new(&schema, writer);
-
-        // Ideally we would just do `writer.append_ser(self)` to use Serde serialization to write
-        // the record but there seems to be some problem with serializing UUIDs, so we have to
-        // construct the record.
-        let mut record = match Record::new(writer.schema()) {
-            Some(r) => r,
-            None => {
-                // avro_rs docs say this can only happen "if the `Schema is not a `Schema::Record`
-                // variant", which shouldn't ever happen, so panic for debugging
-                // https://docs.rs/avro-rs/0.11.0/avro_rs/types/struct.Record.html#method.new
-                panic!("Unable to create Record from ingestion header schema");
-            }
-        };
-
-        record.put("batch_uuid", Value::Uuid(self.batch_uuid));
-        record.put("name", Value::String(self.name.clone()));
-        record.put("bins", Value::Int(self.bins));
-        record.put("epsilon", Value::Double(self.epsilon));
-        record.put("prime", Value::Long(self.prime));
-        record.put("number_of_servers", Value::Int(self.number_of_servers));
-        match self.hamming_weight {
-            Some(v) => record.put("hamming_weight", Value::Union(Box::new(Value::Int(v)))),
-            None => record.put("hamming_weight", Value::Union(Box::new(Value::Null))),
-        }
-        record.put(
-            "batch_start_time",
-            Value::TimestampMillis(self.batch_start_time),
-        );
-        record.put(
-            "batch_end_time",
-            Value::TimestampMillis(self.batch_end_time),
-        );
-        record.put(
-            "packet_file_digest",
-            Value::Bytes(self.packet_file_digest.clone()),
-        );
-
-        writer.append(record).map_err(|e| {
-            Error::AvroError("failed to append record to Avro writer".to_owned(), e)
-        })?;
-
-        writer
-            .flush()
-            .map_err(|e| Error::AvroError("failed to flush Avro writer".to_owned(), e))?;
-
-        Ok(())
+    /// Returns true if the batch was split into shares for the provided number
+    /// of servers, i.e. the PHA and the facilitators this deployment is
+    /// configured with.
+    pub fn check_number_of_servers(&self, number_of_servers: usize) -> bool {
+        self.number_of_servers >= 0 && self.number_of_servers as usize == number_of_servers
     }
 }
 
-/// A single packet from an ingestion batch file. Note that unlike the header
-/// and signature, which are files containing a single record, the data share
-/// file will contain many IngestionDataSharePacket records.
-#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
-pub struct IngestionDataSharePacket {
-    pub uuid: Uuid,
-    pub encrypted_payload: Vec<u8>,
-    pub encryption_key_id: Option<String>,
-    pub r_pit: i64,
-    pub version_configuration: Option<String>,
-    pub device_nonce: Option<Vec<u8>>,
-}
-
-impl Packet for IngestionDataSharePacket {
-    fn schema_raw() -> &'static str {
-        INGESTION_DATA_SHARE_PACKET_SCHEMA
+impl Header for IngestionHeader {
+    fn packet_file_digest(&self) -> &Vec<u8> {
+        &self.packet_file_digest
     }
 
-    fn read<R: Read>(reader: &mut Reader<R>) -> Result<IngestionDataSharePacket, Error> {
-        let record = match reader.next() {
-            Some(Ok(Value::Record(r))) => r,
-            Some(Ok(_)) => {
-                return Err(Error::MalformedDataPacketError(
-                    "value is not a record".to_owned(),
-                ))
-            }
-            Some(Err(e)) => {
-                return Err(Error::AvroError(
-                    "failed to read record from Avro reader".to_owned(),
-                    e,
-                ));
-            }
-            None => return Err(Error::EofError),
-        };
-
-        // As in IngestionSignature::read_signature, , we can't just deserialize into a struct and
-        // must instead walk the vector of record fields.
-        let mut uuid = None;
-        let mut encrypted_payload = None;
-        let mut encryption_key_id = None;
-        let mut r_pit = None;
-        let mut version_configuration = None;
-        let mut device_nonce = None;
-
-        for tuple in record {
-            match (tuple.0.as_str(), tuple.1) {
-                ("uuid", Value::Uuid(v)) => uuid = Some(v),
-                ("encrypted_payload", Value::Bytes(v)) => encrypted_payload = Some(v),
-                ("encryption_key_id", Value::Union(boxed)) => match *boxed {
-                    Value::String(v) => encryption_key_id = Some(v),
-                    Value::Null => encryption_key_id = None,
-                    v => {
-                        return Err(Error::MalformedDataPacketError(format!(
-                            "unexpected boxed value {:?} in encryption_key_id",
-                            v
-                        )))
-                    }
-                },
-                ("r_pit", Value::Long(v)) => r_pit = Some(v),
-                ("version_configuration", Value::Union(boxed)) => match *boxed {
-                    Value::String(v) => version_configuration = Some(v),
-                    Value::Null => version_configuration = None,
-                    v => {
-                        return Err(Error::MalformedDataPacketError(format!(
-                            "unexpected boxed value {:?} in version_configuration",
-                            v
-                        )))
-                    }
-                },
-                ("device_nonce", Value::Union(boxed)) => match *boxed {
-                    Value::Bytes(v) => device_nonce = Some(v),
-                    Value::Null => device_nonce = None,
-                    v => {
-                        return Err(Error::MalformedDataPacketError(format!(
-                            "unexpected boxed value {:?} in device_nonce",
-                            v
-                        )))
-                    }
-                },
-                (f, _) => {
-                    return Err(Error::MalformedDataPacketError(format!(
-                        "unexpected field {} in record",
-                        f
-                    )))
-                }
-            }
-        }
-
-        if uuid.is_none() || encrypted_payload.is_none() || r_pit.is_none() {
-            return Err(Error::MalformedDataPacketError(
-                "missing fields in record".to_owned(),
-            ));
-        }
-
-        Ok(IngestionDataSharePacket {
-            uuid: uuid.unwrap(),
-            encrypted_payload: encrypted_payload.unwrap(),
-            encryption_key_id,
-            r_pit: r_pit.unwrap(),
-            version_configuration,
-            device_nonce,
-        })
+    fn name(&self) -> &str {
+        &self.name
     }
 
-    fn write<W: Write>(&self, writer: &mut Writer<W>) -> Result<(), Error> {
-        // Ideally we would just do `writer.append_ser(self)` to use Serde
-        // serialization to write the record but there seems to be some problem
-        // with serializing UUIDs, so we have to construct the record.
-        // avro_rs docs say this can only fail "if the `Schema is not a
-        // `Schema::Record` variant", which shouldn't ever happen, so panic for
-        // debugging
-        // https://docs.rs/avro-rs/0.11.0/avro_rs/types/struct.Record.html#method.new
-        let mut record = Record::new(writer.schema())
-            .expect("Unable to create Record from ingestion data share packet schema");
-
-        record.put("uuid", Value::Uuid(self.uuid));
-        record.put(
-            "encrypted_payload",
-            Value::Bytes(self.encrypted_payload.clone()),
-        );
-        match &self.encryption_key_id {
-            Some(v) => record.put(
-                "encryption_key_id",
-                Value::Union(Box::new(Value::String(v.to_owned()))),
-            ),
-            None => record.put("encryption_key_id", Value::Union(Box::new(Value::Null))),
-        }
-        record.put("r_pit", Value::Long(self.r_pit));
-        match &self.version_configuration {
-            Some(v) => record.put(
-                "version_configuration",
-                Value::Union(Box::new(Value::String(v.to_owned()))),
-            ),
-            None => record.put("version_configuration", Value::Union(Box::new(Value::Null))),
-        }
-        match &self.device_nonce {
-            Some(v) => record.put(
-                "device_nonce",
-                Value::Union(Box::new(Value::Bytes(v.clone()))),
-            ),
-            None => record.put("device_nonce", Value::Union(Box::new(Value::Null))),
-        }
-
-        writer.append(record).map_err(|e| {
-            Error::AvroError("failed to append record to Avro writer".to_owned(), e)
-        })?;
+    fn batch_uuid(&self) -> Option<&Uuid> {
+        Some(&self.batch_uuid)
+    }
 
-        Ok(())
+    fn time_range(&self) -> Option<(i64, i64)> {
+        Some((self.batch_start_time, self.batch_end_time))
     }
 }
 
-/// The header on a Prio validation (sometimes referred to as verification)
-/// batch.
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
-pub struct ValidationHeader {
-    pub batch_uuid: Uuid,
-    pub name: String,
-    pub bins: i32,
-    pub epsilon: f64,
-    pub prime: i64,
-    pub number_of_servers: i32,
-    pub hamming_weight: Option<i32>,
-    pub packet_file_digest: Vec<u8>,
-}
+impl Packet for IngestionDataSharePacket {}
 
 impl ValidationHeader {
     #[allow(clippy::float_cmp)]
@@ -544,205 +267,21 @@ impl Header for ValidationHeader {
         &self.packet_file_digest
     }
 
-    fn read<R: Read>(reader: R) -> Result<ValidationHeader, Error> {
-        let schema = Schema::parse_str(VALIDATION_HEADER_SCHEMA).map_err(|e| {
-            Error::AvroError("failed to parse validation header schema".to_owned(), e)
-        })?;
-        let mut reader = Reader::with_schema(&schema, reader).map_err(|e| {
-            Error::AvroError(
-                "failed to create reader for validation header".to_owned(),
-                e,
-            )
-        })?;
-
-        // We expect exactly one record in the reader and for it to be an ingestion header
-        let record = match reader.next() {
-            Some(Ok(Value::Record(r))) => r,
-            Some(Ok(_)) => {
-                return Err(Error::MalformedHeaderError(
-                    "value is not a record".to_owned(),
-                ))
-            }
-            Some(Err(e)) => {
-                return Err(Error::AvroError(
-                    "failed to read header from Avro reader".to_owned(),
-                    e,
-                ))
-            }
-            None => return Err(Error::EofError),
-        };
-        if reader.next().is_some() {
-            return Err(Error::MalformedHeaderError(
-                "excess header in reader".to_owned(),
-            ));
-        }
-
-        // Here we might wish to use from_value::<IngestionSignature>(record) but avro_rs does not
-        // seem to recognize it as a Bytes and fails to deserialize it. The value we unwrapped from
-        // reader.next above is a vector of (String, avro_rs::Value) tuples, which we now iterate to
-        // find the struct members.
-        let mut batch_uuid = None;
-        let mut name = None;
-        let mut bins = None;
-        let mut epsilon = None;
-        let mut prime = None;
-        let mut number_of_servers = None;
-        let mut hamming_weight = None;
-        let mut packet_file_digest = None;
-
-        for tuple in record {
-            match (tuple.0.as_str(), tuple.1) {
-                ("batch_uuid", Value::Uuid(v)) => batch_uuid = Some(v),
-                ("name", Value::String(v)) => name = Some(v),
-                ("bins", Value::Int(v)) => bins = Some(v),
-                ("epsilon", Value::Double(v)) => epsilon = Some(v),
-                ("prime", Value::Long(v)) => prime = Some(v),
-                ("number_of_servers", Value::Int(v)) => number_of_servers = Some(v),
-                ("hamming_weight", Value::Union(boxed)) => {
-                    hamming_weight = match *boxed {
-                        Value::Int(v) => Some(v),
-                        Value::Null => None,
-                        v => {
-                            return Err(Error::MalformedHeaderError(format!(
-                                "unexpected value {:?} for hamming weight",
-                                v
-                            )));
-                        }
-                    }
-                }
-                ("packet_file_digest", Value::Bytes(v)) => packet_file_digest = Some(v),
-                (f, v) => {
-                    return Err(Error::MalformedHeaderError(format!(
-                        "unexpected field {} -> {:?} in record",
-                        f, v
-                    )))
-                }
-            }
-        }
-
-        if batch_uuid.is_none()
-            || name.is_none()
-            || bins.is_none()
-            || epsilon.is_none()
-            || prime.is_none()
-            || number_of_servers.is_none()
-            || packet_file_digest.is_none()
-        {
-            return Err(Error::MalformedHeaderError(
-                "missing field(s) in record".to_owned(),
-            ));
-        }
-
-        Ok(ValidationHeader {
-            batch_uuid: batch_uuid.unwrap(),
-            name: name.unwrap(),
-            bins: bins.unwrap(),
-            epsilon: epsilon.unwrap(),
-            prime: prime.unwrap(),
-            number_of_servers: number_of_servers.unwrap(),
-            hamming_weight,
-            packet_file_digest: packet_file_digest.unwrap(),
-        })
+    fn name(&self) -> &str {
+        &self.name
     }
 
-    fn write<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
-        let schema = Schema::parse_str(VALIDATION_HEADER_SCHEMA).map_err(|e| {
-            Error::AvroError("failed to parse validation header schema".to_owned(), e)
-        })?;
-        let mut writer = Writer::new(&schema, writer);
-
-        let mut record = match Record::new(writer.schema()) {
-            Some(r) => r,
-            None => {
-                panic!("Unable to create Record from ingestion header schema");
-            }
-        };
-
-        record.put("batch_uuid", Value::Uuid(self.batch_uuid));
-        record.put("name", Value::String(self.name.clone()));
-        record.put("bins", Value::Int(self.bins));
-        record.put("epsilon", Value::Double(self.epsilon));
-        record.put("prime", Value::Long(self.prime));
-        record.put("number_of_servers", Value::Int(self.number_of_servers));
-        record.put(
-            "hamming_weight",
-            Value::Union(Box::new(
-                self.hamming_weight.map_or(Value::Null, Value::Int),
-            )),
-        );
-        record.put(
-            "packet_file_digest",
-            Value::Bytes(self.packet_file_digest.clone()),
-        );
-
-        writer.append(record).map_err(|e| {
-            Error::AvroError("failed to append record to Avro writer".to_owned(), e)
-        })?;
-
-        writer
-            .flush()
-            .map_err(|e| Error::AvroError("failed to flush Avro writer".to_owned(), e))?;
-
-        Ok(())
-    }
-}
-
-#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
-pub struct ValidationPacket {
-    pub uuid: Uuid,
-    pub f_r: i64,
-    pub g_r: i64,
-    pub h_r: i64,
-}
-
-impl Packet for ValidationPacket {
-    fn schema_raw() -> &'static str {
-        VALIDATION_PACKET_SCHEMA
+    fn batch_uuid(&self) -> Option<&Uuid> {
+        Some(&self.batch_uuid)
     }
 
-    fn read<R: Read>(reader: &mut Reader<R>) -> Result<ValidationPacket, Error> {
-        let header = match reader.next() {
-            Some(Ok(h)) => h,
-            Some(Err(e)) => {
-                return Err(Error::AvroError(
-                    "failed to read header from Avro reader".to_owned(),
-                    e,
-                ))
-            }
-            None => return Err(Error::EofError),
-        };
-
-        from_value::<ValidationPacket>(&header)
-            .map_err(|e| Error::AvroError("failed to parse validation header".to_owned(), e))
-    }
-
-    fn write<W: Write>(&self, writer: &mut Writer<W>) -> Result<(), Error> {
-        // Ideally we would just do `writer.append_ser(self)` to use Serde serialization to write
-        // the record but there seems to be some problem with serializing UUIDs, so we have to
-        // construct the record.
-        let mut record = match Record::new(writer.schema()) {
-            Some(r) => r,
-            None => {
-                // avro_rs docs say this can only happen "if the `Schema is not a `Schema::Record`
-                // variant", which shouldn't ever happen, so panic for debugging
-                // https://docs.rs/avro-rs/0.11.0/avro_rs/types/struct.Record.html#method.new
-                panic!("Unable to create Record from ingestion data share packet schema");
-            }
-        };
-
-        record.put("uuid", Value::Uuid(self.uuid));
-        record.put("f_r", Value::Long(self.f_r));
-        record.put("g_r", Value::Long(self.g_r));
-        record.put("h_r", Value::Long(self.h_r));
-
-        writer.append(record).map_err(|e| {
-            Error::AvroError("failed to append record to Avro writer".to_owned(), e)
-        })?;
-
-        Ok(())
+    fn time_range(&self) -> Option<(i64, i64)> {
+        None
     }
 }
 
+impl Packet for ValidationPacket {}
+
 impl TryFrom<&ValidationPacket> for VerificationMessage {
     type Error = TryFromIntError;
 
@@ -755,23 +294,20 @@ impl TryFrom<&ValidationPacket> for VerificationMessage {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
-pub struct SumPart {
-    pub batch_uuids: Vec<Uuid>,
-    pub name: String,
-    pub bins: i32,
-    pub epsilon: f64,
-    pub prime: i64,
-    pub number_of_servers: i32,
-    pub hamming_weight: Option<i32>,
-    pub sum: Vec<i64>,
-    pub aggregation_start_time: i64,
-    pub aggregation_end_time: i64,
-    pub packet_file_digest: Vec<u8>,
-    pub total_individual_clients: i64,
-}
-
 impl SumPart {
+    /// Returns true if the sum described by other may be added to the one
+    /// described by this header, i.e. if they have the same parameters.
+    #[allow(clippy::float_cmp)]
+    pub fn check_reduction_parameters(&self, other: &SumPart) -> bool {
+        self.name == other.name
+            && self.bins == other.bins
+            && self.epsilon == other.epsilon
+            && self.prime == other.prime
+            && self.number_of_servers == other.number_of_servers
+            && self.hamming_weight == other.hamming_weight
+            && self.noise_epsilon == other.noise_epsilon
+    }
+
     pub fn sum(&self) -> Result<Vec<Field>, TryFromIntError> {
         self.sum
             .iter()
@@ -785,277 +321,73 @@ impl Header for SumPart {
         &self.packet_file_digest
     }
 
-    fn read<R: Read>(reader: R) -> Result<SumPart, Error> {
-        let schema = Schema::parse_str(SUM_PART_SCHEMA)
-            .map_err(|e| Error::AvroError("failed to parse sum part schema".to_owned(), e))?;
-        let mut reader = Reader::with_schema(&schema, reader)
-            .map_err(|e| Error::AvroError("failed to create reader for sum part".to_owned(), e))?;
-
-        // We expect exactly one record in the reader and for it to be a sum
-        // part.
-        let record = match reader.next() {
-            Some(Ok(Value::Record(r))) => r,
-            Some(Ok(_)) => {
-                return Err(Error::MalformedHeaderError(
-                    "value is not a record".to_owned(),
-                ))
-            }
-            Some(Err(e)) => {
-                return Err(Error::AvroError(
-                    "failed to read header from Avro reader".to_owned(),
-                    e,
-                ))
-            }
-            None => return Err(Error::EofError),
-        };
-        if reader.next().is_some() {
-            return Err(Error::MalformedHeaderError(
-                "excess header in reader".to_owned(),
-            ));
-        }
-
-        let mut batch_uuids = None;
-        let mut name = None;
-        let mut bins = None;
-        let mut epsilon = None;
-        let mut prime = None;
-        let mut number_of_servers = None;
-        let mut hamming_weight = None;
-        let mut sum = None;
-        let mut aggregation_start_time = None;
-        let mut aggregation_end_time = None;
-        let mut packet_file_digest = None;
-        let mut total_individual_clients = None;
-
-        for tuple in record {
-            match (tuple.0.as_str(), tuple.1) {
-                ("batch_uuids", Value::Array(vector)) => {
-                    batch_uuids = Some(
-                        vector
-                            .into_iter()
-                            .map(|value| {
-                                if let Value::Uuid(u) = value {
-                                    Ok(u)
-                                } else {
-                                    Err(Error::MalformedHeaderError(format!(
-                                        "unexpected value in batch_uuids array {:?}",
-                                        value
-                                    )))
-                                }
-                            })
-                            .collect::<Result<Vec<_>, _>>()?,
-                    );
-                }
-                ("name", Value::String(v)) => name = Some(v),
-                ("bins", Value::Int(v)) => bins = Some(v),
-                ("epsilon", Value::Double(v)) => epsilon = Some(v),
-                ("prime", Value::Long(v)) => prime = Some(v),
-                ("number_of_servers", Value::Int(v)) => number_of_servers = Some(v),
-                ("hamming_weight", Value::Union(boxed)) => {
-                    hamming_weight = match *boxed {
-                        Value::Int(v) => Some(v),
-                        Value::Null => None,
-                        v => {
-                            return Err(Error::MalformedHeaderError(format!(
-                                "unexpected value {:?} for hamming weight",
-                                v
-                            )));
-                        }
-                    }
-                }
-                ("sum", Value::Array(vector)) => {
-                    sum = Some(
-                        vector
-                            .into_iter()
-                            .map(|value| {
-                                if let Value::Long(l) = value {
-                                    Ok(l)
-                                } else {
-                                    Err(Error::MalformedHeaderError(format!(
-                                        "unexpected value in sum array {:?}",
-                                        value
-                                    )))
-                                }
-                            })
-                            .collect::<Result<Vec<_>, _>>()?,
-                    );
-                }
-                ("aggregation_start_time", Value::TimestampMillis(v)) => {
-                    aggregation_start_time = Some(v)
-                }
-                ("aggregation_end_time", Value::TimestampMillis(v)) => {
-                    aggregation_end_time = Some(v)
-                }
-                ("packet_file_digest", Value::Bytes(v)) => packet_file_digest = Some(v),
-                ("total_individual_clients", Value::Long(v)) => total_individual_clients = Some(v),
-                (f, v) => {
-                    return Err(Error::MalformedHeaderError(format!(
-                        "unexpected field {} -> {:?} in record",
-                        f, v
-                    )))
-                }
-            }
-        }
-
-        if batch_uuids.is_none()
-            || name.is_none()
-            || bins.is_none()
-            || epsilon.is_none()
-            || prime.is_none()
-            || number_of_servers.is_none()
-            || sum.is_none()
-            || aggregation_start_time.is_none()
-            || aggregation_end_time.is_none()
-            || packet_file_digest.is_none()
-        {
-            return Err(Error::MalformedHeaderError(
-                "missing field(s) in record".to_owned(),
-            ));
-        }
-
-        Ok(SumPart {
-            batch_uuids: batch_uuids.unwrap(),
-            name: name.unwrap(),
-            bins: bins.unwrap(),
-            epsilon: epsilon.unwrap(),
-            prime: prime.unwrap(),
-            number_of_servers: number_of_servers.unwrap(),
-            hamming_weight,
-            sum: sum.unwrap(),
-            aggregation_start_time: aggregation_start_time.unwrap(),
-            aggregation_end_time: aggregation_end_time.unwrap(),
-            packet_file_digest: packet_file_digest.unwrap(),
-            total_individual_clients: total_individual_clients.unwrap(),
-        })
+    fn name(&self) -> &str {
+        &self.name
     }
 
-    fn write<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
-        let schema = Schema::parse_str(SUM_PART_SCHEMA)
-            .map_err(|e| Error::AvroError("failed to parse sum part schema".to_owned(), e))?;
-        let mut writer = Writer::new(&schema, writer);
-
-        // Ideally we would just do `writer.append_ser(self)` to use Serde serialization to write
-        // the record but there seems to be some problem with serializing UUIDs, so we have to
-        // construct the record.
-        let mut record = match Record::new(writer.schema()) {
-            Some(r) => r,
-            None => {
-                // avro_rs docs say this can only happen "if the `Schema is not a `Schema::Record`
-                // variant", which shouldn't ever happen, so panic for debugging
-                // https://docs.rs/avro-rs/0.11.0/avro_rs/types/struct.Record.html#method.new
-                panic!("Unable to create Record from sum part schema");
-            }
-        };
-
-        record.put(
-            "batch_uuids",
-            Value::Array(self.batch_uuids.iter().map(|u| Value::Uuid(*u)).collect()),
-        );
-        record.put("name", Value::String(self.name.clone()));
-        record.put("bins", Value::Int(self.bins));
-        record.put("epsilon", Value::Double(self.epsilon));
-        record.put("prime", Value::Long(self.prime));
-        record.put("number_of_servers", Value::Int(self.number_of_servers));
-        match self.hamming_weight {
-            Some(v) => record.put("hamming_weight", Value::Union(Box::new(Value::Int(v)))),
-            None => record.put("hamming_weight", Value::Union(Box::new(Value::Null))),
-        }
-        record.put(
-            "sum",
-            Value::Array(self.sum.iter().map(|l| Value::Long(*l)).collect()),
-        );
-        record.put(
-            "aggregation_start_time",
-            Value::TimestampMillis(self.aggregation_start_time),
-        );
-        record.put(
-            "aggregation_end_time",
-            Value::TimestampMillis(self.aggregation_end_time),
-        );
-        record.put(
-            "packet_file_digest",
-            Value::Bytes(self.packet_file_digest.clone()),
-        );
-        record.put(
-            "total_individual_clients",
-            Value::Long(self.total_individual_clients),
-        );
-
-        writer.append(record).map_err(|e| {
-            Error::AvroError("failed to append record to Avro writer".to_owned(), e)
-        })?;
-
-        writer
-            .flush()
-            .map_err(|e| Error::AvroError("failed to flush Avro writer".to_owned(), e))?;
+    fn batch_uuid(&self) -> Option<&Uuid> {
+        None
+    }
 
-        Ok(())
+    fn time_range(&self) -> Option<(i64, i64)> {
+        Some((self.aggregation_start_time, self.aggregation_end_time))
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
-pub struct InvalidPacket {
-    pub uuid: Uuid,
-}
+impl Packet for InvalidPacket {}
 
-impl Packet for InvalidPacket {
-    fn schema_raw() -> &'static str {
-        INVALID_PACKET_SCHEMA
+impl InvalidUuidSet {
+    /// Returns true if the set described by other was found over the same
+    /// batches and aggregation window as the one described by this header.
+    pub fn check_parameters(&self, other: &InvalidUuidSet) -> bool {
+        self.name == other.name
+            && self.aggregation_start_time == other.aggregation_start_time
+            && self.aggregation_end_time == other.aggregation_end_time
+            && self.batch_uuids.iter().collect::<HashSet<_>>()
+                == other.batch_uuids.iter().collect::<HashSet<_>>()
     }
+}
 
-    fn read<R: Read>(reader: &mut Reader<R>) -> Result<InvalidPacket, Error> {
-        let header = match reader.next() {
-            Some(Ok(h)) => h,
-            Some(Err(e)) => {
-                return Err(Error::AvroError(
-                    "failed to read header from Avro reader".to_owned(),
-                    e,
-                ))
-            }
-            None => return Err(Error::EofError),
-        };
-
-        from_value::<InvalidPacket>(&header)
-            .map_err(|e| Error::AvroError("failed to parse validation header".to_owned(), e))
+impl Header for InvalidUuidSet {
+    fn packet_file_digest(&self) -> &Vec<u8> {
+        &self.packet_file_digest
     }
 
-    fn write<W: Write>(&self, writer: &mut Writer<W>) -> Result<(), Error> {
-        // Ideally we would just do `writer.append_ser(self)` to use Serde serialization to write
-        // the record but there seems to be some problem with serializing UUIDs, so we have to
-        // construct the record.
-        let mut record = match Record::new(writer.schema()) {
-            Some(r) => r,
-            None => {
-                // avro_rs docs say this can only happen "if the `Schema is not a `Schema::Record`
-                // variant", which shouldn't ever happen, so panic for debugging
-                // https://docs.rs/avro-rs/0.11.0/avro_rs/types/struct.Record.html#method.new
-                panic!("Unable to create Record from invalid packet schema");
-            }
-        };
-
-        record.put("uuid", Value::Uuid(self.uuid));
+    fn name(&self) -> &str {
+        &self.name
+    }
 
-        writer.append(record).map_err(|e| {
-            Error::AvroError("failed to append record to Avro writer".to_owned(), e)
-        })?;
+    fn batch_uuid(&self) -> Option<&Uuid> {
+        None
+    }
 
-        Ok(())
+    fn time_range(&self) -> Option<(i64, i64)> {
+        Some((self.aggregation_start_time, self.aggregation_end_time))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use assert_matches::assert_matches;
+    use avro_rs::types::Record;
 
     #[test]
     fn roundtrip_batch_signature() {
         let signature1 = BatchSignature {
             batch_header_signature: vec![1u8, 2u8, 3u8, 4u8],
             key_identifier: "my-cool-key".to_owned(),
+            batch_manifest_signature: None,
         };
         let signature2 = BatchSignature {
             batch_header_signature: vec![5u8, 6u8, 7u8, 9u8],
             key_identifier: "my-other-key".to_owned(),
+            batch_manifest_signature: None,
+        };
+        let signature3 = BatchSignature {
+            batch_header_signature: vec![1u8, 2u8, 3u8, 4u8],
+            key_identifier: "my-cool-key".to_owned(),
+            batch_manifest_signature: Some(vec![10u8, 11u8]),
         };
 
         let mut record_vec = Vec::new();
@@ -1064,6 +396,66 @@ mod tests {
         let signature_again = BatchSignature::read(&record_vec[..]).unwrap();
         assert_eq!(signature1, signature_again);
         assert!(signature2 != signature_again);
+
+        let mut record_vec = Vec::new();
+        signature3.write(&mut record_vec).unwrap();
+        let signature_again = BatchSignature::read(&record_vec[..]).unwrap();
+        assert_eq!(signature3, signature_again);
+        assert!(signature1 != signature_again);
+    }
+
+    #[test]
+    fn read_v1_batch_signature() {
+        // Signatures written by peers using the original schema, without the
+        // batch_manifest_signature field, must still be readable.
+        let v1_schema = Schema::parse_str(
+            r#"{
+                "namespace": "org.abetterinternet.prio.v1",
+                "type": "record",
+                "name": "PrioBatchSignature",
+                "fields": [
+                    {"name": "batch_header_signature", "type": "bytes"},
+                    {"name": "key_identifier", "type": "string"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let mut writer = Writer::new(&v1_schema, Vec::new());
+        let mut record = Record::new(writer.schema()).unwrap();
+        record.put("batch_header_signature", Value::Bytes(vec![1u8, 2u8]));
+        record.put("key_identifier", Value::String("my-cool-key".to_owned()));
+        writer.append(record).unwrap();
+
+        let signature = BatchSignature::read(&writer.into_inner().unwrap()[..]).unwrap();
+        assert_eq!(
+            signature,
+            BatchSignature {
+                batch_header_signature: vec![1u8, 2u8],
+                key_identifier: "my-cool-key".to_owned(),
+                batch_manifest_signature: None,
+            }
+        );
+    }
+
+    #[test]
+    fn batch_manifest_encoding() {
+        let manifest = BatchManifest {
+            aggregation_name: "fake-aggregation".to_owned(),
+            batch_uuid: Some(Uuid::new_v4()),
+            header_object_key: "fake-aggregation/2020/10/31/20/29/uuid.batch".to_owned(),
+            packet_file_object_key: "fake-aggregation/2020/10/31/20/29/uuid.batch.avro".to_owned(),
+            header_digest: vec![1u8; 32],
+            packet_file_digest: vec![2u8; 32],
+        };
+        let encoded = manifest.to_bytes().unwrap();
+        // Encoding must be deterministic so that readers can reconstruct it.
+        assert_eq!(encoded, manifest.to_bytes().unwrap());
+
+        let sum_manifest = BatchManifest {
+            batch_uuid: None,
+            ..manifest
+        };
+        assert!(encoded != sum_manifest.to_bytes().unwrap());
     }
 
     #[test]
@@ -1143,7 +535,7 @@ mod tests {
         }
         writer.flush().unwrap();
 
-        let mut reader = Reader::with_schema(&schema, &record_vec[..]).unwrap();
+        let mut reader = resolving_reader(&schema, &record_vec[..]).unwrap();
         for packet in packets {
             let packet_again = IngestionDataSharePacket::read(&mut reader).expect("read error");
             assert_eq!(packet_again, *packet);
@@ -1198,18 +590,21 @@ mod tests {
                 f_r: 1,
                 g_r: 2,
                 h_r: 3,
+                hamming_weight_share: None,
             },
             ValidationPacket {
                 uuid: Uuid::new_v4(),
                 f_r: 4,
                 g_r: 5,
                 h_r: 6,
+                hamming_weight_share: Some(1),
             },
             ValidationPacket {
                 uuid: Uuid::new_v4(),
                 f_r: 7,
                 g_r: 8,
                 h_r: 9,
+                hamming_weight_share: None,
             },
         ];
 
@@ -1223,7 +618,7 @@ mod tests {
         }
         writer.flush().unwrap();
 
-        let mut reader = Reader::with_schema(&schema, &record_vec[..]).unwrap();
+        let mut reader = resolving_reader(&schema, &record_vec[..]).unwrap();
         for packet in packets {
             let packet_again = ValidationPacket::read(&mut reader).expect("read error");
             assert_eq!(packet_again, *packet);
@@ -1252,6 +647,7 @@ mod tests {
                 aggregation_end_time: 789456321,
                 packet_file_digest: vec![1, 2, 3],
                 total_individual_clients: 2,
+                noise_epsilon: None,
             },
             SumPart {
                 batch_uuids: vec![Uuid::new_v4()],
@@ -1266,6 +662,7 @@ mod tests {
                 aggregation_end_time: 789456321,
                 packet_file_digest: vec![7, 8, 9],
                 total_individual_clients: 2,
+                noise_epsilon: Some(0.5),
             },
         ];
 
@@ -1278,6 +675,34 @@ mod tests {
         }
     }
 
+    #[test]
+    fn roundtrip_invalid_uuid_set() {
+        let header = InvalidUuidSet {
+            batch_uuids: vec![Uuid::new_v4(), Uuid::new_v4()],
+            name: "fake-batch".to_owned(),
+            aggregation_start_time: 789456123,
+            aggregation_end_time: 789456321,
+            packet_file_digest: vec![1, 2, 3],
+            cross_checked: true,
+        };
+
+        let mut record_vec = Vec::new();
+        header.write(&mut record_vec).expect("write error");
+        let header_again = InvalidUuidSet::read(&record_vec[..]).expect("read error");
+        assert_eq!(header_again, header);
+
+        let reordered = InvalidUuidSet {
+            batch_uuids: header.batch_uuids.iter().rev().cloned().collect(),
+            ..header.clone()
+        };
+        assert!(header.check_parameters(&reordered));
+        let other_window = InvalidUuidSet {
+            aggregation_end_time: 789456322,
+            ..header.clone()
+        };
+        assert!(!header.check_parameters(&other_window));
+    }
+
     #[test]
     fn roundtrip_invalid_packet() {
         let packets = &[
@@ -1314,4 +739,108 @@ mod tests {
             v => assert!(false, "wrong error {:?}", v),
         }
     }
+
+    /// Returns the schema of T with its fields edited by the provided function,
+    /// as another version of our schemas might be.
+    fn edited_schema<T: AvroRecord>(edit: impl FnOnce(&mut Vec<serde_json::Value>)) -> Schema {
+        let mut schema: serde_json::Value = serde_json::from_str(T::schema_raw()).unwrap();
+        edit(schema["fields"].as_array_mut().unwrap());
+        Schema::parse(&schema).unwrap()
+    }
+
+    /// Writes the provided records, which may omit or add fields relative to T,
+    /// into an Avro container with the provided schema.
+    fn write_records(schema: &Schema, records: Vec<Vec<(String, Value)>>) -> Vec<u8> {
+        let mut writer = Writer::new(schema, Vec::new());
+        for record in records {
+            writer.append(Value::Record(record)).unwrap();
+        }
+        writer.into_inner().unwrap()
+    }
+
+    fn record_fields<T: AvroRecord>(record: &T) -> Vec<(String, Value)> {
+        match record.to_avro_value() {
+            Value::Record(fields) => fields,
+            v => panic!("unexpected value {:?}", v),
+        }
+    }
+
+    #[test]
+    fn read_header_from_newer_writer() {
+        let header = IngestionHeader {
+            batch_uuid: Uuid::new_v4(),
+            name: "fake-batch".to_owned(),
+            bins: 2,
+            epsilon: 1.601,
+            prime: 17,
+            number_of_servers: 2,
+            hamming_weight: None,
+            batch_start_time: 789456123,
+            batch_end_time: 789456321,
+            packet_file_digest: vec![1u8, 2u8, 3u8, 4u8],
+        };
+
+        // A writer that added an optional field
+        let schema = edited_schema::<IngestionHeader>(|fields| {
+            fields.push(serde_json::json!({
+                "name": "region",
+                "type": ["null", "string"],
+                "default": null,
+            }))
+        });
+        let mut fields = record_fields(&header);
+        fields.push((
+            "region".to_owned(),
+            Value::Union(Box::new(Value::String("us-west".to_owned()))),
+        ));
+        let record_vec = write_records(&schema, vec![fields]);
+
+        let header_again = IngestionHeader::read(&record_vec[..]).expect("read error");
+        assert_eq!(header_again, header);
+    }
+
+    #[test]
+    fn read_packets_from_older_writer() {
+        let packet = IngestionDataSharePacket {
+            uuid: Uuid::new_v4(),
+            encrypted_payload: vec![0u8, 1u8, 2u8, 3u8],
+            encryption_key_id: Some("fake-key-1".to_owned()),
+            r_pit: 1,
+            version_configuration: None,
+            device_nonce: None,
+        };
+
+        // A writer that predates the device_nonce field
+        let schema = edited_schema::<IngestionDataSharePacket>(|fields| {
+            fields.retain(|f| f["name"] != "device_nonce")
+        });
+        let mut fields = record_fields(&packet);
+        fields.retain(|(name, _)| name != "device_nonce");
+        let record_vec = write_records(&schema, vec![fields.clone(), fields]);
+
+        let reader_schema = IngestionDataSharePacket::schema();
+        let mut reader = resolving_reader(&reader_schema, &record_vec[..]).unwrap();
+        for _ in 0..2 {
+            let packet_again = IngestionDataSharePacket::read(&mut reader).expect("read error");
+            assert_eq!(packet_again, packet);
+        }
+        assert_matches!(
+            IngestionDataSharePacket::read(&mut reader),
+            Err(Error::EofError)
+        );
+    }
+
+    #[test]
+    fn reject_incompatible_writer() {
+        // A writer lacking a field that has no default
+        let schema = edited_schema::<InvalidPacket>(|fields| fields.clear());
+        let record_vec = write_records(&schema, vec![vec![]]);
+
+        let reader_schema = InvalidPacket::schema();
+        match resolving_reader(&reader_schema, &record_vec[..]) {
+            Err(Error::IncompatibleSchemaError(name, _)) => assert_eq!(name, "PrioInvalidPacket"),
+            Err(e) => panic!("wrong error {:?}", e),
+            Ok(_) => panic!("incompatible writer schema was accepted"),
+        }
+    }
 }
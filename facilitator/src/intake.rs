@@ -1,14 +1,24 @@
 use crate::{
     batch::{Batch, BatchReader, BatchWriter},
     idl::{IngestionDataSharePacket, IngestionHeader, Packet, ValidationHeader, ValidationPacket},
-    transport::{SignableTransport, VerifiableAndDecryptableTransport},
-    BatchSigningKey, Error,
+    logging::LogContext,
+    metrics::{Registry, StageMetrics},
+    policy::AggregationPolicy,
+    report::StageReport,
+    signing::BatchSigner,
+    transport::{SignableTransport, Transport, VerifiableAndDecryptableTransport},
+    Error, MAX_NUMBER_OF_SERVERS,
+};
+use anyhow::{anyhow, Result};
+use chrono::{Duration, NaiveDateTime};
+use log::info;
+use prio::{
+    encrypt::PrivateKey,
+    finite_field::Field,
+    server::{Server, VerificationMessage},
 };
-use anyhow::{anyhow, Context, Result};
-use chrono::NaiveDateTime;
-use prio::{encrypt::PrivateKey, finite_field::Field, server::Server};
 use ring::signature::UnparsedPublicKey;
-use std::{collections::HashMap, convert::TryFrom, iter::Iterator};
+use std::{collections::HashMap, convert::TryFrom, iter::Iterator, time::Instant};
 use uuid::Uuid;
 
 /// BatchIntaker is responsible for validating a batch of data packet shares
@@ -19,8 +29,16 @@ pub struct BatchIntaker<'a> {
     ingestor_public_keys: &'a HashMap<String, UnparsedPublicKey<Vec<u8>>>,
     packet_decryption_keys: &'a Vec<PrivateKey>,
     validation_batch: BatchWriter<'a, ValidationHeader, ValidationPacket>,
-    batch_signing_key: &'a BatchSigningKey,
-    is_first: bool,
+    additional_validation_transports: Vec<&'a mut dyn Transport>,
+    batch_signer: &'a dyn BatchSigner,
+    server_index: usize,
+    number_of_servers: usize,
+    policy: AggregationPolicy,
+    batch_time_tolerance: Option<Duration>,
+    ingestor_name: Option<String>,
+    log_context: LogContext,
+    metrics: StageMetrics,
+    report: StageReport,
 }
 
 impl<'a> BatchIntaker<'a> {
@@ -30,8 +48,19 @@ impl<'a> BatchIntaker<'a> {
         date: &NaiveDateTime,
         ingestion_transport: &'a mut VerifiableAndDecryptableTransport,
         validation_transport: &'a mut SignableTransport,
-        is_first: bool,
+        server_index: usize,
     ) -> Result<BatchIntaker<'a>> {
+        let log_context = LogContext::new()
+            .aggregation_name(aggregation_name)
+            .batch_uuid(batch_id)
+            .date(date)
+            .role(server_index);
+        let mut validation_batch = BatchWriter::new(
+            Batch::new_validation(aggregation_name, batch_id, date, server_index),
+            &mut *validation_transport.transport,
+        );
+        validation_batch.set_sign_batch_manifest(validation_transport.sign_batch_manifest);
+        validation_batch.set_packet_file_codec(validation_transport.packet_file_codec);
         Ok(BatchIntaker {
             ingestion_batch: BatchReader::new(
                 Batch::new_ingestion(aggregation_name, batch_id, date),
@@ -39,26 +68,98 @@ impl<'a> BatchIntaker<'a> {
             ),
             ingestor_public_keys: &ingestion_transport.transport.batch_signing_public_keys,
             packet_decryption_keys: &ingestion_transport.packet_decryption_keys,
-            validation_batch: BatchWriter::new(
-                Batch::new_validation(aggregation_name, batch_id, date, is_first),
-                &mut *validation_transport.transport,
-            ),
-            batch_signing_key: &validation_transport.batch_signing_key,
-            is_first,
+            validation_batch,
+            additional_validation_transports: Vec::new(),
+            batch_signer: &*validation_transport.batch_signer,
+            server_index,
+            number_of_servers: 2,
+            policy: AggregationPolicy::default(),
+            batch_time_tolerance: None,
+            ingestor_name: None,
+            log_context,
+            metrics: StageMetrics::default(),
+            report: StageReport::default(),
         })
     }
 
+    /// Configures how far the time range in the ingestion batch header may lie
+    /// outside the batch's date. If None, the time range is not checked.
+    pub fn set_batch_time_tolerance(&mut self, tolerance: Option<Duration>) {
+        self.batch_time_tolerance = tolerance;
+    }
+
+    /// Configures the policy the ingestion batch's parameters must satisfy.
+    /// If the policy limits batch time skew, the time range is checked with
+    /// the stricter of that and the batch time tolerance.
+    pub fn set_policy(&mut self, policy: AggregationPolicy) {
+        self.policy = policy;
+    }
+
+    /// Configures the number of servers, i.e. the PHA and the facilitators,
+    /// that ingestion batches must have been split for. Defaults to 2, and
+    /// may not be more than MAX_NUMBER_OF_SERVERS.
+    pub fn set_number_of_servers(&mut self, number_of_servers: usize) -> Result<()> {
+        if number_of_servers > MAX_NUMBER_OF_SERVERS {
+            return Err(Error::ConfigurationError(format!(
+                "{} servers configured but at most {} are supported",
+                number_of_servers, MAX_NUMBER_OF_SERVERS
+            ))
+            .into());
+        }
+        self.number_of_servers = number_of_servers;
+        Ok(())
+    }
+
+    /// Adds a transport to which the validation batch is copied once written,
+    /// for deployments with more than one peer share processor.
+    pub fn add_validation_transport(&mut self, transport: &'a mut dyn Transport) {
+        self.additional_validation_transports.push(transport);
+    }
+
+    /// Records in the report that the ingestion batch was written by the named
+    /// ingestor.
+    pub fn set_ingestor_name(&mut self, name: Option<&str>) {
+        self.ingestor_name = name.map(str::to_owned);
+    }
+
+    /// Records this intaker's metrics in registry.
+    pub fn set_metrics(&mut self, registry: &Registry) -> Result<()> {
+        self.metrics = StageMetrics::new(registry, "intake")?;
+        Ok(())
+    }
+
+    /// Returns the batches read and written and the packets processed by
+    /// generate_validation_share.
+    pub fn report(&self) -> &StageReport {
+        &self.report
+    }
+
     /// Fetches the ingestion batch, validates the signatures over its header
     /// and packet file, then computes validation shares and sends them to the
     /// peer share processor.
     pub fn generate_validation_share(&mut self) -> Result<()> {
+        let _context = self.log_context.clone().enter();
+        info!("generating validation shares");
+        let start = Instant::now();
+
+        self.ingestion_batch
+            .set_time_tolerance(self.policy.batch_time_tolerance(self.batch_time_tolerance));
         let ingestion_header = self.ingestion_batch.header(self.ingestor_public_keys)?;
         if ingestion_header.bins <= 0 {
-            return Err(anyhow!(
+            return Err(Error::MalformedHeaderError(format!(
                 "invalid bins/dimension value {}",
                 ingestion_header.bins
-            ));
+            ))
+            .into());
+        }
+        if !ingestion_header.check_number_of_servers(self.number_of_servers) {
+            return Err(Error::ParameterMismatchError(format!(
+                "ingestion batch is for {} servers but {} are configured",
+                ingestion_header.number_of_servers, self.number_of_servers
+            ))
+            .into());
         }
+        self.policy.check_header(&ingestion_header)?;
 
         // Ideally, we would use the encryption_key_id in the ingestion packet
         // to figure out which private key to use for decryption, but that field
@@ -68,7 +169,13 @@ impl<'a> BatchIntaker<'a> {
         let mut servers = self
             .packet_decryption_keys
             .iter()
-            .map(|k| Server::new(ingestion_header.bins as usize, self.is_first, k.clone()))
+            .map(|k| {
+                Server::new(
+                    ingestion_header.bins as usize,
+                    self.server_index == 0,
+                    k.clone(),
+                )
+            })
             .collect::<Vec<Server>>();
 
         // Read all the ingestion packets, generate a verification message for
@@ -76,6 +183,10 @@ impl<'a> BatchIntaker<'a> {
         let mut ingestion_packet_reader =
             self.ingestion_batch.packet_file_reader(&ingestion_header)?;
 
+        let mut packet_count = 0;
+        let policy = &self.policy;
+        let aggregation_name = &ingestion_header.name;
+        let check_hamming_weight = ingestion_header.hamming_weight.is_some();
         let packet_file_digest =
             self.validation_batch
                 .packet_file_writer(|mut packet_writer| loop {
@@ -86,8 +197,12 @@ impl<'a> BatchIntaker<'a> {
                         Err(e) => return Err(e.into()),
                     };
 
-                    let r_pit = u32::try_from(packet.r_pit)
-                        .with_context(|| format!("illegal r_pit value {}", packet.r_pit))?;
+                    let r_pit = u32::try_from(packet.r_pit).map_err(|_| {
+                        Error::MalformedDataPacketError(format!(
+                            "illegal r_pit value {}",
+                            packet.r_pit
+                        ))
+                    })?;
 
                     // TODO(timg): if this fails for a non-empty subset of the
                     // ingestion packets, do we abort handling of the entire
@@ -104,18 +219,37 @@ impl<'a> BatchIntaker<'a> {
                             None => continue,
                         };
 
+                        // The hamming weight is verified during aggregation, from
+                        // the shares of all the servers.
+                        let hamming_weight_share = if check_hamming_weight {
+                            let share = hamming_weight_share(server, &packet.encrypted_payload)
+                                .map_err(|e| {
+                                    Error::PacketProcessingError(packet.uuid, e.to_string())
+                                })?;
+                            Some(u32::from(share) as i64)
+                        } else {
+                            None
+                        };
+
                         let packet = ValidationPacket {
                             uuid: packet.uuid,
                             f_r: u32::from(validation_message.f_r) as i64,
                             g_r: u32::from(validation_message.g_r) as i64,
                             h_r: u32::from(validation_message.h_r) as i64,
+                            hamming_weight_share,
                         };
                         packet.write(&mut packet_writer)?;
+                        packet_count += 1;
+                        policy.check_packet_count(aggregation_name, packet_count)?;
                         did_create_validation_packet = true;
                         break;
                     }
                     if !did_create_validation_packet {
-                        return Err(anyhow!("failed to construct validation message"));
+                        return Err(Error::PacketProcessingError(
+                            packet.uuid,
+                            "failed to construct validation message".to_owned(),
+                        )
+                        .into());
                     }
                 })?;
 
@@ -131,15 +265,54 @@ impl<'a> BatchIntaker<'a> {
                 hamming_weight: ingestion_header.hamming_weight,
                 packet_file_digest: packet_file_digest.as_ref().to_vec(),
             },
-            &self.batch_signing_key.key,
+            self.batch_signer,
         )?;
 
         // Construct and write out signature
         self.validation_batch
-            .put_signature(&header_signature, &self.batch_signing_key.identifier)
+            .put_signature(&header_signature, self.batch_signer)?;
+        for transport in self.additional_validation_transports.iter_mut() {
+            self.validation_batch.copy_to(&mut **transport)?;
+        }
+        let mut ingestion_report = self.ingestion_batch.report();
+        ingestion_report.ingestor = self.ingestor_name.clone();
+        self.report.inputs.push(ingestion_report);
+        self.report.outputs.push(self.validation_batch.report());
+        self.report.packets.total += packet_count;
+        self.report.packets.valid += packet_count;
+        self.metrics.packets.inc_by(packet_count as f64);
+        self.metrics.batches.inc();
+        self.metrics.duration.observe_since(start);
+        info!("wrote {} validation packets", packet_count);
+        Ok(())
     }
 }
 
+/// Returns this server's share of the sum of the elements of the data vector
+/// in the provided encrypted share, which for a vector of bits is its hamming
+/// weight. libprio does not expose decrypted shares, so the share is added to
+/// server's accumulator, by aggregating it with verification messages that are
+/// trivially valid, and read back as the change in the accumulator's sum. The
+/// server must not otherwise be used for aggregation.
+fn hamming_weight_share(server: &mut Server, encrypted_share: &[u8]) -> Result<Field> {
+    let sum = |server: &Server| {
+        server
+            .total_shares()
+            .iter()
+            .fold(Field::from(0), |sum, share| sum + *share)
+    };
+    let before = sum(server);
+    let zero = VerificationMessage {
+        f_r: Field::from(0),
+        g_r: Field::from(0),
+        h_r: Field::from(0),
+    };
+    server
+        .aggregate(encrypted_share, &zero, &zero)
+        .map_err(|e| anyhow!("failed to decrypt share: {}", e))?;
+    Ok(sum(server) - before)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -152,6 +325,8 @@ mod tests {
         },
         transport::{LocalFileTransport, VerifiableTransport},
     };
+    use avro_rs::Codec;
+    use prio::{client::Client, encrypt::PublicKey};
 
     #[test]
     fn share_validator() {
@@ -176,6 +351,7 @@ mod tests {
             0.11,
             100,
             100,
+            None,
         )
         .expect("failed to generate sample");
 
@@ -209,14 +385,18 @@ mod tests {
 
         let mut pha_validate_transport = SignableTransport {
             transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
-            batch_signing_key: default_pha_signing_private_key(),
+            batch_signer: Box::new(default_pha_signing_private_key()),
+            sign_batch_manifest: true,
+            packet_file_codec: Codec::Null,
         };
 
         let mut facilitator_validate_transport = SignableTransport {
             transport: Box::new(LocalFileTransport::new(
                 facilitator_tempdir.path().to_path_buf(),
             )),
-            batch_signing_key: default_facilitator_signing_private_key(),
+            batch_signer: Box::new(default_facilitator_signing_private_key()),
+            sign_batch_manifest: false,
+            packet_file_codec: Codec::Null,
         };
 
         let mut pha_ingestor = BatchIntaker::new(
@@ -225,13 +405,26 @@ mod tests {
             &date,
             &mut pha_ingest_transport,
             &mut pha_validate_transport,
-            true,
+            0,
         )
         .unwrap();
+        // The PHA's validation batch is also sent to a second facilitator.
+        let second_facilitator_tempdir = tempfile::TempDir::new().unwrap();
+        let mut second_facilitator_transport =
+            LocalFileTransport::new(second_facilitator_tempdir.path().to_path_buf());
+        pha_ingestor.add_validation_transport(&mut second_facilitator_transport);
 
         pha_ingestor
             .generate_validation_share()
             .expect("PHA failed to generate validation");
+        let validation_batch = Batch::new_validation(&aggregation_name, &batch_uuid, &date, 0);
+        for key in &[
+            validation_batch.header_key(),
+            validation_batch.packet_file_key(),
+            validation_batch.signature_key(),
+        ] {
+            assert!(second_facilitator_tempdir.path().join(key).exists());
+        }
 
         let mut facilitator_ingestor = BatchIntaker::new(
             &aggregation_name,
@@ -239,12 +432,62 @@ mod tests {
             &date,
             &mut facilitator_ingest_transport,
             &mut facilitator_validate_transport,
-            false,
+            1,
         )
         .unwrap();
 
+        // libprio only splits data between two servers.
+        let err = facilitator_ingestor.set_number_of_servers(3).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::ConfigurationError(_))
+        ));
+        facilitator_ingestor.set_number_of_servers(2).unwrap();
+
+        // The sample's ten packets are more than the policy allows.
+        facilitator_ingestor.set_policy(AggregationPolicy {
+            max_packets: Some(5),
+            ..AggregationPolicy::default()
+        });
+        let err = facilitator_ingestor
+            .generate_validation_share()
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::PolicyViolationError {
+                parameter: "packet count",
+                ..
+            })
+        ));
+
+        facilitator_ingestor.set_policy(AggregationPolicy::default());
         facilitator_ingestor
             .generate_validation_share()
             .expect("facilitator failed to generate validation");
     }
+
+    #[test]
+    fn hamming_weight_shares() {
+        let pha_key = PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap();
+        let facilitator_key =
+            PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap();
+        let mut client = Client::new(
+            4,
+            PublicKey::from(&pha_key),
+            PublicKey::from(&facilitator_key),
+        )
+        .unwrap();
+        let mut pha_server = Server::new(4, true, pha_key);
+        let mut facilitator_server = Server::new(4, false, facilitator_key);
+
+        for data in &[[1, 0, 1, 1], [0, 0, 0, 0], [1, 1, 1, 1]] {
+            let data: Vec<Field> = data.iter().map(|d| Field::from(*d)).collect();
+            let (pha_share, facilitator_share) = client.encode_simple(&data).unwrap();
+            let weight = hamming_weight_share(&mut pha_server, &pha_share).unwrap()
+                + hamming_weight_share(&mut facilitator_server, &facilitator_share).unwrap();
+            assert_eq!(weight, data.iter().fold(Field::from(0), |sum, d| sum + *d));
+        }
+
+        hamming_weight_share(&mut pha_server, b"not a share").unwrap_err();
+    }
 }
@@ -1,45 +1,152 @@
 use anyhow::Result;
-use ring::{digest, signature::EcdsaKeyPair};
+use ring::digest;
+use signing::SigningKeyPair;
 use std::io::Write;
 
 pub mod aggregation;
 pub mod batch;
+pub mod combine;
+pub mod compatibility;
 pub mod config;
+pub mod dump;
 pub mod idl;
 pub mod intake;
+pub mod logging;
 pub mod manifest;
+pub mod metrics;
+pub mod noise;
+pub mod policy;
+pub mod reduce;
+pub mod report;
 pub mod sample;
+pub mod signing;
 pub mod test_utils;
 pub mod transport;
+pub mod verify;
 mod workflow;
 
 pub use workflow::{workflow_main, WorkflowArgs};
 
 pub const DATE_FORMAT: &str = "%Y/%m/%d/%H/%M";
 
+/// The most servers ingestion batches can be split between. libprio only
+/// supports two, the PHA and one facilitator, so deployments configured with
+/// more are rejected until it supports more.
+pub const MAX_NUMBER_OF_SERVERS: usize = 2;
+
 /// Identity represents a cloud identity: Either an AWS IAM ARN (i.e. "arn:...")
 /// or a GCP ServiceAccount (i.e. "foo@bar.com").
 pub type Identity = String;
 
+/// Boxed error from some other crate, wrapped by some of the Error variants.
+pub type BoxedError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Errors encountered while reading, validating and writing batches. Functions
+/// in this crate that return anyhow::Error wrap these, so callers that need to
+/// distinguish them should use anyhow::Error::downcast_ref, or is_retryable to
+/// classify an error.
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    /// Compatibility enum entry to ease migration.
-    #[error(transparent)]
-    // TODO(yuriks): The `From` impl should stay disabled while not updating errors, to avoid
-    //   accidentally wrapping an `EofError` inside `AnyhowError`, which would then get missed when
-    //   code tries to pattern-match against it. I don't trust our current test coverage enough to
-    //   detect all cases where that happens and leads to incorrect behavior.
-    //   Later on, `anyhow` downcasting with `Error::is` can be used to check for `EofError`.
-    AnyhowError(/*#[from]*/ anyhow::Error),
-
     #[error("avro error: {0}")]
     AvroError(String, #[source] avro_rs::Error),
     #[error("malformed header: {0}")]
     MalformedHeaderError(String),
     #[error("malformed data packet: {0}")]
     MalformedDataPacketError(String),
+    #[error("writer schema cannot be resolved to {0} schema: {1}")]
+    IncompatibleSchemaError(String, String),
     #[error("end of file")]
     EofError,
+    #[error("key identifier {0} not present in key map")]
+    KeyNotFoundError(String),
+    #[error("invalid signature on {0}")]
+    SignatureError(String),
+    #[error("failed to sign {object}")]
+    SigningError {
+        object: String,
+        /// Whether the signer may succeed later, as when a remote signing
+        /// service is unavailable, rather than being unable to sign at all, as
+        /// when its key is malformed.
+        retryable: bool,
+        #[source]
+        source: BoxedError,
+    },
+    #[error("digest of {0} does not match header")]
+    DigestMismatchError(String),
+    #[error("parameter mismatch: {0}")]
+    ParameterMismatchError(String),
+    #[error("invalid configuration: {0}")]
+    ConfigurationError(String),
+    #[error("packet file mismatch: {0}")]
+    PacketMismatchError(String),
+    #[error("failed to process packet {0}: {1}")]
+    PacketProcessingError(uuid::Uuid, String),
+    #[error("object {0} not found")]
+    ObjectNotFoundError(String),
+    #[error("peer validations not yet available for batches {0:?}")]
+    PeerValidationsPendingError(Vec<uuid::Uuid>),
+    #[error("invalid packets found by peers {0:?} not yet available")]
+    PeerInvalidUuidsPendingError(Vec<usize>),
+    #[error("transport error on object {0}")]
+    TransportError(String, #[source] BoxedError),
+    #[error(
+        "{parameter} {value} violates policy for aggregation {aggregation}: must be {requirement}"
+    )]
+    PolicyViolationError {
+        aggregation: String,
+        parameter: &'static str,
+        value: String,
+        requirement: String,
+    },
+    #[error("header {field} {header_value} does not match {path_value} in batch path")]
+    BatchPathMismatchError {
+        field: &'static str,
+        header_value: String,
+        path_value: String,
+    },
+    #[error(
+        "batch time range {start_time}-{end_time} is not within {tolerance} of batch path \
+        range {path_start_time}-{path_end_time}"
+    )]
+    BatchTimeError {
+        start_time: i64,
+        end_time: i64,
+        path_start_time: i64,
+        path_end_time: i64,
+        tolerance: chrono::Duration,
+    },
+}
+
+impl Error {
+    /// Returns true if the operation that failed with this error could succeed
+    /// if retried later, e.g. because a storage service was unavailable or an
+    /// object has not been written yet, or false if the failure is inherent to
+    /// the batch being processed, which should instead be quarantined.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::SigningError { retryable, .. } => *retryable,
+            _ => matches!(
+                self,
+                Error::ObjectNotFoundError(_)
+                    | Error::PeerValidationsPendingError(_)
+                    | Error::PeerInvalidUuidsPendingError(_)
+                    | Error::TransportError(..)
+            ),
+        }
+    }
+}
+
+/// Classifies an error returned from this crate as retryable or permanent, by
+/// finding the first Error in its chain. Errors that don't originate from this
+/// crate can't be classified, and are assumed to be permanent so that
+/// configuration and logic errors are not retried forever. Failures of storage
+/// or network operations that may be transient are wrapped in
+/// Error::TransportError so that they are retried.
+pub fn is_retryable(error: &anyhow::Error) -> bool {
+    match error.chain().find_map(|e| e.downcast_ref::<Error>()) {
+        Some(e) => e.is_retryable(),
+        None => false,
+    }
 }
 
 /// An implementation of transport::TransportWriter that computes a SHA256
@@ -103,8 +210,8 @@ impl<T: Write, W: Write> Write for SidecarWriter<T, W> {
 /// This struct represents a key used by this data share processor to sign
 /// batches (ingestion, validation or sum part).
 pub struct BatchSigningKey {
-    /// The ECDSA P256 key pair to use when signing batches.
-    pub key: EcdsaKeyPair,
+    /// The key pair to use when signing batches.
+    pub key: SigningKeyPair,
     /// The key identifier to be inserted into signature structures, which
     /// must correspond to a batch-signing-key in the data share processor's
     /// specific manifest.
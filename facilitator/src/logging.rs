@@ -0,0 +1,301 @@
+use crate::DATE_FORMAT;
+use anyhow::{anyhow, Result};
+use chrono::{NaiveDateTime, SecondsFormat, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value};
+use std::{
+    cell::RefCell,
+    io::{stderr, Write},
+    marker::PhantomData,
+    str::FromStr,
+};
+use uuid::Uuid;
+
+thread_local! {
+    /// Fields attached to every record logged on this thread, pushed and popped
+    /// by LogContextGuard.
+    static CONTEXT: RefCell<Vec<(&'static str, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// How log records are written to stderr.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// One human readable line per record, with context fields appended as
+    /// key=value pairs.
+    Text,
+    /// One JSON object per line, with context fields as members.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!("unknown log format {}", s)),
+        }
+    }
+}
+
+/// Installs a logger writing records at or above the provided level to stderr
+/// in the provided format. May only be called once per process.
+pub fn init(format: LogFormat, level: LevelFilter) -> Result<()> {
+    log::set_boxed_logger(Box::new(Logger { format, level }))
+        .map_err(|e| anyhow!("failed to install logger: {}", e))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+struct Logger {
+    format: LogFormat,
+    level: LevelFilter,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format_record(self.format, record);
+        // There is nowhere to report a failure to write a log record.
+        let _ = writeln!(stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = stderr().flush();
+    }
+}
+
+/// Formats a record along with the current thread's context fields. If a
+/// field was pushed more than once, the innermost value wins.
+fn format_record(format: LogFormat, record: &Record) -> String {
+    let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let fields = CONTEXT.with(|context| {
+        let mut fields: Vec<(&'static str, String)> = Vec::new();
+        for (key, value) in context.borrow().iter() {
+            match fields.iter_mut().find(|(k, _)| k == key) {
+                Some(field) => field.1 = value.clone(),
+                None => fields.push((key, value.clone())),
+            }
+        }
+        fields
+    });
+
+    match format {
+        LogFormat::Text => {
+            let mut line = format!(
+                "{} {:<5} {}: {}",
+                timestamp,
+                record.level(),
+                record.target(),
+                record.args()
+            );
+            for (key, value) in fields {
+                line.push_str(&format!(" {}={}", key, value));
+            }
+            line
+        }
+        LogFormat::Json => {
+            let mut object = Map::new();
+            object.insert("timestamp".to_owned(), Value::String(timestamp));
+            object.insert(
+                "level".to_owned(),
+                Value::String(level_name(record.level()).to_owned()),
+            );
+            object.insert(
+                "target".to_owned(),
+                Value::String(record.target().to_owned()),
+            );
+            object.insert(
+                "message".to_owned(),
+                Value::String(record.args().to_string()),
+            );
+            for (key, value) in fields {
+                object.insert(key.to_owned(), Value::String(value));
+            }
+            Value::Object(object).to_string()
+        }
+    }
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "error",
+        Level::Warn => "warn",
+        Level::Info => "info",
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    }
+}
+
+/// Describes the batch, aggregation and object that records logged while a
+/// LogContextGuard is alive pertain to.
+#[derive(Clone, Debug, Default)]
+pub struct LogContext {
+    fields: Vec<(&'static str, String)>,
+}
+
+impl LogContext {
+    pub fn new() -> LogContext {
+        LogContext::default()
+    }
+
+    pub fn aggregation_name(mut self, aggregation_name: &str) -> Self {
+        self.fields
+            .push(("aggregation_name", aggregation_name.to_owned()));
+        self
+    }
+
+    pub fn batch_uuid(mut self, batch_uuid: &Uuid) -> Self {
+        self.fields.push(("batch_uuid", batch_uuid.to_string()));
+        self
+    }
+
+    pub fn date(mut self, date: &NaiveDateTime) -> Self {
+        self.fields
+            .push(("date", date.format(DATE_FORMAT).to_string()));
+        self
+    }
+
+    /// Records the range of dates covered by an aggregation.
+    pub fn date_range(mut self, start: &NaiveDateTime, end: &NaiveDateTime) -> Self {
+        self.fields.push((
+            "date",
+            format!("{}-{}", start.format(DATE_FORMAT), end.format(DATE_FORMAT)),
+        ));
+        self
+    }
+
+    /// Records whether this is the PHA, i.e. the server with index 0, or a
+    /// facilitator server.
+    pub fn role(mut self, server_index: usize) -> Self {
+        self.fields.push((
+            "role",
+            if server_index == 0 {
+                "pha"
+            } else {
+                "facilitator"
+            }
+            .to_owned(),
+        ));
+        self
+    }
+
+    pub fn object_key(mut self, object_key: &str) -> Self {
+        self.fields.push(("object_key", object_key.to_owned()));
+        self
+    }
+
+    /// Attaches these fields to all records logged on this thread until the
+    /// returned guard is dropped.
+    pub fn enter(self) -> LogContextGuard {
+        let depth = CONTEXT.with(|context| {
+            let mut context = context.borrow_mut();
+            let depth = context.len();
+            context.extend(self.fields);
+            depth
+        });
+        LogContextGuard {
+            depth,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+/// Removes the fields pushed by LogContext::enter when dropped. Guards must be
+/// dropped in the reverse of the order they were created in, which scoping
+/// them to local variables guarantees.
+#[must_use]
+pub struct LogContextGuard {
+    depth: usize,
+    // The context is per-thread, so the guard must not move between threads.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for LogContextGuard {
+    fn drop(&mut self) {
+        CONTEXT.with(|context| context.borrow_mut().truncate(self.depth));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format_json(message: &str) -> Value {
+        let line = format_record(
+            LogFormat::Json,
+            &Record::builder()
+                .args(format_args!("{}", message))
+                .level(Level::Info)
+                .target("facilitator::test")
+                .build(),
+        );
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn context_fields() {
+        let batch_uuid = Uuid::new_v4();
+        let date = NaiveDateTime::from_timestamp(1234567890, 0);
+
+        let record = format_json("no context");
+        assert_eq!(record["message"], "no context");
+        assert_eq!(record["level"], "info");
+        assert_eq!(record["target"], "facilitator::test");
+        assert!(record.get("batch_uuid").is_none());
+
+        {
+            let _outer = LogContext::new()
+                .aggregation_name("fake-aggregation")
+                .batch_uuid(&batch_uuid)
+                .date(&date)
+                .role(0)
+                .object_key("outer-key")
+                .enter();
+            {
+                let _inner = LogContext::new().object_key("inner-key").enter();
+                let record = format_json("inner");
+                assert_eq!(record["aggregation_name"], "fake-aggregation");
+                assert_eq!(record["batch_uuid"], batch_uuid.to_string());
+                assert_eq!(record["date"], "2009/02/13/23/31");
+                assert_eq!(record["role"], "pha");
+                assert_eq!(record["object_key"], "inner-key");
+            }
+            assert_eq!(format_json("outer")["object_key"], "outer-key");
+        }
+
+        assert!(format_json("after").get("aggregation_name").is_none());
+    }
+
+    #[test]
+    fn text_format() {
+        let _context = LogContext::new().role(1).object_key("key").enter();
+        let line = format_record(
+            LogFormat::Text,
+            &Record::builder()
+                .args(format_args!("hello"))
+                .level(Level::Warn)
+                .target("facilitator::test")
+                .build(),
+        );
+        assert!(
+            line.ends_with("WARN  facilitator::test: hello role=facilitator object_key=key"),
+            "unexpected line {}",
+            line
+        );
+    }
+
+    #[test]
+    fn parse_log_format() {
+        assert_eq!(LogFormat::from_str("json").unwrap(), LogFormat::Json);
+        assert_eq!(LogFormat::from_str("text").unwrap(), LogFormat::Text);
+        LogFormat::from_str("xml").unwrap_err();
+    }
+}
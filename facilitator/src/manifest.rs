@@ -1,16 +1,33 @@
-use crate::config::StoragePath;
+use crate::{config::StoragePath, Error};
 use anyhow::{anyhow, Context, Result};
-use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+use ring::signature::{
+    UnparsedPublicKey, VerificationAlgorithm, ECDSA_P256_SHA256_ASN1, ECDSA_P384_SHA384_ASN1,
+    ED25519,
+};
 use serde::Deserialize;
 use serde_json::from_reader;
 use std::{collections::HashMap, io::Read, str::FromStr};
 use ureq::Response;
 
-// See discussion in SpecificManifest::batch_signing_public_key
-const ECDSA_P256_SPKI_PREFIX: &[u8] = &[
-    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a,
-    0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
-];
+// DER encodings of the object identifiers we expect to find in the
+// AlgorithmIdentifier of a SubjectPublicKeyInfo.
+// id-ecPublicKey, RFC 5480 section 2.1.1
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+// secp256r1 a.k.a. prime256v1 a.k.a. P-256, RFC 5480 section 2.1.1.1
+const OID_CURVE_P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+// secp384r1 a.k.a. P-384, RFC 5480 section 2.1.1.1
+const OID_CURVE_P384: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+// id-Ed25519, RFC 8410 section 3
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+
+// ASN.1 DER tags we need to walk SubjectPublicKeyInfo and Certificate
+// structures.
+const DER_TAG_INTEGER: u8 = 0x02;
+const DER_TAG_BIT_STRING: u8 = 0x03;
+const DER_TAG_NULL: u8 = 0x05;
+const DER_TAG_OID: u8 = 0x06;
+const DER_TAG_SEQUENCE: u8 = 0x30;
+const DER_TAG_CONTEXT_SPECIFIC_0: u8 = 0xa0;
 
 /// A set of batch signing public keys as might be found in a server's global
 /// or specific manifest. The keys are key identifiers and the values are public
@@ -23,7 +40,7 @@ pub type BatchSigningPublicKeys = HashMap<String, UnparsedPublicKey<Vec<u8>>>;
 #[serde(rename_all = "kebab-case")]
 struct BatchSigningPublicKey {
     /// The PEM-armored base64 encoding of the ASN.1 encoding of the PKIX
-    /// SubjectPublicKeyInfo structure of an ECDSA P256 key.
+    /// SubjectPublicKeyInfo structure of a P-256, P-384 or Ed25519 key.
     public_key: String,
     /// The ISO 8601 encoded UTC date at which this key expires.
     expiration: String,
@@ -71,52 +88,268 @@ fn fetch_manifest(manifest_url: &str) -> Result<Response> {
         .timeout_read(10_000) // ten seconds
         .call();
     if response.error() {
-        return Err(anyhow!("failed to fetch manifest: {:?}", response));
+        // The server or the network may recover, so this is retryable.
+        return Err(Error::TransportError(
+            manifest_url.to_owned(),
+            anyhow!("failed to fetch manifest: {:?}", response).into(),
+        )
+        .into());
     }
     Ok(response)
 }
 
-/// Attempts to parse the provided string as a PEM encoded PKIX
-/// SubjectPublicKeyInfo structure containing an ECDSA P256 public key, and
-/// returns an UnparsedPublicKey containing that key on success.
-fn public_key_from_pem(pem_key: &str) -> Result<UnparsedPublicKey<Vec<u8>>> {
-    // No Rust crate that we have found gives us an easy way to parse PKIX
-    // SubjectPublicKeyInfo structures to get at the public key which can
-    // then be used in ring::signature. Since we know the keys we deal with
-    // should always be ECDSA P256, we can instead check that the binary
-    // blob inside the PEM has the expected prefix for this kind of key in
-    // this kind of encoding, as suggested in this GitHub issue on ring:
-    // https://github.com/briansmith/ring/issues/881
-    let pem = pem::parse(&pem_key).context("failed to parse key as PEM")?;
-    if pem.tag != "PUBLIC KEY" {
-        return Err(anyhow!(
-            "key for identifier {} is not a PEM encoded public key"
-        ));
+/// The signature algorithms that may be used with batch signing keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    /// ECDSA over P-256 with SHA-256, signatures in ASN.1 DER encoding.
+    EcdsaP256Sha256,
+    /// ECDSA over P-384 with SHA-384, signatures in ASN.1 DER encoding.
+    EcdsaP384Sha384,
+    /// Ed25519 as specified in RFC 8032.
+    Ed25519,
+}
+
+impl SignatureAlgorithm {
+    /// Returns the ring verification algorithm for this signature algorithm.
+    pub fn verification_algorithm(&self) -> &'static dyn VerificationAlgorithm {
+        match self {
+            SignatureAlgorithm::EcdsaP256Sha256 => &ECDSA_P256_SHA256_ASN1,
+            SignatureAlgorithm::EcdsaP384Sha384 => &ECDSA_P384_SHA384_ASN1,
+            SignatureAlgorithm::Ed25519 => &ED25519,
+        }
     }
 
-    // An ECDSA P256 public key in this encoding will always be 26 bytes of
-    // prefix + 65 bytes of key = 91 bytes total. e.g.,
-    // https://lapo.it/asn1js/#MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgD______________________________________________________________________________________w
-    if pem.contents.len() != 91 {
-        return Err(anyhow!(
-            "PEM contents are wrong size for ASN.1 encoded ECDSA P256 SubjectPublicKeyInfo"
-        ));
+    /// Returns the length in bytes of a public key for this algorithm, as it
+    /// appears in the subjectPublicKey field of a SubjectPublicKeyInfo.
+    fn public_key_len(&self) -> usize {
+        match self {
+            // Uncompressed points: 0x04 || X || Y
+            SignatureAlgorithm::EcdsaP256Sha256 => 65,
+            SignatureAlgorithm::EcdsaP384Sha384 => 97,
+            SignatureAlgorithm::Ed25519 => 32,
+        }
     }
+}
 
-    let (prefix, key) = pem.contents.split_at(ECDSA_P256_SPKI_PREFIX.len());
+/// A minimal reader for the subset of ASN.1 DER needed to get at the public
+/// key in PKIX SubjectPublicKeyInfo and X.509 Certificate structures. It only
+/// understands definite lengths and single byte tags, which is all that those
+/// structures use.
+struct DerReader<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> DerReader<'a> {
+    fn new(input: &'a [u8]) -> DerReader<'a> {
+        DerReader { input }
+    }
 
-    if prefix != ECDSA_P256_SPKI_PREFIX {
+    fn is_empty(&self) -> bool {
+        self.input.is_empty()
+    }
+
+    /// Returns the tag of the next element without consuming it.
+    fn peek_tag(&self) -> Option<u8> {
+        self.input.first().copied()
+    }
+
+    /// Reads one tag-length-value element and returns its tag and contents.
+    fn read_any(&mut self) -> Result<(u8, &'a [u8])> {
+        if self.input.len() < 2 {
+            return Err(anyhow!("truncated DER element"));
+        }
+        let tag = self.input[0];
+        let (length, header_len) = match self.input[1] {
+            short if short & 0x80 == 0 => (short as usize, 2),
+            0x80 => return Err(anyhow!("indefinite length is not allowed in DER")),
+            long => {
+                let length_octets = (long & 0x7f) as usize;
+                if length_octets > std::mem::size_of::<usize>()
+                    || self.input.len() < 2 + length_octets
+                {
+                    return Err(anyhow!("invalid DER length"));
+                }
+                let length_bytes = &self.input[2..2 + length_octets];
+                let length = length_bytes
+                    .iter()
+                    .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+                // DER requires the shortest encoding: the long form only for
+                // lengths that don't fit in the short one, and no leading zeros.
+                if length < 0x80 || length_bytes[0] == 0 {
+                    return Err(anyhow!("non-minimal DER length"));
+                }
+                (length, 2 + length_octets)
+            }
+        };
+        let end = header_len
+            .checked_add(length)
+            .filter(|end| *end <= self.input.len())
+            .ok_or_else(|| anyhow!("DER element length exceeds input"))?;
+
+        let contents = &self.input[header_len..end];
+        self.input = &self.input[end..];
+        Ok((tag, contents))
+    }
+
+    /// Reads one element, failing if it does not have the expected tag.
+    fn read(&mut self, expected_tag: u8) -> Result<&'a [u8]> {
+        let (tag, contents) = self.read_any()?;
+        if tag != expected_tag {
+            return Err(anyhow!(
+                "unexpected DER tag {:#04x}, wanted {:#04x}",
+                tag,
+                expected_tag
+            ));
+        }
+        Ok(contents)
+    }
+
+    /// Reads one element with the expected tag and returns a reader over its
+    /// contents.
+    fn read_nested(&mut self, expected_tag: u8) -> Result<DerReader<'a>> {
+        Ok(DerReader::new(self.read(expected_tag)?))
+    }
+}
+
+/// Parses the provided DER encoded PKIX SubjectPublicKeyInfo (RFC 5280 section
+/// 4.1.2.7) and returns the algorithm the key is to be used with and the bytes
+/// of the subjectPublicKey field.
+fn parse_subject_public_key_info(der: &[u8]) -> Result<(SignatureAlgorithm, &[u8])> {
+    let mut outer = DerReader::new(der);
+    let mut spki = outer
+        .read_nested(DER_TAG_SEQUENCE)
+        .context("SubjectPublicKeyInfo is not a SEQUENCE")?;
+    if !outer.is_empty() {
+        return Err(anyhow!("trailing data after SubjectPublicKeyInfo"));
+    }
+
+    let mut algorithm_identifier = spki
+        .read_nested(DER_TAG_SEQUENCE)
+        .context("AlgorithmIdentifier is not a SEQUENCE")?;
+    let algorithm_oid = algorithm_identifier
+        .read(DER_TAG_OID)
+        .context("failed to read algorithm OID")?;
+
+    let algorithm = match algorithm_oid {
+        OID_EC_PUBLIC_KEY => {
+            // RFC 5480 requires namedCurve parameters for keys used with ECDSA
+            let curve_oid = algorithm_identifier
+                .read(DER_TAG_OID)
+                .context("EC key parameters are not a named curve")?;
+            match curve_oid {
+                OID_CURVE_P256 => SignatureAlgorithm::EcdsaP256Sha256,
+                OID_CURVE_P384 => SignatureAlgorithm::EcdsaP384Sha384,
+                _ => return Err(anyhow!("unsupported EC curve {:02x?}", curve_oid)),
+            }
+        }
+        OID_ED25519 => {
+            // RFC 8410 says parameters MUST be absent, but some encoders emit
+            // NULL, which is harmless.
+            if algorithm_identifier.peek_tag() == Some(DER_TAG_NULL) {
+                algorithm_identifier.read(DER_TAG_NULL)?;
+            }
+            SignatureAlgorithm::Ed25519
+        }
+        _ => {
+            return Err(anyhow!(
+                "unsupported public key algorithm {:02x?}",
+                algorithm_oid
+            ))
+        }
+    };
+    if !algorithm_identifier.is_empty() {
+        return Err(anyhow!("unexpected parameters in AlgorithmIdentifier"));
+    }
+
+    let subject_public_key = spki
+        .read(DER_TAG_BIT_STRING)
+        .context("subjectPublicKey is not a BIT STRING")?;
+    if !spki.is_empty() {
+        return Err(anyhow!("trailing data in SubjectPublicKeyInfo"));
+    }
+
+    // The first octet of a BIT STRING's contents is the number of unused bits
+    // in the final octet, which must be zero for any of the keys we support.
+    let key = match subject_public_key.split_first() {
+        Some((0, key)) => key,
+        _ => return Err(anyhow!("subjectPublicKey has unused bits")),
+    };
+    if key.len() != algorithm.public_key_len() {
         return Err(anyhow!(
-            "PEM contents are not ASN.1 encoded ECDSA P256 SubjectPublicKeyInfo"
+            "public key is {} bytes, but {:?} keys are {} bytes",
+            key.len(),
+            algorithm,
+            algorithm.public_key_len()
         ));
     }
 
+    Ok((algorithm, key))
+}
+
+/// Extracts the DER encoded SubjectPublicKeyInfo from the provided DER encoded
+/// X.509 certificate (RFC 5280 section 4.1). The certificate's signature and
+/// validity are not checked.
+fn subject_public_key_info_from_certificate(der: &[u8]) -> Result<&[u8]> {
+    let mut certificate = DerReader::new(der)
+        .read_nested(DER_TAG_SEQUENCE)
+        .context("Certificate is not a SEQUENCE")?;
+    let mut tbs_certificate = certificate
+        .read_nested(DER_TAG_SEQUENCE)
+        .context("TBSCertificate is not a SEQUENCE")?;
+
+    // version is optional and defaults to v1
+    if tbs_certificate.peek_tag() == Some(DER_TAG_CONTEXT_SPECIFIC_0) {
+        tbs_certificate.read(DER_TAG_CONTEXT_SPECIFIC_0)?;
+    }
+    tbs_certificate
+        .read(DER_TAG_INTEGER)
+        .context("failed to read certificate serial number")?;
+    // signature, issuer, validity and subject
+    for field in &["signature", "issuer", "validity", "subject"] {
+        tbs_certificate
+            .read(DER_TAG_SEQUENCE)
+            .with_context(|| format!("failed to read certificate {}", field))?;
+    }
+
+    // We want the whole TLV encoding of subjectPublicKeyInfo, not just its
+    // contents, so note where it starts before reading past it.
+    let remaining = tbs_certificate.input;
+    tbs_certificate
+        .read(DER_TAG_SEQUENCE)
+        .context("failed to read certificate subjectPublicKeyInfo")?;
+    Ok(&remaining[..remaining.len() - tbs_certificate.input.len()])
+}
+
+/// Attempts to parse the provided bytes as a DER encoded PKIX
+/// SubjectPublicKeyInfo structure containing a P-256, P-384 or Ed25519 public
+/// key, and returns an UnparsedPublicKey that will verify signatures with the
+/// algorithm appropriate for the key.
+pub fn public_key_from_spki_der(der: &[u8]) -> Result<UnparsedPublicKey<Vec<u8>>> {
+    let (algorithm, key) = parse_subject_public_key_info(der)?;
     Ok(UnparsedPublicKey::new(
-        &ECDSA_P256_SHA256_ASN1,
+        algorithm.verification_algorithm(),
         Vec::from(key),
     ))
 }
 
+/// Attempts to parse the provided string as a PEM encoded PKIX
+/// SubjectPublicKeyInfo structure or X.509 certificate containing a P-256,
+/// P-384 or Ed25519 public key, and returns an UnparsedPublicKey containing
+/// that key on success.
+pub fn public_key_from_pem(pem_key: &str) -> Result<UnparsedPublicKey<Vec<u8>>> {
+    let pem = pem::parse(&pem_key).context("failed to parse key as PEM")?;
+    match pem.tag.as_str() {
+        "PUBLIC KEY" => public_key_from_spki_der(&pem.contents),
+        "CERTIFICATE" => {
+            public_key_from_spki_der(subject_public_key_info_from_certificate(&pem.contents)?)
+        }
+        tag => Err(anyhow!(
+            "PEM block with tag {} is not a public key or certificate",
+            tag
+        )),
+    }
+}
+
 impl SpecificManifest {
     /// Load the specific manifest for the specified peer relative to the
     /// provided base path. Returns an error if the manifest could not be
@@ -137,7 +370,7 @@ impl SpecificManifest {
 
     /// Attempts to parse the values in this manifest's
     /// batch-signing-public-keys field as PEM encoded SubjectPublicKeyInfo
-    /// structures containing ECDSA P256 keys, and returns a map of key
+    /// structures, and returns a map of key
     /// identifier to the public keys on success, or an error otherwise.
     pub fn batch_signing_public_keys(&self) -> Result<BatchSigningPublicKeys> {
         let mut keys = HashMap::new();
@@ -182,7 +415,7 @@ pub struct IngestionServerGlobalManifest {
     /// The identity used by the ingestor to authenticate when writing to
     /// ingestion buckets.
     server_identity: IngestionServerIdentity,
-    /// ECDSA P256 public keys used by the ingestor to sign ingestion batches.
+    /// Public keys used by the ingestor to sign ingestion batches.
     /// The keys in this dictionary should match key_identifier values in
     /// PrioBatchSignatures received from the ingestor.
     batch_signing_public_keys: HashMap<String, BatchSigningPublicKey>,
@@ -207,7 +440,7 @@ impl IngestionServerGlobalManifest {
 
     /// Attempts to parse the values in this manifest's
     /// batch-signing-public-keys field as PEM encoded SubjectPublicKeyInfo
-    /// structures containing ECDSA P256 keys, and returns a map of key
+    /// structures, and returns a map of key
     /// identifier to the public keys on success, or an error otherwise.
     pub fn batch_signing_public_keys(&self) -> Result<BatchSigningPublicKeys> {
         let mut keys = HashMap::new();
@@ -273,7 +506,10 @@ mod tests {
     use crate::test_utils::{
         default_ingestor_private_key, DEFAULT_INGESTOR_SUBJECT_PUBLIC_KEY_INFO,
     };
-    use ring::rand::SystemRandom;
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, Ed25519KeyPair, ECDSA_P384_SHA384_ASN1_SIGNING},
+    };
     use rusoto_core::Region;
     use std::io::Cursor;
 
@@ -491,6 +727,126 @@ mod tests {
         }
     }
 
+    // P-384 and Ed25519 keys generated with openssl, as PKCS#8 documents
+    // (base64) and PEM encoded SubjectPublicKeyInfo structures.
+    const P384_PRIVATE_KEY: &str =
+        "MIG2AgEAMBAGByqGSM49AgEGBSuBBAAiBIGeMIGbAgEBBDDs0Dezqf5j4EDUS3oWAMxBLzAksQ7K3oa\
+        hgDor1b8eiCJvi/eWplnq0yPuHdYIxGqhZANiAAQ4wL9i1Q945/WZuj4G6mQn1Dr/dPL9OdE47gMm9Nr\
+        zvtO7ZZIULJm29pSI5uqpk/Tyv06xbhPjJz8tDJqiIto4ojSs6Q2hFmnK8xBLdPfaGyAgW4x1l+kWyuK\
+        UVQmy0ig=";
+    const P384_PUBLIC_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MHYwEAYHKoZIzj0CAQYFK4EEACIDYgAEOMC/YtUPeOf1mbo+BupkJ9Q6/3Ty/TnR
+OO4DJvTa877Tu2WSFCyZtvaUiObqqZP08r9OsW4T4yc/LQyaoiLaOKI0rOkNoRZp
+yvMQS3T32hsgIFuMdZfpFsrilFUJstIo
+-----END PUBLIC KEY-----
+";
+    // Self signed certificate for P384_PUBLIC_KEY
+    const P384_CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----
+MIIBrzCCATagAwIBAgIUF6f8cCf7S2gMYX38bIH6ZXCbqOcwCgYIKoZIzj0EAwIw
+DzENMAsGA1UEAwwEdGVzdDAeFw0yNjEwMTkwMjIwMTdaFw0zNjEwMTYwMjIwMTda
+MA8xDTALBgNVBAMMBHRlc3QwdjAQBgcqhkjOPQIBBgUrgQQAIgNiAAQ4wL9i1Q94
+5/WZuj4G6mQn1Dr/dPL9OdE47gMm9NrzvtO7ZZIULJm29pSI5uqpk/Tyv06xbhPj
+Jz8tDJqiIto4ojSs6Q2hFmnK8xBLdPfaGyAgW4x1l+kWyuKUVQmy0iijUzBRMB0G
+A1UdDgQWBBS57vD0gX+iOLLtibMNqYkJQEz0LzAfBgNVHSMEGDAWgBS57vD0gX+i
+OLLtibMNqYkJQEz0LzAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA2cAMGQC
+MEGIf9Ege+31M0UfRjHKFnuQUawIM/vrIbMlknUSoMu42PgTj3nqBFDfxTBT3yOv
+7AIwXry5r+0aFCaO+sK60O+NNd5ETxfBEYWBg5eEPGzec1+WB1vcoC6nAEYjL3hj
+AOm7
+-----END CERTIFICATE-----
+";
+    const ED25519_PRIVATE_KEY: &str =
+        "MC4CAQAwBQYDK2VwBCIEILL1isg2gzmylzDOkJImDP8+3pfwnAuoZz0jIjQEYsnJ";
+    const ED25519_PUBLIC_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MCowBQYDK2VwAyEA2ZH4irFnnYDxDKbFLz1Wf6BamqZ9SAkpY0sccBt94r0=
+-----END PUBLIC KEY-----
+";
+    // A P-521 key, which we don't support
+    const P521_PUBLIC_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MIGbMBAGByqGSM49AgEGBSuBBAAjA4GGAAQANUYHnLrvNsMzKIALUUyrxplpOoB0
+oufAqzLvyRKpV7NRrU+L4/6VtqMgqcIn9JoG+/Vx49OmjQVuZW1PhF4dBgUBlYbz
+BPa4UcuQ6rK3Tkqzke0L6HcQzkDypnmR3hcQzvC/r+B9WbYOrl0wp9dEEDWJkLpG
+UnMsOcrf2cpD615QCqY=
+-----END PUBLIC KEY-----
+";
+
+    #[test]
+    fn parse_p256_public_key() {
+        let public_key = public_key_from_pem(&format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            DEFAULT_INGESTOR_SUBJECT_PUBLIC_KEY_INFO
+        ))
+        .unwrap();
+        let content = b"some content";
+        let signature = default_ingestor_private_key()
+            .key
+            .sign(&SystemRandom::new(), content)
+            .unwrap();
+        public_key.verify(content, signature.as_ref()).unwrap();
+
+        // The same key with its length encoded in long form is BER but not
+        // DER, and should be rejected.
+        let der = base64::decode(DEFAULT_INGESTOR_SUBJECT_PUBLIC_KEY_INFO).unwrap();
+        let mut long_form = vec![0x30, 0x81];
+        long_form.extend_from_slice(&der[1..]);
+        assert!(public_key_from_spki_der(&long_form).is_err());
+        let mut padded_long_form = vec![0x30, 0x82, 0x00];
+        padded_long_form.extend_from_slice(&der[1..]);
+        assert!(public_key_from_spki_der(&padded_long_form).is_err());
+    }
+
+    #[test]
+    fn parse_p384_public_key() {
+        let key_pair = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P384_SHA384_ASN1_SIGNING,
+            &base64::decode(P384_PRIVATE_KEY).unwrap(),
+        )
+        .unwrap();
+        let content = b"some content";
+        let signature = key_pair.sign(&SystemRandom::new(), content).unwrap();
+
+        for pem in &[P384_PUBLIC_KEY, P384_CERTIFICATE] {
+            let public_key = public_key_from_pem(pem).unwrap();
+            public_key.verify(content, signature.as_ref()).unwrap();
+            public_key
+                .verify(b"other content", signature.as_ref())
+                .unwrap_err();
+        }
+    }
+
+    #[test]
+    fn parse_ed25519_public_key() {
+        let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(
+            &base64::decode(ED25519_PRIVATE_KEY).unwrap(),
+        )
+        .unwrap();
+        let content = b"some content";
+        let signature = key_pair.sign(content);
+
+        let public_key = public_key_from_pem(ED25519_PUBLIC_KEY).unwrap();
+        public_key.verify(content, signature.as_ref()).unwrap();
+        public_key
+            .verify(b"other content", signature.as_ref())
+            .unwrap_err();
+    }
+
+    #[test]
+    fn parse_unsupported_public_keys() {
+        // Unsupported curve
+        assert!(public_key_from_pem(P521_PUBLIC_KEY).is_err());
+
+        let der = base64::decode(DEFAULT_INGESTOR_SUBJECT_PUBLIC_KEY_INFO).unwrap();
+        // Trailing garbage after the SubjectPublicKeyInfo
+        let mut trailing = der.clone();
+        trailing.push(0);
+        assert!(public_key_from_spki_der(&trailing).is_err());
+        // Truncated SubjectPublicKeyInfo
+        assert!(public_key_from_spki_der(&der[..der.len() - 1]).is_err());
+        // Indefinite length
+        let mut indefinite = der.clone();
+        indefinite[1] = 0x80;
+        assert!(public_key_from_spki_der(&indefinite).is_err());
+    }
+
     #[test]
     fn load_ingestor_global_manifest() {
         let manifest_with_aws_identity = r#"
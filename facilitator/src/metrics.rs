@@ -0,0 +1,575 @@
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as FmtWrite,
+    fs::{rename, File},
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Content type of the Prometheus text exposition format.
+/// https://github.com/prometheus/docs/blob/master/content/docs/instrumenting/exposition_formats.md
+pub const TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Upper bounds, in seconds, of the buckets of duration histograms. Batches
+/// can take anywhere from milliseconds to many minutes to process.
+const DURATION_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 600.0, 1800.0];
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Counter,
+    Histogram(&'static [f64]),
+}
+
+enum Series {
+    Counter(f64),
+    Histogram {
+        bucket_counts: Vec<u64>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    series: BTreeMap<Labels, Series>,
+}
+
+/// A Registry holds the values of a set of metrics, and renders them in the
+/// Prometheus text format. Clones of a Registry share the same metrics.
+#[derive(Clone, Default)]
+pub struct Registry {
+    families: Arc<Mutex<BTreeMap<&'static str, Family>>>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// Returns a handle to the counter with the provided name and labels,
+    /// creating it with value 0 if it does not yet exist. Fails if name is
+    /// already registered as a histogram.
+    pub fn counter(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&'static str, &str)],
+    ) -> Result<Counter> {
+        let labels = owned_labels(labels);
+        self.register(name, help, Kind::Counter, &labels, || Series::Counter(0.0))?;
+        Ok(Counter {
+            registry: self.clone(),
+            name,
+            labels,
+        })
+    }
+
+    /// Returns a handle to the histogram of durations with the provided name
+    /// and labels, creating it if it does not yet exist. Fails if name is
+    /// already registered as a counter.
+    pub fn duration_histogram(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&'static str, &str)],
+    ) -> Result<Histogram> {
+        let labels = owned_labels(labels);
+        self.register(
+            name,
+            help,
+            Kind::Histogram(DURATION_BUCKETS),
+            &labels,
+            || Series::Histogram {
+                bucket_counts: vec![0; DURATION_BUCKETS.len()],
+                sum: 0.0,
+                count: 0,
+            },
+        )?;
+        Ok(Histogram {
+            registry: self.clone(),
+            name,
+            labels,
+        })
+    }
+
+    fn register<F: FnOnce() -> Series>(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        kind: Kind,
+        labels: &[(&'static str, String)],
+        initial: F,
+    ) -> Result<()> {
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(name).or_insert_with(|| Family {
+            help,
+            kind,
+            series: BTreeMap::new(),
+        });
+        match (&family.kind, &kind) {
+            (Kind::Counter, Kind::Counter) | (Kind::Histogram(_), Kind::Histogram(_)) => (),
+            _ => {
+                return Err(anyhow!(
+                    "metric {} is already registered as a different type",
+                    name
+                ))
+            }
+        }
+        family.series.entry(labels.to_vec()).or_insert_with(initial);
+        Ok(())
+    }
+
+    fn update<F: FnOnce(&mut Series)>(&self, name: &str, labels: &[(&'static str, String)], f: F) {
+        let mut families = self.families.lock().unwrap();
+        if let Some(series) = families
+            .get_mut(name)
+            .and_then(|family| family.series.get_mut(labels))
+        {
+            f(series);
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap();
+        let mut out = String::new();
+        for (name, family) in families.iter() {
+            let type_name = match family.kind {
+                Kind::Counter => "counter",
+                Kind::Histogram(_) => "histogram",
+            };
+            // Writing to a String cannot fail.
+            writeln!(out, "# HELP {} {}", name, family.help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, type_name).unwrap();
+            for (labels, series) in family.series.iter() {
+                match (series, &family.kind) {
+                    (Series::Counter(value), Kind::Counter) => {
+                        writeln!(out, "{}{} {}", name, format_labels(labels, None), value).unwrap();
+                    }
+                    (
+                        Series::Histogram {
+                            bucket_counts,
+                            sum,
+                            count,
+                        },
+                        Kind::Histogram(bounds),
+                    ) => {
+                        let mut cumulative = 0;
+                        for (bound, bucket_count) in bounds.iter().zip(bucket_counts) {
+                            cumulative += bucket_count;
+                            writeln!(
+                                out,
+                                "{}_bucket{} {}",
+                                name,
+                                format_labels(labels, Some(&bound.to_string())),
+                                cumulative
+                            )
+                            .unwrap();
+                        }
+                        writeln!(
+                            out,
+                            "{}_bucket{} {}",
+                            name,
+                            format_labels(labels, Some("+Inf")),
+                            count
+                        )
+                        .unwrap();
+                        writeln!(out, "{}_sum{} {}", name, format_labels(labels, None), sum)
+                            .unwrap();
+                        writeln!(
+                            out,
+                            "{}_count{} {}",
+                            name,
+                            format_labels(labels, None),
+                            count
+                        )
+                        .unwrap();
+                    }
+                    // register never mixes series of different kinds in
+                    // a family.
+                    _ => (),
+                }
+            }
+        }
+        out
+    }
+
+    /// Pushes all metrics to a Prometheus Pushgateway at gateway_url, replacing
+    /// any metrics previously pushed with the same job name and grouping
+    /// labels.
+    /// https://github.com/prometheus/pushgateway#api
+    pub fn push(&self, gateway_url: &str, job: &str, grouping: &[(&str, &str)]) -> Result<()> {
+        let mut url = format!(
+            "{}/metrics/job/{}",
+            gateway_url.trim_end_matches('/'),
+            urlencoding::encode(job)
+        );
+        for (label, value) in grouping {
+            url.push_str(&format!("/{}/{}", label, urlencoding::encode(value)));
+        }
+        let response = ureq::put(&url)
+            .set("Content-Type", TEXT_CONTENT_TYPE)
+            // By default, ureq will wait forever to connect or read
+            .timeout_connect(10_000) // ten seconds
+            .timeout_read(10_000) // ten seconds
+            .send_string(&self.render());
+        if response.error() {
+            return Err(anyhow!("failed to push metrics to {}: {:?}", url, response));
+        }
+        Ok(())
+    }
+
+    /// Writes all metrics to the file at path, for consumption by the node
+    /// exporter's textfile collector. The metrics are written to a temporary
+    /// file which is then renamed, so the collector never sees a partial file.
+    pub fn write_textfile(&self, path: &Path) -> Result<()> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let mut file = File::create(&temp_path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        file.write_all(self.render().as_bytes())
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        rename(&temp_path, path)
+            .with_context(|| format!("failed to rename metrics file to {}", path.display()))
+    }
+}
+
+fn owned_labels(labels: &[(&'static str, &str)]) -> Labels {
+    labels
+        .iter()
+        .map(|(name, value)| (*name, (*value).to_owned()))
+        .collect()
+}
+
+fn format_labels(labels: &[(&'static str, String)], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// A monotonically increasing value in a Registry.
+#[derive(Clone)]
+pub struct Counter {
+    registry: Registry,
+    name: &'static str,
+    labels: Labels,
+}
+
+impl Counter {
+    pub fn inc(&self) {
+        self.inc_by(1.0);
+    }
+
+    pub fn inc_by(&self, amount: f64) {
+        self.registry.update(self.name, &self.labels, |series| {
+            if let Series::Counter(value) = series {
+                *value += amount;
+            }
+        });
+    }
+}
+
+/// A distribution of observed durations in a Registry.
+#[derive(Clone)]
+pub struct Histogram {
+    registry: Registry,
+    name: &'static str,
+    labels: Labels,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        self.registry.update(self.name, &self.labels, |series| {
+            if let Series::Histogram {
+                bucket_counts,
+                sum,
+                count,
+            } = series
+            {
+                if let Some(bucket) = DURATION_BUCKETS.iter().position(|bound| seconds <= *bound) {
+                    bucket_counts[bucket] += 1;
+                }
+                *sum += seconds;
+                *count += 1;
+            }
+        });
+    }
+
+    /// Records the time elapsed since start.
+    pub fn observe_since(&self, start: Instant) {
+        self.observe(start.elapsed());
+    }
+}
+
+/// The metrics recorded by one stage of batch processing, e.g. intake or
+/// aggregation.
+#[derive(Clone)]
+pub struct StageMetrics {
+    /// Number of batches the stage finished processing.
+    pub batches: Counter,
+    /// Number of packets the stage processed.
+    pub packets: Counter,
+    /// Number of packets found to be invalid, for stages which validate
+    /// packets.
+    pub invalid_packets: Option<Counter>,
+    /// How long the stage took for each batch it processed.
+    pub duration: Histogram,
+}
+
+impl StageMetrics {
+    pub fn new(registry: &Registry, stage: &str) -> Result<StageMetrics> {
+        let labels = &[("stage", stage)];
+        Ok(StageMetrics {
+            batches: registry.counter(
+                "facilitator_batches_total",
+                "Number of batches processed",
+                labels,
+            )?,
+            packets: registry.counter(
+                "facilitator_packets_total",
+                "Number of packets processed",
+                labels,
+            )?,
+            invalid_packets: None,
+            duration: registry.duration_histogram(
+                "facilitator_stage_duration_seconds",
+                "Time taken to process a batch",
+                labels,
+            )?,
+        })
+    }
+
+    /// Creates metrics for a stage which validates packets, and so also counts
+    /// the invalid ones.
+    pub fn validating(registry: &Registry, stage: &str) -> Result<StageMetrics> {
+        Ok(StageMetrics {
+            invalid_packets: Some(registry.counter(
+                "facilitator_invalid_packets_total",
+                "Number of packets that failed validation",
+                &[("stage", stage)],
+            )?),
+            ..StageMetrics::new(registry, stage)?
+        })
+    }
+}
+
+impl Default for StageMetrics {
+    /// Returns metrics recorded into a registry of their own, which is never
+    /// exported.
+    fn default() -> Self {
+        // A new registry cannot hold conflicting metrics.
+        StageMetrics::new(&Registry::new(), "unknown").unwrap()
+    }
+}
+
+/// Starts a thread serving the metrics in registry over HTTP at /metrics on
+/// the provided address, and returns the address it is bound to.
+pub fn start_metrics_server(registry: Registry, address: &str) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(address)
+        .with_context(|| format!("failed to bind metrics server to {}", address))?;
+    let local_address = listener.local_addr()?;
+    thread::spawn(move || {
+        // A failure to accept or serve one scrape should not stop the server.
+        for stream in listener.incoming().flatten() {
+            let _ = serve_metrics_request(&registry, stream);
+        }
+    });
+    Ok(local_address)
+}
+
+fn serve_metrics_request(registry: &Registry, mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the request headers, which we don't need.
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", TEXT_CONTENT_TYPE, registry.render()),
+        (Some("GET"), Some(_)) => ("404 Not Found", "text/plain", "not found\n".to_owned()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".to_owned(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{mock, Matcher};
+
+    #[test]
+    fn render_metrics() {
+        let registry = Registry::new();
+        let counter = registry
+            .counter(
+                "test_counter_total",
+                "A counter",
+                &[("transport", "s3"), ("direction", "read")],
+            )
+            .unwrap();
+        counter.inc();
+        counter.inc_by(2.0);
+        registry
+            .counter("test_counter_total", "A counter", &[("transport", "gcs")])
+            .unwrap();
+        let histogram = registry
+            .duration_histogram("test_duration_seconds", "A histogram", &[])
+            .unwrap();
+        histogram.observe(Duration::from_millis(300));
+        histogram.observe(Duration::from_secs(7200));
+
+        let rendered = registry.render();
+        let expected = "\
+# HELP test_counter_total A counter
+# TYPE test_counter_total counter
+test_counter_total{transport=\"gcs\"} 0
+test_counter_total{transport=\"s3\",direction=\"read\"} 3
+# HELP test_duration_seconds A histogram
+# TYPE test_duration_seconds histogram
+test_duration_seconds_bucket{le=\"0.1\"} 0
+test_duration_seconds_bucket{le=\"0.5\"} 1
+";
+        assert!(
+            rendered.starts_with(expected),
+            "unexpected rendering:\n{}",
+            rendered
+        );
+        assert!(rendered.contains("test_duration_seconds_bucket{le=\"1800\"} 1\n"));
+        assert!(rendered.contains("test_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(rendered.contains("test_duration_seconds_sum 7200.3\n"));
+        assert!(rendered.contains("test_duration_seconds_count 2\n"));
+    }
+
+    #[test]
+    fn conflicting_metric_types() {
+        let registry = Registry::new();
+        registry.counter("test_total", "help", &[]).unwrap();
+        assert!(registry
+            .duration_histogram("test_total", "help", &[("stage", "intake")])
+            .is_err());
+        registry
+            .duration_histogram("test_seconds", "help", &[])
+            .unwrap();
+        assert!(registry.counter("test_seconds", "help", &[]).is_err());
+        assert!(registry.render().contains("test_total 0\n"));
+    }
+
+    #[test]
+    fn escape_labels() {
+        let registry = Registry::new();
+        registry
+            .counter("test_total", "help", &[("key", "a\"b\\c\nd")])
+            .unwrap();
+        assert!(registry
+            .render()
+            .contains("test_total{key=\"a\\\"b\\\\c\\nd\"} 0\n"));
+    }
+
+    #[test]
+    fn push_metrics() {
+        let registry = Registry::new();
+        registry
+            .counter("test_total", "help", &[("stage", "intake")])
+            .unwrap()
+            .inc();
+
+        let mocked_put = mock("PUT", "/metrics/job/facilitator/subcommand/intake-batch")
+            .match_header("Content-Type", TEXT_CONTENT_TYPE)
+            .match_body(Matcher::Regex(
+                "test_total\\{stage=\"intake\"\\} 1".to_owned(),
+            ))
+            .with_status(200)
+            .expect(1)
+            .create();
+        registry
+            .push(
+                &format!("{}/", mockito::server_url()),
+                "facilitator",
+                &[("subcommand", "intake-batch")],
+            )
+            .unwrap();
+        mocked_put.assert();
+
+        let mocked_put = mock("PUT", "/metrics/job/failing")
+            .with_status(500)
+            .create();
+        registry
+            .push(&mockito::server_url(), "failing", &[])
+            .unwrap_err();
+        mocked_put.assert();
+    }
+
+    #[test]
+    fn write_textfile() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let path = tempdir.path().join("facilitator.prom");
+        let registry = Registry::new();
+        registry.counter("test_total", "help", &[]).unwrap().inc();
+
+        registry.write_textfile(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), registry.render());
+        assert!(!tempdir.path().join("facilitator.prom.tmp").exists());
+    }
+
+    #[test]
+    fn metrics_server() {
+        let registry = Registry::new();
+        registry
+            .counter("test_total", "help", &[])
+            .unwrap()
+            .inc_by(5.0);
+        let address = start_metrics_server(registry, "127.0.0.1:0").unwrap();
+
+        let response = ureq::get(&format!("http://{}/metrics", address)).call();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.header("Content-Type"), Some(TEXT_CONTENT_TYPE));
+        assert!(response.into_string().unwrap().contains("test_total 5\n"));
+
+        let response = ureq::get(&format!("http://{}/other", address)).call();
+        assert_eq!(response.status(), 404);
+    }
+}
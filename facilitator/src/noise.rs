@@ -0,0 +1,196 @@
+//! Differential privacy noise added to sum parts. Noise is sampled exactly,
+//! with integer arithmetic only, following Canonne, Kamath and Steinke, "The
+//! Discrete Gaussian for Differential Privacy", https://arxiv.org/abs/2004.00010.
+//! Sampling with floating point arithmetic instead would give a distribution
+//! whose rounding artifacts could reveal the sum being hidden.
+
+use crate::Error;
+use prio::finite_field::{Field, MODULUS};
+use rand::Rng;
+
+/// Noise epsilons are rounded down to a multiple of 1 / EPSILON_DENOMINATOR,
+/// which only increases the noise added.
+const EPSILON_DENOMINATOR: u128 = 1 << 32;
+
+/// Returns the numerator of epsilon as a fraction over EPSILON_DENOMINATOR,
+/// rounded down, or None if epsilon is not a finite number between 2^-32 and
+/// 2^64.
+pub fn epsilon_numerator(epsilon: f64) -> Option<u128> {
+    // Written so that NaN fails the comparison too.
+    if epsilon >= 1.0 / EPSILON_DENOMINATOR as f64 && epsilon < 2f64.powi(64) {
+        Some((epsilon * EPSILON_DENOMINATOR as f64) as u128)
+    } else {
+        None
+    }
+}
+
+/// Adds noise drawn from the discrete Laplace distribution with scale
+/// sensitivity / epsilon to each element of sum, which makes it differentially
+/// private with parameter epsilon if each client changes it by at most
+/// sensitivity in L1 norm.
+pub fn add_discrete_laplace_noise<R: Rng>(
+    sum: &mut [Field],
+    sensitivity: i32,
+    epsilon: f64,
+    rng: &mut R,
+) -> Result<(), Error> {
+    let numerator = epsilon_numerator(epsilon).ok_or_else(|| {
+        Error::ConfigurationError(format!(
+            "sum noise epsilon {} must be between 2^-32 and 2^64",
+            epsilon
+        ))
+    })?;
+    if sensitivity <= 0 {
+        // Without sensitivity there is nothing to hide.
+        return Ok(());
+    }
+    // The scale sensitivity / epsilon is t / s.
+    let t = sensitivity as u128 * EPSILON_DENOMINATOR;
+    let s = numerator;
+    for element in sum.iter_mut() {
+        let (negative, magnitude) = sample_discrete_laplace(t, s, rng);
+        let magnitude = Field::from((magnitude % MODULUS as u128) as u32);
+        if negative {
+            *element -= magnitude;
+        } else {
+            *element += magnitude;
+        }
+    }
+    Ok(())
+}
+
+/// Samples from the discrete Laplace distribution with scale t / s, whose
+/// probability of y is proportional to exp(-|y| s / t), returning whether the
+/// sample is negative and its magnitude. This is algorithm 2 of Canonne et al.
+fn sample_discrete_laplace<R: Rng>(t: u128, s: u128, rng: &mut R) -> (bool, u128) {
+    loop {
+        let u = rng.gen_range(0, t);
+        if !sample_bernoulli_exp(u, t, rng) {
+            continue;
+        }
+        let mut v = 0;
+        while sample_bernoulli_exp(1, 1, rng) {
+            v += 1;
+        }
+        let y = (u + t * v) / s;
+        let negative = rng.gen::<bool>();
+        // Zero would otherwise be sampled twice as often as it should.
+        if negative && y == 0 {
+            continue;
+        }
+        return (negative, y);
+    }
+}
+
+/// Samples true with probability exp(-n / d), for d > 0. This is algorithm 1
+/// of Canonne et al.
+fn sample_bernoulli_exp<R: Rng>(n: u128, d: u128, rng: &mut R) -> bool {
+    // exp(-n / d) is exp(-1) to the power of the integer part of n / d, times
+    // exp of minus the fractional part.
+    for _ in 0..n / d {
+        if !sample_bernoulli_exp_at_most_one(1, 1, rng) {
+            return false;
+        }
+    }
+    sample_bernoulli_exp_at_most_one(n % d, d, rng)
+}
+
+/// Samples true with probability exp(-n / d), for n <= d.
+fn sample_bernoulli_exp_at_most_one<R: Rng>(n: u128, d: u128, rng: &mut R) -> bool {
+    // The index of the first k for which a Bernoulli(n / (d k)) trial fails is
+    // odd with probability exp(-n / d).
+    let mut k = 1;
+    while rng.gen_range(0, d * k) < n {
+        k += 1;
+    }
+    k % 2 == 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn bernoulli_exp() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let trials = 100_000;
+        for (n, d) in &[(0, 1), (1, 3), (1, 1), (5, 2)] {
+            let successes = (0..trials)
+                .filter(|_| sample_bernoulli_exp(*n, *d, &mut rng))
+                .count();
+            let p = (-(*n as f64) / *d as f64).exp();
+            // Within five standard deviations.
+            let tolerance = 5.0 * (p * (1.0 - p) / trials as f64).sqrt();
+            let frequency = successes as f64 / trials as f64;
+            assert!(
+                (frequency - p).abs() <= tolerance,
+                "exp(-{}/{}): frequency {}, expected {}",
+                n,
+                d,
+                frequency,
+                p
+            );
+        }
+    }
+
+    #[test]
+    fn discrete_laplace_pmf() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let samples = 100_000;
+        // Scale sensitivity / epsilon = 2.
+        let mut sum = vec![Field::from(0); samples];
+        add_discrete_laplace_noise(&mut sum, 1, 0.5, &mut rng).unwrap();
+
+        // Map field elements back to the integers nearest zero, and count
+        // those in [-10, 10], lumping the rest into a tail bucket.
+        let mut counts = [0u64; 22];
+        for element in sum {
+            let n = u32::from(element) as i64;
+            let n = if n > MODULUS as i64 / 2 {
+                n - MODULUS as i64
+            } else {
+                n
+            };
+            let bucket = if n.abs() <= 10 { (n + 10) as usize } else { 21 };
+            counts[bucket] += 1;
+        }
+
+        // Pr(y) = (1 - q) / (1 + q) q^|y| with q = exp(-epsilon / sensitivity).
+        let q = (-0.5f64).exp();
+        let pmf = |y: i64| (1.0 - q) / (1.0 + q) * q.powi(y.abs() as i32);
+        let mut expected: Vec<f64> = (-10..=10).map(pmf).collect();
+        expected.push(1.0 - expected.iter().sum::<f64>());
+
+        // Pearson's chi-squared statistic, over 21 degrees of freedom, exceeds
+        // 46.8 with probability 0.001.
+        let chi_squared: f64 = counts
+            .iter()
+            .zip(&expected)
+            .map(|(count, p)| {
+                let expected_count = p * samples as f64;
+                (*count as f64 - expected_count).powi(2) / expected_count
+            })
+            .sum();
+        assert!(chi_squared < 46.8, "chi squared {}", chi_squared);
+    }
+
+    #[test]
+    fn noise_parameters() {
+        let mut rng = StdRng::seed_from_u64(1);
+
+        // Without sensitivity there is nothing to hide.
+        let mut sum = vec![Field::from(5); 10];
+        add_discrete_laplace_noise(&mut sum, 0, 0.5, &mut rng).unwrap();
+        assert_eq!(sum, vec![Field::from(5); 10]);
+
+        assert_eq!(epsilon_numerator(0.5), Some(1 << 31));
+        assert_eq!(epsilon_numerator(3.0), Some(3 << 32));
+        for epsilon in &[0.0, -1.0, 1e-10, 1e20, f64::NAN, f64::INFINITY] {
+            assert!(matches!(
+                add_discrete_laplace_noise(&mut sum, 1, *epsilon, &mut rng),
+                Err(Error::ConfigurationError(_))
+            ));
+        }
+    }
+}
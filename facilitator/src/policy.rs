@@ -0,0 +1,442 @@
+use crate::{idl::IngestionHeader, noise::epsilon_numerator, Error};
+use anyhow::{Context, Result};
+use chrono::Duration;
+use prio::finite_field::MODULUS;
+use serde::Deserialize;
+use std::{collections::HashMap, fmt::Display, fs, path::Path};
+
+/// Limits on the parameters of the ingestion batches in an aggregation, which
+/// BatchIntaker and BatchAggregator check before processing a batch. Each limit
+/// is optional, except the prime, which defaults to libprio's modulus.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AggregationPolicy {
+    /// Smallest allowed number of bins.
+    pub min_bins: Option<i32>,
+    /// Largest allowed number of bins.
+    pub max_bins: Option<i32>,
+    /// The prime that the field the shares are in must be defined by. If
+    /// None, libprio's modulus is required.
+    pub prime: Option<i64>,
+    /// Smallest allowed differential privacy parameter.
+    pub min_epsilon: Option<f64>,
+    /// Largest allowed differential privacy parameter.
+    pub max_epsilon: Option<f64>,
+    /// The hamming weight batches must declare.
+    pub hamming_weight: Option<i32>,
+    /// Largest allowed number of packets in a batch.
+    pub max_packets: Option<u64>,
+    /// How far, in seconds, a batch's time range may lie outside the date in
+    /// its path.
+    pub max_batch_time_skew: Option<i64>,
+    /// Smallest number of valid packets a sum part may be computed over. A
+    /// sum over fewer is withheld.
+    pub min_contributions: Option<u64>,
+    /// Differential privacy parameter of the noise each server adds to its
+    /// share of the sum. No noise is added if None.
+    pub sum_noise_epsilon: Option<f64>,
+    /// Whether servers exchange the sets of packets they found invalid before
+    /// writing sum parts, so that each excludes those any of them found
+    /// invalid.
+    pub cross_check_invalid_uuids: Option<bool>,
+}
+
+impl AggregationPolicy {
+    /// Returns this policy with any limit it leaves unset taken from other.
+    pub fn or(self, other: &AggregationPolicy) -> AggregationPolicy {
+        AggregationPolicy {
+            min_bins: self.min_bins.or(other.min_bins),
+            max_bins: self.max_bins.or(other.max_bins),
+            prime: self.prime.or(other.prime),
+            min_epsilon: self.min_epsilon.or(other.min_epsilon),
+            max_epsilon: self.max_epsilon.or(other.max_epsilon),
+            hamming_weight: self.hamming_weight.or(other.hamming_weight),
+            max_packets: self.max_packets.or(other.max_packets),
+            max_batch_time_skew: self.max_batch_time_skew.or(other.max_batch_time_skew),
+            min_contributions: self.min_contributions.or(other.min_contributions),
+            sum_noise_epsilon: self.sum_noise_epsilon.or(other.sum_noise_epsilon),
+            cross_check_invalid_uuids: self
+                .cross_check_invalid_uuids
+                .or(other.cross_check_invalid_uuids),
+        }
+    }
+
+    /// Checks that the policy for the named aggregation can be applied,
+    /// returning an Error::ConfigurationError if not.
+    pub fn validate(&self, aggregation: &str) -> Result<(), Error> {
+        if let Some(epsilon) = self.sum_noise_epsilon {
+            if epsilon_numerator(epsilon).is_none() {
+                return Err(Error::ConfigurationError(format!(
+                    "sum-noise-epsilon {} for aggregation {} must be between 2^-32 and 2^64",
+                    epsilon, aggregation
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the parameters in the provided ingestion header against this
+    /// policy, returning an Error::PolicyViolationError naming the first that
+    /// violates it.
+    pub fn check_header(&self, header: &IngestionHeader) -> Result<(), Error> {
+        let violation = |parameter, value: &dyn Display, requirement: String| {
+            Err(Error::PolicyViolationError {
+                aggregation: header.name.clone(),
+                parameter,
+                value: value.to_string(),
+                requirement,
+            })
+        };
+        if let Some(min_bins) = self.min_bins {
+            if header.bins < min_bins {
+                return violation("bins", &header.bins, format!("at least {}", min_bins));
+            }
+        }
+        if let Some(max_bins) = self.max_bins {
+            if header.bins > max_bins {
+                return violation("bins", &header.bins, format!("at most {}", max_bins));
+            }
+        }
+        let prime = self.prime.unwrap_or(MODULUS as i64);
+        if header.prime != prime {
+            return violation("prime", &header.prime, format!("equal to {}", prime));
+        }
+        if let Some(min_epsilon) = self.min_epsilon {
+            if header.epsilon.is_nan() || header.epsilon < min_epsilon {
+                return violation(
+                    "epsilon",
+                    &header.epsilon,
+                    format!("at least {}", min_epsilon),
+                );
+            }
+        }
+        if let Some(max_epsilon) = self.max_epsilon {
+            if header.epsilon.is_nan() || header.epsilon > max_epsilon {
+                return violation(
+                    "epsilon",
+                    &header.epsilon,
+                    format!("at most {}", max_epsilon),
+                );
+            }
+        }
+        if let Some(hamming_weight) = self.hamming_weight {
+            if header.hamming_weight != Some(hamming_weight) {
+                return violation(
+                    "hamming_weight",
+                    &format!("{:?}", header.hamming_weight),
+                    format!("equal to {}", hamming_weight),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that a batch of the named aggregation with packet_count packets
+    /// so far does not exceed the policy's limit.
+    pub fn check_packet_count(&self, aggregation: &str, packet_count: u64) -> Result<(), Error> {
+        match self.max_packets {
+            Some(max_packets) if packet_count > max_packets => Err(Error::PolicyViolationError {
+                aggregation: aggregation.to_owned(),
+                parameter: "packet count",
+                value: packet_count.to_string(),
+                requirement: format!("at most {}", max_packets),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the reason a sum over valid_packets valid packets must be
+    /// withheld, or None if it may be written.
+    pub fn sum_withheld_reason(&self, valid_packets: u64) -> Option<String> {
+        match self.min_contributions {
+            Some(min_contributions) if valid_packets < min_contributions => Some(format!(
+                "{} valid contributions are fewer than the minimum of {}",
+                valid_packets, min_contributions
+            )),
+            _ => None,
+        }
+    }
+
+    /// Returns the tolerance with which to check the time ranges of batches:
+    /// the stricter of the policy's max_batch_time_skew and the provided one.
+    pub fn batch_time_tolerance(&self, tolerance: Option<Duration>) -> Option<Duration> {
+        let skew = self.max_batch_time_skew.map(Duration::seconds);
+        match (skew, tolerance) {
+            (Some(skew), Some(tolerance)) => Some(skew.min(tolerance)),
+            (skew, tolerance) => skew.or(tolerance),
+        }
+    }
+}
+
+/// The policies for a set of aggregations, as read from a TOML or YAML file
+/// with a table of limits per aggregation name under "aggregation" and a
+/// "default" table whose limits apply where an aggregation's don't, e.g.:
+///
+/// ```toml
+/// [default]
+/// max-packets = 100000
+///
+/// [aggregation.kittens-seen]
+/// min-bins = 10
+/// max-bins = 100
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AggregationPolicies {
+    default: AggregationPolicy,
+    aggregation: HashMap<String, AggregationPolicy>,
+}
+
+impl AggregationPolicies {
+    /// Reads policies from the file at path, parsed as YAML if its extension
+    /// is .yaml or .yml, or as TOML otherwise.
+    pub fn from_path(path: &Path) -> Result<AggregationPolicies> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read policy file {}", path.display()))?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => AggregationPolicies::from_yaml(&contents),
+            _ => AggregationPolicies::from_toml(&contents),
+        }
+        .with_context(|| format!("failed to parse policy file {}", path.display()))
+    }
+
+    pub fn from_toml(contents: &str) -> Result<AggregationPolicies> {
+        let policies: AggregationPolicies = toml::from_str(contents)?;
+        policies.validate()?;
+        Ok(policies)
+    }
+
+    pub fn from_yaml(contents: &str) -> Result<AggregationPolicies> {
+        let policies: AggregationPolicies = serde_yaml::from_str(contents)?;
+        policies.validate()?;
+        Ok(policies)
+    }
+
+    /// Checks that each aggregation's policy can be applied.
+    fn validate(&self) -> Result<(), Error> {
+        self.default.validate("default")?;
+        for aggregation_name in self.aggregation.keys() {
+            self.policy(aggregation_name).validate(aggregation_name)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the policy for the named aggregation.
+    pub fn policy(&self, aggregation_name: &str) -> AggregationPolicy {
+        match self.aggregation.get(aggregation_name) {
+            Some(policy) => policy.clone().or(&self.default),
+            None => self.default.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> IngestionHeader {
+        IngestionHeader {
+            batch_uuid: uuid::Uuid::new_v4(),
+            name: "kittens-seen".to_owned(),
+            bins: 50,
+            epsilon: 0.5,
+            prime: MODULUS as i64,
+            number_of_servers: 2,
+            hamming_weight: None,
+            batch_start_time: 0,
+            batch_end_time: 0,
+            packet_file_digest: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn parse_policies() {
+        let toml = r#"
+            [default]
+            max-packets = 100
+            max-epsilon = 1.0
+            min-contributions = 50
+            cross-check-invalid-uuids = true
+
+            [aggregation.kittens-seen]
+            min-bins = 10
+            max-bins = 100
+            max-epsilon = 2.0
+        "#;
+        let yaml = r#"
+            default:
+              max-packets: 100
+              max-epsilon: 1.0
+              min-contributions: 50
+              cross-check-invalid-uuids: true
+            aggregation:
+              kittens-seen:
+                min-bins: 10
+                max-bins: 100
+                max-epsilon: 2.0
+        "#;
+        let policies = AggregationPolicies::from_toml(toml).unwrap();
+        assert_eq!(policies, AggregationPolicies::from_yaml(yaml).unwrap());
+
+        assert_eq!(
+            policies.policy("kittens-seen"),
+            AggregationPolicy {
+                min_bins: Some(10),
+                max_bins: Some(100),
+                max_epsilon: Some(2.0),
+                max_packets: Some(100),
+                min_contributions: Some(50),
+                cross_check_invalid_uuids: Some(true),
+                ..AggregationPolicy::default()
+            }
+        );
+        assert_eq!(
+            policies.policy("puppies-seen"),
+            AggregationPolicy {
+                max_epsilon: Some(1.0),
+                max_packets: Some(100),
+                min_contributions: Some(50),
+                cross_check_invalid_uuids: Some(true),
+                ..AggregationPolicy::default()
+            }
+        );
+
+        AggregationPolicies::from_toml("[default]\nmax-kittens = 1").unwrap_err();
+
+        // Policies that can't be applied are rejected when they are loaded.
+        for toml in &[
+            "[default]\nsum-noise-epsilon = 0.0",
+            "[aggregation.kittens-seen]\nsum-noise-epsilon = -1.0",
+            "[aggregation.kittens-seen]\nsum-noise-epsilon = nan",
+        ] {
+            let err = AggregationPolicies::from_toml(toml).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::ConfigurationError(_))
+            ));
+        }
+        AggregationPolicies::from_toml("[default]\nsum-noise-epsilon = 0.5").unwrap();
+    }
+
+    #[test]
+    fn check_header() {
+        let policy = AggregationPolicy {
+            min_bins: Some(10),
+            max_bins: Some(100),
+            min_epsilon: Some(0.1),
+            max_epsilon: Some(1.0),
+            ..AggregationPolicy::default()
+        };
+        policy.check_header(&header()).unwrap();
+
+        let violation = |header: IngestionHeader, policy: &AggregationPolicy| match policy
+            .check_header(&header)
+            .unwrap_err()
+        {
+            Error::PolicyViolationError { parameter, .. } => parameter,
+            e => panic!("unexpected error {}", e),
+        };
+        assert_eq!(
+            violation(
+                IngestionHeader {
+                    bins: 5,
+                    ..header()
+                },
+                &policy
+            ),
+            "bins"
+        );
+        assert_eq!(
+            violation(
+                IngestionHeader {
+                    bins: 101,
+                    ..header()
+                },
+                &policy
+            ),
+            "bins"
+        );
+        assert_eq!(
+            violation(
+                IngestionHeader {
+                    epsilon: f64::NAN,
+                    ..header()
+                },
+                &policy
+            ),
+            "epsilon"
+        );
+        // Only libprio's prime is accepted unless the policy says otherwise.
+        assert_eq!(
+            violation(
+                IngestionHeader {
+                    prime: 17,
+                    ..header()
+                },
+                &policy
+            ),
+            "prime"
+        );
+        AggregationPolicy {
+            prime: Some(17),
+            ..AggregationPolicy::default()
+        }
+        .check_header(&IngestionHeader {
+            prime: 17,
+            ..header()
+        })
+        .unwrap();
+        assert_eq!(
+            violation(
+                header(),
+                &AggregationPolicy {
+                    hamming_weight: Some(1),
+                    ..AggregationPolicy::default()
+                }
+            ),
+            "hamming_weight"
+        );
+    }
+
+    #[test]
+    fn min_contributions() {
+        let policy = AggregationPolicy {
+            min_contributions: Some(10),
+            ..AggregationPolicy::default()
+        };
+        assert!(policy.sum_withheld_reason(9).is_some());
+        assert_eq!(policy.sum_withheld_reason(10), None);
+        assert_eq!(AggregationPolicy::default().sum_withheld_reason(0), None);
+    }
+
+    #[test]
+    fn packet_count_and_time_skew() {
+        let policy = AggregationPolicy {
+            max_packets: Some(10),
+            max_batch_time_skew: Some(60),
+            ..AggregationPolicy::default()
+        };
+        policy.check_packet_count("kittens-seen", 10).unwrap();
+        policy.check_packet_count("kittens-seen", 11).unwrap_err();
+        AggregationPolicy::default()
+            .check_packet_count("kittens-seen", u64::MAX)
+            .unwrap();
+
+        assert_eq!(
+            policy.batch_time_tolerance(None),
+            Some(Duration::seconds(60))
+        );
+        assert_eq!(
+            policy.batch_time_tolerance(Some(Duration::seconds(30))),
+            Some(Duration::seconds(30))
+        );
+        assert_eq!(
+            policy.batch_time_tolerance(Some(Duration::seconds(120))),
+            Some(Duration::seconds(60))
+        );
+        assert_eq!(
+            AggregationPolicy::default().batch_time_tolerance(None),
+            None
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use crate::{
+    batch::{Batch, BatchReader, BatchWriter},
+    idl::{InvalidPacket, Packet, SumPart},
+    logging::LogContext,
+    metrics::{Registry, StageMetrics},
+    report::StageReport,
+    transport::{SignableTransport, VerifiableTransport},
+    Error,
+};
+use anyhow::Result;
+use chrono::{Duration, NaiveDateTime};
+use log::info;
+use prio::finite_field::Field;
+use std::{collections::HashSet, time::Instant};
+use uuid::Uuid;
+
+/// Combines sum parts that one server wrote for several aggregation windows
+/// into a single sum part covering all of them.
+pub struct SumPartReducer<'a> {
+    server_index: usize,
+    aggregation_name: &'a str,
+    sum_part_transport: &'a mut VerifiableTransport,
+    reduction_transport: &'a mut SignableTransport,
+    batch_time_tolerance: Option<Duration>,
+    metrics: StageMetrics,
+    report: StageReport,
+}
+
+impl<'a> SumPartReducer<'a> {
+    pub fn new(
+        aggregation_name: &'a str,
+        server_index: usize,
+        sum_part_transport: &'a mut VerifiableTransport,
+        reduction_transport: &'a mut SignableTransport,
+    ) -> SumPartReducer<'a> {
+        SumPartReducer {
+            server_index,
+            aggregation_name,
+            sum_part_transport,
+            reduction_transport,
+            batch_time_tolerance: None,
+            metrics: StageMetrics::default(),
+            report: StageReport::default(),
+        }
+    }
+
+    /// Configures how far the time range in sum part headers may lie outside
+    /// the window in their path. If None, the time range is not checked.
+    pub fn set_batch_time_tolerance(&mut self, tolerance: Option<Duration>) {
+        self.batch_time_tolerance = tolerance;
+    }
+
+    /// Records this reducer's metrics in registry.
+    pub fn set_metrics(&mut self, registry: &Registry) -> Result<()> {
+        self.metrics = StageMetrics::new(registry, "reduce")?;
+        Ok(())
+    }
+
+    /// Returns the sum parts read and written by reduce.
+    pub fn report(&self) -> &StageReport {
+        &self.report
+    }
+
+    /// Reads the sum parts for the provided aggregation windows, each given as
+    /// its start and end, and writes a sum part covering the earliest start to
+    /// the latest end, whose sum and invalid packets are those of all of them.
+    /// The sum parts must have the same parameters and no batch may be counted
+    /// in more than one of them, or the error is an
+    /// Error::ParameterMismatchError. Since the output would overwrite an
+    /// input if its window were among the provided ones, e.g. if there is
+    /// only one, that is an Error::ConfigurationError, as is providing none.
+    pub fn reduce(&mut self, windows: &[(NaiveDateTime, NaiveDateTime)]) -> Result<()> {
+        let start = windows
+            .iter()
+            .map(|(start, _)| *start)
+            .min()
+            .ok_or_else(|| Error::ConfigurationError("no sum parts to reduce".to_owned()))?;
+        let end = windows.iter().map(|(_, end)| *end).max().unwrap();
+        if windows.contains(&(start, end)) {
+            return Err(Error::ConfigurationError(format!(
+                "reduced sum part for {} to {} would overwrite an input sum part",
+                start, end
+            ))
+            .into());
+        }
+        let _context = LogContext::new()
+            .aggregation_name(self.aggregation_name)
+            .date_range(&start, &end)
+            .role(self.server_index)
+            .enter();
+        info!("reducing {} sum parts", windows.len());
+
+        let mut reduced: Option<SumPart> = None;
+        let mut sum: Vec<Field> = Vec::new();
+        let mut batch_uuids: Vec<Uuid> = Vec::new();
+        let mut seen_batch_uuids = HashSet::new();
+        let mut invalid_uuids = Vec::new();
+
+        for (window_start, window_end) in windows {
+            let _context = LogContext::new()
+                .date_range(window_start, window_end)
+                .enter();
+            let timer = Instant::now();
+            let mut reader: BatchReader<'_, SumPart, InvalidPacket> = BatchReader::new(
+                Batch::new_sum(
+                    self.aggregation_name,
+                    window_start,
+                    window_end,
+                    self.server_index,
+                ),
+                &mut *self.sum_part_transport.transport,
+            );
+            reader.set_time_tolerance(self.batch_time_tolerance);
+            let sum_part = reader.header(&self.sum_part_transport.batch_signing_public_keys)?;
+
+            let part_sum = sum_part.sum().map_err(|e| {
+                Error::MalformedHeaderError(format!("sum part element not in field: {}", e))
+            })?;
+            if part_sum.len() != sum_part.bins as usize {
+                return Err(Error::MalformedHeaderError(format!(
+                    "sum part has {} elements but bins is {}",
+                    part_sum.len(),
+                    sum_part.bins
+                ))
+                .into());
+            }
+            for batch_uuid in &sum_part.batch_uuids {
+                if !seen_batch_uuids.insert(*batch_uuid) {
+                    return Err(Error::ParameterMismatchError(format!(
+                        "batch {} is counted in more than one sum part",
+                        batch_uuid
+                    ))
+                    .into());
+                }
+                batch_uuids.push(*batch_uuid);
+            }
+
+            invalid_uuids.extend(reader.invalid_uuids(&sum_part)?);
+            self.report.inputs.push(reader.report());
+
+            match &mut reduced {
+                None => {
+                    sum = part_sum;
+                    reduced = Some(sum_part);
+                }
+                Some(reduced) => {
+                    if !reduced.check_reduction_parameters(&sum_part) {
+                        return Err(Error::ParameterMismatchError(format!(
+                            "sum part for {} to {} has different parameters than the first",
+                            window_start, window_end
+                        ))
+                        .into());
+                    }
+                    for (element, part_element) in sum.iter_mut().zip(part_sum) {
+                        *element += part_element;
+                    }
+                    reduced.aggregation_start_time = reduced
+                        .aggregation_start_time
+                        .min(sum_part.aggregation_start_time);
+                    reduced.aggregation_end_time = reduced
+                        .aggregation_end_time
+                        .max(sum_part.aggregation_end_time);
+                    reduced.total_individual_clients += sum_part.total_individual_clients;
+                }
+            }
+            self.metrics.batches.inc();
+            self.metrics.duration.observe_since(timer);
+        }
+
+        let mut writer: BatchWriter<'_, SumPart, InvalidPacket> = BatchWriter::new(
+            Batch::new_sum(self.aggregation_name, &start, &end, self.server_index),
+            &mut *self.reduction_transport.transport,
+        );
+        writer.set_sign_batch_manifest(self.reduction_transport.sign_batch_manifest);
+        writer.set_packet_file_codec(self.reduction_transport.packet_file_codec);
+
+        let invalid_packet_count = invalid_uuids.len();
+        let invalid_packets_digest = writer.packet_file_writer(|packet_file_writer| {
+            for invalid_uuid in invalid_uuids {
+                InvalidPacket { uuid: invalid_uuid }.write(packet_file_writer)?
+            }
+            Ok(())
+        })?;
+
+        // reduced is Some since there is at least one window.
+        let reduced = SumPart {
+            batch_uuids,
+            sum: sum.iter().map(|f| u32::from(*f) as i64).collect(),
+            packet_file_digest: invalid_packets_digest.as_ref().to_vec(),
+            ..reduced.unwrap()
+        };
+        let signature = writer.put_header(&reduced, &*self.reduction_transport.batch_signer)?;
+        writer.put_signature(&signature, &*self.reduction_transport.batch_signer)?;
+        self.report.outputs.push(writer.report());
+        info!(
+            "wrote sum part over {} batches with {} invalid packets",
+            reduced.batch_uuids.len(),
+            invalid_packet_count
+        );
+        Ok(())
+    }
+}
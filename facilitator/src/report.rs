@@ -0,0 +1,194 @@
+use crate::transport::Transport;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use uuid::Uuid;
+
+/// Describes one batch read or written by a job: the keys of its objects and,
+/// as far as they were obtained, the digests of its header and packet file and
+/// the identifier of the key its signature was made with. Digests are base64
+/// encoded SHA-256.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BatchReport {
+    pub header_key: String,
+    pub packet_file_key: String,
+    pub signature_key: String,
+    pub header_digest: Option<String>,
+    pub packet_file_digest: Option<String>,
+    pub key_identifier: Option<String>,
+    /// The name of the ingestor that wrote the batch, if it is an ingestion
+    /// batch read from one of several named ingestors.
+    pub ingestor: Option<String>,
+}
+
+/// Numbers of packets processed by a job.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PacketCounts {
+    pub total: u64,
+    pub valid: u64,
+    pub invalid: u64,
+}
+
+/// What a BatchIntaker or BatchAggregator read and wrote.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StageReport {
+    pub inputs: Vec<BatchReport>,
+    pub outputs: Vec<BatchReport>,
+    pub packets: PacketCounts,
+    /// Why a BatchAggregator wrote no sum part, if it withheld one.
+    pub sum_part_withheld: Option<String>,
+    /// The UUIDs of batches a BatchAggregator left out of its sum part because
+    /// peer validations for them were not yet available.
+    #[serde(default)]
+    pub pending_batches: Vec<Uuid>,
+}
+
+/// When a job ran.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Timings {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub duration_seconds: Option<f64>,
+}
+
+/// The outcome of one facilitator subcommand, for consumption by the workflow
+/// manager and dashboards.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JobReport {
+    pub subcommand: String,
+    pub aggregation_name: Option<String>,
+    pub success: bool,
+    pub error: Option<String>,
+    /// Whether the error may go away if the job is retried. None if the job
+    /// succeeded.
+    pub retryable: Option<bool>,
+    #[serde(flatten)]
+    pub stage: StageReport,
+    pub timings: Timings,
+}
+
+impl JobReport {
+    /// Creates a report for a job starting now.
+    pub fn new(subcommand: &str, aggregation_name: Option<&str>) -> JobReport {
+        JobReport {
+            subcommand: subcommand.to_owned(),
+            aggregation_name: aggregation_name.map(str::to_owned),
+            success: false,
+            error: None,
+            retryable: None,
+            stage: StageReport::default(),
+            timings: Timings {
+                started_at: Utc::now(),
+                finished_at: None,
+                duration_seconds: None,
+            },
+        }
+    }
+
+    /// Records that the job finished now, with the provided result. Has no
+    /// effect if the job was already recorded as finished.
+    pub fn finish(&mut self, result: &Result<()>) {
+        if self.timings.finished_at.is_some() {
+            return;
+        }
+        let finished_at = Utc::now();
+        self.timings.duration_seconds = Some(
+            (finished_at - self.timings.started_at)
+                .to_std()
+                .map(|d| d.as_secs_f64())
+                .unwrap_or(0.0),
+        );
+        self.timings.finished_at = Some(finished_at);
+        match result {
+            Ok(()) => self.success = true,
+            Err(e) => {
+                self.error = Some(format!("{:?}", e));
+                self.retryable = Some(crate::is_retryable(e));
+            }
+        }
+    }
+
+    /// Writes the report as a single line of JSON.
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<()> {
+        serde_json::to_writer(&mut writer, self).context("failed to serialize job report")?;
+        writeln!(writer).context("failed to write job report")?;
+        Ok(())
+    }
+
+    /// Uploads the report to the object at key in transport.
+    pub fn upload(&self, transport: &mut dyn Transport, key: &str) -> Result<()> {
+        let mut writer = transport.put(key)?;
+        self.write(&mut writer)?;
+        writer.complete_upload()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{transport::LocalFileTransport, Error};
+    use anyhow::anyhow;
+    use std::io::Read;
+
+    #[test]
+    fn finish_job_report() {
+        let mut report = JobReport::new("aggregate", Some("fake-aggregation"));
+        report.stage.packets.total = 10;
+        report.finish(&Err(Error::ObjectNotFoundError("key".to_owned()).into()));
+        // A second call must not overwrite the first outcome.
+        report.finish(&Ok(()));
+        assert!(!report.success);
+        assert_eq!(report.retryable, Some(true));
+        assert_eq!(report.error, Some("object key not found".to_owned()));
+        assert!(report.timings.finished_at.is_some());
+
+        let mut report = JobReport::new("intake-batch", None);
+        report.finish(&Ok(()));
+        assert!(report.success);
+        assert_eq!(report.retryable, None);
+
+        let mut report = JobReport::new("intake-batch", None);
+        report.finish(&Err(anyhow!("something else")));
+        assert_eq!(report.retryable, Some(false));
+    }
+
+    #[test]
+    fn roundtrip_job_report() {
+        let mut report = JobReport::new("intake-batch", Some("fake-aggregation"));
+        report.stage.inputs.push(BatchReport {
+            header_key: "a/b.batch".to_owned(),
+            packet_file_key: "a/b.batch.avro".to_owned(),
+            signature_key: "a/b.batch.sig".to_owned(),
+            header_digest: Some("aGVhZGVy".to_owned()),
+            packet_file_digest: Some("cGFja2V0cw==".to_owned()),
+            key_identifier: Some("key-id".to_owned()),
+            ingestor: Some("fake-ingestor".to_owned()),
+        });
+        report.stage.packets = PacketCounts {
+            total: 3,
+            valid: 2,
+            invalid: 1,
+        };
+        report.finish(&Ok(()));
+        // Not every f64 survives a trip through JSON unchanged.
+        report.timings.duration_seconds = Some(1.5);
+
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        report.upload(&mut transport, "a/b.report.json").unwrap();
+
+        let mut serialized = String::new();
+        transport
+            .get("a/b.report.json")
+            .unwrap()
+            .read_to_string(&mut serialized)
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_str(&serialized).unwrap();
+        // Stage fields are flattened into the top level object.
+        assert_eq!(value["packets"]["invalid"], 1);
+        assert_eq!(value["inputs"][0]["key_identifier"], "key-id");
+        let report_again: JobReport = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(report, report_again);
+    }
+}
@@ -1,8 +1,8 @@
 use crate::{
     batch::{Batch, BatchWriter},
     idl::{IngestionDataSharePacket, IngestionHeader, Packet},
+    signing::BatchSigner,
     transport::Transport,
-    BatchSigningKey,
 };
 use anyhow::{anyhow, Context, Result};
 use chrono::NaiveDateTime;
@@ -12,9 +12,13 @@ use prio::{
     finite_field::{Field, MODULUS},
     server::Server,
 };
-use rand::{thread_rng, Rng};
+use rand::{seq::index, thread_rng, Rng};
 use uuid::Uuid;
 
+/// Writes an ingestion batch of packet_count random vectors of dim bits, shared
+/// between the PHA and facilitator, and returns their sum. If hamming_weight is
+/// given, each vector has exactly that many bits set, and the batch headers
+/// declare it.
 #[allow(clippy::too_many_arguments)] // Grandfathered in
 pub fn generate_ingestion_sample(
     pha_transport: &mut dyn Transport,
@@ -24,16 +28,25 @@ pub fn generate_ingestion_sample(
     date: &NaiveDateTime,
     pha_key: &PrivateKey,
     facilitator_key: &PrivateKey,
-    ingestor_signing_key: &BatchSigningKey,
+    ingestor_signer: &dyn BatchSigner,
     dim: i32,
     packet_count: usize,
     epsilon: f64,
     batch_start_time: i64,
     batch_end_time: i64,
+    hamming_weight: Option<i32>,
 ) -> Result<Vec<Field>> {
     if dim <= 0 {
         return Err(anyhow!("dimension must be an integer greater than zero"));
     }
+    if let Some(hamming_weight) = hamming_weight {
+        if hamming_weight < 0 || hamming_weight > dim {
+            return Err(anyhow!(
+                "hamming weight must be between zero and the dimension {}",
+                dim
+            ));
+        }
+    }
 
     let mut pha_ingestion_batch: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
         BatchWriter::new(
@@ -73,9 +86,24 @@ pub fn generate_ingestion_sample(
 
                     for _ in 0..packet_count {
                         // Generate random bit vector
-                        let data = (0..dim)
-                            .map(|_| Field::from(thread_rng.gen_range(0, 2)))
-                            .collect::<Vec<Field>>();
+                        let data = match hamming_weight {
+                            Some(hamming_weight) => {
+                                let mut data = vec![Field::from(0); dim as usize];
+                                for index in index::sample(
+                                    &mut thread_rng,
+                                    dim as usize,
+                                    hamming_weight as usize,
+                                )
+                                .into_iter()
+                                {
+                                    data[index] = Field::from(1);
+                                }
+                                data
+                            }
+                            None => (0..dim)
+                                .map(|_| Field::from(thread_rng.gen_range(0, 2)))
+                                .collect::<Vec<Field>>(),
+                        };
 
                         for (r, d) in reference_sum.iter_mut().zip(data.iter()) {
                             *r += *d
@@ -122,18 +150,16 @@ pub fn generate_ingestion_sample(
                     epsilon,
                     prime: MODULUS as i64,
                     number_of_servers: 2,
-                    hamming_weight: None,
+                    hamming_weight,
                     batch_start_time,
                     batch_end_time,
                     packet_file_digest: facilitator_packet_file_digest.as_ref().to_vec(),
                 },
-                &ingestor_signing_key.key,
+                ingestor_signer,
             )?;
 
-            Ok(facilitator_ingestion_batch.put_signature(
-                &facilitator_header_signature,
-                &ingestor_signing_key.identifier,
-            )?)
+            Ok(facilitator_ingestion_batch
+                .put_signature(&facilitator_header_signature, ingestor_signer)?)
         })?;
 
     let pha_header_signature = pha_ingestion_batch.put_header(
@@ -144,14 +170,14 @@ pub fn generate_ingestion_sample(
             epsilon,
             prime: MODULUS as i64,
             number_of_servers: 2,
-            hamming_weight: None,
+            hamming_weight,
             batch_start_time,
             batch_end_time,
             packet_file_digest: pha_packet_file_digest.as_ref().to_vec(),
         },
-        &ingestor_signing_key.key,
+        ingestor_signer,
     )?;
-    pha_ingestion_batch.put_signature(&pha_header_signature, &ingestor_signing_key.identifier)?;
+    pha_ingestion_batch.put_signature(&pha_header_signature, ingestor_signer)?;
     Ok(reference_sum)
 }
 
@@ -191,6 +217,7 @@ mod tests {
             0.11,
             100,
             100,
+            None,
         );
         assert!(res.is_ok(), "error writing sample data {:?}", res.err());
         let expected_path = format!("fake-aggregation/2009/02/13/23/31/{}.batch", batch_uuid);
@@ -218,4 +245,49 @@ mod tests {
             assert_eq!(parsed_header.batch_end_time, 100);
         }
     }
+
+    #[test]
+    fn write_sample_with_hamming_weight() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let batch_uuid = Uuid::new_v4();
+        let mut transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let mut generate = |hamming_weight| {
+            generate_ingestion_sample(
+                &mut transport,
+                &mut LocalFileTransport::new(tempdir.path().to_path_buf()),
+                &batch_uuid,
+                "fake-aggregation",
+                &NaiveDate::from_ymd(2009, 2, 13).and_hms(23, 31, 0),
+                &PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
+                &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+                &default_ingestor_private_key(),
+                10,
+                10,
+                0.11,
+                100,
+                100,
+                Some(hamming_weight),
+            )
+        };
+
+        let reference_sum = generate(3).unwrap();
+        let total = reference_sum
+            .iter()
+            .fold(Field::from(0), |total, bin| total + *bin);
+        assert_eq!(total, Field::from(30));
+
+        generate(-1).unwrap_err();
+        generate(11).unwrap_err();
+
+        let header = IngestionHeader::read(
+            transport
+                .get(&format!(
+                    "fake-aggregation/2009/02/13/23/31/{}.batch",
+                    batch_uuid
+                ))
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(header.hamming_weight, Some(3));
+    }
 }
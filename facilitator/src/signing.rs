@@ -0,0 +1,687 @@
+use crate::{
+    manifest::{BatchSigningPublicKeys, SignatureAlgorithm},
+    BatchSigningKey, Error,
+};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use ring::{
+    error::Unspecified,
+    rand::{SecureRandom, SystemRandom},
+    signature::{
+        EcdsaKeyPair, Ed25519KeyPair, KeyPair, Signature, UnparsedPublicKey,
+        ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P384_SHA384_ASN1_SIGNING,
+    },
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Message signed by a signer when checking that its key matches the public key
+/// published in our specific manifest.
+const MANIFEST_CHECK_MESSAGE: &[u8] = b"batch signing key manifest check";
+
+/// A BatchSigner produces signatures over batch headers on behalf of this data
+/// share processor. Implementations may hold the private key in memory or
+/// delegate signing to some other component so that the private key never has
+/// to enter the facilitator process.
+pub trait BatchSigner {
+    /// Returns the identifier of the key used by this signer, which must
+    /// correspond to a batch-signing-key in the data share processor's specific
+    /// manifest.
+    fn key_identifier(&self) -> &str;
+
+    /// Signs the provided message and returns the signature, in whatever
+    /// encoding is appropriate for the key's algorithm (e.g., ASN.1 DER for
+    /// ECDSA).
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// A private key with which batches may be signed, using any of the algorithms
+/// that batch signing public keys in manifests may use.
+pub enum SigningKeyPair {
+    EcdsaP256(EcdsaKeyPair),
+    EcdsaP384(EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+impl SigningKeyPair {
+    /// Parses a DER encoded PKCS#8 document, choosing the algorithm from the
+    /// key it holds.
+    pub fn from_pkcs8(der: &[u8]) -> Result<SigningKeyPair> {
+        if let Ok(key) = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, der) {
+            return Ok(SigningKeyPair::EcdsaP256(key));
+        }
+        if let Ok(key) = EcdsaKeyPair::from_pkcs8(&ECDSA_P384_SHA384_ASN1_SIGNING, der) {
+            return Ok(SigningKeyPair::EcdsaP384(key));
+        }
+        // OpenSSL writes version 1 PKCS#8 documents, which lack the public key
+        // that from_pkcs8 would check the private key against.
+        Ed25519KeyPair::from_pkcs8_maybe_unchecked(der)
+            .map(SigningKeyPair::Ed25519)
+            .map_err(|e| {
+                anyhow!(
+                    "failed to parse PKCS#8 private key as ECDSA P-256, ECDSA P-384 \
+                    or Ed25519: {}",
+                    e
+                )
+            })
+    }
+
+    /// Returns the algorithm of this key's signatures.
+    pub fn algorithm(&self) -> SignatureAlgorithm {
+        match self {
+            SigningKeyPair::EcdsaP256(_) => SignatureAlgorithm::EcdsaP256Sha256,
+            SigningKeyPair::EcdsaP384(_) => SignatureAlgorithm::EcdsaP384Sha384,
+            SigningKeyPair::Ed25519(_) => SignatureAlgorithm::Ed25519,
+        }
+    }
+
+    /// Signs message. ring only needs randomness for ECDSA signatures.
+    pub fn sign(&self, rng: &dyn SecureRandom, message: &[u8]) -> Result<Signature, Unspecified> {
+        match self {
+            SigningKeyPair::EcdsaP256(key) | SigningKeyPair::EcdsaP384(key) => {
+                key.sign(rng, message)
+            }
+            SigningKeyPair::Ed25519(key) => Ok(key.sign(message)),
+        }
+    }
+
+    /// Returns the public key with which this key's signatures are verified.
+    pub fn public_key(&self) -> UnparsedPublicKey<Vec<u8>> {
+        let public_key = match self {
+            SigningKeyPair::EcdsaP256(key) | SigningKeyPair::EcdsaP384(key) => {
+                key.public_key().as_ref().to_vec()
+            }
+            SigningKeyPair::Ed25519(key) => key.public_key().as_ref().to_vec(),
+        };
+        UnparsedPublicKey::new(self.algorithm().verification_algorithm(), public_key)
+    }
+}
+
+impl BatchSigner for BatchSigningKey {
+    fn key_identifier(&self) -> &str {
+        &self.identifier
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let signature = self
+            .key
+            .sign(&SystemRandom::new(), message)
+            .context("failed to sign message")?;
+        Ok(signature.as_ref().to_vec())
+    }
+}
+
+impl<S: BatchSigner + ?Sized> BatchSigner for Box<S> {
+    fn key_identifier(&self) -> &str {
+        (**self).key_identifier()
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        (**self).sign(message)
+    }
+}
+
+impl BatchSigningKey {
+    /// Constructs a BatchSigningKey from the provided PKCS#8 document, which
+    /// may be DER encoded, base64 encoded DER or PEM armored, holding an ECDSA
+    /// P-256, ECDSA P-384 or Ed25519 private key.
+    pub fn from_pkcs8(document: &[u8], identifier: &str) -> Result<BatchSigningKey> {
+        let der = if let Ok(pem) = pem::parse(document) {
+            if pem.tag != "PRIVATE KEY" {
+                return Err(anyhow!(
+                    "PEM block with tag {} is not a PKCS#8 private key",
+                    pem.tag
+                ));
+            }
+            pem.contents
+        } else if let Ok(decoded) = base64::decode(trim_ascii_whitespace(document)) {
+            decoded
+        } else {
+            document.to_vec()
+        };
+
+        Ok(BatchSigningKey {
+            key: SigningKeyPair::from_pkcs8(&der)?,
+            identifier: identifier.to_owned(),
+        })
+    }
+}
+
+fn trim_ascii_whitespace(bytes: &[u8]) -> &[u8] {
+    let start = bytes
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(bytes.len());
+    let end = bytes
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(start, |p| p + 1);
+    &bytes[start..end]
+}
+
+/// A BatchSigner that reads a PKCS#8 private key from a file, such as a
+/// Kubernetes secret mounted into the container. The file is read only once,
+/// when the signer is created, so that every batch is signed with the key that
+/// was checked against our specific manifest, even if the mounted secret is
+/// rotated while the process runs. The next job picks up the new key.
+pub struct FileBatchSigner {
+    key: BatchSigningKey,
+}
+
+impl FileBatchSigner {
+    /// Creates a FileBatchSigner with the key read from the provided path.
+    pub fn new(path: PathBuf, identifier: &str) -> Result<FileBatchSigner> {
+        let document = fs::read(&path)
+            .with_context(|| format!("failed to read signing key {}", path.display()))?;
+        let key = BatchSigningKey::from_pkcs8(&document, identifier)
+            .with_context(|| format!("invalid signing key in {}", path.display()))?;
+        Ok(FileBatchSigner { key })
+    }
+}
+
+impl BatchSigner for FileBatchSigner {
+    fn key_identifier(&self) -> &str {
+        self.key.key_identifier()
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        self.key.sign(message)
+    }
+}
+
+/// The body of a request to a remote signing service.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct SignRequest {
+    /// Identifier of the key the service should sign with.
+    key_identifier: String,
+    /// Base64 encoding of the message to be signed.
+    message: String,
+}
+
+/// The body of a remote signing service's response to a SignRequest.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct SignResponse {
+    /// Base64 encoding of the signature over the message.
+    signature: String,
+}
+
+/// A BatchSigner that delegates signing to an external service over HTTP, such
+/// as a sidecar container fronting a cloud KMS or an HSM. The service is
+/// expected to handle POST requests to `{base_url}/sign` whose JSON body
+/// contains the key identifier and the base64 encoded message, and respond with
+/// a JSON object containing the base64 encoded signature, e.g.:
+///
+///   request:  {"key_identifier": "key-1", "message": "aGVsbG8="}
+///   response: {"signature": "MEUCIQ..."}
+#[derive(Debug)]
+pub struct RemoteBatchSigner {
+    sign_url: String,
+    identifier: String,
+}
+
+impl RemoteBatchSigner {
+    /// Creates a RemoteBatchSigner that will request signatures with the
+    /// identified key from the service at base_url.
+    pub fn new(base_url: &str, identifier: &str) -> RemoteBatchSigner {
+        RemoteBatchSigner {
+            sign_url: format!("{}/sign", base_url.trim_end_matches('/')),
+            identifier: identifier.to_owned(),
+        }
+    }
+}
+
+impl BatchSigner for RemoteBatchSigner {
+    fn key_identifier(&self) -> &str {
+        &self.identifier
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let http_response = ureq::post(&self.sign_url)
+            // By default, ureq will wait forever to connect or read.
+            .timeout_connect(10_000) // ten seconds
+            .timeout_read(10_000) // ten seconds
+            .send_json(
+                serde_json::to_value(SignRequest {
+                    key_identifier: self.identifier.clone(),
+                    message: base64::encode(message),
+                })
+                .context("failed to encode signing request")?,
+            );
+        if http_response.synthetic() || http_response.server_error() {
+            // The service or the network may recover, so this is retryable.
+            return Err(Error::TransportError(
+                self.sign_url.clone(),
+                anyhow!("failed to get signature: {:?}", http_response).into(),
+            )
+            .into());
+        }
+        if http_response.error() {
+            return Err(anyhow!(
+                "failed to get signature from {}: {:?}",
+                self.sign_url,
+                http_response
+            ));
+        }
+
+        let response = http_response
+            .into_json_deserialize::<SignResponse>()
+            .context("failed to deserialize response from signing service")?;
+        base64::decode(&response.signature).context("signature from signing service is not base64")
+    }
+}
+
+/// Checks that the key used by the provided signer appears in the provided set
+/// of batch signing public keys, which should be taken from this data share
+/// processor's specific manifest, by signing a fixed message and verifying the
+/// signature with the published public key. Returns an error if the key is
+/// absent from the manifest or if the published public key does not match.
+pub fn check_signer_is_published(
+    signer: &dyn BatchSigner,
+    published_keys: &BatchSigningPublicKeys,
+) -> Result<()> {
+    let public_key = published_keys.get(signer.key_identifier()).ok_or_else(|| {
+        anyhow!(
+            "batch signing key {} is not yet published in specific manifest",
+            signer.key_identifier()
+        )
+    })?;
+    let signature = signer.sign(MANIFEST_CHECK_MESSAGE)?;
+    public_key
+        .verify(MANIFEST_CHECK_MESSAGE, &signature)
+        .map_err(|_| {
+            anyhow!(
+                "batch signing key {} does not match public key published in specific manifest",
+                signer.key_identifier()
+            )
+        })
+}
+
+/// A single entry in a batch signing key set file. Exactly one of private_key,
+/// private_key_file or signer_url must be provided.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct KeySetEntry {
+    /// Identifier of the key, matching an entry in batch-signing-public-keys
+    /// in our specific manifest.
+    identifier: String,
+    /// RFC 3339 timestamp from which on this key should be used for signing.
+    activation_time: DateTime<Utc>,
+    /// Base64 encoded PKCS#8 document containing the private key.
+    private_key: Option<String>,
+    /// Path to a file containing the private key, as for FileBatchSigner.
+    private_key_file: Option<PathBuf>,
+    /// Base URL of a remote signing service, as for RemoteBatchSigner.
+    signer_url: Option<String>,
+}
+
+/// A set of batch signing keys, each of which becomes active at some time. Key
+/// rotation is performed by adding a new key to the set with an activation
+/// time in the future, publishing its public key in our specific manifest so
+/// that peers trust it by the time it becomes active, and removing the old key
+/// from the manifest and the set once batches signed with it have drained.
+#[derive(Default)]
+pub struct BatchSigningKeySet {
+    keys: Vec<(DateTime<Utc>, Box<dyn BatchSigner>)>,
+}
+
+impl BatchSigningKeySet {
+    pub fn new() -> BatchSigningKeySet {
+        BatchSigningKeySet::default()
+    }
+
+    /// Adds a signer to the set, which will be used from activation_time until
+    /// the activation time of some other key.
+    pub fn add(&mut self, activation_time: DateTime<Utc>, signer: Box<dyn BatchSigner>) {
+        self.keys.push((activation_time, signer));
+    }
+
+    /// Loads a key set from a JSON document containing a list of objects, each
+    /// with an identifier, an activation-time and one of private-key,
+    /// private-key-file or signer-url, e.g.:
+    ///
+    ///   [
+    ///     {"identifier": "key-1", "activation-time": "2020-10-01T00:00:00Z",
+    ///      "private-key-file": "/secrets/key-1"},
+    ///     {"identifier": "key-2", "activation-time": "2020-11-01T00:00:00Z",
+    ///      "private-key-file": "/secrets/key-2"}
+    ///   ]
+    pub fn from_json(json: &[u8]) -> Result<BatchSigningKeySet> {
+        let entries: Vec<KeySetEntry> =
+            serde_json::from_slice(json).context("failed to decode JSON batch signing key set")?;
+        let mut key_set = BatchSigningKeySet::new();
+        for entry in entries {
+            let signer: Box<dyn BatchSigner> =
+                match (entry.private_key, entry.private_key_file, entry.signer_url) {
+                    (Some(key), None, None) => Box::new(BatchSigningKey::from_pkcs8(
+                        key.as_bytes(),
+                        &entry.identifier,
+                    )?),
+                    (None, Some(path), None) => {
+                        Box::new(FileBatchSigner::new(path, &entry.identifier)?)
+                    }
+                    (None, None, Some(url)) => {
+                        Box::new(RemoteBatchSigner::new(&url, &entry.identifier))
+                    }
+                    _ => {
+                        return Err(anyhow!(
+                            "key {} must have exactly one of private-key, \
+                            private-key-file or signer-url",
+                            entry.identifier
+                        ))
+                    }
+                };
+            key_set.add(entry.activation_time, signer);
+        }
+        Ok(key_set)
+    }
+
+    /// Loads a key set from a JSON file. See from_json for the format.
+    pub fn from_file(path: &Path) -> Result<BatchSigningKeySet> {
+        let json =
+            fs::read(path).with_context(|| format!("failed to read key set {}", path.display()))?;
+        BatchSigningKeySet::from_json(&json)
+    }
+
+    /// Selects the key that is active at the provided time, which is the one
+    /// with the latest activation time that is not after `now`, and checks
+    /// that it is published in our specific manifest. Returns an error rather
+    /// than falling back to an older key if the active key is not published,
+    /// since that indicates the rotation was not staged correctly.
+    pub fn into_active_signer(
+        self,
+        now: &DateTime<Utc>,
+        published_keys: &BatchSigningPublicKeys,
+    ) -> Result<Box<dyn BatchSigner>> {
+        let (_, signer) = self
+            .keys
+            .into_iter()
+            .filter(|(activation_time, _)| activation_time <= now)
+            .max_by_key(|(activation_time, _)| *activation_time)
+            .ok_or_else(|| anyhow!("no batch signing key is active at {}", now))?;
+        check_signer_is_published(&*signer, published_keys)?;
+        Ok(signer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        default_facilitator_signing_private_key, default_facilitator_signing_public_key,
+        default_ingestor_private_key, default_ingestor_public_key, DEFAULT_INGESTOR_PRIVATE_KEY,
+    };
+    use chrono::TimeZone;
+    use mockito::{mock, Matcher};
+    use std::{collections::HashMap, io::Write};
+
+    #[test]
+    fn in_memory_signer() {
+        let key = default_ingestor_private_key();
+        let signature = key.sign(b"some content").unwrap();
+        assert_eq!(key.key_identifier(), "default-ingestor-signing-key");
+        default_ingestor_public_key()
+            .verify(b"some content", &signature)
+            .unwrap();
+    }
+
+    #[test]
+    fn signer_algorithms() {
+        let rng = SystemRandom::new();
+        for (pkcs8, algorithm) in &[
+            (
+                EcdsaKeyPair::generate_pkcs8(&ECDSA_P384_SHA384_ASN1_SIGNING, &rng).unwrap(),
+                SignatureAlgorithm::EcdsaP384Sha384,
+            ),
+            (
+                Ed25519KeyPair::generate_pkcs8(&rng).unwrap(),
+                SignatureAlgorithm::Ed25519,
+            ),
+        ] {
+            let key = BatchSigningKey::from_pkcs8(pkcs8.as_ref(), "key").unwrap();
+            assert_eq!(key.key.algorithm(), *algorithm);
+            let signature = key.sign(b"some content").unwrap();
+            key.key
+                .public_key()
+                .verify(b"some content", &signature)
+                .unwrap();
+            key.key
+                .public_key()
+                .verify(b"other content", &signature)
+                .unwrap_err();
+        }
+        assert_eq!(
+            default_ingestor_private_key().key.algorithm(),
+            SignatureAlgorithm::EcdsaP256Sha256
+        );
+        assert!(BatchSigningKey::from_pkcs8(b"not a key", "key").is_err());
+    }
+
+    #[test]
+    fn file_signer() {
+        let der = base64::decode(DEFAULT_INGESTOR_PRIVATE_KEY).unwrap();
+        let pem = pem::encode(&pem::Pem {
+            tag: "PRIVATE KEY".to_owned(),
+            contents: der.clone(),
+        });
+        for contents in &[
+            der,
+            DEFAULT_INGESTOR_PRIVATE_KEY.as_bytes().to_vec(),
+            pem.into_bytes(),
+        ] {
+            let mut key_file = tempfile::NamedTempFile::new().unwrap();
+            key_file.write_all(contents).unwrap();
+
+            let signer = FileBatchSigner::new(key_file.path().to_path_buf(), "file-key").unwrap();
+            assert_eq!(signer.key_identifier(), "file-key");
+            let signature = signer.sign(b"some content").unwrap();
+            default_ingestor_public_key()
+                .verify(b"some content", &signature)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn file_signer_bad_key() {
+        let mut key_file = tempfile::NamedTempFile::new().unwrap();
+        key_file.write_all(b"not a key").unwrap();
+        assert!(FileBatchSigner::new(key_file.path().to_path_buf(), "file-key").is_err());
+
+        assert!(FileBatchSigner::new(PathBuf::from("/no/such/key"), "file-key").is_err());
+    }
+
+    #[test]
+    fn file_signer_keeps_published_key() {
+        let mut key_file = tempfile::NamedTempFile::new().unwrap();
+        key_file
+            .write_all(DEFAULT_INGESTOR_PRIVATE_KEY.as_bytes())
+            .unwrap();
+        let mut key_set = BatchSigningKeySet::new();
+        key_set.add(
+            Utc.ymd(2020, 10, 1).and_hms(0, 0, 0),
+            Box::new(FileBatchSigner::new(key_file.path().to_path_buf(), "file-key").unwrap()),
+        );
+        let mut published_keys = HashMap::new();
+        published_keys.insert("file-key".to_owned(), default_ingestor_public_key());
+        let signer = key_set
+            .into_active_signer(&Utc.ymd(2020, 10, 15).and_hms(0, 0, 0), &published_keys)
+            .unwrap();
+
+        // Rotating the secret under a running job must not change the key that
+        // was checked against the manifest.
+        fs::write(
+            key_file.path(),
+            base64::encode(
+                Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                    .unwrap()
+                    .as_ref(),
+            ),
+        )
+        .unwrap();
+        let signature = signer.sign(b"some content").unwrap();
+        default_ingestor_public_key()
+            .verify(b"some content", &signature)
+            .unwrap();
+    }
+
+    #[test]
+    fn remote_signer() {
+        // Stand in for the signing service by signing with the default key.
+        let signature = default_ingestor_private_key()
+            .sign(b"some content")
+            .unwrap();
+        let mocked_sign = mock("POST", "/sign")
+            .match_body(Matcher::Json(
+                serde_json::to_value(SignRequest {
+                    key_identifier: "remote-key".to_owned(),
+                    message: base64::encode(b"some content"),
+                })
+                .unwrap(),
+            ))
+            .with_status(200)
+            .with_body(
+                serde_json::to_string(&SignResponse {
+                    signature: base64::encode(&signature),
+                })
+                .unwrap(),
+            )
+            .expect(1)
+            .create();
+
+        let signer = RemoteBatchSigner::new(&format!("{}/", mockito::server_url()), "remote-key");
+        assert_eq!(signer.key_identifier(), "remote-key");
+        let remote_signature = signer.sign(b"some content").unwrap();
+        mocked_sign.assert();
+        default_ingestor_public_key()
+            .verify(b"some content", &remote_signature)
+            .unwrap();
+    }
+
+    #[test]
+    fn remote_signer_error() {
+        let mocked_sign = mock("POST", "/sign").with_status(500).expect(1).create();
+
+        let signer = RemoteBatchSigner::new(&mockito::server_url(), "remote-key");
+        assert!(signer.sign(b"some content").is_err());
+        mocked_sign.assert();
+    }
+
+    fn rotation_key_set() -> BatchSigningKeySet {
+        let mut key_set = BatchSigningKeySet::new();
+        key_set.add(
+            Utc.ymd(2020, 10, 1).and_hms(0, 0, 0),
+            Box::new(default_ingestor_private_key()),
+        );
+        key_set.add(
+            Utc.ymd(2020, 11, 1).and_hms(0, 0, 0),
+            Box::new(default_facilitator_signing_private_key()),
+        );
+        key_set
+    }
+
+    #[test]
+    fn key_set_selects_active_key() {
+        let mut published_keys = HashMap::new();
+        published_keys.insert(
+            "default-ingestor-signing-key".to_owned(),
+            default_ingestor_public_key(),
+        );
+        published_keys.insert(
+            "default-facilitator-signing-key".to_owned(),
+            default_facilitator_signing_public_key(),
+        );
+
+        let signer = rotation_key_set()
+            .into_active_signer(&Utc.ymd(2020, 10, 15).and_hms(0, 0, 0), &published_keys)
+            .unwrap();
+        assert_eq!(signer.key_identifier(), "default-ingestor-signing-key");
+
+        let signer = rotation_key_set()
+            .into_active_signer(&Utc.ymd(2020, 11, 1).and_hms(0, 0, 0), &published_keys)
+            .unwrap();
+        assert_eq!(signer.key_identifier(), "default-facilitator-signing-key");
+
+        assert!(rotation_key_set()
+            .into_active_signer(&Utc.ymd(2020, 9, 1).and_hms(0, 0, 0), &published_keys)
+            .is_err());
+    }
+
+    #[test]
+    fn key_set_refuses_unpublished_key() {
+        // Only the old key is published, so once the new key becomes active
+        // we must refuse to sign rather than fall back to the old one.
+        let mut published_keys = HashMap::new();
+        published_keys.insert(
+            "default-ingestor-signing-key".to_owned(),
+            default_ingestor_public_key(),
+        );
+        assert!(rotation_key_set()
+            .into_active_signer(&Utc.ymd(2020, 10, 15).and_hms(0, 0, 0), &published_keys)
+            .is_ok());
+        assert!(rotation_key_set()
+            .into_active_signer(&Utc.ymd(2020, 11, 15).and_hms(0, 0, 0), &published_keys)
+            .is_err());
+
+        // The new key's identifier is published but with the wrong public key.
+        published_keys.insert(
+            "default-facilitator-signing-key".to_owned(),
+            default_ingestor_public_key(),
+        );
+        let err = rotation_key_set()
+            .into_active_signer(&Utc.ymd(2020, 11, 15).and_hms(0, 0, 0), &published_keys)
+            .err()
+            .unwrap();
+        // Retrying won't publish the key.
+        assert!(!crate::is_retryable(&err));
+    }
+
+    #[test]
+    fn key_set_from_json() {
+        let mut key_file = tempfile::NamedTempFile::new().unwrap();
+        key_file
+            .write_all(DEFAULT_INGESTOR_PRIVATE_KEY.as_bytes())
+            .unwrap();
+        let json = format!(
+            r#"[
+                {{"identifier": "key-1", "activation-time": "2020-10-01T00:00:00Z",
+                  "private-key": "{}"}},
+                {{"identifier": "key-2", "activation-time": "2020-11-01T00:00:00Z",
+                  "private-key-file": "{}"}},
+                {{"identifier": "key-3", "activation-time": "2020-12-01T00:00:00Z",
+                  "signer-url": "http://localhost:8080"}}
+            ]"#,
+            DEFAULT_INGESTOR_PRIVATE_KEY,
+            key_file.path().display()
+        );
+        let key_set = BatchSigningKeySet::from_json(json.as_bytes()).unwrap();
+        let identifiers: Vec<_> = key_set
+            .keys
+            .iter()
+            .map(|(activation_time, signer)| (*activation_time, signer.key_identifier()))
+            .collect();
+        assert_eq!(
+            identifiers,
+            vec![
+                (Utc.ymd(2020, 10, 1).and_hms(0, 0, 0), "key-1"),
+                (Utc.ymd(2020, 11, 1).and_hms(0, 0, 0), "key-2"),
+                (Utc.ymd(2020, 12, 1).and_hms(0, 0, 0), "key-3"),
+            ]
+        );
+
+        let invalid_entries = &[
+            // No key
+            r#"[{"identifier": "key-1", "activation-time": "2020-10-01T00:00:00Z"}]"#,
+            // Two keys
+            r#"[{"identifier": "key-1", "activation-time": "2020-10-01T00:00:00Z",
+                 "private-key-file": "/a", "signer-url": "http://localhost"}]"#,
+            // Bad timestamp
+            r#"[{"identifier": "key-1", "activation-time": "yesterday",
+                 "signer-url": "http://localhost"}]"#,
+        ];
+        for invalid in invalid_entries {
+            assert!(BatchSigningKeySet::from_json(invalid.as_bytes()).is_err());
+        }
+    }
+}
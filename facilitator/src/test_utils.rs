@@ -1,8 +1,5 @@
-use crate::BatchSigningKey;
-use ring::signature::{
-    EcdsaKeyPair, KeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_ASN1,
-    ECDSA_P256_SHA256_ASN1_SIGNING,
-};
+use crate::{signing::SigningKeyPair, BatchSigningKey};
+use ring::signature::{EcdsaKeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_ASN1_SIGNING};
 
 /// Default keys used in testing and for sample data generation. These are
 /// stored in base64 to make it convenient to copy/paste them into other tools
@@ -47,68 +44,53 @@ pub const DEFAULT_PHA_SUBJECT_PUBLIC_KEY_INFO: &str =
 /// Constructs an EcdsaKeyPair from the default ingestor server.
 pub fn default_ingestor_private_key() -> BatchSigningKey {
     BatchSigningKey {
-        key: EcdsaKeyPair::from_pkcs8(
-            &ECDSA_P256_SHA256_ASN1_SIGNING,
-            &base64::decode(DEFAULT_INGESTOR_PRIVATE_KEY).unwrap(),
-        )
-        // Since we know DEFAULT_INGESTOR_PRIVATE_KEY is valid, it
-        // is ok to unwrap() here.
-        .unwrap(),
+        key: SigningKeyPair::EcdsaP256(
+            EcdsaKeyPair::from_pkcs8(
+                &ECDSA_P256_SHA256_ASN1_SIGNING,
+                &base64::decode(DEFAULT_INGESTOR_PRIVATE_KEY).unwrap(),
+            )
+            // Since we know DEFAULT_INGESTOR_PRIVATE_KEY is valid, it
+            // is ok to unwrap() here.
+            .unwrap(),
+        ),
         identifier: "default-ingestor-signing-key".to_owned(),
     }
 }
 
 pub fn default_ingestor_public_key() -> UnparsedPublicKey<Vec<u8>> {
-    UnparsedPublicKey::new(
-        &ECDSA_P256_SHA256_ASN1,
-        default_ingestor_private_key()
-            .key
-            .public_key()
-            .as_ref()
-            .to_vec(),
-    )
+    default_ingestor_private_key().key.public_key()
 }
 
 pub fn default_facilitator_signing_private_key() -> BatchSigningKey {
     BatchSigningKey {
-        key: EcdsaKeyPair::from_pkcs8(
-            &ECDSA_P256_SHA256_ASN1_SIGNING,
-            &base64::decode(DEFAULT_FACILITATOR_SIGNING_PRIVATE_KEY).unwrap(),
-        )
-        .unwrap(),
+        key: SigningKeyPair::EcdsaP256(
+            EcdsaKeyPair::from_pkcs8(
+                &ECDSA_P256_SHA256_ASN1_SIGNING,
+                &base64::decode(DEFAULT_FACILITATOR_SIGNING_PRIVATE_KEY).unwrap(),
+            )
+            .unwrap(),
+        ),
         identifier: "default-facilitator-signing-key".to_owned(),
     }
 }
 
 pub fn default_facilitator_signing_public_key() -> UnparsedPublicKey<Vec<u8>> {
-    UnparsedPublicKey::new(
-        &ECDSA_P256_SHA256_ASN1,
-        default_facilitator_signing_private_key()
-            .key
-            .public_key()
-            .as_ref()
-            .to_vec(),
-    )
+    default_facilitator_signing_private_key().key.public_key()
 }
 
 pub fn default_pha_signing_private_key() -> BatchSigningKey {
     BatchSigningKey {
-        key: EcdsaKeyPair::from_pkcs8(
-            &ECDSA_P256_SHA256_ASN1_SIGNING,
-            &base64::decode(DEFAULT_PHA_SIGNING_PRIVATE_KEY).unwrap(),
-        )
-        .unwrap(),
+        key: SigningKeyPair::EcdsaP256(
+            EcdsaKeyPair::from_pkcs8(
+                &ECDSA_P256_SHA256_ASN1_SIGNING,
+                &base64::decode(DEFAULT_PHA_SIGNING_PRIVATE_KEY).unwrap(),
+            )
+            .unwrap(),
+        ),
         identifier: "default-pha-signing-key".to_owned(),
     }
 }
 
 pub fn default_pha_signing_public_key() -> UnparsedPublicKey<Vec<u8>> {
-    UnparsedPublicKey::new(
-        &ECDSA_P256_SHA256_ASN1,
-        default_pha_signing_private_key()
-            .key
-            .public_key()
-            .as_ref()
-            .to_vec(),
-    )
+    default_pha_signing_private_key().key.public_key()
 }
@@ -2,8 +2,14 @@ mod gcs;
 mod local;
 mod s3;
 
-use crate::{manifest::BatchSigningPublicKeys, BatchSigningKey};
-use anyhow::Result;
+use crate::{
+    manifest::BatchSigningPublicKeys,
+    metrics::{Counter, Registry},
+    signing::BatchSigner,
+    Error,
+};
+use anyhow::{anyhow, Result};
+use avro_rs::Codec;
 use prio::encrypt::PrivateKey;
 use std::{
     boxed::Box,
@@ -26,9 +32,59 @@ pub struct VerifiableAndDecryptableTransport {
     pub packet_decryption_keys: Vec<PrivateKey>,
 }
 
+/// The transport from which the ingestion batches written by one ingestor are
+/// read, along with the keys to verify and decrypt them.
+pub struct IngestorTransport {
+    /// The name of the ingestor, recorded in job reports as the provenance of
+    /// the batches read from it. None if there is only one ingestor.
+    pub name: Option<String>,
+    pub transport: VerifiableAndDecryptableTransport,
+}
+
+/// Returns the index of the ingestor whose transport holds the object at key.
+/// If there is only one ingestor, it is assumed to hold the object.
+pub fn find_ingestor(ingestors: &mut [IngestorTransport], key: &str) -> Result<usize> {
+    if ingestors.len() == 1 {
+        return Ok(0);
+    }
+    let mut found = Vec::new();
+    for (index, ingestor) in ingestors.iter_mut().enumerate() {
+        if ingestor.transport.transport.transport.exists(key)? {
+            found.push(index);
+        }
+    }
+    match found[..] {
+        [index] => Ok(index),
+        [] => Err(Error::ObjectNotFoundError(key.to_owned()).into()),
+        _ => Err(anyhow!(
+            "{} is held by more than one ingestor: {}",
+            key,
+            found
+                .iter()
+                .map(|index| ingestors[*index].name.as_deref().unwrap_or("unnamed"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// The transport from which the validation batches written by one peer share
+/// processor are read.
+pub struct PeerValidationTransport {
+    /// The index of the peer among the servers, which names the validation
+    /// batches it writes.
+    pub server_index: usize,
+    pub transport: VerifiableTransport,
+}
+
 pub struct SignableTransport {
     pub transport: Box<dyn Transport>,
-    pub batch_signing_key: BatchSigningKey,
+    pub batch_signer: Box<dyn BatchSigner>,
+    /// Whether to write version 2 batch signatures, which also sign the batch
+    /// manifest.
+    pub sign_batch_manifest: bool,
+    /// The Avro codec with which to compress packet files.
+    pub packet_file_codec: Codec,
 }
 
 /// A TransportWriter extends std::io::Write but adds methods that explicitly
@@ -55,12 +111,116 @@ impl<T: TransportWriter + ?Sized> TransportWriter for Box<T> {
     }
 }
 
+/// The metrics recorded by a Transport implementation.
+#[derive(Clone)]
+pub struct TransportMetrics {
+    /// Number of bytes read from objects.
+    pub bytes_read: Counter,
+    /// Number of bytes written to objects.
+    pub bytes_written: Counter,
+    /// Number of times part of an upload had to be sent again.
+    pub upload_retries: Counter,
+}
+
+impl TransportMetrics {
+    /// Creates metrics in registry labeled with the kind of transport, e.g.
+    /// "s3".
+    pub fn new(registry: &Registry, transport: &str) -> Result<TransportMetrics> {
+        Ok(TransportMetrics {
+            bytes_read: registry.counter(
+                "facilitator_transport_bytes_total",
+                "Number of bytes transferred by transports",
+                &[("transport", transport), ("direction", "read")],
+            )?,
+            bytes_written: registry.counter(
+                "facilitator_transport_bytes_total",
+                "Number of bytes transferred by transports",
+                &[("transport", transport), ("direction", "write")],
+            )?,
+            upload_retries: registry.counter(
+                "facilitator_transport_upload_retries_total",
+                "Number of times part of an upload had to be sent again",
+                &[("transport", transport)],
+            )?,
+        })
+    }
+
+    /// Wraps reader so that the bytes read from it are counted.
+    fn meter_reader(&self, reader: Box<dyn Read>) -> Box<dyn Read> {
+        Box::new(MeteredReader {
+            reader,
+            bytes_read: self.bytes_read.clone(),
+        })
+    }
+
+    /// Wraps writer so that the bytes written to it are counted.
+    fn meter_writer(&self, writer: Box<dyn TransportWriter>) -> Box<dyn TransportWriter> {
+        Box::new(MeteredWriter {
+            writer,
+            bytes_written: self.bytes_written.clone(),
+        })
+    }
+}
+
+impl Default for TransportMetrics {
+    /// Returns metrics recorded into a registry of their own, which is never
+    /// exported.
+    fn default() -> Self {
+        // A new registry cannot hold conflicting metrics.
+        TransportMetrics::new(&Registry::new(), "unknown").unwrap()
+    }
+}
+
+struct MeteredReader {
+    reader: Box<dyn Read>,
+    bytes_read: Counter,
+}
+
+impl Read for MeteredReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.bytes_read.inc_by(n as f64);
+        Ok(n)
+    }
+}
+
+struct MeteredWriter {
+    writer: Box<dyn TransportWriter>,
+    bytes_written: Counter,
+}
+
+impl Write for MeteredWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.bytes_written.inc_by(n as f64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl TransportWriter for MeteredWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        self.writer.complete_upload()
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        self.writer.cancel_upload()
+    }
+}
+
 /// A transport moves object in and out of some data store, such as a cloud
 /// object store like Amazon S3, or local files, or buffers in memory.
 pub trait Transport {
     /// Returns an std::io::Read instance from which the contents of the value
-    /// of the provided key may be read.
+    /// of the provided key may be read. If there is no object with the key, the
+    /// error is an Error::ObjectNotFoundError.
     fn get(&mut self, key: &str) -> Result<Box<dyn Read>>;
+    /// Returns true if an object with the provided key exists, without
+    /// fetching its contents.
+    fn exists(&mut self, key: &str) -> Result<bool>;
     /// Returns an std::io::Write instance into which the contents of the value
     /// may be written.
     fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>>;
@@ -1,10 +1,13 @@
 use crate::{
     config::{GCSPath, Identity},
-    transport::{Transport, TransportWriter},
+    logging::LogContext,
+    metrics::{Counter, Registry},
+    transport::{Transport, TransportMetrics, TransportWriter},
     Error,
 };
 use anyhow::{anyhow, Context, Result};
 use chrono::{prelude::Utc, DateTime, Duration};
+use log::debug;
 use serde::Deserialize;
 use std::{
     io,
@@ -196,6 +199,7 @@ impl OauthTokenProvider {
 pub struct GCSTransport {
     path: GCSPath,
     oauth_token_provider: OauthTokenProvider,
+    metrics: TransportMetrics,
 }
 
 impl GCSTransport {
@@ -208,12 +212,21 @@ impl GCSTransport {
         GCSTransport {
             path: path.ensure_directory_prefix(),
             oauth_token_provider: OauthTokenProvider::new(identity.map(|x| x.to_string())),
+            metrics: TransportMetrics::default(),
         }
     }
+
+    /// Records this transport's metrics in registry.
+    pub fn set_metrics(&mut self, registry: &Registry) -> Result<()> {
+        self.metrics = TransportMetrics::new(registry, "gcs")?;
+        Ok(())
+    }
 }
 
-impl Transport for GCSTransport {
-    fn get(&mut self, key: &str) -> Result<Box<dyn Read>> {
+impl GCSTransport {
+    /// Returns the URL of the object with the provided key and an authorized
+    /// GET request for it.
+    fn object_request(&mut self, key: &str) -> Result<(String, ureq::Request)> {
         // Per API reference, the object key must be URL encoded.
         // API reference: https://cloud.google.com/storage/docs/json_api/v1/objects/get
         let encoded_key = urlencoding::encode(&[&self.path.key, key].concat());
@@ -222,10 +235,8 @@ impl Transport for GCSTransport {
             STORAGE_API_BASE_URL, self.path.bucket, encoded_key
         );
 
-        let response = ureq::get(&url)
-            // Ensures response body will be content and not JSON metadata.
-            // https://cloud.google.com/storage/docs/json_api/v1/objects/get#parameters
-            .query("alt", "media")
+        let mut request = ureq::get(&url);
+        request
             .set(
                 "Authorization",
                 &format!(
@@ -236,8 +247,24 @@ impl Transport for GCSTransport {
             )
             // By default, ureq will wait forever to connect or read
             .timeout_connect(10_000) // ten seconds
-            .timeout_read(10_000) // ten seconds
+            .timeout_read(10_000); // ten seconds
+        Ok((url, request))
+    }
+}
+
+impl Transport for GCSTransport {
+    fn get(&mut self, key: &str) -> Result<Box<dyn Read>> {
+        let _context = LogContext::new().object_key(key).enter();
+        debug!("fetching object from GCS");
+        let (url, mut request) = self.object_request(key)?;
+        let response = request
+            // Ensures response body will be content and not JSON metadata.
+            // https://cloud.google.com/storage/docs/json_api/v1/objects/get#parameters
+            .query("alt", "media")
             .call();
+        if response.status() == 404 {
+            return Err(Error::ObjectNotFoundError(url).into());
+        }
         if response.error() {
             return Err(anyhow!(
                 "failed to fetch object {} from GCS: {:?}",
@@ -245,10 +272,29 @@ impl Transport for GCSTransport {
                 response
             ));
         }
-        Ok(Box::new(response.into_reader()))
+        Ok(self.metrics.meter_reader(Box::new(response.into_reader())))
+    }
+
+    fn exists(&mut self, key: &str) -> Result<bool> {
+        let _context = LogContext::new().object_key(key).enter();
+        debug!("checking for object in GCS");
+        // Without alt=media, the API returns only the object's metadata.
+        let (url, mut request) = self.object_request(key)?;
+        let response = request.call();
+        match response.status() {
+            404 => Ok(false),
+            _ if response.error() => Err(anyhow!(
+                "failed to fetch metadata for object {} from GCS: {:?}",
+                url,
+                response
+            )),
+            _ => Ok(true),
+        }
     }
 
     fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+        let _context = LogContext::new().object_key(key).enter();
+        debug!("uploading object to GCS");
         // The Oauth token will only be used once, during the call to
         // StreamingTransferWriter::new, so we don't have to worry about it
         // expiring during the lifetime of that object, and so obtain a token
@@ -257,11 +303,13 @@ impl Transport for GCSTransport {
         let oauth_token = self
             .oauth_token_provider
             .ensure_storage_access_oauth_token()?;
-        Ok(Box::new(StreamingTransferWriter::new(
+        let mut writer = StreamingTransferWriter::new(
             self.path.bucket.to_owned(),
             [&self.path.key, key].concat(),
             oauth_token,
-        )?))
+        )?;
+        writer.upload_retries = self.metrics.upload_retries.clone();
+        Ok(self.metrics.meter_writer(Box::new(writer)))
     }
 }
 
@@ -294,6 +342,8 @@ struct StreamingTransferWriter {
     minimum_upload_chunk_size: usize,
     object_upload_position: usize,
     buffer: Vec<u8>,
+    /// Counts chunks that GCS only partially accepted.
+    upload_retries: Counter,
 }
 
 impl StreamingTransferWriter {
@@ -353,6 +403,7 @@ impl StreamingTransferWriter {
             buffer: Vec::with_capacity(minimum_upload_chunk_size * 2),
             object_upload_position: 0,
             upload_session_uri: upload_session_uri.to_owned(),
+            upload_retries: TransportMetrics::default().upload_retries,
         })
     }
 
@@ -443,6 +494,9 @@ impl StreamingTransferWriter {
                 // will reject it. Instead, leave the portion of the chunk that
                 // we didn't manage to upload back in self.buffer so it can be
                 // handled by a subsequent call to upload_chunk.
+                if end + 1 < self.object_upload_position + body.len() {
+                    self.upload_retries.inc();
+                }
                 self.buffer = self.buffer.split_off(end + 1 - self.object_upload_position);
                 self.object_upload_position = end + 1;
                 Ok(())
@@ -464,7 +518,7 @@ impl Write for StreamingTransferWriter {
         self.buffer.extend_from_slice(buf);
         while self.buffer.len() >= self.minimum_upload_chunk_size {
             self.upload_chunk(false)
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, Error::AnyhowError(e)))?;
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
         }
 
         Ok(buf.len())
@@ -1,23 +1,39 @@
-use crate::transport::{Transport, TransportWriter};
+use crate::{
+    logging::LogContext,
+    metrics::Registry,
+    transport::{Transport, TransportMetrics, TransportWriter},
+    Error,
+};
 use anyhow::{Context, Result};
+use log::debug;
 
 use std::{
     boxed::Box,
     fs::{create_dir_all, File},
-    io::Read,
+    io::{ErrorKind, Read},
     path::{PathBuf, MAIN_SEPARATOR},
 };
 
 /// A transport implementation backed by the local filesystem.
 pub struct LocalFileTransport {
     directory: PathBuf,
+    metrics: TransportMetrics,
 }
 
 impl LocalFileTransport {
     /// Creates a LocalFileTransport under the specified path. The key parameter
     /// provided to `put` or `get` will be interpreted as a relative path.
     pub fn new(directory: PathBuf) -> LocalFileTransport {
-        LocalFileTransport { directory }
+        LocalFileTransport {
+            directory,
+            metrics: TransportMetrics::default(),
+        }
+    }
+
+    /// Records this transport's metrics in registry.
+    pub fn set_metrics(&mut self, registry: &Registry) -> Result<()> {
+        self.metrics = TransportMetrics::new(registry, "local")?;
+        Ok(())
     }
 
     /// Callers will construct keys using "/" as a separator. This function
@@ -30,13 +46,31 @@ impl LocalFileTransport {
 
 impl Transport for LocalFileTransport {
     fn get(&mut self, key: &str) -> Result<Box<dyn Read>> {
+        let _context = LogContext::new().object_key(key).enter();
+        debug!("opening file");
         let path = self.directory.join(LocalFileTransport::relative_path(key));
-        let f =
-            File::open(path.as_path()).with_context(|| format!("opening {}", path.display()))?;
-        Ok(Box::new(f))
+        let f = match File::open(path.as_path()) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(Error::ObjectNotFoundError(path.display().to_string()).into())
+            }
+            Err(e) => return Err(e).with_context(|| format!("opening {}", path.display())),
+        };
+        Ok(self.metrics.meter_reader(Box::new(f)))
+    }
+
+    fn exists(&mut self, key: &str) -> Result<bool> {
+        let _context = LogContext::new().object_key(key).enter();
+        debug!("checking for file");
+        Ok(self
+            .directory
+            .join(LocalFileTransport::relative_path(key))
+            .is_file())
     }
 
     fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
+        let _context = LogContext::new().object_key(key).enter();
+        debug!("creating file");
         let path = self.directory.join(LocalFileTransport::relative_path(key));
         if let Some(parent) = path.parent() {
             create_dir_all(parent)
@@ -44,7 +78,7 @@ impl Transport for LocalFileTransport {
         }
         let f =
             File::create(path.as_path()).with_context(|| format!("creating {}", path.display()))?;
-        Ok(Box::new(f))
+        Ok(self.metrics.meter_writer(Box::new(f)))
     }
 }
 
@@ -63,16 +97,24 @@ impl TransportWriter for File {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use assert_matches::assert_matches;
 
     #[test]
     fn roundtrip_file_transport() {
         let tempdir = tempfile::TempDir::new().unwrap();
         let mut file_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let registry = Registry::new();
+        file_transport.set_metrics(&registry).unwrap();
         let content = vec![1, 2, 3, 4, 5, 6, 7, 8];
 
         {
             let ret = file_transport.get("path2");
             assert!(ret.is_err(), "unexpected return value {:?}", ret.err());
+            assert_matches!(
+                ret.err().unwrap().downcast_ref::<Error>(),
+                Some(Error::ObjectNotFoundError(_))
+            );
+            assert!(!file_transport.exists("path2").unwrap());
         }
 
         for path in &["path", "path3/with/separators"] {
@@ -84,6 +126,7 @@ mod tests {
                 .write_all(&content)
                 .expect("failed to write");
 
+            assert!(file_transport.exists(path).unwrap());
             let reader = file_transport.get(path);
             assert!(reader.is_ok(), "create reader failed: {:?}", reader.err());
 
@@ -94,5 +137,13 @@ mod tests {
                 .expect("failed to read");
             assert_eq!(content_again, content);
         }
+
+        let metrics = registry.render();
+        assert!(metrics.contains(
+            "facilitator_transport_bytes_total{transport=\"local\",direction=\"read\"} 16\n"
+        ));
+        assert!(metrics.contains(
+            "facilitator_transport_bytes_total{transport=\"local\",direction=\"write\"} 16\n"
+        ));
     }
 }
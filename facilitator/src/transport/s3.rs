@@ -1,20 +1,24 @@
 use crate::{
     config::{Identity, S3Path},
-    transport::{Transport, TransportWriter},
+    logging::LogContext,
+    metrics::Registry,
+    transport::{Transport, TransportMetrics, TransportWriter},
     Error,
 };
 use anyhow::{Context, Result};
 use derivative::Derivative;
 use hyper_rustls::HttpsConnector;
+use log::debug;
 use rusoto_core::{
     credential::{
         AutoRefreshingProvider, CredentialsError, DefaultCredentialsProvider, Secret, Variable,
     },
-    ByteStream, Region,
+    ByteStream, Region, RusotoError,
 };
 use rusoto_s3::{
     AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
-    CompletedPart, CreateMultipartUploadRequest, GetObjectRequest, S3Client, UploadPartRequest, S3,
+    CompletedPart, CreateMultipartUploadRequest, GetObjectError, GetObjectRequest, HeadObjectError,
+    HeadObjectRequest, S3Client, UploadPartRequest, S3,
 };
 use rusoto_sts::WebIdentityProvider;
 use std::{
@@ -53,6 +57,7 @@ pub struct S3Transport {
     iam_role: Option<String>,
     // client_provider allows injection of mock S3Client for testing purposes
     client_provider: ClientProvider,
+    metrics: TransportMetrics,
 }
 
 impl S3Transport {
@@ -173,8 +178,15 @@ impl S3Transport {
             path: path.ensure_directory_prefix(),
             iam_role: identity.map(|x| x.to_string()),
             client_provider,
+            metrics: TransportMetrics::default(),
         }
     }
+
+    /// Records this transport's metrics in registry.
+    pub fn set_metrics(&mut self, registry: &Registry) -> Result<()> {
+        self.metrics = TransportMetrics::new(registry, "s3")?;
+        Ok(())
+    }
 }
 
 // ClientProvider allows mocking out a client for testing.
@@ -182,30 +194,71 @@ type ClientProvider = Box<dyn Fn(&Region, Option<String>) -> Result<S3Client>>;
 
 impl Transport for S3Transport {
     fn get(&mut self, key: &str) -> Result<Box<dyn Read>> {
+        let _context = LogContext::new().object_key(key).enter();
+        debug!("fetching object from S3");
         let mut runtime = basic_runtime()?;
         let client = (self.client_provider)(&self.path.region, self.iam_role.clone())?;
-        let get_output = runtime
-            .block_on(client.get_object(GetObjectRequest {
-                bucket: self.path.bucket.to_owned(),
-                key: [&self.path.key, key].concat(),
-                ..Default::default()
-            }))
-            .context("error getting S3 object")?;
+        let object_key = [&self.path.key, key].concat();
+        let get_output = match runtime.block_on(client.get_object(GetObjectRequest {
+            bucket: self.path.bucket.to_owned(),
+            key: object_key.clone(),
+            ..Default::default()
+        })) {
+            Ok(output) => output,
+            Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => {
+                return Err(Error::ObjectNotFoundError(object_key).into())
+            }
+            Err(e) if is_not_found(&e) => return Err(Error::ObjectNotFoundError(object_key).into()),
+            Err(e) => return Err(e).context("error getting S3 object"),
+        };
 
         let body = get_output.body.context("no body in GetObjectResponse")?;
 
-        Ok(Box::new(StreamingBodyReader::new(body, runtime)))
+        Ok(self
+            .metrics
+            .meter_reader(Box::new(StreamingBodyReader::new(body, runtime))))
+    }
+
+    fn exists(&mut self, key: &str) -> Result<bool> {
+        let _context = LogContext::new().object_key(key).enter();
+        debug!("checking for object in S3");
+        let mut runtime = basic_runtime()?;
+        let client = (self.client_provider)(&self.path.region, self.iam_role.clone())?;
+        match runtime.block_on(client.head_object(HeadObjectRequest {
+            bucket: self.path.bucket.to_owned(),
+            key: [&self.path.key, key].concat(),
+            ..Default::default()
+        })) {
+            Ok(_) => Ok(true),
+            Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(false),
+            Err(e) if is_not_found(&e) => Ok(false),
+            Err(e) => Err(e).context("error checking for S3 object"),
+        }
     }
 
     fn put(&mut self, key: &str) -> Result<Box<dyn TransportWriter>> {
-        Ok(Box::new(MultipartUploadWriter::new(
-            self.path.bucket.to_owned(),
-            [&self.path.key, key].concat(),
-            // Set buffer size to 5 MB, which is the minimum required by Amazon
-            // https://docs.aws.amazon.com/AmazonS3/latest/dev/qfacts.html
-            5_242_880,
-            (self.client_provider)(&self.path.region, self.iam_role.clone())?,
-        )?))
+        let _context = LogContext::new().object_key(key).enter();
+        debug!("uploading object to S3");
+        Ok(self
+            .metrics
+            .meter_writer(Box::new(MultipartUploadWriter::new(
+                self.path.bucket.to_owned(),
+                [&self.path.key, key].concat(),
+                // Set buffer size to 5 MB, which is the minimum required by Amazon
+                // https://docs.aws.amazon.com/AmazonS3/latest/dev/qfacts.html
+                5_242_880,
+                (self.client_provider)(&self.path.region, self.iam_role.clone())?,
+            )?)))
+    }
+}
+
+/// Returns true if the error is a 404 response that Rusoto could not parse into
+/// a service error, as happens for HeadObject requests, whose responses have no
+/// body, and GetObject requests against some S3-compatible stores.
+fn is_not_found<E>(error: &RusotoError<E>) -> bool {
+    match error {
+        RusotoError::Unknown(response) => response.status.as_u16() == 404,
+        _ => false,
     }
 }
 
@@ -357,9 +410,8 @@ impl Write for MultipartUploadWriter {
         // enough content.
         self.buffer.extend_from_slice(buf);
         if self.buffer.len() >= self.minimum_upload_part_size {
-            self.upload_part().map_err(|e| {
-                std::io::Error::new(std::io::ErrorKind::Other, Error::AnyhowError(e))
-            })?;
+            self.upload_part()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
         }
 
         Ok(buf.len())
@@ -412,6 +464,7 @@ impl TransportWriter for MultipartUploadWriter {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use assert_matches::assert_matches;
     use rusoto_core::signature::SignedRequest;
     use rusoto_mock::{
         MockCredentialsProvider, MockRequestDispatcher, MultipleMockRequestDispatcher,
@@ -659,6 +712,49 @@ mod tests {
         writer.complete_upload().unwrap_err();
     }
 
+    #[test]
+    fn s3_object_exists() {
+        let s3_path = S3Path {
+            region: Region::UsWest2,
+            bucket: TEST_BUCKET.into(),
+            key: "".into(),
+        };
+
+        for (status, exists) in &[(404, false), (200, true)] {
+            let status = *status;
+            let mut transport = S3Transport::new_with_client(
+                s3_path.clone(),
+                None,
+                Box::new(move |region: &Region, _: Option<String>| {
+                    Ok(S3Client::new_with(
+                        MockRequestDispatcher::with_status(status).with_request_checker(
+                            |request: &SignedRequest| {
+                                assert_eq!(request.method, "HEAD");
+                                assert_eq!(request.path, "/fake-bucket/fake-key");
+                            },
+                        ),
+                        MockCredentialsProvider,
+                        region.clone(),
+                    ))
+                }),
+            );
+            assert_eq!(transport.exists(TEST_KEY).unwrap(), *exists);
+        }
+
+        let mut transport = S3Transport::new_with_client(
+            s3_path,
+            None,
+            Box::new(|region: &Region, _: Option<String>| {
+                Ok(S3Client::new_with(
+                    MockRequestDispatcher::with_status(403),
+                    MockCredentialsProvider,
+                    region.clone(),
+                ))
+            }),
+        );
+        transport.exists(TEST_KEY).unwrap_err();
+    }
+
     #[test]
     fn roundtrip_s3_transport() {
         let s3_path = S3Path {
@@ -679,6 +775,10 @@ mod tests {
 
         let ret = transport.get(TEST_KEY);
         assert!(ret.is_err(), "unexpected return value {:?}", ret.err());
+        assert_matches!(
+            ret.err().unwrap().downcast_ref::<Error>(),
+            Some(Error::ObjectNotFoundError(_))
+        );
 
         let mut transport = S3Transport::new_with_client(
             s3_path.clone(),
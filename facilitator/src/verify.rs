@@ -0,0 +1,546 @@
+use crate::{
+    batch::{Batch, BatchReader},
+    idl::{
+        Header, IngestionDataSharePacket, IngestionHeader, InvalidPacket, Packet, SumPart,
+        ValidationHeader, ValidationPacket,
+    },
+    manifest::BatchSigningPublicKeys,
+    report::BatchReport,
+    transport::Transport,
+    Error,
+};
+use anyhow::{anyhow, Context, Result};
+use avro_rs::Reader;
+use chrono::Duration;
+use prio::finite_field::MODULUS;
+use std::{collections::HashSet, fmt, io::Cursor, str::FromStr};
+use uuid::Uuid;
+
+/// The kinds of batch that can be verified.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BatchKind {
+    Ingestion,
+    Validation,
+    Sum,
+}
+
+impl FromStr for BatchKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ingestion" => Ok(BatchKind::Ingestion),
+            "validation" => Ok(BatchKind::Validation),
+            "sum" => Ok(BatchKind::Sum),
+            _ => Err(anyhow!("unknown batch kind {}", s)),
+        }
+    }
+}
+
+/// The outcome of one of the checks made by verify_batch.
+#[derive(Debug)]
+pub enum CheckOutcome {
+    Passed(String),
+    Failed(anyhow::Error),
+    /// The check could not be made because an earlier one failed.
+    Skipped,
+}
+
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: CheckOutcome,
+}
+
+/// The results of verifying a batch, in the order the checks were made.
+#[derive(Debug)]
+pub struct VerificationReport {
+    pub batch: BatchReport,
+    pub checks: Vec<Check>,
+    /// The number of packets in the batch, if they could all be read.
+    pub packet_count: Option<u64>,
+}
+
+impl VerificationReport {
+    /// Returns true if every check passed.
+    pub fn passed(&self) -> bool {
+        self.checks
+            .iter()
+            .all(|check| matches!(check.outcome, CheckOutcome::Passed(_)))
+    }
+
+    /// Returns the error from the first failed check, if any, so that callers
+    /// can classify it with is_retryable.
+    pub fn into_result(self) -> Result<()> {
+        for check in self.checks {
+            if let CheckOutcome::Failed(e) = check.outcome {
+                return Err(e.context(format!("batch failed {} check", check.name)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for VerificationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "batch {}", self.batch.header_key)?;
+        for check in &self.checks {
+            match &check.outcome {
+                CheckOutcome::Passed(detail) => writeln!(f, "  PASS {}: {}", check.name, detail)?,
+                CheckOutcome::Failed(e) => writeln!(f, "  FAIL {}: {:#}", check.name, e)?,
+                CheckOutcome::Skipped => writeln!(
+                    f,
+                    "  SKIP {}: not checked because an earlier check failed",
+                    check.name
+                )?,
+            }
+        }
+        write!(
+            f,
+            "{}",
+            if self.passed() {
+                "batch is valid"
+            } else {
+                "batch is NOT valid"
+            }
+        )
+    }
+}
+
+/// Checks the signature, header parameters, packet file digest, packet schema
+/// and packet count of the batch, which must be of the provided kind, reading
+/// it from transport. Unlike BatchReader, this does not stop at the first
+/// problem, but reports the outcome of every check that could be made.
+pub fn verify_batch(
+    kind: BatchKind,
+    batch: Batch,
+    transport: &mut dyn Transport,
+    public_keys: &BatchSigningPublicKeys,
+    time_tolerance: Option<Duration>,
+) -> VerificationReport {
+    match kind {
+        BatchKind::Ingestion => verify::<IngestionHeader, IngestionDataSharePacket>(
+            batch,
+            transport,
+            public_keys,
+            time_tolerance,
+        ),
+        BatchKind::Validation => verify::<ValidationHeader, ValidationPacket>(
+            batch,
+            transport,
+            public_keys,
+            time_tolerance,
+        ),
+        BatchKind::Sum => {
+            verify::<SumPart, InvalidPacket>(batch, transport, public_keys, time_tolerance)
+        }
+    }
+}
+
+fn verify<H: VerifiableHeader, P: VerifiablePacket>(
+    batch: Batch,
+    transport: &mut dyn Transport,
+    public_keys: &BatchSigningPublicKeys,
+    time_tolerance: Option<Duration>,
+) -> VerificationReport {
+    let mut reader: BatchReader<'_, H, P> = BatchReader::new(batch, transport);
+    reader.set_time_tolerance(time_tolerance);
+    let mut checks = Vec::new();
+
+    // Each check needs the output of the one before it, except that the
+    // packet file can be read even if the header parameters are inconsistent.
+    let header = record(
+        &mut checks,
+        "signature",
+        reader.header(public_keys).map(|header| {
+            let key_identifier = reader.report().key_identifier.unwrap_or_default();
+            (header, format!("header signed with key {}", key_identifier))
+        }),
+    );
+    let packet_ids = match header {
+        Some(header) => {
+            let problems = header.parameter_problems();
+            let parameters = if problems.is_empty() {
+                Ok(((), "parameters are consistent".to_owned()))
+            } else {
+                Err(Error::MalformedHeaderError(problems.join("; ")).into())
+            };
+            record(&mut checks, "header parameters", parameters);
+
+            let packet_reader = reader
+                .packet_file_reader(&header)
+                .map(|packet_reader| (packet_reader, "packet file matches header".to_owned()));
+            match record(&mut checks, "packet file digest", packet_reader) {
+                Some(mut packet_reader) => record(
+                    &mut checks,
+                    "packet schema",
+                    read_packet_ids::<P>(&mut packet_reader),
+                ),
+                None => skip(&mut checks, &["packet schema"]),
+            }
+        }
+        None => skip(
+            &mut checks,
+            &["header parameters", "packet file digest", "packet schema"],
+        ),
+    };
+    let packet_count = packet_ids.as_ref().map(|ids| ids.len() as u64);
+    match packet_ids {
+        Some(packet_ids) => record(&mut checks, "packet count", count_packets(&packet_ids)),
+        None => skip(&mut checks, &["packet count"]),
+    };
+
+    VerificationReport {
+        batch: reader.report(),
+        checks,
+        packet_count,
+    }
+}
+
+/// Records the outcome of the named check, returning the value it produced if
+/// it passed.
+fn record<T>(
+    checks: &mut Vec<Check>,
+    name: &'static str,
+    result: Result<(T, String)>,
+) -> Option<T> {
+    let (value, outcome) = match result {
+        Ok((value, detail)) => (Some(value), CheckOutcome::Passed(detail)),
+        Err(e) => (None, CheckOutcome::Failed(e)),
+    };
+    checks.push(Check { name, outcome });
+    value
+}
+
+/// Records that the named checks were skipped.
+fn skip<T>(checks: &mut Vec<Check>, names: &[&'static str]) -> Option<T> {
+    for name in names {
+        checks.push(Check {
+            name,
+            outcome: CheckOutcome::Skipped,
+        });
+    }
+    None
+}
+
+/// Reads every packet from the reader, returning their UUIDs.
+fn read_packet_ids<P: VerifiablePacket>(
+    packet_reader: &mut Reader<Cursor<Vec<u8>>>,
+) -> Result<(Vec<Uuid>, String)> {
+    let mut packet_ids = Vec::new();
+    loop {
+        match P::read(packet_reader) {
+            Ok(packet) => packet_ids.push(*packet.uuid()),
+            Err(Error::EofError) => break,
+            Err(e) => return Err(e).context(format!("failed to read packet {}", packet_ids.len())),
+        }
+    }
+    Ok((packet_ids, "all packets match schema".to_owned()))
+}
+
+/// Counts the packets, checking that no UUID appears twice.
+fn count_packets(packet_ids: &[Uuid]) -> Result<((), String)> {
+    let mut seen = HashSet::new();
+    if let Some(duplicate) = packet_ids.iter().find(|uuid| !seen.insert(*uuid)) {
+        return Err(Error::MalformedDataPacketError(format!(
+            "duplicate packet UUID {}",
+            duplicate
+        ))
+        .into());
+    }
+    Ok(((), format!("{} distinct packets", packet_ids.len())))
+}
+
+/// Headers whose parameters can be checked for consistency with each other
+/// and with the field used by this facilitator.
+trait VerifiableHeader: Header {
+    /// Returns a description of each inconsistency in the header, or an empty
+    /// Vec if there are none.
+    fn parameter_problems(&self) -> Vec<String>;
+}
+
+/// Checks the parameters common to all headers.
+fn common_parameter_problems(
+    bins: i32,
+    epsilon: f64,
+    prime: i64,
+    number_of_servers: i32,
+    hamming_weight: Option<i32>,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    if bins <= 0 {
+        problems.push(format!("bins {} is not positive", bins));
+    }
+    if !(epsilon.is_finite() && epsilon > 0.0) {
+        problems.push(format!("epsilon {} is not positive", epsilon));
+    }
+    if prime != MODULUS as i64 {
+        problems.push(format!("prime {} is not {}", prime, MODULUS));
+    }
+    if number_of_servers < 2 {
+        problems.push(format!(
+            "number_of_servers {} is less than 2",
+            number_of_servers
+        ));
+    }
+    if let Some(hamming_weight) = hamming_weight {
+        if hamming_weight < 0 || hamming_weight > bins {
+            problems.push(format!(
+                "hamming_weight {} is not between 0 and bins",
+                hamming_weight
+            ));
+        }
+    }
+    problems
+}
+
+impl VerifiableHeader for IngestionHeader {
+    fn parameter_problems(&self) -> Vec<String> {
+        let mut problems = common_parameter_problems(
+            self.bins,
+            self.epsilon,
+            self.prime,
+            self.number_of_servers,
+            self.hamming_weight,
+        );
+        if self.batch_start_time > self.batch_end_time {
+            problems.push("batch_start_time is after batch_end_time".to_owned());
+        }
+        problems
+    }
+}
+
+impl VerifiableHeader for ValidationHeader {
+    fn parameter_problems(&self) -> Vec<String> {
+        common_parameter_problems(
+            self.bins,
+            self.epsilon,
+            self.prime,
+            self.number_of_servers,
+            self.hamming_weight,
+        )
+    }
+}
+
+impl VerifiableHeader for SumPart {
+    fn parameter_problems(&self) -> Vec<String> {
+        let mut problems = common_parameter_problems(
+            self.bins,
+            self.epsilon,
+            self.prime,
+            self.number_of_servers,
+            self.hamming_weight,
+        );
+        if self.sum.len() != self.bins as usize {
+            problems.push(format!(
+                "sum has {} elements but bins is {}",
+                self.sum.len(),
+                self.bins
+            ));
+        }
+        if self.sum.iter().any(|s| *s < 0 || *s >= MODULUS as i64) {
+            problems.push("sum has elements outside the field".to_owned());
+        }
+        if self.aggregation_start_time > self.aggregation_end_time {
+            problems.push("aggregation_start_time is after aggregation_end_time".to_owned());
+        }
+        if self.total_individual_clients < 0 {
+            problems.push("total_individual_clients is negative".to_owned());
+        }
+        if let Some(noise_epsilon) = self.noise_epsilon {
+            if noise_epsilon.is_nan() || noise_epsilon <= 0.0 {
+                problems.push(format!("noise_epsilon {} is not positive", noise_epsilon));
+            }
+        }
+        let mut seen = HashSet::new();
+        if let Some(duplicate) = self.batch_uuids.iter().find(|uuid| !seen.insert(*uuid)) {
+            problems.push(format!("batch_uuids contains {} twice", duplicate));
+        }
+        problems
+    }
+}
+
+/// Packets identified by a UUID, which must be unique within a batch.
+trait VerifiablePacket: Packet {
+    fn uuid(&self) -> &Uuid;
+}
+
+impl VerifiablePacket for IngestionDataSharePacket {
+    fn uuid(&self) -> &Uuid {
+        &self.uuid
+    }
+}
+
+impl VerifiablePacket for ValidationPacket {
+    fn uuid(&self) -> &Uuid {
+        &self.uuid
+    }
+}
+
+impl VerifiablePacket for InvalidPacket {
+    fn uuid(&self) -> &Uuid {
+        &self.uuid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sample::generate_ingestion_sample,
+        test_utils::{
+            default_ingestor_private_key, default_ingestor_public_key,
+            DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY, DEFAULT_PHA_ECIES_PRIVATE_KEY,
+        },
+        transport::LocalFileTransport,
+    };
+    use assert_matches::assert_matches;
+    use chrono::NaiveDate;
+    use prio::encrypt::PrivateKey;
+    use std::{collections::HashMap, io::Write};
+
+    fn outcomes(report: &VerificationReport) -> Vec<(&'static str, &'static str)> {
+        report
+            .checks
+            .iter()
+            .map(|check| {
+                (
+                    check.name,
+                    match check.outcome {
+                        CheckOutcome::Passed(_) => "pass",
+                        CheckOutcome::Failed(_) => "fail",
+                        CheckOutcome::Skipped => "skip",
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn verify_ingestion_batch() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let mut other_transport = LocalFileTransport::new(tempdir.path().join("other"));
+        let batch_uuid = Uuid::new_v4();
+        let date = NaiveDate::from_ymd(2009, 2, 13).and_hms(23, 31, 0);
+        let batch = || Batch::new_ingestion("fake-aggregation", &batch_uuid, &date);
+        let mut public_keys = HashMap::new();
+        public_keys.insert(
+            default_ingestor_private_key().identifier,
+            default_ingestor_public_key(),
+        );
+
+        // A batch that has not been written yet may show up later.
+        let report = verify_batch(
+            BatchKind::Ingestion,
+            batch(),
+            &mut transport,
+            &public_keys,
+            None,
+        );
+        assert_eq!(
+            outcomes(&report),
+            vec![
+                ("signature", "fail"),
+                ("header parameters", "skip"),
+                ("packet file digest", "skip"),
+                ("packet schema", "skip"),
+                ("packet count", "skip"),
+            ]
+        );
+        assert!(crate::is_retryable(&report.into_result().unwrap_err()));
+
+        generate_ingestion_sample(
+            &mut transport,
+            &mut other_transport,
+            &batch_uuid,
+            "fake-aggregation",
+            &date,
+            &PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
+            &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+            &default_ingestor_private_key(),
+            10,
+            7,
+            0.11,
+            100,
+            100,
+            None,
+        )
+        .unwrap();
+
+        let report = verify_batch(
+            BatchKind::Ingestion,
+            batch(),
+            &mut transport,
+            &public_keys,
+            None,
+        );
+        assert!(report.passed(), "unexpected report {}", report);
+        assert_eq!(report.packet_count, Some(7));
+        assert_matches!(
+            &report.checks[4].outcome,
+            CheckOutcome::Passed(detail) if detail == "7 distinct packets"
+        );
+        assert_eq!(
+            report.batch.key_identifier,
+            Some("default-ingestor-signing-key".to_owned())
+        );
+        report.into_result().unwrap();
+
+        let mut writer = transport.put(batch().packet_file_key()).unwrap();
+        writer.write_all(b"tampered").unwrap();
+        writer.complete_upload().unwrap();
+        let report = verify_batch(
+            BatchKind::Ingestion,
+            batch(),
+            &mut transport,
+            &public_keys,
+            None,
+        );
+        assert_eq!(
+            outcomes(&report),
+            vec![
+                ("signature", "pass"),
+                ("header parameters", "pass"),
+                ("packet file digest", "fail"),
+                ("packet schema", "skip"),
+                ("packet count", "skip"),
+            ]
+        );
+        assert!(report.to_string().ends_with("batch is NOT valid"));
+        let err = report.into_result().unwrap_err();
+        assert_matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::DigestMismatchError(_))
+        );
+        assert!(!crate::is_retryable(&err));
+    }
+
+    #[test]
+    fn sum_part_parameter_problems() {
+        let sum_part = SumPart {
+            batch_uuids: vec![Uuid::nil(), Uuid::nil()],
+            name: "fake-aggregation".to_owned(),
+            bins: 2,
+            epsilon: 1.0,
+            prime: MODULUS as i64,
+            number_of_servers: 2,
+            hamming_weight: Some(3),
+            sum: vec![1, 2, 3],
+            aggregation_start_time: 0,
+            aggregation_end_time: 1,
+            packet_file_digest: vec![],
+            total_individual_clients: 3,
+            noise_epsilon: Some(0.0),
+        };
+        assert_eq!(
+            sum_part.parameter_problems(),
+            vec![
+                "hamming_weight 3 is not between 0 and bins",
+                "sum has 3 elements but bins is 2",
+                "noise_epsilon 0 is not positive",
+                "batch_uuids contains 00000000-0000-0000-0000-000000000000 twice",
+            ]
+        );
+    }
+}
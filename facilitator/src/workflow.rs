@@ -1,6 +1,7 @@
 // TODO: remove once things are less stubby
 #![allow(dead_code, unreachable_code, unused_variables)]
 
+use crate::metrics::{start_metrics_server, Registry};
 use anyhow::Result;
 use std::path::{Path, PathBuf};
 use structopt::StructOpt;
@@ -87,6 +88,10 @@ pub struct WorkflowArgs {
     /// Path to config file
     #[structopt(long)]
     config_path: PathBuf,
+
+    /// Address on which to serve Prometheus metrics at /metrics
+    #[structopt(long, env = "METRICS_LISTEN_ADDRESS", default_value = "0.0.0.0:8080")]
+    metrics_listen_address: String,
 }
 
 fn get_config(path: &Path) -> Result<config::Config> {
@@ -139,6 +144,9 @@ fn process_validation() -> Result<()> {
         //  - Inputs: `.batch*`, `.validity_?*`
         //  - finish validation, produce sum data
         //  - Outputs: `.sum_N`, `.invalid_uuid_N.avro`
+        //  - if the policy cross-checks invalid UUIDs, also `.invalid_set_N`
+        //    in the peer's validation bucket, and the job is retried until
+        //    the peer's is present
         //  At end of job:
         //  - delete matching `.expected` marker (optional, could be GCd by manager instead)
         //  - delete/archive inputs
@@ -157,7 +165,7 @@ fn process_reduce() -> Result<()> {
         todo!("group files by time intervals, skip batches with .expected markers");
 
     for batch in &batches_to_run {
-        // TODO: reduce jobs
+        // TODO: reduce jobs, run with `facilitator reduce`
         //  - Inputs: time range, num of expected batches (just in case?)
         //  - lists Sum->Reduce bucket using the date range provided and aggregates
         //  - Outputs: `{date}-{date}.sum_N`, `.invalid_uuid_N.avro`, `.sum_N.sig`
@@ -170,6 +178,9 @@ fn process_reduce() -> Result<()> {
 }
 
 pub fn workflow_main(args: WorkflowArgs) -> Result<()> {
+    let registry = Registry::new();
+    start_metrics_server(registry.clone(), &args.metrics_listen_address)?;
+
     let config = get_config(&args.config_path)?;
 
     todo!("initialize environment: S3/k8s credentials, etc.");
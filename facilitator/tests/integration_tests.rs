@@ -1,9 +1,20 @@
-use chrono::NaiveDateTime;
+use assert_matches::assert_matches;
+use avro_rs::Codec;
+use chrono::{Duration, NaiveDateTime};
 use facilitator::{
     aggregation::BatchAggregator,
-    batch::{Batch, BatchReader},
-    idl::{IngestionDataSharePacket, SumPart},
+    batch::{Batch, BatchReader, BatchWriter},
+    combine::combine_sums,
+    idl::{
+        Header, IngestionDataSharePacket, IngestionHeader, InvalidPacket, InvalidUuidSet, Packet,
+        SumPart,
+    },
     intake::BatchIntaker,
+    manifest::BatchSigningPublicKeys,
+    metrics::Registry,
+    policy::AggregationPolicy,
+    reduce::SumPartReducer,
+    report::{JobReport, StageReport},
     sample::generate_ingestion_sample,
     test_utils::{
         default_facilitator_signing_private_key, default_facilitator_signing_public_key,
@@ -12,18 +23,22 @@ use facilitator::{
         DEFAULT_PHA_ECIES_PRIVATE_KEY,
     },
     transport::{
-        LocalFileTransport, SignableTransport, VerifiableAndDecryptableTransport,
-        VerifiableTransport,
+        IngestorTransport, LocalFileTransport, PeerValidationTransport, SignableTransport,
+        Transport, VerifiableAndDecryptableTransport, VerifiableTransport,
     },
+    BatchSigningKey, Error,
 };
-use prio::{encrypt::PrivateKey, util::reconstruct_shares};
-use std::collections::HashMap;
+use prio::{encrypt::PrivateKey, finite_field::Field, util::reconstruct_shares};
+use std::{collections::HashMap, fs::File, path::Path, process::Command};
 use uuid::Uuid;
 
 #[test]
 fn end_to_end() {
     let pha_tempdir = tempfile::TempDir::new().unwrap();
     let facilitator_tempdir = tempfile::TempDir::new().unwrap();
+    // The PHA reads batch 2 from a second ingestor's bucket, while the
+    // facilitator reads both batches from the same one.
+    let pha_second_ingestor_tempdir = tempfile::TempDir::new().unwrap();
 
     let aggregation_name = "fake-aggregation-1".to_owned();
     let date = NaiveDateTime::from_timestamp(2234567890, 654321);
@@ -53,11 +68,12 @@ fn end_to_end() {
         0.11,
         100,
         100,
+        None,
     )
     .unwrap();
 
     let batch_2_reference_sum = generate_ingestion_sample(
-        &mut LocalFileTransport::new(pha_tempdir.path().to_path_buf()),
+        &mut LocalFileTransport::new(pha_second_ingestor_tempdir.path().to_path_buf()),
         &mut LocalFileTransport::new(facilitator_tempdir.path().to_path_buf()),
         &batch_2_uuid,
         &aggregation_name,
@@ -70,6 +86,7 @@ fn end_to_end() {
         0.11,
         100,
         100,
+        None,
     )
     .unwrap();
 
@@ -78,9 +95,9 @@ fn end_to_end() {
         default_ingestor_private_key().identifier,
         default_ingestor_public_key(),
     );
-    let mut pha_ingest_transport = VerifiableAndDecryptableTransport {
+    let ingest_transport = |path: &std::path::Path| VerifiableAndDecryptableTransport {
         transport: VerifiableTransport {
-            transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
+            transport: Box::new(LocalFileTransport::new(path.to_path_buf())),
             batch_signing_public_keys: ingestor_pub_keys.clone(),
         },
         packet_decryption_keys: vec![
@@ -88,39 +105,44 @@ fn end_to_end() {
             PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
         ],
     };
-
-    let mut facilitator_ingest_transport = VerifiableAndDecryptableTransport {
-        transport: VerifiableTransport {
-            transport: Box::new(LocalFileTransport::new(
-                facilitator_tempdir.path().to_path_buf(),
-            )),
-            batch_signing_public_keys: ingestor_pub_keys.clone(),
+    let mut pha_ingestors = vec![
+        IngestorTransport {
+            name: Some("first-ingestor".to_owned()),
+            transport: ingest_transport(pha_tempdir.path()),
         },
-        packet_decryption_keys: vec![
-            PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
-            PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
-        ],
-    };
+        IngestorTransport {
+            name: Some("second-ingestor".to_owned()),
+            transport: ingest_transport(pha_second_ingestor_tempdir.path()),
+        },
+    ];
+    let mut facilitator_ingestors = vec![IngestorTransport {
+        name: None,
+        transport: ingest_transport(facilitator_tempdir.path()),
+    }];
 
     let mut pha_validate_signable_transport = SignableTransport {
         transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
-        batch_signing_key: default_pha_signing_private_key(),
+        batch_signer: Box::new(default_pha_signing_private_key()),
+        sign_batch_manifest: true,
+        packet_file_codec: Codec::Snappy,
     };
 
     let mut facilitator_validate_signable_transport = SignableTransport {
         transport: Box::new(LocalFileTransport::new(
             facilitator_tempdir.path().to_path_buf(),
         )),
-        batch_signing_key: default_facilitator_signing_private_key(),
+        batch_signer: Box::new(default_facilitator_signing_private_key()),
+        sign_batch_manifest: true,
+        packet_file_codec: Codec::Null,
     };
 
     BatchIntaker::new(
         &aggregation_name,
         &batch_1_uuid,
         &date,
-        &mut pha_ingest_transport,
+        &mut pha_ingestors[0].transport,
         &mut pha_validate_signable_transport,
-        true,
+        0,
     )
     .unwrap()
     .generate_validation_share()
@@ -130,9 +152,9 @@ fn end_to_end() {
         &aggregation_name,
         &batch_2_uuid,
         &date,
-        &mut pha_ingest_transport,
+        &mut pha_ingestors[1].transport,
         &mut pha_validate_signable_transport,
-        true,
+        0,
     )
     .unwrap()
     .generate_validation_share()
@@ -142,9 +164,9 @@ fn end_to_end() {
         &aggregation_name,
         &batch_1_uuid,
         &date,
-        &mut facilitator_ingest_transport,
+        &mut facilitator_ingestors[0].transport,
         &mut facilitator_validate_signable_transport,
-        false,
+        1,
     )
     .unwrap()
     .generate_validation_share()
@@ -154,9 +176,9 @@ fn end_to_end() {
         &aggregation_name,
         &batch_2_uuid,
         &date,
-        &mut facilitator_ingest_transport,
+        &mut facilitator_ingestors[0].transport,
         &mut facilitator_validate_signable_transport,
-        false,
+        1,
     )
     .unwrap()
     .generate_validation_share()
@@ -187,38 +209,128 @@ fn end_to_end() {
         batch_signing_public_keys: facilitator_pub_keys.clone(),
     };
 
+    // Each server reads the other's validations as those of its only peer.
+    let mut pha_peer_validate_transports = vec![PeerValidationTransport {
+        server_index: 1,
+        transport: VerifiableTransport {
+            transport: Box::new(LocalFileTransport::new(
+                facilitator_tempdir.path().to_path_buf(),
+            )),
+            batch_signing_public_keys: facilitator_pub_keys.clone(),
+        },
+    }];
+    let mut facilitator_peer_validate_transports = vec![PeerValidationTransport {
+        server_index: 0,
+        transport: VerifiableTransport {
+            transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
+            batch_signing_public_keys: pha_pub_keys.clone(),
+        },
+    }];
+
     let mut pha_aggregation_transport = SignableTransport {
         transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
-        batch_signing_key: default_pha_signing_private_key(),
+        batch_signer: Box::new(default_pha_signing_private_key()),
+        sign_batch_manifest: true,
+        packet_file_codec: Codec::Null,
     };
-    BatchAggregator::new(
+    // A batch the peer has not validated yet is left out of the sum part, and
+    // if no batch is ready, there is no sum part.
+    {
+        let mut pha_aggregator = BatchAggregator::new(
+            &aggregation_name,
+            &start_date,
+            &end_date,
+            0,
+            &mut pha_ingestors,
+            &mut pha_validate_verifiable_transport,
+            &mut pha_peer_validate_transports,
+            &mut pha_aggregation_transport,
+        )
+        .unwrap();
+        let missing_batch_uuid = Uuid::new_v4();
+        let with_missing_batch = vec![(batch_1_uuid, date), (missing_batch_uuid, date)];
+        assert_eq!(
+            pha_aggregator
+                .pending_peer_validations(&with_missing_batch)
+                .unwrap(),
+            vec![(missing_batch_uuid, date)]
+        );
+        pha_aggregator
+            .generate_sum_part(&with_missing_batch)
+            .unwrap();
+        assert_eq!(
+            pha_aggregator.report().pending_batches,
+            vec![missing_batch_uuid]
+        );
+        assert_eq!(pha_aggregator.report().packets.total, 10);
+
+        let err = pha_aggregator
+            .generate_sum_part(&[(missing_batch_uuid, date)])
+            .unwrap_err();
+        assert_matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::PeerValidationsPendingError(uuids)) if uuids == &vec![missing_batch_uuid]
+        );
+        assert!(facilitator::is_retryable(&err));
+    }
+    let sum_part = BatchReader::<'_, SumPart, InvalidPacket>::new(
+        Batch::new_sum(&aggregation_name, &start_date, &end_date, 0),
+        &mut *pha_aggregation_transport.transport,
+    )
+    .header(&pha_pub_keys)
+    .unwrap();
+    assert_eq!(sum_part.batch_uuids, vec![batch_1_uuid]);
+
+    let mut pha_aggregator = BatchAggregator::new(
         &aggregation_name,
         &start_date,
         &end_date,
-        true,
-        &mut pha_ingest_transport,
+        0,
+        &mut pha_ingestors,
         &mut pha_validate_verifiable_transport,
-        &mut facilitator_validate_verifiable_transport,
+        &mut pha_peer_validate_transports,
         &mut pha_aggregation_transport,
     )
-    .unwrap()
-    .generate_sum_part(&batch_ids_and_dates)
     .unwrap();
+    pha_aggregator
+        .generate_sum_part(&batch_ids_and_dates)
+        .unwrap();
+    // Each ingestion batch is recorded along with the ingestor it came from,
+    // followed by the own and peer validation batches.
+    let ingestors: Vec<_> = pha_aggregator
+        .report()
+        .inputs
+        .iter()
+        .map(|input| input.ingestor.as_deref())
+        .collect();
+    assert_eq!(
+        ingestors,
+        vec![
+            Some("first-ingestor"),
+            None,
+            None,
+            Some("second-ingestor"),
+            None,
+            None
+        ]
+    );
 
     let mut facilitator_aggregation_transport = SignableTransport {
         transport: Box::new(LocalFileTransport::new(
             facilitator_tempdir.path().to_path_buf(),
         )),
-        batch_signing_key: default_facilitator_signing_private_key(),
+        batch_signer: Box::new(default_facilitator_signing_private_key()),
+        sign_batch_manifest: true,
+        packet_file_codec: Codec::Deflate,
     };
     BatchAggregator::new(
         &aggregation_name,
         &start_date,
         &end_date,
-        false,
-        &mut facilitator_ingest_transport,
+        1,
+        &mut facilitator_ingestors,
         &mut facilitator_validate_verifiable_transport,
-        &mut pha_validate_verifiable_transport,
+        &mut facilitator_peer_validate_transports,
         &mut facilitator_aggregation_transport,
     )
     .unwrap()
@@ -227,7 +339,7 @@ fn end_to_end() {
 
     let mut pha_aggregation_batch_reader: BatchReader<'_, SumPart, IngestionDataSharePacket> =
         BatchReader::new(
-            Batch::new_sum(&aggregation_name, &start_date, &end_date, true),
+            Batch::new_sum(&aggregation_name, &start_date, &end_date, 0),
             &mut *pha_aggregation_transport.transport,
         );
     let pha_sum_part = pha_aggregation_batch_reader.header(&pha_pub_keys).unwrap();
@@ -244,7 +356,7 @@ fn end_to_end() {
         SumPart,
         IngestionDataSharePacket,
     > = BatchReader::new(
-        Batch::new_sum(&aggregation_name, &start_date, &end_date, false),
+        Batch::new_sum(&aggregation_name, &start_date, &end_date, 1),
         &mut *facilitator_aggregation_transport.transport,
     );
     let facilitator_sum_part = facilitator_aggregation_batch_reader
@@ -276,12 +388,952 @@ fn end_to_end() {
         facilitator_sum_part.total_individual_clients, pha_sum_part.total_individual_clients
     );
 
+    // Both batches hold ten valid packets, each from one client.
+    assert_eq!(facilitator_sum_part.total_individual_clients, 20);
+}
+
+#[test]
+fn more_than_two_servers() {
+    let tempdir = tempfile::TempDir::new().unwrap();
+    let transport = |name: &str| -> Box<dyn Transport> {
+        Box::new(LocalFileTransport::new(tempdir.path().join(name)))
+    };
+
+    // libprio only splits data between two servers, so a third is rejected
+    // when the intaker and aggregator are configured.
+    let mut ingestors = vec![IngestorTransport {
+        name: None,
+        transport: VerifiableAndDecryptableTransport {
+            transport: VerifiableTransport {
+                transport: transport("ingestion"),
+                batch_signing_public_keys: HashMap::new(),
+            },
+            packet_decryption_keys: vec![
+                PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap()
+            ],
+        },
+    }];
+    let mut validate_transport = SignableTransport {
+        transport: transport("pha"),
+        batch_signer: Box::new(default_pha_signing_private_key()),
+        sign_batch_manifest: true,
+        packet_file_codec: Codec::Null,
+    };
+    let mut intaker = BatchIntaker::new(
+        "fake-aggregation",
+        &Uuid::new_v4(),
+        &NaiveDateTime::from_timestamp(2234567890, 0),
+        &mut ingestors[0].transport,
+        &mut validate_transport,
+        0,
+    )
+    .unwrap();
+    let err = intaker.set_number_of_servers(3).unwrap_err();
+    assert_matches!(
+        err.downcast_ref::<Error>(),
+        Some(Error::ConfigurationError(_))
+    );
+    assert!(!facilitator::is_retryable(&err));
+
+    let mut own_validate_transport = VerifiableTransport {
+        transport: transport("pha"),
+        batch_signing_public_keys: HashMap::new(),
+    };
+    let mut peer_validate_transports = (1..3)
+        .map(|server_index| PeerValidationTransport {
+            server_index,
+            transport: VerifiableTransport {
+                transport: transport(&format!("server-{}", server_index)),
+                batch_signing_public_keys: HashMap::new(),
+            },
+        })
+        .collect::<Vec<_>>();
+    let mut aggregation_transport = SignableTransport {
+        transport: transport("pha"),
+        batch_signer: Box::new(default_pha_signing_private_key()),
+        sign_batch_manifest: true,
+        packet_file_codec: Codec::Null,
+    };
+    let start = NaiveDateTime::from_timestamp(1234567890, 0);
+    let end = NaiveDateTime::from_timestamp(3234567890, 0);
+    let err = match BatchAggregator::new(
+        "fake-aggregation",
+        &start,
+        &end,
+        0,
+        &mut ingestors,
+        &mut own_validate_transport,
+        &mut peer_validate_transports,
+        &mut aggregation_transport,
+    ) {
+        Ok(_) => panic!("aggregator configured with two peers"),
+        Err(err) => err,
+    };
+    assert_matches!(
+        err.downcast_ref::<Error>(),
+        Some(Error::ConfigurationError(_))
+    );
+}
+
+/// Two servers, the PHA (server 0) and a facilitator (server 1), each of which
+/// reads its ingestion batches from and writes its own batches to a directory
+/// of its own, from which the other reads them.
+struct TwoServers {
+    tempdirs: [tempfile::TempDir; 2],
+    ingestor_pub_keys: BatchSigningPublicKeys,
+    pub_keys: [BatchSigningPublicKeys; 2],
+}
+
+impl TwoServers {
+    const ECIES_KEYS: [&'static str; 2] = [
+        DEFAULT_PHA_ECIES_PRIVATE_KEY,
+        DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY,
+    ];
+
+    fn new() -> TwoServers {
+        let mut ingestor_pub_keys = HashMap::new();
+        ingestor_pub_keys.insert(
+            default_ingestor_private_key().identifier,
+            default_ingestor_public_key(),
+        );
+        let mut pha_pub_keys = HashMap::new();
+        pha_pub_keys.insert(
+            default_pha_signing_private_key().identifier,
+            default_pha_signing_public_key(),
+        );
+        let mut facilitator_pub_keys = HashMap::new();
+        facilitator_pub_keys.insert(
+            default_facilitator_signing_private_key().identifier,
+            default_facilitator_signing_public_key(),
+        );
+        TwoServers {
+            tempdirs: [
+                tempfile::TempDir::new().unwrap(),
+                tempfile::TempDir::new().unwrap(),
+            ],
+            ingestor_pub_keys,
+            pub_keys: [pha_pub_keys, facilitator_pub_keys],
+        }
+    }
+
+    fn path(&self, server_index: usize) -> &Path {
+        self.tempdirs[server_index].path()
+    }
+
+    fn signing_key(server_index: usize) -> BatchSigningKey {
+        match server_index {
+            0 => default_pha_signing_private_key(),
+            _ => default_facilitator_signing_private_key(),
+        }
+    }
+
+    /// Writes an ingestion batch of ten packets with ten bins for both servers,
+    /// returning the sum of its data.
+    fn generate_sample(
+        &self,
+        aggregation_name: &str,
+        batch_uuid: &Uuid,
+        date: &NaiveDateTime,
+        hamming_weight: Option<i32>,
+    ) -> Vec<Field> {
+        generate_ingestion_sample(
+            &mut self.transport(0),
+            &mut self.transport(1),
+            batch_uuid,
+            aggregation_name,
+            date,
+            &PrivateKey::from_base64(Self::ECIES_KEYS[0]).unwrap(),
+            &PrivateKey::from_base64(Self::ECIES_KEYS[1]).unwrap(),
+            &default_ingestor_private_key(),
+            10,
+            10,
+            0.11,
+            100,
+            100,
+            hamming_weight,
+        )
+        .unwrap()
+    }
+
+    fn transport(&self, server_index: usize) -> LocalFileTransport {
+        LocalFileTransport::new(self.path(server_index).to_path_buf())
+    }
+
+    fn ingestors(&self, server_index: usize) -> Vec<IngestorTransport> {
+        vec![IngestorTransport {
+            name: None,
+            transport: VerifiableAndDecryptableTransport {
+                transport: VerifiableTransport {
+                    transport: Box::new(self.transport(server_index)),
+                    batch_signing_public_keys: self.ingestor_pub_keys.clone(),
+                },
+                packet_decryption_keys: vec![PrivateKey::from_base64(
+                    Self::ECIES_KEYS[server_index],
+                )
+                .unwrap()],
+            },
+        }]
+    }
+
+    /// Returns a transport that writes batches signed by the server.
+    fn signable_transport(&self, server_index: usize) -> SignableTransport {
+        SignableTransport {
+            transport: Box::new(self.transport(server_index)),
+            batch_signer: Box::new(Self::signing_key(server_index)),
+            sign_batch_manifest: true,
+            packet_file_codec: Codec::Null,
+        }
+    }
+
+    /// Returns a transport that reads batches signed by the server.
+    fn verifiable_transport(&self, server_index: usize) -> VerifiableTransport {
+        VerifiableTransport {
+            transport: Box::new(self.transport(server_index)),
+            batch_signing_public_keys: self.pub_keys[server_index].clone(),
+        }
+    }
+
+    /// Generates both servers' validation shares for the batch.
+    fn intake(&self, aggregation_name: &str, batch_uuid: &Uuid, date: &NaiveDateTime) {
+        for server_index in 0..2 {
+            BatchIntaker::new(
+                aggregation_name,
+                batch_uuid,
+                date,
+                &mut self.ingestors(server_index)[0].transport,
+                &mut self.signable_transport(server_index),
+                server_index,
+            )
+            .unwrap()
+            .generate_validation_share()
+            .unwrap();
+        }
+    }
+
+    /// Aggregates the batches into the server's sum part for the window under
+    /// the policy, returning the outcome and the aggregator's report.
+    fn aggregate(
+        &self,
+        server_index: usize,
+        aggregation_name: &str,
+        window: (&NaiveDateTime, &NaiveDateTime),
+        batch_ids: &[(Uuid, NaiveDateTime)],
+        policy: &AggregationPolicy,
+    ) -> (anyhow::Result<()>, StageReport) {
+        self.aggregate_with_metrics(
+            server_index,
+            aggregation_name,
+            window,
+            batch_ids,
+            policy,
+            &Registry::new(),
+        )
+    }
+
+    /// Like aggregate, recording the aggregator's metrics in registry.
+    fn aggregate_with_metrics(
+        &self,
+        server_index: usize,
+        aggregation_name: &str,
+        window: (&NaiveDateTime, &NaiveDateTime),
+        batch_ids: &[(Uuid, NaiveDateTime)],
+        policy: &AggregationPolicy,
+        registry: &Registry,
+    ) -> (anyhow::Result<()>, StageReport) {
+        let mut ingestors = self.ingestors(server_index);
+        let mut own_validation_transport = self.verifiable_transport(server_index);
+        let mut peer_validation_transports = vec![PeerValidationTransport {
+            server_index: 1 - server_index,
+            transport: self.verifiable_transport(1 - server_index),
+        }];
+        let mut aggregation_transport = self.signable_transport(server_index);
+        let mut aggregator = BatchAggregator::new(
+            aggregation_name,
+            window.0,
+            window.1,
+            server_index,
+            &mut ingestors,
+            &mut own_validation_transport,
+            &mut peer_validation_transports,
+            &mut aggregation_transport,
+        )
+        .unwrap();
+        aggregator.set_policy(policy.clone()).unwrap();
+        aggregator.set_metrics(registry).unwrap();
+        let result = aggregator.generate_sum_part(batch_ids);
+        (result, aggregator.report().clone())
+    }
+
+    /// Reads the header of the server's sum part for the window.
+    fn sum_part(
+        &self,
+        server_index: usize,
+        aggregation_name: &str,
+        window: (&NaiveDateTime, &NaiveDateTime),
+    ) -> SumPart {
+        let mut transport = self.transport(server_index);
+        BatchReader::<'_, SumPart, InvalidPacket>::new(
+            Batch::new_sum(aggregation_name, window.0, window.1, server_index),
+            &mut transport,
+        )
+        .header(&self.pub_keys[server_index])
+        .unwrap()
+    }
+}
+
+/// Rewrites the header of the ingestion batch in dir to declare the provided
+/// hamming weight, re-signing it with the default ingestor key.
+fn redeclare_hamming_weight(
+    dir: &Path,
+    aggregation_name: &str,
+    batch_uuid: &Uuid,
+    date: &NaiveDateTime,
+    hamming_weight: i32,
+) {
+    let batch = Batch::new_ingestion(aggregation_name, batch_uuid, date);
+    let mut transport = LocalFileTransport::new(dir.to_path_buf());
+    let header = IngestionHeader::read(transport.get(batch.header_key()).unwrap()).unwrap();
+    let mut writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+        BatchWriter::new(batch, &mut transport);
+    let signature = writer
+        .put_header(
+            &IngestionHeader {
+                hamming_weight: Some(hamming_weight),
+                ..header
+            },
+            &default_ingestor_private_key(),
+        )
+        .unwrap();
+    writer
+        .put_signature(&signature, &default_ingestor_private_key())
+        .unwrap();
+}
+
+#[test]
+fn hamming_weight() {
+    let servers = TwoServers::new();
+    let aggregation_name = "fake-aggregation-1".to_owned();
+    let date = NaiveDateTime::from_timestamp(2234567890, 654321);
+    let valid_batch_uuid = Uuid::new_v4();
+    let invalid_batch_uuid = Uuid::new_v4();
+
+    // Both batches hold vectors with three bits set, but the headers of the
+    // second are rewritten to declare a hamming weight of two.
+    for batch_uuid in &[valid_batch_uuid, invalid_batch_uuid] {
+        servers.generate_sample(&aggregation_name, batch_uuid, &date, Some(3));
+    }
+    for server_index in 0..2 {
+        redeclare_hamming_weight(
+            servers.path(server_index),
+            &aggregation_name,
+            &invalid_batch_uuid,
+            &date,
+            2,
+        );
+    }
+    for batch_uuid in &[valid_batch_uuid, invalid_batch_uuid] {
+        servers.intake(&aggregation_name, batch_uuid, &date);
+    }
+
+    // The batches declare different hamming weights, so they are aggregated
+    // separately, into sum parts for windows of their own.
+    let policy = AggregationPolicy {
+        sum_noise_epsilon: Some(1.0),
+        ..AggregationPolicy::default()
+    };
+    let second_window_end = date + Duration::minutes(1);
+    for (batch_uuid, window_end, valid) in &[
+        (valid_batch_uuid, date, 10),
+        (invalid_batch_uuid, second_window_end, 0),
+    ] {
+        let (result, report) = servers.aggregate(
+            0,
+            &aggregation_name,
+            (&date, window_end),
+            &[(*batch_uuid, date)],
+            &policy,
+        );
+        result.unwrap();
+        assert_eq!(report.packets.total, 10);
+        assert_eq!(report.packets.valid, *valid);
+
+        let sum_part = servers.sum_part(0, &aggregation_name, (&date, window_end));
+        assert_eq!(sum_part.batch_uuids, vec![*batch_uuid]);
+        assert_eq!(sum_part.noise_epsilon, Some(1.0));
+    }
+}
+
+#[test]
+fn min_contributions() {
+    let servers = TwoServers::new();
+    let aggregation_name = "fake-aggregation-1".to_owned();
+    let date = NaiveDateTime::from_timestamp(2234567890, 654321);
+    let batch_uuid = Uuid::new_v4();
+    servers.generate_sample(&aggregation_name, &batch_uuid, &date, None);
+    servers.intake(&aggregation_name, &batch_uuid, &date);
+
+    // The batch's ten valid packets are too few for either server to reveal
+    // its share of their sum, so neither writes a sum part.
+    let policy = AggregationPolicy {
+        min_contributions: Some(11),
+        ..AggregationPolicy::default()
+    };
+    for server_index in 0..2 {
+        let (result, report) = servers.aggregate(
+            server_index,
+            &aggregation_name,
+            (&date, &date),
+            &[(batch_uuid, date)],
+            &policy,
+        );
+        result.unwrap();
+        assert_eq!(report.packets.valid, 10);
+        assert!(report.sum_part_withheld.is_some());
+        // The only batch written is the set of packets found invalid.
+        assert_eq!(report.outputs.len(), 1);
+        assert!(!servers
+            .path(server_index)
+            .join(Batch::new_sum(&aggregation_name, &date, &date, server_index).header_key())
+            .exists());
+    }
+
+    // Ten are enough under a lower minimum.
+    let policy = AggregationPolicy {
+        min_contributions: Some(10),
+        ..AggregationPolicy::default()
+    };
+    for server_index in 0..2 {
+        let (result, report) = servers.aggregate(
+            server_index,
+            &aggregation_name,
+            (&date, &date),
+            &[(batch_uuid, date)],
+            &policy,
+        );
+        result.unwrap();
+        assert_eq!(report.sum_part_withheld, None);
+        let sum_part = servers.sum_part(server_index, &aggregation_name, (&date, &date));
+        assert_eq!(sum_part.batch_uuids, vec![batch_uuid]);
+    }
+}
+
+#[test]
+fn reduce_and_combine() {
+    let servers = TwoServers::new();
+    let aggregation_name = "fake-aggregation-1".to_owned();
+    let first_window = (
+        NaiveDateTime::from_timestamp(2234567880, 0),
+        NaiveDateTime::from_timestamp(2234567940, 0),
+    );
+    let second_window = (
+        NaiveDateTime::from_timestamp(2234568000, 0),
+        NaiveDateTime::from_timestamp(2234568060, 0),
+    );
+    let windows = [first_window, second_window];
+    let batch_uuids = [Uuid::new_v4(), Uuid::new_v4()];
+
+    // The packets in the second batch have two bits set but it declares a
+    // hamming weight of one, so they are all invalid.
+    let mut reference_sums = Vec::new();
+    for (index, batch_uuid) in batch_uuids.iter().enumerate() {
+        reference_sums.push(servers.generate_sample(
+            &aggregation_name,
+            batch_uuid,
+            &windows[index].0,
+            Some(index as i32 + 1),
+        ));
+    }
+    for server_index in 0..2 {
+        redeclare_hamming_weight(
+            servers.path(server_index),
+            &aggregation_name,
+            &batch_uuids[1],
+            &windows[1].0,
+            1,
+        );
+    }
+    for (batch_uuid, window) in batch_uuids.iter().zip(&windows) {
+        servers.intake(&aggregation_name, batch_uuid, &window.0);
+    }
+
+    let mut sums = Vec::new();
+    for server_index in 0..2 {
+        for (batch_uuid, window) in batch_uuids.iter().zip(&windows) {
+            let (result, _) = servers.aggregate(
+                server_index,
+                &aggregation_name,
+                (&window.0, &window.1),
+                &[(*batch_uuid, window.0)],
+                &AggregationPolicy::default(),
+            );
+            result.unwrap();
+        }
+        // Sum parts count the clients whose packets were summed, not the bins,
+        // so the second window's has none.
+        let clients: Vec<i64> = windows
+            .iter()
+            .map(|window| {
+                servers
+                    .sum_part(server_index, &aggregation_name, (&window.0, &window.1))
+                    .total_individual_clients
+            })
+            .collect();
+        assert_eq!(clients, vec![10, 0]);
+
+        let mut sum_part_transport = servers.verifiable_transport(server_index);
+        let mut output_transport = servers.signable_transport(server_index);
+        let mut reducer = SumPartReducer::new(
+            &aggregation_name,
+            server_index,
+            &mut sum_part_transport,
+            &mut output_transport,
+        );
+        reducer.reduce(&windows).unwrap();
+        assert_eq!(reducer.report().inputs.len(), 2);
+        assert_eq!(reducer.report().outputs.len(), 1);
+        // A batch may only be counted once.
+        let err = reducer
+            .reduce(&[first_window, first_window, second_window])
+            .unwrap_err();
+        assert_matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::ParameterMismatchError(_))
+        );
+        // The reduced sum part may not overwrite one of its inputs.
+        for windows in &[vec![], vec![first_window], vec![first_window, first_window]] {
+            let err = reducer.reduce(windows).unwrap_err();
+            assert_matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::ConfigurationError(_))
+            );
+        }
+
+        let mut transport = servers.transport(server_index);
+        let mut reader: BatchReader<'_, SumPart, InvalidPacket> = BatchReader::new(
+            Batch::new_sum(
+                &aggregation_name,
+                &first_window.0,
+                &second_window.1,
+                server_index,
+            ),
+            &mut transport,
+        );
+        let sum_part = reader.header(&servers.pub_keys[server_index]).unwrap();
+        assert_eq!(sum_part.batch_uuids, batch_uuids.to_vec());
+        assert_eq!(
+            sum_part.aggregation_start_time,
+            first_window.0.timestamp_millis()
+        );
+        assert_eq!(
+            sum_part.aggregation_end_time,
+            second_window.1.timestamp_millis()
+        );
+        let mut invalid_packet_reader = reader.packet_file_reader(&sum_part).unwrap();
+        let mut invalid_packet_count = 0;
+        while InvalidPacket::read(&mut invalid_packet_reader).is_ok() {
+            invalid_packet_count += 1;
+        }
+        assert_eq!(invalid_packet_count, 10);
+        // The reduced sum part counts the clients of its inputs, not their
+        // twenty bins.
+        assert_eq!(sum_part.total_individual_clients, 10);
+        sums.push(sum_part.sum().unwrap());
+    }
+
     assert_eq!(
-        reconstructed.len() as i64,
-        facilitator_sum_part.total_individual_clients,
-        "Total individual clients does not match the length of sum\n\
-        \ttotal individual clients: {}\n\tlength of sum: {}",
-        facilitator_sum_part.total_individual_clients,
-        reconstructed.len()
+        reconstruct_shares(&sums[0], &sums[1]).unwrap(),
+        reference_sums[0]
     );
+
+    let mut report = StageReport::default();
+    let combined_sum = combine_sums(
+        &aggregation_name,
+        &first_window.0,
+        &second_window.1,
+        &mut [
+            servers.verifiable_transport(0),
+            servers.verifiable_transport(1),
+        ],
+        &mut report,
+    )
+    .unwrap();
+    let expected_histogram: Vec<i64> = reference_sums[0]
+        .iter()
+        .map(|f| u32::from(*f) as i64)
+        .collect();
+    assert_eq!(combined_sum.histogram, expected_histogram);
+    assert_eq!(combined_sum.batch_uuids, batch_uuids.to_vec());
+    assert_eq!(combined_sum.invalid_uuids.len(), 10);
+    assert_eq!(combined_sum.total_individual_clients, 10);
+    assert_eq!(report.inputs.len(), 2);
+
+    // The first window's sum parts hold every valid packet and no invalid ones.
+    let combined_sum = combine_sums(
+        &aggregation_name,
+        &first_window.0,
+        &first_window.1,
+        &mut [
+            servers.verifiable_transport(0),
+            servers.verifiable_transport(1),
+        ],
+        &mut report,
+    )
+    .unwrap();
+    assert_eq!(combined_sum.batch_uuids, vec![batch_uuids[0]]);
+    assert!(combined_sum.invalid_uuids.is_empty());
+    assert_eq!(combined_sum.histogram, expected_histogram);
+}
+
+#[test]
+fn cross_check_invalid_uuids() {
+    let servers = TwoServers::new();
+    let aggregation_name = "fake-aggregation-1".to_owned();
+    let date = NaiveDateTime::from_timestamp(2234567890, 654321);
+    let batch_uuid = Uuid::new_v4();
+
+    servers.generate_sample(&aggregation_name, &batch_uuid, &date, None);
+    servers.intake(&aggregation_name, &batch_uuid, &date);
+
+    let policy = AggregationPolicy {
+        cross_check_invalid_uuids: Some(true),
+        ..AggregationPolicy::default()
+    };
+    let aggregate = |server_index| {
+        servers.aggregate(
+            server_index,
+            &aggregation_name,
+            (&date, &date),
+            &[(batch_uuid, date)],
+            &policy,
+        )
+    };
+
+    // Until the facilitator has written the packets it found invalid, the PHA
+    // can't finish its sum part.
+    let (result, _) = aggregate(0);
+    let err = result.unwrap_err();
+    assert_matches!(
+        err.downcast_ref(),
+        Some(Error::PeerInvalidUuidsPendingError(peers)) if peers == &vec![1]
+    );
+    assert!(facilitator::is_retryable(&err));
+    assert!(!servers
+        .path(0)
+        .join(Batch::new_sum(&aggregation_name, &date, &date, 0).header_key())
+        .exists());
+
+    // The PHA has written its set, though, so the facilitator can finish.
+    let (result, report) = aggregate(1);
+    result.unwrap();
+    assert_eq!(report.packets.valid, 10);
+
+    let (result, report) = aggregate(0);
+    result.unwrap();
+    assert_eq!(report.packets.valid, 10);
+
+    // Were the facilitator to find a packet invalid that the PHA did not, the
+    // PHA would aggregate again without it.
+    let mut pha_transport = servers.transport(0);
+    let mut ingestion_batch: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+        BatchReader::new(
+            Batch::new_ingestion(&aggregation_name, &batch_uuid, &date),
+            &mut pha_transport,
+        );
+    let ingestion_header = ingestion_batch.header(&servers.ingestor_pub_keys).unwrap();
+    let excluded_uuid = IngestionDataSharePacket::read(
+        &mut ingestion_batch
+            .packet_file_reader(&ingestion_header)
+            .unwrap(),
+    )
+    .unwrap()
+    .uuid;
+    let mut facilitator_transport = servers.transport(1);
+    let mut writer: BatchWriter<'_, InvalidUuidSet, InvalidPacket> = BatchWriter::new(
+        Batch::new_invalid_uuid_set(&aggregation_name, &date, &date, 1),
+        &mut facilitator_transport,
+    );
+    let packet_file_digest = writer
+        .packet_file_writer(|packet_file_writer| {
+            InvalidPacket {
+                uuid: excluded_uuid,
+            }
+            .write(packet_file_writer)?;
+            Ok(())
+        })
+        .unwrap();
+    let signature = writer
+        .put_header(
+            &InvalidUuidSet {
+                batch_uuids: vec![batch_uuid],
+                name: aggregation_name.clone(),
+                aggregation_start_time: date.timestamp_millis(),
+                aggregation_end_time: date.timestamp_millis(),
+                packet_file_digest: packet_file_digest.as_ref().to_vec(),
+                cross_checked: true,
+            },
+            &default_facilitator_signing_private_key(),
+        )
+        .unwrap();
+    writer
+        .put_signature(&signature, &default_facilitator_signing_private_key())
+        .unwrap();
+
+    let registry = Registry::new();
+    let (result, report) = servers.aggregate_with_metrics(
+        0,
+        &aggregation_name,
+        (&date, &date),
+        &[(batch_uuid, date)],
+        &policy,
+        &registry,
+    );
+    result.unwrap();
+    assert_eq!(report.packets.total, 10);
+    assert_eq!(report.packets.valid, 9);
+    assert_eq!(report.packets.invalid, 1);
+    // The ingestion and validation batches are read once more, in the second
+    // pass, and the facilitator's invalid packets once.
+    assert_eq!(report.inputs.len(), 4);
+    assert_eq!(report.outputs.len(), 2);
+
+    let mut pha_transport = servers.transport(0);
+    let mut sum_part_batch: BatchReader<'_, SumPart, InvalidPacket> = BatchReader::new(
+        Batch::new_sum(&aggregation_name, &date, &date, 0),
+        &mut pha_transport,
+    );
+    let sum_part = sum_part_batch.header(&servers.pub_keys[0]).unwrap();
+    assert_eq!(
+        sum_part_batch.invalid_uuids(&sum_part).unwrap(),
+        vec![excluded_uuid]
+    );
+
+    // Only the second pass, whose packets are summed, is counted.
+    let metrics = registry.render();
+    for line in &[
+        "facilitator_batches_total{stage=\"aggregate\"} 1\n",
+        "facilitator_packets_total{stage=\"aggregate\"} 10\n",
+        "facilitator_invalid_packets_total{stage=\"aggregate\"} 1\n",
+        "facilitator_stage_duration_seconds_count{stage=\"aggregate\"} 1\n",
+    ] {
+        assert!(metrics.contains(line), "{} not in:\n{}", line, metrics);
+    }
+}
+
+#[test]
+fn cross_check_mismatch() {
+    let aggregation_name = "fake-aggregation-1".to_owned();
+    let date = NaiveDateTime::from_timestamp(2234567890, 654321);
+    let batch_uuid = Uuid::new_v4();
+    let cross_check = AggregationPolicy {
+        cross_check_invalid_uuids: Some(true),
+        ..AggregationPolicy::default()
+    };
+    let no_cross_check = AggregationPolicy::default();
+    let assert_mismatch = |result: anyhow::Result<()>| {
+        let err = result.unwrap_err();
+        assert_matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::ParameterMismatchError(_))
+        );
+        assert!(!facilitator::is_retryable(&err));
+    };
+
+    for pha_cross_checks in &[true, false] {
+        let servers = TwoServers::new();
+        servers.generate_sample(&aggregation_name, &batch_uuid, &date, None);
+        servers.intake(&aggregation_name, &batch_uuid, &date);
+        let aggregate = |server_index| {
+            let cross_checks = (server_index == 0) == *pha_cross_checks;
+            servers.aggregate(
+                server_index,
+                &aggregation_name,
+                (&date, &date),
+                &[(batch_uuid, date)],
+                if cross_checks {
+                    &cross_check
+                } else {
+                    &no_cross_check
+                },
+            )
+        };
+
+        // The server that cross-checks waits on the other's set, and once it
+        // is there finds that the other doesn't, rather than waiting forever.
+        let (cross_checking, other) = if *pha_cross_checks { (0, 1) } else { (1, 0) };
+        let (result, _) = aggregate(cross_checking);
+        assert!(facilitator::is_retryable(&result.unwrap_err()));
+        // The other server finds out too, from the set already written.
+        assert_mismatch(aggregate(other).0);
+        assert_mismatch(aggregate(cross_checking).0);
+    }
+
+    // Nor can a server without a peer cross-check invalid packets.
+    let servers = TwoServers::new();
+    let mut ingestors = servers.ingestors(0);
+    let mut own_validation_transport = servers.verifiable_transport(0);
+    let mut peer_validation_transports = vec![];
+    let mut aggregation_transport = servers.signable_transport(0);
+    let mut aggregator = BatchAggregator::new(
+        &aggregation_name,
+        &date,
+        &date,
+        0,
+        &mut ingestors,
+        &mut own_validation_transport,
+        &mut peer_validation_transports,
+        &mut aggregation_transport,
+    )
+    .unwrap();
+    let err = aggregator.set_policy(cross_check).unwrap_err();
+    assert_matches!(
+        err.downcast_ref::<Error>(),
+        Some(Error::ConfigurationError(_))
+    );
+}
+
+/// Runs the facilitator binary with args, returning whether it succeeded and
+/// the job report it wrote.
+fn run_facilitator(args: &[&str]) -> (bool, JobReport) {
+    let report_file = tempfile::NamedTempFile::new().unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_facilitator"))
+        // Keep settings in the environment from leaking into the arguments.
+        .env_clear()
+        .arg("--report")
+        .arg(report_file.path())
+        .args(args)
+        .status()
+        .unwrap();
+    let report = serde_json::from_reader(File::open(report_file.path()).unwrap()).unwrap();
+    (status.success(), report)
+}
+
+#[test]
+fn intake_batch_command() {
+    let tempdir = tempfile::TempDir::new().unwrap();
+    let dir = |name: &str| tempdir.path().join(name).to_str().unwrap().to_owned();
+    let batch_uuid = Uuid::new_v4();
+    let date = "2020/10/01/12/00";
+
+    let (success, _) = run_facilitator(&[
+        "generate-ingestion-sample",
+        "--peer-output",
+        &dir("pha-ingestion"),
+        "--own-output",
+        &dir("facilitator-ingestion"),
+        "--batch-id",
+        &batch_uuid.to_string(),
+        "--date",
+        date,
+        "--packet-count",
+        "10",
+    ]);
+    assert!(success);
+
+    let (success, report) = run_facilitator(&[
+        "intake-batch",
+        "--ingestor-input",
+        &dir("facilitator-ingestion"),
+        "--peer-output",
+        &dir("validations"),
+        "--batch-id",
+        &batch_uuid.to_string(),
+        "--date",
+        date,
+    ]);
+    assert!(success, "{:?}", report);
+    assert!(report.success);
+    assert_eq!(report.subcommand, "intake-batch");
+    assert_eq!(report.stage.inputs.len(), 1);
+    assert_eq!(report.stage.outputs.len(), 1);
+    assert_eq!(report.stage.packets.total, 10);
+    let validation_batch = Batch::new_validation(
+        "fake-aggregation",
+        &batch_uuid,
+        &NaiveDateTime::parse_from_str(date, facilitator::DATE_FORMAT).unwrap(),
+        1,
+    );
+    for key in &[
+        validation_batch.header_key(),
+        validation_batch.packet_file_key(),
+        validation_batch.signature_key(),
+    ] {
+        assert!(Path::new(&dir("validations")).join(key).exists());
+    }
+}
+
+#[test]
+fn intake_batch_from_named_ingestors() {
+    let tempdir = tempfile::TempDir::new().unwrap();
+    let dir = |name: &str| tempdir.path().join(name).to_str().unwrap().to_owned();
+    let batch_uuid = Uuid::new_v4();
+    let date = "2020/10/01/12/00";
+
+    // Only the second ingestor wrote the batch.
+    let (success, _) = run_facilitator(&[
+        "generate-ingestion-sample",
+        "--peer-output",
+        &dir("google/pha"),
+        "--own-output",
+        &dir("google/facilitator"),
+        "--batch-id",
+        &batch_uuid.to_string(),
+        "--date",
+        date,
+    ]);
+    assert!(success);
+
+    let intake = |server: &str, server_args: &[&str], batch_uuid: &Uuid| {
+        let mut args = vec![
+            "intake-batch",
+            "--ingestor-name",
+            "apple",
+            "--ingestor-name",
+            "google",
+        ];
+        let apple_input = dir(&format!("apple/{}", server));
+        let google_input = dir(&format!("google/{}", server));
+        let validations = dir(&format!("{}-validations", server));
+        let batch_uuid = batch_uuid.to_string();
+        args.extend_from_slice(&[
+            "--ingestor-input",
+            &apple_input,
+            "--ingestor-input",
+            &google_input,
+            "--peer-output",
+            &validations,
+            "--batch-id",
+            &batch_uuid,
+            "--date",
+            date,
+        ]);
+        args.extend_from_slice(server_args);
+        run_facilitator(&args)
+    };
+
+    for (server, server_args) in &[
+        (
+            "pha",
+            &[
+                "--is-first",
+                "--packet-decryption-keys",
+                DEFAULT_PHA_ECIES_PRIVATE_KEY,
+            ][..],
+        ),
+        ("facilitator", &[][..]),
+    ] {
+        let (success, report) = intake(server, server_args, &batch_uuid);
+        assert!(success, "{:?}", report);
+        assert_eq!(report.stage.packets.total, 10);
+        assert_eq!(report.stage.inputs.len(), 1);
+        assert_eq!(report.stage.inputs[0].ingestor.as_deref(), Some("google"));
+        assert_eq!(report.stage.outputs.len(), 1);
+        assert!(Path::new(&dir(&format!("{}-validations", server)))
+            .join(&report.stage.outputs[0].signature_key)
+            .exists());
+    }
+
+    // A batch that neither ingestor has written yet may show up later.
+    let (success, report) = intake("facilitator", &[], &Uuid::new_v4());
+    assert!(!success);
+    assert_eq!(report.retryable, Some(true));
 }